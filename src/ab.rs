@@ -0,0 +1,202 @@
+//! Double-buffered storage, for filesystems without atomic renames
+//!
+//! The value alternates between two slot files, `<blob>.a` and
+//! `<blob>.b`, and a small `<blob>.ptr` file names the current one.
+//! A commit rewrites the other slot in place and syncs it, then
+//! rewrites the pointer, so a commit torn before the pointer changes
+//! leaves the previous slot current. Slots and the pointer carry an
+//! epoch and a checksum, so a torn pointer is detected too, and the
+//! newest intact slot is used instead.
+
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use super::fnv1a;
+use super::vfs::{FileId, Fs};
+
+const MAGIC: &str = "ab1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    fn path(self, p: &Path) -> PathBuf {
+        match self {
+            Slot::A => p.with_extension("a"),
+            Slot::B => p.with_extension("b"),
+        }
+    }
+
+    fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Slot::A => "a",
+            Slot::B => "b",
+        }
+    }
+}
+
+// The current slot and its epoch
+type Current = (u64, Slot);
+
+/// Opens the current value, with the epoch as its id
+pub fn open(fs: &dyn Fs, p: &Path) -> io::Result<Option<(Box<dyn Read>, FileId)>> {
+    match current(fs, p)? {
+        Some(((epoch, _), buf)) => {
+            let r: Box<dyn Read> = Box::new(Cursor::new(buf));
+            Ok(Some((r, FileId::counter(epoch))))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn id(fs: &dyn Fs, p: &Path) -> io::Result<Option<FileId>> {
+    if let Some((epoch, _)) = read_ptr(fs, p)? {
+        return Ok(Some(FileId::counter(epoch)));
+    }
+    Ok(current(fs, p)?.map(|((epoch, _), _)| FileId::counter(epoch)))
+}
+
+/// Makes `buf` the current value
+pub fn store(fs: &dyn Fs, p: &Path, buf: &[u8]) -> io::Result<()> {
+    // An intact pointer is only written once its slot is synced, so
+    // the slot needn't be read back to trust it
+    let current = match read_ptr(fs, p)? {
+        Some(current) => Some(current),
+        None => current(fs, p)?.map(|(current, _)| current),
+    };
+    let (epoch, slot) = match current {
+        Some((epoch, slot)) => (epoch + 1, slot.other()),
+        None => (0, Slot::A),
+    };
+
+    let slot_path = slot.path(p);
+    let mut out = fs.create(&slot_path)?;
+    writeln!(out, "{} {} {} {:016x}", MAGIC, epoch, buf.len(), fnv1a(buf))?;
+    out.write_all(buf)?;
+    out.flush()?;
+    drop(out);
+    fs.sync(&slot_path)?;
+
+    let ptr_path = p.with_extension("ptr");
+    let line = format!("{} {} {}", MAGIC, epoch, slot.name());
+    let mut out = fs.create(&ptr_path)?;
+    writeln!(out, "{} {:016x}", line, fnv1a(line.as_bytes()))?;
+    out.flush()?;
+    drop(out);
+    fs.sync(&ptr_path)
+}
+
+pub fn sync(fs: &dyn Fs, p: &Path) -> io::Result<()> {
+    for path in &[Slot::A.path(p), Slot::B.path(p), p.with_extension("ptr")] {
+        if fs.id(path)?.is_some() {
+            fs.sync(path)?;
+        }
+    }
+    Ok(())
+}
+
+// The current slot and the value in it, trusting the pointer only if
+// the slot it names is intact
+fn current(fs: &dyn Fs, p: &Path) -> io::Result<Option<(Current, Vec<u8>)>> {
+    if let Some((epoch, slot)) = read_ptr(fs, p)? {
+        if let Some((slot_epoch, buf)) = read_slot(fs, &slot.path(p))? {
+            if slot_epoch == epoch {
+                return Ok(Some(((epoch, slot), buf)));
+            }
+        }
+        warn!("blobject pointer names a bad slot, scanning slots");
+    }
+
+    let mut found = None;
+    let mut any = false;
+    for &slot in &[Slot::A, Slot::B] {
+        let path = slot.path(p);
+        any |= fs.id(&path)?.is_some();
+        if let Some((epoch, buf)) = read_slot(fs, &path)? {
+            match found {
+                Some(((e, _), _)) if e >= epoch => (),
+                _ => found = Some(((epoch, slot), buf)),
+            }
+        }
+    }
+
+    if found.is_none() && any {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "no intact blobject slot"));
+    }
+    Ok(found)
+}
+
+fn read_ptr(fs: &dyn Fs, p: &Path) -> io::Result<Option<Current>> {
+    let buf = match read_all(fs, &p.with_extension("ptr"))? {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+    let text = String::from_utf8_lossy(&buf);
+    let text = text.trim_end();
+    let (line, check) = match text.rsplit_once(' ') {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    if u64::from_str_radix(check, 16).ok() != Some(fnv1a(line.as_bytes())) {
+        return Ok(None);
+    }
+
+    let mut fields = line.split(' ');
+    let current = match (fields.next(), fields.next(), fields.next()) {
+        (Some(MAGIC), Some(epoch), Some("a")) => epoch.parse().ok().map(|e| (e, Slot::A)),
+        (Some(MAGIC), Some(epoch), Some("b")) => epoch.parse().ok().map(|e| (e, Slot::B)),
+        _ => None,
+    };
+    Ok(current)
+}
+
+// The epoch and value in a slot, or `None` if it's missing or torn
+fn read_slot(fs: &dyn Fs, p: &Path) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let mut buf = match read_all(fs, p)? {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+    let end = match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+
+    let header = String::from_utf8_lossy(&buf[..end]).into_owned();
+    let mut fields = header.split(' ');
+    let (epoch, len, hash) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+        (Some(MAGIC), Some(epoch), Some(len), Some(hash)) => {
+            match (epoch.parse::<u64>(), len.parse::<usize>(), u64::from_str_radix(hash, 16)) {
+                (Ok(epoch), Ok(len), Ok(hash)) => (epoch, len, hash),
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    buf.drain(..end + 1);
+    if buf.len() != len || fnv1a(&buf) != hash {
+        return Ok(None);
+    }
+    Ok(Some((epoch, buf)))
+}
+
+fn read_all(fs: &dyn Fs, p: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs.open(p)? {
+        Some((mut f, _)) => {
+            let mut buf = Vec::new();
+            f.read_to_end(&mut buf)?;
+            Ok(Some(buf))
+        }
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,167 @@
+//! Taking over a file written by something else, and handing it back
+//!
+//! `Builder::adopt` opens a plain JSON file that was written without
+//! a blob handle, by hand or by an older writer, and rewrites it
+//! under the exclusive flock in the blob's own format. Every handle
+//! opened on it afterwards, including the adopter, manages it as
+//! usual. Adopting a file that's already managed just opens it, so
+//! any number of processes can start by adopting.
+//!
+//! `AtomBlob::detach` does the reverse, leaving the value as plain
+//! JSON at the blob's path with none of the blob's lock or metadata
+//! files beside it, for a different tool to take over.
+//!
+//! The flock only excludes blob handles: whatever wrote the file
+//! before must have stopped writing it, and other handles must be
+//! closed before detaching.
+
+use serde::{Serialize, Deserialize};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use super::{AtomBlob, Builder, Held, Parse, Result, ResultExt, Strategy, TypedBuilder,
+            chunk, envelope, lock, poisoned, ser_reload, ser_store_with, syncs_as_it_goes,
+            validate};
+#[cfg(unix)]
+use super::emergency;
+
+impl Builder {
+    /// Opens the existing plain JSON file at the builder's path,
+    /// rewriting it as a managed blob if it isn't one
+    ///
+    /// Fails if there's no file, or it doesn't parse or validate as
+    /// `T`. With `Strategy::DoubleBuffer` the value is copied into the
+    /// blob's slots, and the plain file is left as it was.
+    pub fn adopt<T>(self) -> Result<AtomBlob<T>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+    {
+        self.typed().adopt()
+    }
+}
+
+impl<T> TypedBuilder<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    /// Like `Builder::adopt`, validating the plain file, and writing
+    /// its legacy copy, as the typed options say
+    pub fn adopt(mut self) -> Result<AtomBlob<T>> {
+        self.builder = self.builder.lazy(true);
+        let mut blob = self.open()?;
+        blob.take_over()?;
+        Ok(blob)
+    }
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// Like `new`, but adopts an existing plain file; see
+    /// `Builder::adopt`
+    pub fn adopt<P>(p: P) -> Result<AtomBlob<T>>
+        where P: AsRef<Path>, T: Send + Sync + 'static,
+    {
+        Builder::new(p).adopt()
+    }
+
+    fn take_over(&mut self) -> Result<()> {
+        let _held = Held::enter(self.key())?;
+        let parts = self.parts();
+        let _flock = {
+            let _intent = match parts.intent {
+                Some(i) => Some(i.lock_exclusive()?),
+                None => None,
+            };
+            parts.wait_for_flock(true)?
+        };
+        #[cfg(unix)]
+        let _in_flight = emergency::InFlight::new();
+        let shared = &*self.shared;
+        let mut val = shared.v.write().map_err(poisoned)?;
+
+        // Adopted already, by this or another process
+        let managed = envelope::reload::<T>(parts.fs, shared.strategy, parts.path, None,
+                                            shared.parse());
+        if let Some(Ok(r)) = managed {
+            let (v, id) = r.ok_or("reloading unknown blobject file")?;
+            validate(shared.validate.as_deref(), &v.value)?;
+            *lock(&shared.committed_at) = v.committed_at();
+            shared.hashed(Some(&id));
+            *lock(&shared.loaded) = Some(id);
+            *val = v.value;
+            shared.loaded_now(true);
+            shared.unloaded.store(false, Ordering::SeqCst);
+            debug!("adopted blobject was already managed");
+            return Ok(());
+        }
+
+        let parse = Parse { enveloped: false, ..shared.parse() };
+        let plain = ser_reload::<T>(parts.fs, Strategy::Rename, parts.path, None, parse);
+        let v = match plain {
+            Some(r) => r?.ok_or("reloading unknown blobject file")?.0,
+            None => return Err(format!("no file to adopt at {}", parts.path.display()).into()),
+        };
+        validate(shared.validate.as_deref(), &v)?;
+
+        let stamp = shared.stamp();
+        parts.commit_with(stamp.committed_at, None, |fs, strategy, p| {
+            parts.dual_write(Some(&v))?;
+            let bytes = envelope::store(fs, strategy, p, &v, stamp, shared.store())?;
+            Ok((bytes, syncs_as_it_goes(strategy)))
+        })?;
+        *val = v;
+        shared.unloaded.store(false, Ordering::SeqCst);
+
+        debug!("adopted blobject");
+
+        Ok(())
+    }
+
+    /// Leaves the committed value as a plain JSON file at the blob's
+    /// path, synced, and removes the blob's lock and metadata files
+    ///
+    /// The value is rewritten without the envelope, and with
+    /// `Strategy::DoubleBuffer` or `Strategy::Chunked` copied out of
+    /// the slots or chunks. History
+    /// generations and archives are left as they are. Other handles
+    /// on the blob, in this process or others, must be closed first:
+    /// with the flock removed they no longer exclude each other.
+    pub fn detach(self) -> Result<()> {
+        let _held = Held::enter(self.key())?;
+        let parts = self.parts();
+        let flock = {
+            let _intent = match parts.intent {
+                Some(i) => Some(i.lock_exclusive()?),
+                None => None,
+            };
+            parts.wait_for_flock(true)?
+        };
+        #[cfg(unix)]
+        let _in_flight = emergency::InFlight::new();
+        let shared = &*self.shared;
+        let mut val = shared.v.write().map_err(poisoned)?;
+        if parts.needs_reload(&flock) {
+            parts.reload(&mut val)?;
+        }
+
+        if shared.strategy != Strategy::Rename || shared.enveloped() {
+            ser_store_with(parts.fs, Strategy::Rename, parts.path, &*val, shared.store())?;
+        }
+        if shared.strategy == Strategy::Chunked {
+            chunk::remove_all(parts.fs, parts.path).chain_err(|| "removing blobject chunks")?;
+        }
+
+        for ext in &["a", "b", "ptr", "cache", "intent", "flock"] {
+            match parts.fs.remove(&parts.path.with_extension(ext)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                r => r.chain_err(|| format!("removing blobject .{} file", ext))?,
+            }
+        }
+        // Also makes the removals durable
+        parts.fs.sync(parts.path)
+            .chain_err(|| "syncing detached blobject")?;
+
+        debug!("blobject detached");
+
+        Ok(())
+    }
+}
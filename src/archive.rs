@@ -0,0 +1,180 @@
+//! Content-addressed copies of a blob
+//!
+//! `AtomBlob::archive` copies the committed file to
+//! `<stem>-<sha256>.<ext>` next to the blob, and appends the hash and
+//! time to the index in `<blob>.archive`. Archives are never modified,
+//! and archiving an unchanged blob again only adds to the index, so
+//! keeping many snapshots of a slowly changing blob is cheap.
+
+use serde::{Serialize, Deserialize};
+use serde_derive::{Serialize as SerializeDerive, Deserialize as DeserializeDerive};
+use std::collections::BTreeSet;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use super::{AtomBlob, FlockGuard, Held, Result, ResultExt, Strategy, envelope, json_to_vec,
+            tmp_file};
+use super::sha256;
+use super::vfs::Fs;
+
+/// An entry in a blob's archive index
+#[derive(Clone, Debug, PartialEq, Eq, SerializeDerive, DeserializeDerive)]
+pub struct Archived {
+    /// Lowercase hex SHA-256 of the archived file
+    pub hash: String,
+    pub archived_at: SystemTime,
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// Archives the committed value, returning its index entry
+    ///
+    /// Fails if nothing has been committed.
+    pub fn archive(&mut self) -> Result<Archived> {
+        let _held = Held::enter(self.key())?;
+        // Exclusive, so archivers don't race on the index
+        let _flock = {
+            let _intent = match self.intent {
+                Some(ref i) => Some(i.lock_exclusive()?),
+                None => None,
+            };
+            FlockGuard::exclusive(&self.flock)?
+        };
+
+        let (fs, path) = (&*self.fs, &*self.path);
+        let buf = match self.shared.strategy.open(fs, path)
+            .chain_err(|| "opening blobject to archive")?
+        {
+            Some((mut r, _)) => {
+                let mut buf = Vec::new();
+                r.read_to_end(&mut buf).chain_err(|| "reading blobject to archive")?;
+                buf
+            }
+            None => return Err("no committed blobject to archive".into()),
+        };
+
+        let hash = sha256::hex(&buf);
+        let archive = archive_path(path, &hash);
+        if fs.id(&archive).chain_err(|| "archiving blobject")?.is_none() {
+            write(fs, &archive, &buf).chain_err(|| "archiving blobject")?;
+        }
+
+        let entry = Archived {
+            hash,
+            archived_at: SystemTime::now(),
+        };
+        let mut index = read_index(fs, path)?;
+        index.push(entry.clone());
+        let index_buf = json_to_vec(&index)
+            .chain_err(|| "serializing blobject archive index")?;
+        write(fs, &index_path(path), &index_buf)
+            .chain_err(|| "writing blobject archive index")?;
+
+        debug!("archived blobject as {}", entry.hash);
+
+        Ok(entry)
+    }
+
+    /// The archive index, oldest first
+    pub fn archives(&self) -> Result<Vec<Archived>> {
+        read_index(&*self.fs, &self.path)
+    }
+
+    /// Commits the archived value with this hash
+    ///
+    /// Fails if the archive is missing or doesn't match its hash.
+    pub fn restore_from_archive(&mut self, hash: &str) -> Result<()> {
+        if hash.len() != 64 || !hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return Err(format!("{:?} isn't a blobject archive hash", hash).into());
+        }
+        let (fs, path) = (self.fs.clone(), self.path.clone());
+        let archive = archive_path(&path, hash);
+        let buf = match fs.open(&archive).chain_err(|| "opening blobject archive")? {
+            Some((mut r, _)) => {
+                let mut buf = Vec::new();
+                r.read_to_end(&mut buf).chain_err(|| "reading blobject archive")?;
+                buf
+            }
+            None => return Err(format!("no blobject archive {}", hash).into()),
+        };
+        if sha256::hex(&buf) != hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("blobject archive {} is corrupt", hash)).into());
+        }
+
+        let v = match envelope::reload(&*fs, Strategy::Rename, &archive, None,
+                                       self.shared.parse()) {
+            Some(r) => r?.expect("reloading unknown file").0.value,
+            None => return Err(format!("no blobject archive {}", hash).into()),
+        };
+        let mut guard = self.get_mut()?;
+        *guard = v;
+        guard.commit()?;
+
+        debug!("restored blobject from archive {}", hash);
+
+        Ok(())
+    }
+}
+
+fn read_index(fs: &dyn Fs, p: &Path) -> Result<Vec<Archived>> {
+    match fs.open(&index_path(p)).chain_err(|| "opening blobject archive index")? {
+        Some((r, _)) => serde_json::from_reader(BufReader::new(r))
+            .chain_err(|| "parsing blobject archive index"),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Durably replaces the file at `p` with `buf`
+fn write(fs: &dyn Fs, p: &Path, buf: &[u8]) -> io::Result<()> {
+    let (tmp_path, mut out) = tmp_file(fs, p)?;
+    out.write_all(buf)?;
+    out.flush()?;
+    drop(out);
+    fs.rename(&tmp_path, p)?;
+    fs.sync(p)
+}
+
+fn archive_path(p: &Path, hash: &str) -> PathBuf {
+    let stem = p.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let name = match p.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, hash, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, hash),
+    };
+    p.with_file_name(name)
+}
+
+/// Moves the archives and index of `from` to `to`
+pub(crate) fn rename(fs: &dyn Fs, from: &Path, to: &Path) -> Result<()> {
+    let index = read_index(fs, from)?;
+    let hashes: BTreeSet<&str> = index.iter().map(|a| &*a.hash).collect();
+    for hash in hashes {
+        match fs.rename(&archive_path(from, hash), &archive_path(to, hash)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            r => r.chain_err(|| "moving blobject archive")?,
+        }
+    }
+    if !index.is_empty() {
+        fs.rename(&index_path(from), &index_path(to))
+            .chain_err(|| "moving blobject archive index")?;
+    }
+    Ok(())
+}
+
+/// Whether `p` is named like an archive of some blob
+pub(crate) fn is_archive(p: &Path) -> bool {
+    let stem = match p.file_stem().and_then(|s| s.to_str()) {
+        Some(stem) => stem,
+        None => return false,
+    };
+    match stem.rsplit_once('-') {
+        Some((_, hash)) => hash.len() == 64
+            && hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)),
+        None => false,
+    }
+}
+
+fn index_path(p: &Path) -> PathBuf {
+    p.with_extension("archive")
+}
@@ -0,0 +1,416 @@
+//! Tokio-friendly blob handles
+//!
+//! `AsyncAtomBlob` follows the same protocol as `AtomBlob`, but lock
+//! acquisition, reloads and commits run on the blocking pool, and the
+//! guards own their locks. Guards are `Send` so a task can hold
+//! exclusive access across `.await`s, e.g. to make a network call
+//! between reading and writing the blob.
+//!
+//! Commits store the file as an `AtomBlob`'s do, then sync it and its
+//! directory before they return, since waiting on the disk doesn't
+//! hold up the runtime.
+
+use serde::{Serialize, Deserialize};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock,
+                  OwnedRwLockReadGuard, OwnedRwLockWriteGuard};
+use tokio::task;
+use super::{Builder, Parse, Policy, Result, ResultExt, Store, envelope, ser_store_bytes, wait};
+use super::flock::{DirtyFlock, State};
+
+pub struct AsyncAtomBlob<T> {
+    flock: Arc<Mutex<DirtyFlock>>,
+    v: Arc<RwLock<T>>,
+    options: Arc<Builder>,
+}
+
+impl Builder {
+    /// Opens the blob for use from async code
+    ///
+    /// Commits keep the builder's filesystem, including `io_uring`'s,
+    /// strategy, stamps and write buffering, and loads its parsing
+    /// options. The guards never panic, so `strict_no_panic` holds
+    /// too. Options only an `AtomBlob` can keep fail here, rather than
+    /// being ignored: writer priority, acquire modes other than
+    /// `Acquire::Block`, lock wait hooks, idle timeouts, lazy opens,
+    /// revalidation, reload deadlines, staleness, background and group
+    /// syncs, conflict policies, reload warnings, kept generations,
+    /// mirrors, dual writes, validators, commit hooks, content hashes,
+    /// space checks, read repair and sidecars.
+    pub async fn open_async<T>(self) -> Result<AsyncAtomBlob<T>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+    {
+        if let Some(option) = unsupported(&self) {
+            return Err(format!("async blobjects can't keep the {} option", option).into());
+        }
+        let options = Arc::new(self.pin()?);
+        let flock = new_flock(&options);
+        let v = load(options.clone()).await?;
+        Ok(AsyncAtomBlob {
+            flock: Arc::new(Mutex::new(flock)),
+            v: Arc::new(RwLock::new(v)),
+            options,
+        })
+    }
+}
+
+impl<T> AsyncAtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    pub async fn new<P>(p: P) -> Result<AsyncAtomBlob<T>>
+        where P: AsRef<Path>,
+    {
+        Builder::new(p).open_async().await
+    }
+
+    pub async fn get(&self) -> Result<AsyncBlobRef<T>> {
+        let (flock, state) = lock(self.flock.clone(), false).await?;
+        let v = if state == State::Dirty {
+            let mut val = self.v.clone().write_owned().await;
+            match load(self.options.clone()).await {
+                Ok(newval) => *val = newval,
+                Err(e) => {
+                    unlock(&flock);
+                    return Err(e);
+                }
+            }
+            val.downgrade()
+        } else {
+            self.v.clone().read_owned().await
+        };
+        Ok(AsyncBlobRef {
+            v: Some(v),
+            flock,
+        })
+    }
+
+    pub async fn get_mut(&self) -> Result<AsyncBlobMutRef<T>> {
+        let (flock, state) = lock(self.flock.clone(), true).await?;
+        let mut v = self.v.clone().write_owned().await;
+        if state == State::Dirty {
+            match load(self.options.clone()).await {
+                Ok(newval) => *v = newval,
+                Err(e) => {
+                    unlock(&flock);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(AsyncBlobMutRef {
+            v: Some(v),
+            flock: Some(flock),
+            options: self.options.clone(),
+            committed: false,
+        })
+    }
+
+    /// Flushes the committed blob to stable storage, then releases
+    /// this handle, as `AtomBlob::shutdown`
+    ///
+    /// Waits first for guards that are committing as they drop, from
+    /// any handle.
+    pub async fn shutdown(self) -> Result<()> {
+        let (flock, _) = lock(self.flock.clone(), true).await?;
+        // Guards committing on drop keep the value until it's stored
+        let v = self.v.clone().write_owned().await;
+        let options = self.options.clone();
+        let r = task::spawn_blocking(move || options.strategy.sync(&*options.fs, &options.path))
+            .await;
+        // NB: Lock drop order
+        drop(v);
+        unlock(&flock);
+        r.chain_err(|| "joining shutdown task")?
+            .chain_err(|| "syncing blobject on shutdown")?;
+
+        debug!("blobject shut down");
+
+        Ok(())
+    }
+
+    /// Waits for `token` to complete, then shuts down as `shutdown`
+    ///
+    /// `token` is whatever the program signals shutdown with, e.g.
+    /// `CancellationToken::cancelled_owned`. Spawned as a task with a
+    /// clone of the handle, the blob is on disk once the task is
+    /// joined.
+    pub async fn shutdown_on<F>(self, token: F) -> Result<()>
+        where F: Future<Output = ()>
+    {
+        token.await;
+        self.shutdown().await
+    }
+
+    pub fn clone(&self) -> AsyncAtomBlob<T> {
+        AsyncAtomBlob {
+            flock: Arc::new(Mutex::new(new_flock(&self.options))),
+            v: self.v.clone(),
+            options: self.options.clone(),
+        }
+    }
+}
+
+pub struct AsyncBlobRef<T> {
+    v: Option<OwnedRwLockReadGuard<T>>,
+    flock: OwnedMutexGuard<DirtyFlock>,
+}
+
+pub struct AsyncBlobMutRef<T: Serialize + Send + Sync + 'static> {
+    v: Option<OwnedRwLockWriteGuard<T>>,
+    flock: Option<OwnedMutexGuard<DirtyFlock>>,
+    options: Arc<Builder>,
+    committed: bool,
+}
+
+impl<T> Deref for AsyncBlobRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.v.as_ref().expect("live guard")
+    }
+}
+
+impl<T> Drop for AsyncBlobRef<T> {
+    fn drop(&mut self) {
+        // NB: Lock drop order
+        drop(self.v.take());
+        unlock(&self.flock);
+    }
+}
+
+impl<T> Deref for AsyncBlobMutRef<T>
+    where T: Serialize + Send + Sync + 'static
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.v.as_ref().expect("live guard")
+    }
+}
+
+impl<T> DerefMut for AsyncBlobMutRef<T>
+    where T: Serialize + Send + Sync + 'static
+{
+    fn deref_mut(&mut self) -> &mut T {
+        self.v.as_mut().expect("live guard")
+    }
+}
+
+impl<T> AsyncBlobMutRef<T>
+    where T: Serialize + Send + Sync + 'static
+{
+    pub async fn commit(&mut self) -> Result<()> {
+        let v = self.v.as_ref().expect("live guard");
+        let buf = envelope::to_vec(&**v, stamp(&self.options))?;
+        let options = self.options.clone();
+        task::spawn_blocking(move || store(&options, &buf)).await
+            .chain_err(|| "joining commit task")??;
+        self.committed = true;
+
+        debug!("new blobject committed");
+
+        Ok(())
+    }
+}
+
+impl<T> Drop for AsyncBlobMutRef<T>
+    where T: Serialize + Send + Sync + 'static
+{
+    fn drop(&mut self) {
+        let v = self.v.take().expect("live guard");
+        let flock = self.flock.take().expect("live guard");
+        let finish = move |commit: Option<Arc<Builder>>| {
+            if let Some(options) = commit {
+                let r = envelope::to_vec(&*v, stamp(&options))
+                    .and_then(|buf| store(&options, &buf));
+                if let Err(e) = r {
+                    error!("blobject failed to commit on drop: {}", e);
+                }
+            }
+            // NB: Lock drop order
+            drop(v);
+            unlock(&flock);
+        };
+        let commit = if self.committed { None } else { Some(self.options.clone()) };
+
+        // Committing on drop must not block the runtime; the locks
+        // move to the blocking pool and are released there
+        match (commit, Handle::try_current()) {
+            (Some(options), Ok(handle)) => {
+                handle.spawn_blocking(move || finish(Some(options)));
+            }
+            (commit, _) => finish(commit),
+        }
+    }
+}
+
+async fn lock(flock: Arc<Mutex<DirtyFlock>>, exclusive: bool)
+              -> Result<(OwnedMutexGuard<DirtyFlock>, State)>
+{
+    let flock = flock.lock_owned().await;
+    let r = task::spawn_blocking(move || {
+        let state = if exclusive {
+            flock.lock_exclusive()?
+        } else {
+            flock.lock_shared()?
+        };
+        if let Err(e) = flock.check(exclusive, state) {
+            unlock(&flock);
+            return Err(e);
+        }
+        Ok((flock, state))
+    }).await;
+    r.chain_err(|| "joining flock task")?
+}
+
+// The flock a sync handle on the same blob takes, so the two exclude
+// each other
+fn new_flock(b: &Builder) -> DirtyFlock {
+    DirtyFlock::new(b.fs.lock_path(&b.path.with_extension("flock")), b.fs.in_process())
+}
+
+fn unlock(flock: &DirtyFlock) {
+    if let Err(e) = flock.unlock() {
+        error!("dropping async flock: {}", e);
+    }
+}
+
+async fn load<T>(options: Arc<Builder>) -> Result<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + 'static
+{
+    let r = task::spawn_blocking(move || {
+        let b = &*options;
+        let parse = Parse { lenient: b.lenient, on_error: b.on_load_error.as_deref(),
+                            enveloped: b.enveloped(), buffer_size: b.buffer_size,
+                            max_schema: if b.refuse_newer { b.schema_version } else { None },
+                            mirror: None, deny_duplicates: b.deny_duplicates,
+                            deny_unknown: b.deny_unknown, hash: None };
+        envelope::reload(&*b.fs, b.strategy, &b.path, None, parse)
+    }).await.chain_err(|| "joining load task")?;
+    match r {
+        Some(v) => {
+            let (v, _) = v?.ok_or("reloading unknown blobject file")?;
+            Ok(v.value)
+        }
+        None => Ok(T::default()),
+    }
+}
+
+// What to record with a commit made now
+fn stamp(b: &Builder) -> envelope::Stamp {
+    envelope::Stamp {
+        committed_at: if b.commit_times { Some(b.clock.system_time()) } else { None },
+        schema: b.schema_version,
+    }
+}
+
+// Stores a serialized value as `AtomBlob` commits do, then syncs it.
+// Blocks.
+fn store(b: &Builder, buf: &[u8]) -> Result<()> {
+    let store = Store {
+        buffer_size: b.buffer_size,
+        preallocate: b.preallocate,
+        direct: b.direct_io,
+        chunk_size: Some(b.chunk_size),
+        ..Store::default()
+    };
+    ser_store_bytes(&*b.fs, b.strategy, &b.path, buf, store)?;
+    b.strategy.sync(&*b.fs, &b.path)
+        .chain_err(|| "syncing blobject commit")
+}
+
+// The first option set that async handles can't keep
+fn unsupported(b: &Builder) -> Option<&'static str> {
+    #[cfg(feature = "sidecar")]
+    if b.sidecar {
+        return Some("sidecar");
+    }
+    let options = [
+        (b.writer_priority, "writer_priority"),
+        (b.acquire != wait::Acquire::Block, "acquire"),
+        (b.on_lock_wait.is_some(), "on_lock_wait"),
+        (b.idle_timeout.is_some(), "idle_timeout"),
+        (b.lazy, "lazy"),
+        (b.revalidate_every.is_some(), "revalidate_every"),
+        (b.reload_deadline.is_some(), "reload_deadline"),
+        (b.max_staleness.is_some(), "max_staleness"),
+        (b.background_sync.is_some(), "background_sync or group_commit"),
+        (!matches!(b.policy, Policy::FailOnConflict), "conflict_policy"),
+        (b.warn_on_reloads.is_some(), "warn_on_reloads"),
+        (b.keeps_generations(), "keep_generations"),
+        (b.mirror.is_some(), "mirror"),
+        (b.on_commit.is_some(), "on_commit"),
+        (b.content_hashes, "content_hashes"),
+        (b.space_margin.is_some(), "check_space"),
+        (b.read_repair, "read_repair"),
+    ];
+    options.iter().find(|(set, _)| *set).map(|&(_, name)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use tokio::runtime::{self, Runtime};
+    use super::*;
+    use super::super::AtomBlob;
+    use super::super::vfs::MockFs;
+
+    fn runtime() -> Runtime {
+        runtime::Builder::new_current_thread().build().unwrap()
+    }
+
+    #[test]
+    fn excludes_sync_handles() {
+        let fs = MockFs::new();
+        let rt = runtime();
+        let mut blob: AtomBlob<Vec<u32>> = Builder::new("async.json").fs(fs.clone()).open().unwrap();
+        let a = rt.block_on(Builder::new("async.json").fs(fs.clone()).open_async::<Vec<u32>>())
+            .unwrap();
+        let released = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::channel();
+        let released2 = released.clone();
+        let t = thread::spawn(move || {
+            let mut g = blob.get_mut().unwrap();
+            g.push(1);
+            tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            released2.store(true, Ordering::SeqCst);
+        });
+        rx.recv().unwrap();
+        rt.block_on(async {
+            let mut g = a.get_mut().await.unwrap();
+            assert!(released.load(Ordering::SeqCst));
+            assert_eq!(*g, vec![1]);
+            g.push(2);
+            g.commit().await.unwrap();
+        });
+        t.join().unwrap();
+        let stored: Vec<u32> = serde_json::from_slice(&fs.read("async.json").unwrap()).unwrap();
+        assert_eq!(stored, vec![1, 2]);
+        // Locked in memory, as the sync handle was
+        assert!(!Path::new("async.flock").exists());
+    }
+
+    #[test]
+    fn refuses_options_it_cant_keep() {
+        let rt = runtime();
+        let builders = [
+            ("lazy", Builder::new("async.json").lazy(true)),
+            ("acquire", Builder::new("async.json").acquire(wait::Acquire::FailFast)),
+            ("idle_timeout", Builder::new("async.json").idle_timeout(Duration::from_secs(1))),
+            ("group_commit", Builder::new("async.json").group_commit(Duration::from_secs(1))),
+        ];
+        for (option, b) in builders {
+            match rt.block_on(b.fs(MockFs::new()).open_async::<Vec<u32>>()) {
+                Err(e) => assert!(e.to_string().contains(option), "{}", e),
+                Ok(_) => panic!("opened with {}", option),
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Loading and storing a value in one call, without a handle
+//!
+//! `store_json_atomic` writes a value to a temp file beside the path,
+//! renames it into place and syncs it, as a commit does, so a crash
+//! leaves either the old file or the new one. `load_json` reads it
+//! back, or the default value if there's no file. Neither keeps
+//! anything between calls, for programs that read or write a file
+//! now and then and don't need a cached value.
+//!
+//! These take no locks, so concurrent writers each replace the whole
+//! file, and the last one wins. The `_locked` variants take the flock
+//! an `AtomBlob` on the path would, so they can share the file with
+//! handles, which see stores as they would another handle's commits.
+
+use serde::{Serialize, Deserialize};
+use std::path::Path;
+use super::{Parse, Result, ResultExt, Strategy, ser_reload, ser_store};
+use super::flock::{DirtyFlock, FlockGuard};
+use super::vfs::{Fs, RealFs};
+
+/// The value in the file at `p`, or the default if there isn't one
+pub fn load_json<T, P>(p: P) -> Result<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default, P: AsRef<Path>,
+{
+    let parse = Parse { lenient: false, on_error: None, enveloped: false, buffer_size: None,
+                        max_schema: None, mirror: None, deny_duplicates: false,
+                        deny_unknown: false, hash: None };
+    match ser_reload(&RealFs, Strategy::Rename, p.as_ref(), None, parse) {
+        Some(v) => Ok(v?.expect("reloading unknown file").0),
+        None => Ok(T::default()),
+    }
+}
+
+/// Replaces the file at `p` with `v`, durably
+pub fn store_json_atomic<T, P>(p: P, v: &T) -> Result<()>
+    where T: Serialize, P: AsRef<Path>,
+{
+    let p = p.as_ref();
+    ser_store(&RealFs, Strategy::Rename, p, v)?;
+    RealFs.sync(p).chain_err(|| "syncing blobject")
+}
+
+/// Like `load_json`, holding the blob's shared flock while reading
+pub fn load_json_locked<T, P>(p: P) -> Result<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default, P: AsRef<Path>,
+{
+    let flock = flock(p.as_ref());
+    let _flock = FlockGuard::shared(&flock)?;
+    load_json(p)
+}
+
+/// Like `store_json_atomic`, holding the blob's exclusive flock while
+/// writing
+pub fn store_json_atomic_locked<T, P>(p: P, v: &T) -> Result<()>
+    where T: Serialize, P: AsRef<Path>,
+{
+    let flock = flock(p.as_ref());
+    let _flock = FlockGuard::exclusive(&flock)?;
+    store_json_atomic(p, v)
+}
+
+fn flock(p: &Path) -> DirtyFlock {
+    DirtyFlock::new(RealFs.lock_path(&p.with_extension("flock")), None)
+}
@@ -0,0 +1,287 @@
+//! One process owning a blob for the others on the host
+//!
+//! When many processes share a hot blob, each commit makes every
+//! other handle reload it, and they all contend for its flock. A
+//! `Broker` is instead the only process that opens the file, and the
+//! rest connect to it over a Unix domain socket with a `BrokeredBlob`,
+//! which has the same guards as an `AtomBlob`. A shared guard asks the
+//! broker for the value, which is sent only if it's changed since the
+//! client's last read, and an exclusive guard holds the broker's
+//! exclusive guard until it commits, so writers exclude each other as
+//! they do locally.
+//!
+//! Messages are lines of JSON. A client that holds an exclusive guard
+//! holds the blob, as a local guard does, until it commits or hangs up.
+
+use serde::{Serialize, Deserialize};
+use serde::de::IgnoredAny;
+use serde_derive::{Serialize as SerializeDerive, Deserialize as DeserializeDerive};
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::thread;
+use super::{AtomBlob, BlobMutRef, Error, Result, ResultExt};
+
+#[derive(SerializeDerive, DeserializeDerive)]
+enum Request<T> {
+    // The value, unless it's still at generation `known`
+    Get { known: Option<u64> },
+    // Likewise, then holding the exclusive guard until a commit
+    Lock { known: Option<u64> },
+    Commit { value: T },
+}
+
+#[derive(SerializeDerive, DeserializeDerive)]
+struct Reply<V> {
+    generation: u64,
+    value: Option<V>,
+    error: Option<String>,
+}
+
+/// Serves a blob to other processes, one thread per connection
+pub struct Broker {
+    listener: UnixListener,
+}
+
+impl Broker {
+    /// Listens on the socket at `p`, replacing a socket left by a
+    /// broker that's gone
+    pub fn bind<P: AsRef<Path>>(p: P) -> Result<Broker> {
+        let p = p.as_ref();
+        if p.exists() && UnixStream::connect(p).is_err() {
+            std::fs::remove_file(p).chain_err(|| "removing stale blobject broker socket")?;
+        }
+        let listener = UnixListener::bind(p).chain_err(|| "binding blobject broker socket")?;
+        Ok(Broker { listener })
+    }
+
+    /// Serves `blob` until accepting a connection fails
+    pub fn serve<T>(self, blob: &AtomBlob<T>) -> Result<()>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+    {
+        loop {
+            let (stream, _) = self.listener.accept()
+                .chain_err(|| "accepting blobject broker connection")?;
+            let mut blob = blob.clone();
+            thread::Builder::new()
+                .name("blobject-broker".to_string())
+                .spawn(move || {
+                    if let Err(e) = handle(&mut blob, stream) {
+                        debug!("serving brokered blobject: {}", e);
+                    }
+                })
+                .chain_err(|| "starting blobject broker thread")?;
+        }
+    }
+}
+
+fn handle<T>(blob: &mut AtomBlob<T>, stream: UnixStream) -> Result<()>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    let mut r = BufReader::new(&stream);
+    loop {
+        let e = match read::<Request<T>, _>(&mut r)? {
+            None => return Ok(()),
+            Some(Request::Get { known }) => match blob.get() {
+                Ok(g) => {
+                    write(&stream, &reply(&*g, g.generation(), known))?;
+                    continue;
+                }
+                Err(e) => e,
+            },
+            Some(Request::Lock { known }) => match blob.get_mut() {
+                Ok(g) => {
+                    locked(&mut r, &stream, g, known)?;
+                    continue;
+                }
+                Err(e) => e,
+            },
+            Some(Request::Commit { .. }) => "brokered blobject commit without a guard".into(),
+        };
+        write(&stream, &failed(e))?;
+    }
+}
+
+// Serves a client holding the exclusive guard
+fn locked<T>(r: &mut BufReader<&UnixStream>, w: &UnixStream, mut g: BlobMutRef<'_, T>,
+             known: Option<u64>) -> Result<()>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    write(w, &reply(&*g, generation(&g), known))?;
+    let r = match read::<Request<T>, _>(r)? {
+        Some(Request::Commit { value }) => {
+            *g = value;
+            g.commit()
+        }
+        Some(_) => Err("brokered blobject guard expected a commit".into()),
+        // The guard still commits, as it was
+        None => return Ok(()),
+    };
+    match r {
+        Ok(receipt) => write(w, &Reply::<()> { generation: receipt.generation, value: None,
+                                               error: None }),
+        Err(e) => write(w, &failed(e)),
+    }
+}
+
+fn generation<T: Serialize>(g: &BlobMutRef<'_, T>) -> u64 {
+    g.parts.shared.generation.load(Ordering::SeqCst)
+}
+
+fn reply<T>(v: &T, generation: u64, known: Option<u64>) -> Reply<&T> {
+    let value = if known == Some(generation) { None } else { Some(v) };
+    Reply { generation, value, error: None }
+}
+
+fn failed(e: Error) -> Reply<()> {
+    Reply { generation: 0, value: None, error: Some(e.to_string()) }
+}
+
+fn read<M, R>(r: &mut R) -> Result<Option<M>>
+    where for <'de> M: Deserialize<'de>, R: BufRead,
+{
+    let mut line = String::new();
+    if r.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&line).chain_err(|| "parsing blobject broker message")?))
+}
+
+fn write<M: Serialize>(mut w: &UnixStream, m: &M) -> Result<()> {
+    let mut buf = serde_json::to_vec(m).chain_err(|| "serializing blobject broker message")?;
+    buf.push(b'\n');
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+/// A blob owned by a `Broker` in another process
+pub struct BrokeredBlob<T> {
+    stream: BufReader<UnixStream>,
+    value: T,
+    // The broker's generation of `value`
+    generation: Option<u64>,
+    no_panic: bool,
+}
+
+pub struct BrokeredRef<'a, T: 'a> {
+    blob: &'a BrokeredBlob<T>,
+}
+
+pub struct BrokeredMutRef<'a, T: 'a + Serialize> {
+    blob: &'a mut BrokeredBlob<T>,
+    committed: bool,
+}
+
+impl<T> BrokeredBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// Connects to the broker listening on the socket at `p`
+    pub fn connect<P: AsRef<Path>>(p: P) -> Result<BrokeredBlob<T>> {
+        let stream = UnixStream::connect(p).chain_err(|| "connecting to blobject broker")?;
+        Ok(BrokeredBlob {
+            stream: BufReader::new(stream),
+            value: T::default(),
+            generation: None,
+            no_panic: false,
+        })
+    }
+
+    /// Logs a failed commit of a guard dropped without committing,
+    /// rather than panicking, as `Builder::strict_no_panic`
+    pub fn strict_no_panic(mut self, strict_no_panic: bool) -> BrokeredBlob<T> {
+        self.no_panic = strict_no_panic;
+        self
+    }
+
+    pub fn get(&mut self) -> Result<BrokeredRef<'_, T>> {
+        let known = self.generation;
+        self.fetch(&Request::Get { known })?;
+        Ok(BrokeredRef { blob: self })
+    }
+
+    /// Takes the broker's exclusive guard, until the returned guard
+    /// commits
+    pub fn get_mut(&mut self) -> Result<BrokeredMutRef<'_, T>> {
+        let known = self.generation;
+        self.fetch(&Request::Lock { known })?;
+        Ok(BrokeredMutRef { blob: self, committed: false })
+    }
+
+    fn fetch(&mut self, req: &Request<T>) -> Result<()> {
+        let reply: Reply<T> = call(&mut self.stream, req)?;
+        if let Some(v) = reply.value {
+            self.value = v;
+        }
+        self.generation = Some(reply.generation);
+        Ok(())
+    }
+}
+
+// Sends a request and reads its reply
+fn call<M, V>(stream: &mut BufReader<UnixStream>, req: &M) -> Result<Reply<V>>
+    where M: Serialize, for <'de> V: Deserialize<'de>,
+{
+    write(stream.get_ref(), req)?;
+    let reply: Reply<V> = match read(stream)? {
+        Some(r) => r,
+        None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+            .chain_err(|| "blobject broker hung up"),
+    };
+    match reply.error {
+        Some(e) => Err(format!("blobject broker: {}", e).into()),
+        None => Ok(reply),
+    }
+}
+
+impl<'a, T: 'a> Deref for BrokeredRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.blob.value
+    }
+}
+
+impl<'a, T: 'a + Serialize> Deref for BrokeredMutRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.blob.value
+    }
+}
+
+impl<'a, T: 'a + Serialize> DerefMut for BrokeredMutRef<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.blob.value
+    }
+}
+
+impl<'a, T: 'a + Serialize> BrokeredMutRef<'a, T> {
+    /// Commits the value, and releases the broker's guard
+    pub fn commit(&mut self) -> Result<()> {
+        if self.committed {
+            return Err("brokered blobject guard already committed".into());
+        }
+        self.committed = true;
+        let blob = &mut *self.blob;
+        // The reply has no value
+        let r = call::<_, IgnoredAny>(&mut blob.stream, &Request::Commit { value: &blob.value });
+        blob.generation = r.as_ref().ok().map(|r| r.generation);
+        r.map(|_| ())
+    }
+}
+
+impl<'a, T: 'a + Serialize> Drop for BrokeredMutRef<'a, T> {
+    fn drop(&mut self) {
+        if !self.committed {
+            if let Err(e) = self.commit() {
+                if self.blob.no_panic {
+                    error!("brokered blobject failed to commit on drop: {}", e);
+                } else {
+                    panic!("brokered blobject failed to commit on drop: {:?}", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,324 @@
+//! Chunked storage, for blobs too big to rewrite on every commit
+//!
+//! A blob larger than its chunk size is split into chunks, cut where
+//! the content says to, so an edit in the middle of the file moves
+//! only the chunks around it. Each chunk is stored once, in
+//! `<blob>.chunks/`, named by its SHA-256, and the blob file is
+//! replaced by a manifest listing them in order. A commit writes only
+//! the chunks that aren't there already, then renames the new manifest
+//! into place, so it's as atomic as any other commit, and deletes the
+//! chunks no longer listed. Reading checks every chunk against its
+//! name.
+//!
+//! Blobs no larger than one chunk are stored whole, as with
+//! `Strategy::Rename`.
+
+use std::collections::HashSet;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use super::sha256;
+use super::tmp_file;
+use super::vfs::{FileId, Fs};
+
+const MAGIC: &str = "chunks1";
+
+/// The chunk size unless `Builder::chunk_size` says otherwise
+pub const DEFAULT_SIZE: u64 = 4 << 20;
+
+/// Opens the value, reading the chunks in turn if it's chunked
+pub fn open(fs: &dyn Fs, p: &Path) -> io::Result<Option<(Box<dyn Read>, FileId)>> {
+    let (mut r, id) = match fs.open(p)? {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    let mut head = vec![0; MAGIC.len() + 1];
+    let n = read_up_to(&mut *r, &mut head)?;
+    head.truncate(n);
+    if head != format!("{} ", MAGIC).as_bytes() {
+        // Stored whole
+        return Ok(Some((Box::new(Cursor::new(head).chain(r)), id)));
+    }
+
+    let mut manifest = head;
+    r.read_to_end(&mut manifest)?;
+    let dir = dir(p);
+    let mut buf = Vec::new();
+    for (name, len) in parse(&manifest)? {
+        let chunk = match fs.open(&dir.join(&name))? {
+            Some((r, _)) => read_all(r)?,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("missing blobject chunk {}", name))),
+        };
+        if chunk.len() != len || sha256::hex(&chunk) != name {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("corrupt blobject chunk {}", name)));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    let r: Box<dyn Read> = Box::new(Cursor::new(buf));
+    Ok(Some((r, id)))
+}
+
+/// Makes `buf` the current value, chunked if it's larger than
+/// `size`
+pub fn store(fs: &dyn Fs, p: &Path, buf: &[u8], size: u64) -> io::Result<()> {
+    let dir = dir(p);
+    let size = size.max(1) as usize;
+    let mut keep = HashSet::new();
+    if buf.len() <= size {
+        replace(fs, p, buf)?;
+    } else {
+        fs.create_dir_all(&dir)?;
+        let mut manifest = format!("{} {}\n", MAGIC, buf.len());
+        let mut start = 0;
+        for end in cuts(buf, size) {
+            let chunk = &buf[start..end];
+            let name = sha256::hex(chunk);
+            let path = dir.join(&name);
+            if !keep.contains(&name) && fs.id(&path)?.is_none() {
+                replace(fs, &path, chunk)?;
+            }
+            manifest.push_str(&format!("{} {}\n", name, chunk.len()));
+            keep.insert(name);
+            start = end;
+        }
+        replace(fs, p, manifest.as_bytes())?;
+    }
+    collect(fs, &dir, &keep)
+}
+
+pub fn sync(fs: &dyn Fs, p: &Path) -> io::Result<()> {
+    if let Some(chunks) = manifest(fs, p)? {
+        let dir = dir(p);
+        for (name, _) in chunks {
+            fs.sync(&dir.join(name))?;
+        }
+    }
+    fs.sync(p)
+}
+
+/// Deletes every chunk of the blob at `p`
+pub fn remove_all(fs: &dyn Fs, p: &Path) -> io::Result<()> {
+    collect(fs, &dir(p), &HashSet::new())
+}
+
+fn dir(p: &Path) -> PathBuf {
+    p.with_extension("chunks")
+}
+
+// A new file at `p` holding `buf`
+fn replace(fs: &dyn Fs, p: &Path, buf: &[u8]) -> io::Result<()> {
+    let (tmp, _) = tmp_file(fs, p)?;
+    let r = fs.replace(&tmp, p, buf);
+    if r.is_err() {
+        let _ = fs.remove(&tmp);
+    }
+    r
+}
+
+// Deletes the files in `dir` not named in `keep`, including any left
+// by commits that didn't finish
+fn collect(fs: &dyn Fs, dir: &Path, keep: &HashSet<String>) -> io::Result<()> {
+    let files = match fs.read_dir(dir) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        r => r?,
+    };
+    for f in files {
+        let name = f.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if !keep.contains(name) {
+            match fs.remove(&f) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                r => r?,
+            }
+        }
+    }
+    Ok(())
+}
+
+// The chunks the manifest at `p` lists, or `None` if the blob is
+// stored whole
+fn manifest(fs: &dyn Fs, p: &Path) -> io::Result<Option<Vec<(String, usize)>>> {
+    match fs.open(p)? {
+        Some((r, _)) => {
+            let buf = read_all(r)?;
+            if buf.starts_with(format!("{} ", MAGIC).as_bytes()) {
+                Ok(Some(parse(&buf)?))
+            } else {
+                Ok(None)
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+fn parse(manifest: &[u8]) -> io::Result<Vec<(String, usize)>> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "bad blobject chunk manifest");
+    let text = std::str::from_utf8(manifest).map_err(|_| bad())?;
+    let mut lines = text.lines();
+    let len: usize = match lines.next().and_then(|l| l.split_once(' ')) {
+        Some((MAGIC, len)) => len.parse().map_err(|_| bad())?,
+        _ => return Err(bad()),
+    };
+    let chunks = lines.map(|l| {
+        let (name, len) = l.split_once(' ').ok_or_else(bad)?;
+        if name.len() != 64 || !name.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(bad());
+        }
+        Ok((name.to_owned(), len.parse().map_err(|_| bad())?))
+    }).collect::<io::Result<Vec<(String, usize)>>>()?;
+    if chunks.iter().map(|(_, n)| n).sum::<usize>() != len {
+        return Err(bad());
+    }
+    Ok(chunks)
+}
+
+// Where to cut `buf` into chunks of about `size` bytes, each cut
+// after the last byte of a chunk
+//
+// Cuts fall where a hash of the 64 bytes before them has enough
+// leading zeros, so they move with the content around them. Chunks
+// are kept between a quarter and four times `size`.
+fn cuts(buf: &[u8], size: usize) -> Vec<usize> {
+    let (min, max) = ((size / 4).max(1), size.saturating_mul(4));
+    let bits = (size - min).max(1).next_power_of_two().trailing_zeros();
+    let mut cuts = Vec::new();
+    let mut start = 0;
+    while start < buf.len() {
+        let limit = buf.len().min(start.saturating_add(max));
+        let mut end = limit;
+        let mut h = 0u64;
+        for (i, &b) in buf[start..limit].iter().enumerate() {
+            h = (h << 1).wrapping_add(gear(b));
+            if i >= min && bits > 0 && h >> (64 - bits) == 0 {
+                end = start + i + 1;
+                break;
+            }
+        }
+        cuts.push(end);
+        start = end;
+    }
+    cuts
+}
+
+// A fixed pseudorandom value for each byte, by splitmix64
+fn gear(b: u8) -> u64 {
+    let mut z = (b as u64).wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn read_up_to(r: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+fn read_all<R: Read>(mut r: R) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    r.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+    use std::path::Path;
+    use super::{cuts, dir, manifest, open, store};
+    use super::super::vfs::{Fs, MockFs};
+
+    // Bytes without repeats a chunker could cut into equal chunks
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed;
+        (0..len).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        }).collect()
+    }
+
+    fn read(fs: &dyn Fs, p: &Path) -> io::Result<Vec<u8>> {
+        let (mut r, _) = open(fs, p)?.unwrap();
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn chunks(fs: &dyn Fs, p: &Path) -> Vec<String> {
+        manifest(fs, p).unwrap().unwrap().into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn cuts_within_bounds() {
+        let buf = noise(100_000, 1);
+        let cuts = cuts(&buf, 1024);
+        assert_eq!(cuts.last(), Some(&buf.len()));
+        let mut start = 0;
+        for &end in &cuts[..cuts.len() - 1] {
+            assert!(end - start > 256 && end - start <= 4096, "chunk of {}", end - start);
+            start = end;
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let fs = MockFs::new();
+        let p = Path::new("chunked.bin");
+        let small = noise(100, 2);
+        store(&fs, p, &small, 1024).unwrap();
+        assert_eq!(fs.read(p), Some(small.clone()));
+        assert_eq!(read(&fs, p).unwrap(), small);
+
+        let big = noise(50_000, 3);
+        store(&fs, p, &big, 1024).unwrap();
+        assert!(chunks(&fs, p).len() > 1);
+        assert_eq!(read(&fs, p).unwrap(), big);
+
+        // Going back to whole deletes the chunks
+        store(&fs, p, &small, 1024).unwrap();
+        assert_eq!(read(&fs, p).unwrap(), small);
+        assert!(fs.read_dir(&dir(p)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rewrites_chunks_near_edits() {
+        let fs = MockFs::new();
+        let p = Path::new("chunked.bin");
+        let mut buf = noise(200_000, 4);
+        store(&fs, p, &buf, 1024).unwrap();
+        let before = chunks(&fs, p);
+
+        buf[100_000] ^= 0xff;
+        store(&fs, p, &buf, 1024).unwrap();
+        let after = chunks(&fs, p);
+        assert_eq!(read(&fs, p).unwrap(), buf);
+
+        let new = after.iter().filter(|c| !before.contains(c)).count();
+        assert!((1..=3).contains(&new), "{} of {} chunks rewritten", new, after.len());
+    }
+
+    #[test]
+    fn rejects_corrupt_chunks() {
+        let fs = MockFs::new();
+        let p = Path::new("chunked.bin");
+        store(&fs, p, &noise(50_000, 5), 1024).unwrap();
+        let chunk = dir(p).join(&chunks(&fs, p)[1]);
+        let mut data = fs.read(&chunk).unwrap();
+        data[0] ^= 1;
+        fs.write(&chunk, data);
+        let e = read(&fs, p).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        fs.remove(&chunk).unwrap();
+        let e = read(&fs, p).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+}
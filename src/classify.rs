@@ -0,0 +1,103 @@
+//! Telling errors apart by what to do about them
+//!
+//! Errors are chained, with the kind that matters often a few causes
+//! down, under context like "loading blobject". `Error::is_retryable`,
+//! `Error::is_corruption` and `Error::is_conflict` look through the
+//! whole chain, so retry loops and alerting can branch on them rather
+//! than on the messages.
+
+use std::error::Error as StdError;
+use std::io;
+use super::{Error, ErrorKind};
+use super::quota::Rejected;
+
+// What a chain can be made of
+enum Cause<'a> {
+    Kind(&'a ErrorKind),
+    Io(&'a io::Error),
+    Json(&'a serde_json::Error),
+}
+
+impl Error {
+    /// Whether trying again, from a fresh guard, may succeed
+    ///
+    /// So for a lock that wasn't free in time, a draft committed over
+    /// by another writer, and I/O that was interrupted or timed out.
+    pub fn is_retryable(&self) -> bool {
+        self.any_cause(&mut |c| match c {
+            Cause::Kind(k) => matches!(*k, ErrorKind::Locked | ErrorKind::Conflict),
+            Cause::Io(e) => matches!(e.kind(), io::ErrorKind::Interrupted
+                                     | io::ErrorKind::WouldBlock
+                                     | io::ErrorKind::TimedOut),
+            Cause::Json(_) => false,
+        })
+    }
+
+    /// Whether a blob file, or something else read, is damaged
+    ///
+    /// So for a file that isn't JSON or ends early, a value rejected by
+    /// `TypedBuilder::validate`, and chunks, slots and archives whose
+    /// contents don't check out. A file that's whole JSON but doesn't
+    /// fit the value type isn't counted, being more likely written by
+    /// another schema.
+    pub fn is_corruption(&self) -> bool {
+        self.any_cause(&mut |c| match c {
+            Cause::Kind(k) => matches!(*k, ErrorKind::InvalidValue),
+            Cause::Io(e) => e.kind() == io::ErrorKind::InvalidData,
+            Cause::Json(e) => e.is_syntax() || e.is_eof(),
+        })
+    }
+
+    /// Whether another writer committed first, with
+    /// `ErrorKind::Conflict`
+    pub fn is_conflict(&self) -> bool {
+        self.any_cause(&mut |c| matches!(c, Cause::Kind(ErrorKind::Conflict)))
+    }
+
+    /// Whether a commit was failed for taking a store over its
+    /// `BlobStore::quota`
+    pub fn is_over_quota(&self) -> bool {
+        self.any_cause(&mut |c| match c {
+            Cause::Io(e) => e.get_ref().is_some_and(|e| e.is::<Rejected>()),
+            _ => false,
+        })
+    }
+
+    // Whether `f` holds for anything in the chain
+    fn any_cause(&self, f: &mut dyn FnMut(Cause<'_>) -> bool) -> bool {
+        if f(Cause::Kind(self.kind())) {
+            return true;
+        }
+        if let ErrorKind::Io(ref e) = *self.kind() {
+            if any_io(e, f) {
+                return true;
+            }
+        }
+        match self.1.next_error {
+            Some(ref e) => any_std(&**e, f),
+            None => false,
+        }
+    }
+}
+
+fn any_std(e: &(dyn StdError + Send + 'static), f: &mut dyn FnMut(Cause<'_>) -> bool) -> bool {
+    if let Some(e) = e.downcast_ref::<Error>() {
+        e.any_cause(f)
+    } else if let Some(e) = e.downcast_ref::<io::Error>() {
+        any_io(e, f)
+    } else if let Some(e) = e.downcast_ref::<serde_json::Error>() {
+        f(Cause::Json(e))
+    } else {
+        false
+    }
+}
+
+fn any_io(e: &io::Error, f: &mut dyn FnMut(Cause<'_>) -> bool) -> bool {
+    if f(Cause::Io(e)) {
+        return true;
+    }
+    match e.get_ref() {
+        Some(inner) => any_std(inner, f),
+        None => false,
+    }
+}
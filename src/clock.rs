@@ -0,0 +1,59 @@
+//! The clock idle timeouts are measured on, and commits are stamped
+//! with
+//!
+//! Handles use `SystemClock` unless opened with `Builder::clock`.
+//! With a `MockClock` a test can make a value idle by advancing the
+//! clock instead of sleeping; the next guard on it then reloads it.
+
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    /// Time since some fixed point; must never go backwards
+    fn now(&self) -> Duration;
+
+    /// The wall-clock time, for commit times other processes can read
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Monotonic time since the first use in this process
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        static BASE: OnceLock<Instant> = OnceLock::new();
+        BASE.get_or_init(Instant::now).elapsed()
+    }
+}
+
+/// A clock that only moves when told to
+///
+/// Clones share the same time. Its wall-clock time starts at the Unix
+/// epoch.
+#[derive(Clone, Debug, Default)]
+pub struct MockClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock::default()
+    }
+
+    pub fn advance(&self, d: Duration) {
+        *self.now.lock().expect("poisoned mock clock") += d;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().expect("poisoned mock clock")
+    }
+
+    fn system_time(&self) -> SystemTime {
+        UNIX_EPOCH + self.now()
+    }
+}
@@ -0,0 +1,74 @@
+//! Counters kept in untyped blobs
+//!
+//! Each helper is a whole read-modify-write under the exclusive lock,
+//! so concurrent increments from any number of processes all count.
+//! Paths are dot-separated object keys, and missing objects and
+//! counters along them are created, counters starting at zero.
+
+use serde_json::{Map, Value};
+use super::{DynAtomBlob, Result};
+
+impl DynAtomBlob {
+    /// Adds `delta` to the integer at `path`, returning the new value
+    ///
+    /// Fails, without changing anything, if the path runs through
+    /// something that isn't an object, if the counter isn't an
+    /// integer, or if it would overflow.
+    pub fn increment(&mut self, path: &str, delta: i64) -> Result<i64> {
+        let mut guard = self.get_mut()?;
+        let n = get(&guard, path)?.checked_add(delta).ok_or_else(|| {
+            format!("blobject counter {:?} overflowed", path)
+        })?;
+        *insert(&mut guard, path) = Value::from(n);
+        guard.commit()?;
+        Ok(n)
+    }
+
+    /// Subtracts `delta` from the integer at `path`, returning the new
+    /// value
+    pub fn decrement(&mut self, path: &str, delta: i64) -> Result<i64> {
+        let delta = delta.checked_neg().ok_or("blobject counter delta overflowed")?;
+        self.increment(path, delta)
+    }
+
+    /// Increments the integer at `path` by one, returning the new
+    /// value, for handing out sequence numbers
+    pub fn next_sequence(&mut self, path: &str) -> Result<i64> {
+        self.increment(path, 1)
+    }
+}
+
+// The integer at `path`, or zero if it's missing
+fn get(mut v: &Value, path: &str) -> Result<i64> {
+    for key in path.split('.') {
+        v = match *v {
+            Value::Object(ref m) => match m.get(key) {
+                Some(v) => v,
+                None => return Ok(0),
+            },
+            Value::Null => return Ok(0),
+            _ => return Err(format!("blobject path {:?} isn't through objects", path).into()),
+        };
+    }
+    match *v {
+        Value::Null => Ok(0),
+        ref v => v.as_i64().ok_or_else(|| {
+            format!("blobject counter {:?} isn't an integer", path).into()
+        }),
+    }
+}
+
+// The value at `path`, creating it and the objects on the way if
+// missing, after `get` has checked the path
+fn insert<'a>(mut v: &'a mut Value, path: &str) -> &'a mut Value {
+    for key in path.split('.') {
+        if v.is_null() {
+            *v = Value::Object(Map::new());
+        }
+        v = match *v {
+            Value::Object(ref mut m) => m.entry(key).or_insert(Value::Null),
+            _ => unreachable!(),
+        };
+    }
+    v
+}
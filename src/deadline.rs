@@ -0,0 +1,135 @@
+//! Reloads that readers stop waiting for
+//!
+//! With `Builder::reload_deadline`, a shared guard that must reload
+//! parses the file on a thread of its own, and waits only until the
+//! deadline. If the parse is late the guard reads the value already
+//! loaded, and the parse is left running, recorded on the open blob
+//! as late. The next guard waits on the same parse instead of
+//! starting another, and whichever guard finds it done installs its
+//! value. An exclusive guard doesn't wait for it: it reloads as usual
+//! and the late parse is dropped.
+//!
+//! The thread holds a shared flock of its own while it reads, so the
+//! file can't be replaced under it even once the guard that started
+//! it is gone.
+
+use serde::{Serialize, Deserialize};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{Parts, Result, ResultExt, Shared, envelope, lock, poisoned, repair};
+use super::flock::{DirtyFlock, FlockGuard};
+use super::vfs::{FileId, Fs};
+
+// What a reload read, and whether it was recovered
+type Done<T> = (envelope::Reloaded<T>, bool);
+
+struct Slot<T> {
+    done: Mutex<Option<Done<T>>>,
+    cond: Condvar,
+}
+
+pub struct Late<T> {
+    // Milliseconds on the blob's clock when the reload started
+    pub since: u64,
+    slot: Arc<Slot<T>>,
+}
+
+type Start<T> = dyn Fn(Option<FileId>) -> Result<Arc<Slot<T>>> + Send + Sync;
+
+/// Starts reloads on their own threads
+pub struct Reloader<T> {
+    start: Box<Start<T>>,
+}
+
+impl<T> Reloader<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    pub fn new(shared: Weak<Shared<T>>, fs: Arc<dyn Fs>, path: PathBuf) -> Reloader<T> {
+        let start = move |known: Option<FileId>| {
+            let slot = Arc::new(Slot { done: Mutex::new(None), cond: Condvar::new() });
+            let (shared, fs, path, ours) = (shared.clone(), fs.clone(), path.clone(), slot.clone());
+            thread::Builder::new()
+                .name("blobject-reload".to_string())
+                .spawn(move || {
+                    let shared = match shared.upgrade() {
+                        Some(s) => s,
+                        None => return,
+                    };
+                    let flock = DirtyFlock::new(fs.lock_path(&path.with_extension("flock")),
+                                                fs.in_process());
+                    let done = match FlockGuard::shared(&flock) {
+                        Ok(_flock) => {
+                            repair::take();
+                            let r = envelope::reload(&*fs, shared.strategy, &path, known.as_ref(),
+                                                     shared.parse());
+                            (r, repair::take())
+                        }
+                        Err(e) => (Some(Err(e)), false),
+                    };
+                    *lock(&ours.done) = Some(done);
+                    ours.cond.notify_all();
+                })
+                .chain_err(|| "starting blobject reload thread")?;
+            Ok(slot)
+        };
+        Reloader { start: Box::new(start) }
+    }
+}
+
+/// Reloads the value, or gives up after `deadline` and leaves the
+/// reload late
+pub fn reload<T>(parts: Parts<'_, T>, reloader: &Reloader<T>, deadline: Duration) -> Result<()>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    let end = Instant::now() + deadline;
+    loop {
+        let slot = {
+            let mut late = lock(&parts.shared.late);
+            match *late {
+                Some(ref l) => l.slot.clone(),
+                None => {
+                    let known = lock(&parts.shared.loaded).clone();
+                    let slot = (reloader.start)(known)?;
+                    *late = Some(Late { since: parts.now(), slot: slot.clone() });
+                    slot
+                }
+            }
+        };
+
+        let done = {
+            let done = lock(&slot.done);
+            let wait = end.saturating_duration_since(Instant::now());
+            let (mut done, _) = slot.cond.wait_timeout_while(done, wait, |d| d.is_none())
+                .unwrap_or_else(PoisonError::into_inner);
+            done.take()
+        };
+        // Late, or taken by another guard
+        let (r, repaired) = match done {
+            Some(d) => d,
+            None => {
+                debug!("blobject reload is late");
+                return Ok(());
+            }
+        };
+
+        let mut val = parts.shared.v.write().map_err(poisoned)?;
+        {
+            let mut late = lock(&parts.shared.late);
+            match *late {
+                Some(ref l) if Arc::ptr_eq(&l.slot, &slot) => *late = None,
+                // A reload since has overtaken it
+                _ => return Ok(()),
+            }
+        }
+        let loaded = lock(&parts.shared.loaded);
+        parts.install(&mut val, loaded, r, repaired)?;
+
+        // A late reload may have read a file replaced since
+        let current = parts.shared.strategy.id(parts.fs, parts.path).ok().flatten();
+        if current == *lock(&parts.shared.loaded) {
+            return Ok(());
+        }
+    }
+}
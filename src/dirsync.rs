@@ -0,0 +1,180 @@
+//! Sharing directory syncs between the blobs of a store
+//!
+//! A commit is durable once its file is synced, and then the directory
+//! it was renamed into, which costs as much again or more. Syncing the
+//! directory makes every rename into it durable, not just the one it
+//! was done for, so with `BlobStore::batch_syncs` each sync of a
+//! commit syncs its own file, then hands its directory to a thread
+//! that syncs each directory once for all the commits that asked in
+//! the same window. The sync still waits for that, so a commit is as
+//! durable once synced as it would be alone.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{Result, ResultExt};
+use super::vfs::{FileId, Fs};
+
+type Job = (PathBuf, Sender<io::Result<()>>);
+
+// Passes everything but syncs to `inner`
+pub struct DirSyncs {
+    inner: Arc<dyn Fs>,
+    jobs: Sender<Job>,
+}
+
+impl DirSyncs {
+    /// Starts the sync thread, which exits once this is dropped
+    ///
+    /// Each batch waits `window` after the first sync it covers for
+    /// more to join it.
+    pub fn start(inner: Arc<dyn Fs>, window: Duration) -> Result<DirSyncs> {
+        let (jobs, rx) = mpsc::channel();
+        let fs = inner.clone();
+        thread::Builder::new()
+            .name("blobject-dir-sync".to_string())
+            .spawn(move || run(&*fs, window, rx))
+            .chain_err(|| "starting blobject directory sync thread")?;
+        Ok(DirSyncs { inner, jobs })
+    }
+}
+
+fn run(fs: &dyn Fs, window: Duration, rx: Receiver<Job>) {
+    while let Ok(job) = rx.recv() {
+        let mut jobs = vec![job];
+        let deadline = Instant::now() + window;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                break;
+            }
+            match rx.recv_timeout(left) {
+                Ok(job) => jobs.push(job),
+                Err(_) => break,
+            }
+        }
+        jobs.extend(rx.try_iter());
+
+        let commits = jobs.len();
+        let mut dirs: BTreeMap<PathBuf, Vec<Sender<io::Result<()>>>> = BTreeMap::new();
+        for (dir, job) in jobs {
+            dirs.entry(dir).or_default().push(job);
+        }
+        debug!("syncing {} directories for {} blobject commits", dirs.len(), commits);
+        for (dir, jobs) in dirs {
+            let r = fs.sync_dir(&dir);
+            if let Err(ref e) = r {
+                error!("syncing blobject directory {}: {}", dir.display(), e);
+            }
+            for job in jobs {
+                let r = match r {
+                    Ok(()) => Ok(()),
+                    Err(ref e) => Err(io::Error::new(e.kind(), e.to_string())),
+                };
+                // The commit may have given up waiting
+                let _ = job.send(r);
+            }
+        }
+    }
+}
+
+impl Fs for DirSyncs {
+    fn open(&self, p: &Path) -> io::Result<Option<(Box<dyn Read>, FileId)>> {
+        self.inner.open(p)
+    }
+
+    fn id(&self, p: &Path) -> io::Result<Option<FileId>> {
+        self.inner.id(p)
+    }
+
+    fn create(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        self.inner.create(p)
+    }
+
+    fn create_new(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        self.inner.create_new(p)
+    }
+
+    fn create_sized(&self, p: &Path, size: u64) -> io::Result<Box<dyn Write>> {
+        self.inner.create_sized(p, size)
+    }
+
+    fn create_direct(&self, p: &Path, size: Option<u64>) -> io::Result<Box<dyn Write>> {
+        self.inner.create_direct(p, size)
+    }
+
+    fn append(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        self.inner.append(p)
+    }
+
+    fn rename(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.inner.rename(src, dst)
+    }
+
+    fn replace(&self, tmp: &Path, p: &Path, buf: &[u8]) -> io::Result<()> {
+        self.inner.replace(tmp, p, buf)
+    }
+
+    fn remove(&self, p: &Path) -> io::Result<()> {
+        self.inner.remove(p)
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.inner.link(src, dst)
+    }
+
+    fn copy(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.inner.copy(src, dst)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(dir)
+    }
+
+    fn walk(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.walk(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(dir)
+    }
+
+    fn sync(&self, p: &Path) -> io::Result<()> {
+        self.inner.sync_contents(p)?;
+        let dir = p.parent().unwrap_or_else(|| Path::new("")).to_owned();
+        let (tx, rx) = mpsc::channel();
+        if self.jobs.send((dir.clone(), tx)).is_err() {
+            error!("blobject directory sync thread is gone");
+            return self.inner.sync_dir(&dir);
+        }
+        rx.recv().unwrap_or_else(|_| self.inner.sync_dir(&dir))
+    }
+
+    fn sync_contents(&self, p: &Path) -> io::Result<()> {
+        self.inner.sync_contents(p)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.inner.sync_dir(dir)
+    }
+
+    fn available(&self, p: &Path) -> io::Result<Option<u64>> {
+        self.inner.available(p)
+    }
+
+    fn canonicalize(&self, p: &Path) -> io::Result<PathBuf> {
+        self.inner.canonicalize(p)
+    }
+
+    fn lock_path(&self, p: &Path) -> PathBuf {
+        self.inner.lock_path(p)
+    }
+
+    fn in_process(&self) -> Option<u64> {
+        self.inner.in_process()
+    }
+}
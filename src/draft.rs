@@ -0,0 +1,153 @@
+//! Edits made without holding the blob's locks
+//!
+//! A `Draft` is a private copy of the value that can be edited for as
+//! long as needed, then committed with a merge function that resolves
+//! it against whatever other writers committed in the meantime. It
+//! also keeps the value it was copied from, for three-way merges.
+
+use serde::{Serialize, Deserialize};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use super::{AtomBlob, CommitReceipt, FileId, Result, lock};
+
+pub struct Draft<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    blob: AtomBlob<T>,
+    base_id: Option<FileId>,
+    base: T,
+    v: T,
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Clone,
+{
+    /// Copies the current value into a draft, releasing the locks
+    /// before returning
+    pub fn draft(&mut self) -> Result<Draft<T>> {
+        let shared = self.shared.clone();
+        let (base, base_id) = {
+            let v = self.get()?;
+            let base_id = lock(&shared.loaded).clone();
+            ((*v).clone(), base_id)
+        };
+        Ok(Draft {
+            blob: self.clone(),
+            base_id,
+            v: base.clone(),
+            base,
+        })
+    }
+}
+
+impl<T> Draft<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// The value the draft was copied from
+    pub fn base(&self) -> &T {
+        &self.base
+    }
+
+    /// Commits the draft
+    ///
+    /// If the blob was committed since the draft was taken, by any
+    /// handle in any process, the committed value is `merge(disk,
+    /// local)` instead of the draft. Dropping a draft without
+    /// committing discards it.
+    pub fn commit<F>(self, merge: F) -> Result<CommitReceipt>
+        where F: FnOnce(T, T) -> T
+    {
+        self.commit3(|_, disk, local| merge(disk, local))
+    }
+
+    /// Like `commit`, but the merge also gets the base, as
+    /// `merge(base, disk, local)`
+    pub fn commit3<F>(self, merge: F) -> Result<CommitReceipt>
+        where F: FnOnce(T, T, T) -> T
+    {
+        let Draft { mut blob, base_id, base, v } = self;
+        let shared = blob.shared.clone();
+        let mut guard = blob.get_mut()?;
+        let changed = *lock(&shared.loaded) != base_id;
+        if changed {
+            debug!("merging blobject draft");
+            let disk = mem::take(&mut *guard);
+            *guard = merge(base, disk, v);
+        } else {
+            *guard = v;
+        }
+        guard.commit()
+    }
+
+    /// Commits the draft, settling a commit made since it was taken
+    /// by the blob's `Builder::conflict_policy`
+    pub fn commit_by_policy(self) -> Result<CommitReceipt> {
+        let Draft { mut blob, base_id, base, v } = self;
+        let shared = blob.shared.clone();
+        let mut guard = blob.get_mut()?;
+        let changed = *lock(&shared.loaded) != base_id;
+        if changed {
+            debug!("settling blobject draft by {:?}", shared.policy);
+            // Resolved before replacing anything, so a failure commits
+            // the other writer's value as it is
+            *guard = shared.policy.resolve(&base, &guard, v)?;
+        } else {
+            *guard = v;
+        }
+        guard.commit()
+    }
+}
+
+impl<T> Deref for Draft<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.v
+    }
+}
+
+impl<T> DerefMut for Draft<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{AtomBlob, Builder};
+    use super::super::vfs::MockFs;
+
+    fn blob() -> AtomBlob<Vec<u32>> {
+        let mut blob: AtomBlob<Vec<u32>> = Builder::new("draft.json").fs(MockFs::new()).open().unwrap();
+        blob.get_mut().unwrap().push(1);
+        blob
+    }
+
+    #[test]
+    fn commits_unmerged_if_alone() {
+        let mut blob = blob();
+        let mut draft = blob.draft().unwrap();
+        draft.push(2);
+        draft.commit3(|_, _, _| panic!("merged")).unwrap();
+        assert_eq!(*blob.get().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn merges_past_other_commits() {
+        let mut blob = blob();
+        let mut draft = blob.draft().unwrap();
+        draft.push(2);
+        blob.get_mut().unwrap().push(3);
+        draft.commit3(|base, disk, local| {
+            assert_eq!((&base, &disk, &local), (&vec![1], &vec![1, 3], &vec![1, 2]));
+            let mut v = disk;
+            v.extend(local.into_iter().filter(|x| !base.contains(x)));
+            v
+        }).unwrap();
+        assert_eq!(*blob.get().unwrap(), vec![1, 3, 2]);
+    }
+}
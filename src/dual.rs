@@ -0,0 +1,52 @@
+//! A copy of each commit in an older schema, for rollouts
+//!
+//! With `TypedBuilder::dual_write`, each commit first converts the value
+//! and stores it at the legacy path, as a plain JSON file, under the
+//! exclusive flock a handle on that path would take. Binaries still
+//! reading the older schema there see each commit as they would one
+//! of their own, while binaries on the new schema share the blob.
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use super::{Result, ResultExt, Strategy, delete, lock, ser_store};
+use super::flock::{DirtyFlock, FlockGuard};
+use super::vfs::Fs;
+
+type Write<T> = dyn Fn(&dyn Fs, &Path, &T) -> Result<()> + Send + Sync;
+
+// Writes the legacy copy
+pub struct Convert<T>(Arc<Write<T>>);
+
+impl<T: 'static> Convert<T> {
+    pub fn new<L, F>(to: F) -> Convert<T>
+        where L: Serialize, F: Fn(&T) -> L + Send + Sync + 'static
+    {
+        Convert(Arc::new(move |fs, p, v| ser_store(fs, Strategy::Rename, p, &to(v))))
+    }
+}
+
+pub struct DualWrite<T> {
+    path: PathBuf,
+    // Lock files aren't shareable across threads
+    flock: Mutex<DirtyFlock>,
+    write: Arc<Write<T>>,
+}
+
+impl<T> DualWrite<T> {
+    pub fn new(path: PathBuf, flock: DirtyFlock, convert: &Convert<T>) -> DualWrite<T> {
+        DualWrite { path, flock: Mutex::new(flock), write: convert.0.clone() }
+    }
+
+    /// Stores the legacy copy of `v`, or deletes it for `None`
+    pub fn commit(&self, fs: &dyn Fs, v: Option<&T>) -> Result<()> {
+        let flock = lock(&self.flock);
+        let _flock = FlockGuard::exclusive(&flock)?;
+        match v {
+            Some(v) => (self.write)(fs, &self.path, v)
+                .chain_err(|| "writing legacy blobject copy"),
+            None => delete(fs, &self.path).map(|_| ())
+                .chain_err(|| "deleting legacy blobject copy"),
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Persisting state on SIGTERM/SIGINT
+//!
+//! Once `install_signal_handlers` has run, a termination signal
+//! arriving while any `BlobMutRef` is alive is held until the last
+//! such guard has committed and dropped, then re-raised with the
+//! default disposition. With no guard alive the signal is re-raised
+//! immediately.
+//!
+//! State a program keeps outside a guard, committing it now and then,
+//! is what a signal usually loses. `AtomBlob::snapshot_on_signal`
+//! returns `Snapshots`, which serialize each value staged with them
+//! on a background thread, writing and syncing it to a file beside
+//! the blob's. All that's left for the handler is to rename the ready
+//! snapshot over the blob file, and sync the directory, before the
+//! signal is re-raised.
+//!
+//! Constraints:
+//!
+//! - Only state inside a guard, or staged and serialized by the time
+//!   the signal arrives, is protected; the process still exits on the
+//!   signal, so handlers for other cleanup must not rely on running
+//!   afterwards.
+//! - A guard held for a long time delays exit by as long, which a
+//!   supervisor may answer with SIGKILL.
+//! - Any previously installed handlers for these signals are
+//!   replaced.
+//! - The handler renames without taking the blob's flock, so a
+//!   snapshot replaces whatever another process committed since it
+//!   was staged. A commit or reload through this process's handles
+//!   discards a snapshot staged before it, but one landing while the
+//!   handler runs may still be replaced.
+//! - Only blobs stored by `Strategy::Rename` on the real filesystem
+//!   can be snapshotted, and at most 16 at once. A discarded snapshot
+//!   file outlives a process that exits on the signal.
+
+use serde::{Serialize, Deserialize};
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use libc::c_int;
+use super::{AtomBlob, Result, ResultExt, Shared, Strategy, envelope, pid};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+static PENDING: AtomicI32 = AtomicI32::new(0);
+static SLOTS: [AtomicPtr<Slot>; 16] = [const { AtomicPtr::new(ptr::null_mut()) }; 16];
+
+// Values of `Slot::ready` besides the index of a snapshot file
+const NONE: usize = 2;
+// Taken by the handler, for good
+const BUSY: usize = 3;
+
+/// Installs handlers for SIGTERM and SIGINT
+pub fn install_signal_handlers() -> Result<()> {
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    for &sig in &[libc::SIGTERM, libc::SIGINT] {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle_signal as extern "C" fn(c_int) as usize;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(sig, &action, ptr::null_mut()) != 0 {
+                INSTALLED.store(false, Ordering::SeqCst);
+                return Err(io::Error::last_os_error())
+                    .chain_err(|| "installing emergency signal handler");
+            }
+        }
+    }
+
+    debug!("installed emergency signal handlers");
+
+    Ok(())
+}
+
+extern "C" fn handle_signal(sig: c_int) {
+    persist();
+    PENDING.store(sig, Ordering::SeqCst);
+    if IN_FLIGHT.load(Ordering::SeqCst) == 0 {
+        reraise(sig);
+    }
+}
+
+fn reraise(sig: c_int) {
+    // Both calls are async-signal-safe
+    unsafe {
+        libc::signal(sig, libc::SIG_DFL);
+        libc::raise(sig);
+    }
+}
+
+// Renames each ready snapshot into place. Only makes
+// async-signal-safe calls.
+fn persist() {
+    for slot in &SLOTS {
+        // Safety: slots are never freed
+        let slot = match unsafe { slot.load(Ordering::SeqCst).as_ref() } {
+            Some(slot) => slot,
+            None => continue,
+        };
+        let ready = slot.ready.swap(BUSY, Ordering::SeqCst);
+        if ready >= NONE {
+            continue;
+        }
+        unsafe {
+            if libc::rename(slot.files[ready].as_ptr(), slot.dst.as_ptr()) != 0 {
+                continue;
+            }
+            let fd = libc::open(slot.dir.as_ptr(), libc::O_RDONLY);
+            if fd >= 0 {
+                libc::fsync(fd);
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+// Where a blob's snapshots are written, and which is ready, read by
+// the handler. Leaked, so it can't be freed under the handler.
+pub(crate) struct Slot {
+    ready: AtomicUsize,
+    files: [CString; 2],
+    dst: CString,
+    dir: CString,
+}
+
+impl Slot {
+    // Discards any ready snapshot, unless the handler has it
+    fn disarm(&self) {
+        let _ = self.ready.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |ready| {
+            if ready == BUSY { None } else { Some(NONE) }
+        });
+    }
+}
+
+// Called once a commit has replaced the blob file
+pub(crate) fn committed(snapshot: &AtomicPtr<Slot>) {
+    // Safety: slots are never freed
+    if let Some(slot) = unsafe { snapshot.load(Ordering::SeqCst).as_ref() } {
+        slot.disarm();
+    }
+}
+
+/// A blob's snapshots, renamed over its file by the signal handlers;
+/// see the module docs
+///
+/// Dropping it discards any snapshot not yet renamed.
+pub struct Snapshots<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    jobs: Option<Sender<(T, u64)>>,
+    worker: Option<JoinHandle<()>>,
+    shared: Arc<Shared<T>>,
+    slot: &'static Slot,
+    index: usize,
+    files: [PathBuf; 2],
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    /// Starts keeping snapshots of values staged with the returned
+    /// `Snapshots`, for the signal handlers to persist, installing
+    /// them if they aren't already
+    pub fn snapshot_on_signal(&self) -> Result<Snapshots<T>> {
+        if self.shared.strategy != Strategy::Rename || self.fs.in_process().is_some() {
+            return Err("blobject snapshots need the rename strategy and the real filesystem".into());
+        }
+        install_signal_handlers()?;
+
+        let p = &*self.path;
+        let files = [0, 1].map(|i| p.with_extension(format!("emergency{}-{}", i, pid())));
+        let dir = match p.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let c = |p: &Path| CString::new(p.as_os_str().as_bytes())
+            .chain_err(|| "naming blobject snapshot");
+        let slot: &'static Slot = Box::leak(Box::new(Slot {
+            ready: AtomicUsize::new(NONE),
+            files: [c(&files[0])?, c(&files[1])?],
+            dst: c(p)?,
+            dir: c(dir)?,
+        }));
+        let slot_ptr = slot as *const Slot as *mut Slot;
+        if self.shared.snapshot.compare_exchange(ptr::null_mut(), slot_ptr, Ordering::SeqCst,
+                                                 Ordering::SeqCst).is_err() {
+            return Err("blobject already has snapshots".into());
+        }
+        let index = match SLOTS.iter().position(|s| {
+            s.compare_exchange(ptr::null_mut(), slot_ptr, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        }) {
+            Some(index) => index,
+            None => {
+                self.shared.snapshot.store(ptr::null_mut(), Ordering::SeqCst);
+                return Err("too many blobject snapshots".into());
+            }
+        };
+
+        let (jobs, rx) = mpsc::channel();
+        let shared = self.shared.clone();
+        let worker_files = files.clone();
+        let worker = thread::Builder::new()
+            .name("blobject-snapshot".to_string())
+            .spawn(move || run(&shared, slot, &worker_files, rx));
+        // Dropped on failure, unregistering the slot
+        let mut snapshots = Snapshots {
+            jobs: Some(jobs),
+            worker: None,
+            shared: self.shared.clone(),
+            slot,
+            index,
+            files,
+        };
+        snapshots.worker = Some(worker.chain_err(|| "starting blobject snapshot thread")?);
+        Ok(snapshots)
+    }
+}
+
+impl<T> Snapshots<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    /// Queues `v` to be serialized, to persist if a signal arrives
+    /// before the blob is next committed or reloaded
+    ///
+    /// Values staged faster than they're serialized are skipped, but
+    /// for the newest.
+    pub fn stage(&self, v: T) {
+        let generation = self.shared.generation.load(Ordering::SeqCst);
+        if let Some(jobs) = &self.jobs {
+            if jobs.send((v, generation)).is_err() {
+                error!("blobject snapshot thread is gone");
+            }
+        }
+    }
+}
+
+impl<T> Drop for Snapshots<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("blobject snapshot thread panicked");
+            }
+        }
+        self.slot.disarm();
+        SLOTS[self.index].store(ptr::null_mut(), Ordering::SeqCst);
+        self.shared.snapshot.store(ptr::null_mut(), Ordering::SeqCst);
+        for file in &self.files {
+            if let Err(e) = fs::remove_file(file) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("deleting blobject snapshot: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// Writes each staged value to the snapshot file the handler isn't
+// reading, then makes it the ready one
+fn run<T>(shared: &Shared<T>, slot: &Slot, files: &[PathBuf; 2], rx: Receiver<(T, u64)>)
+    where for <'de> T: Serialize + Deserialize<'de> + Default
+{
+    let mut next = 0;
+    while let Ok(job) = rx.recv() {
+        // Only the newest matters
+        let (v, generation) = rx.try_iter().last().unwrap_or(job);
+        let r = envelope::to_vec(&v, shared.stamp())
+            .and_then(|buf| write(&files[next], &buf).chain_err(|| "writing blobject snapshot"));
+        if let Err(e) = r {
+            warn!("snapshotting blobject: {}", e);
+            continue;
+        }
+        let current = |ready| if ready == BUSY { None } else { Some(next) };
+        if slot.ready.fetch_update(Ordering::SeqCst, Ordering::SeqCst, current).is_err() {
+            // The handler has run
+            return;
+        }
+        // Committed or reloaded since it was staged, so it's stale. A
+        // commit after this check disarms it itself
+        if shared.generation.load(Ordering::SeqCst) != generation {
+            slot.disarm();
+        }
+        next = 1 - next;
+    }
+}
+
+fn write(p: &Path, buf: &[u8]) -> io::Result<()> {
+    let mut f = File::create(p)?;
+    f.write_all(buf)?;
+    f.sync_all()
+}
+
+/// Held by every `BlobMutRef` for its lifetime
+pub(crate) struct InFlight(());
+
+impl InFlight {
+    pub(crate) fn new() -> InFlight {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        InFlight(())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if IN_FLIGHT.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let sig = PENDING.load(Ordering::SeqCst);
+            if sig != 0 {
+                debug!("re-raising deferred signal {}", sig);
+                reraise(sig);
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Commit times and schema versions stored with the value
+//!
+//! With `Builder::commit_times`, the blob file holds
+//! `{"committed_at": <ms since the Unix epoch>, "value": <value>}`
+//! instead of the bare value, and with `Builder::schema_version`,
+//! `{"schema": <version>, "value": <value>}`, or both. Every handle on
+//! the blob, in every process, must agree on whether there's an
+//! envelope.
+
+use serde::{Serialize, Deserialize};
+use serde_derive::{Serialize as SerializeDerive, Deserialize as DeserializeDerive};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use super::{ErrorKind, FileId, Parse, Result, ResultExt, Store, Strategy, json_to_vec,
+            ser_reload, ser_store_with};
+use super::vfs::Fs;
+
+#[derive(Default, SerializeDerive, DeserializeDerive)]
+pub struct Envelope<T> {
+    #[serde(default)]
+    committed_at: Option<u64>,
+    #[serde(default)]
+    schema: Option<u64>,
+    pub value: T,
+}
+
+impl<T> Envelope<T> {
+    /// A value stored without a commit time
+    pub fn bare(value: T) -> Envelope<T> {
+        Envelope { committed_at: None, schema: None, value }
+    }
+
+    pub fn committed_at(&self) -> Option<SystemTime> {
+        self.committed_at.map(|ms| UNIX_EPOCH + Duration::from_millis(ms))
+    }
+
+    /// Fails if the value was written by a newer schema than
+    /// `parse` accepts
+    pub fn check(&self, parse: Parse<'_>) -> Result<()> {
+        match (self.schema, parse.max_schema) {
+            (Some(found), Some(max)) if found > max => {
+                Err(ErrorKind::FutureVersion(found, max).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub type Reloaded<T> = Option<Result<Option<(Envelope<T>, FileId)>>>;
+
+#[derive(SerializeDerive)]
+struct EnvelopeRef<'a, T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    committed_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schema: Option<u64>,
+    value: &'a T,
+}
+
+/// What's stored around a committed value
+#[derive(Clone, Copy, Default)]
+pub struct Stamp {
+    pub committed_at: Option<SystemTime>,
+    pub schema: Option<u64>,
+}
+
+impl Stamp {
+    fn wrap<'a, T>(&self, value: &'a T) -> Option<EnvelopeRef<'a, T>> {
+        if self.committed_at.is_none() && self.schema.is_none() {
+            return None;
+        }
+        Some(EnvelopeRef {
+            committed_at: self.committed_at.map(millis),
+            schema: self.schema,
+            value,
+        })
+    }
+}
+
+/// Like `ser_reload`, unwrapping the value if `parse.enveloped`
+pub fn reload<T>(fs: &dyn Fs, strategy: Strategy, p: &Path, known: Option<&FileId>,
+                 parse: Parse<'_>) -> Reloaded<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default
+{
+    if parse.enveloped {
+        return ser_reload(fs, strategy, p, known, parse).map(|r| {
+            let r: Option<(Envelope<T>, FileId)> = r?;
+            if let Some((ref v, _)) = r {
+                v.check(parse)?;
+            }
+            Ok(r)
+        });
+    }
+    ser_reload(fs, strategy, p, known, parse)
+        .map(|r| r.map(|v| v.map(|(value, id)| (Envelope::bare(value), id))))
+}
+
+/// Serializes the value, wrapping it if it's stamped
+pub fn to_vec<T>(t: &T, stamp: Stamp) -> Result<Vec<u8>>
+    where T: Serialize
+{
+    let r = match stamp.wrap(t) {
+        Some(envelope) => json_to_vec(&envelope),
+        None => json_to_vec(t),
+    };
+    r.chain_err(|| "serializing blobject to file")
+}
+
+/// Like `ser_store`, wrapping the value if it's stamped
+pub fn store<T>(fs: &dyn Fs, strategy: Strategy, p: &Path, t: &T,
+                stamp: Stamp, store: Store<'_>) -> Result<u64>
+    where T: Serialize
+{
+    match stamp.wrap(t) {
+        Some(envelope) => ser_store_with(fs, strategy, p, &envelope, store),
+        None => ser_store_with(fs, strategy, p, t, store),
+    }
+}
+
+fn millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
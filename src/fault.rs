@@ -0,0 +1,207 @@
+//! Failing chosen filesystem calls, for testing commit errors
+//!
+//! A `FaultyFs` wraps another filesystem, and before each write, sync
+//! and rename asks a policy whether to fail it instead. Calls of each
+//! kind are numbered from 1, so a test can fail exactly the third
+//! rename, say, and check what its own code does about it; `nth` is
+//! the policy for that. A write is the creation of a file to write,
+//! as of a temp file a commit serializes the value into, and
+//! `Fs::replace` is both a write and a rename. Clones share the
+//! policy and the counts.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use super::vfs::{FileId, Fs};
+
+/// A kind of call a `FaultyFs` can fail
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Op {
+    Write,
+    Sync,
+    Rename,
+}
+
+/// Decides whether the `n`th call of a kind, on a path, fails, and
+/// with what
+pub type Policy = dyn Fn(Op, u64, &Path) -> Option<io::Error> + Send + Sync;
+
+/// A policy failing only the `n`th call of `op`, with an error of
+/// `kind`
+pub fn nth(op: Op, n: u64, kind: io::ErrorKind)
+           -> impl Fn(Op, u64, &Path) -> Option<io::Error> + Send + Sync + 'static
+{
+    move |o, i, p| {
+        if o == op && i == n {
+            Some(io::Error::new(kind, format!("injected {:?} fault at {}", op, p.display())))
+        } else {
+            None
+        }
+    }
+}
+
+/// A filesystem failing the calls its policy chooses
+#[derive(Clone)]
+pub struct FaultyFs<F> {
+    inner: F,
+    faults: Arc<Faults>,
+}
+
+struct Faults {
+    policy: Box<Policy>,
+    writes: AtomicU64,
+    syncs: AtomicU64,
+    renames: AtomicU64,
+}
+
+impl<F: Fs> FaultyFs<F> {
+    pub fn new<P>(inner: F, policy: P) -> FaultyFs<F>
+        where P: Fn(Op, u64, &Path) -> Option<io::Error> + Send + Sync + 'static
+    {
+        FaultyFs {
+            inner,
+            faults: Arc::new(Faults {
+                policy: Box::new(policy),
+                writes: AtomicU64::new(0),
+                syncs: AtomicU64::new(0),
+                renames: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// The filesystem calls are passed to
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// How many calls of `op` there have been, failed or not
+    pub fn calls(&self, op: Op) -> u64 {
+        self.count(op).load(Ordering::SeqCst)
+    }
+
+    fn count(&self, op: Op) -> &AtomicU64 {
+        match op {
+            Op::Write => &self.faults.writes,
+            Op::Sync => &self.faults.syncs,
+            Op::Rename => &self.faults.renames,
+        }
+    }
+
+    fn check(&self, op: Op, p: &Path) -> io::Result<()> {
+        let n = self.count(op).fetch_add(1, Ordering::SeqCst) + 1;
+        match (self.faults.policy)(op, n, p) {
+            Some(e) => {
+                debug!("injecting blobject {:?} fault: {}", op, e);
+                Err(e)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl<F: Fs> Fs for FaultyFs<F> {
+    fn open(&self, p: &Path) -> io::Result<Option<(Box<dyn Read>, FileId)>> {
+        self.inner.open(p)
+    }
+
+    fn id(&self, p: &Path) -> io::Result<Option<FileId>> {
+        self.inner.id(p)
+    }
+
+    fn create(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        self.check(Op::Write, p)?;
+        self.inner.create(p)
+    }
+
+    fn create_new(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        self.check(Op::Write, p)?;
+        self.inner.create_new(p)
+    }
+
+    fn create_sized(&self, p: &Path, size: u64) -> io::Result<Box<dyn Write>> {
+        self.check(Op::Write, p)?;
+        self.inner.create_sized(p, size)
+    }
+
+    fn create_direct(&self, p: &Path, size: Option<u64>) -> io::Result<Box<dyn Write>> {
+        self.check(Op::Write, p)?;
+        self.inner.create_direct(p, size)
+    }
+
+    fn append(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        self.check(Op::Write, p)?;
+        self.inner.append(p)
+    }
+
+    fn rename(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.check(Op::Rename, dst)?;
+        self.inner.rename(src, dst)
+    }
+
+    fn replace(&self, tmp: &Path, p: &Path, buf: &[u8]) -> io::Result<()> {
+        self.check(Op::Write, tmp)?;
+        if let Err(e) = self.check(Op::Rename, p) {
+            // Leaving the temp file written, as a failed rename would
+            let mut f = self.inner.create(tmp)?;
+            f.write_all(buf)?;
+            f.flush()?;
+            return Err(e);
+        }
+        self.inner.replace(tmp, p, buf)
+    }
+
+    fn remove(&self, p: &Path) -> io::Result<()> {
+        self.inner.remove(p)
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.inner.link(src, dst)
+    }
+
+    fn copy(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.inner.copy(src, dst)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(dir)
+    }
+
+    fn walk(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.walk(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(dir)
+    }
+
+    fn sync(&self, p: &Path) -> io::Result<()> {
+        self.check(Op::Sync, p)?;
+        self.inner.sync(p)
+    }
+
+    fn sync_contents(&self, p: &Path) -> io::Result<()> {
+        self.check(Op::Sync, p)?;
+        self.inner.sync_contents(p)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.inner.sync_dir(dir)
+    }
+
+    fn available(&self, p: &Path) -> io::Result<Option<u64>> {
+        self.inner.available(p)
+    }
+
+    fn canonicalize(&self, p: &Path) -> io::Result<PathBuf> {
+        self.inner.canonicalize(p)
+    }
+
+    fn lock_path(&self, p: &Path) -> PathBuf {
+        self.inner.lock_path(p)
+    }
+
+    fn in_process(&self) -> Option<u64> {
+        self.inner.in_process()
+    }
+}
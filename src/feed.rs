@@ -0,0 +1,400 @@
+//! Committed values of a blob, streamed over gRPC
+//!
+//! A `FeedServer` is a tonic service, `atomic_blobject.Feed`, with one
+//! server-streaming method, `Subscribe`, that sends a subscriber the
+//! blob's value as JSON, and again after every commit. A `FeedClient`
+//! is the other end, connecting to a server and receiving the values
+//! of a `Subscription` in turn.
+//!
+//! Commits are pushed to the server by `Builder::on_commit`, through
+//! a `Feed`:
+//!
+//! ```ignore
+//! let feed = Feed::new();
+//! let notify = feed.clone();
+//! let blob = Builder::new("config.json")
+//!     .on_commit(move |r| notify.notify(r))
+//!     .open()?;
+//! Server::builder().add_service(feed.server(blob)).serve(addr).await?;
+//! ```
+//!
+//! So the feed has the commits of the handles opened by that
+//! `Builder`, and their clones, not of other processes. A subscriber
+//! is sent the value as it is when it subscribes, then the newest
+//! value after each commit. One that reads slower than the blob
+//! changes skips to the newest value, instead of being sent every
+//! one in between.
+//!
+//! The messages are written out by hand, so it takes no `protoc` to
+//! build. As a `.proto` file, the service is:
+//!
+//! ```text
+//! syntax = "proto3";
+//! package atomic_blobject;
+//!
+//! service Feed {
+//!   rpc Subscribe(SubscribeRequest) returns (stream Generation);
+//! }
+//!
+//! message SubscribeRequest {}
+//!
+//! message Generation {
+//!   // BlobRef::generation, which is local to the server's process
+//!   uint64 generation = 1;
+//!   // The value, as JSON
+//!   bytes value = 2;
+//! }
+//! ```
+
+use prost::bytes::{Buf, BufMut};
+use prost::encoding::{self, DecodeContext, WireType};
+use prost::{DecodeError, Message};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use std::convert::{Infallible, TryInto};
+use std::marker::PhantomData;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, watch};
+use tonic::body::BoxBody;
+use tonic::codec::{ProstCodec, Streaming};
+use tonic::codegen::{Body, BoxFuture, Context, Poll, Service, StdError, empty_body, http};
+use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
+use tonic::server::{Grpc, NamedService, ServerStreamingService};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request, Response, Status};
+use super::{AtomBlob, CommitReceipt, Result, ResultExt, json_to_vec, lock};
+
+const SUBSCRIBE: &str = "/atomic_blobject.Feed/Subscribe";
+
+/// Tells a `FeedServer` of commits
+#[derive(Clone)]
+pub struct Feed {
+    commits: Arc<watch::Sender<u64>>,
+}
+
+impl Feed {
+    pub fn new() -> Feed {
+        Feed { commits: Arc::new(watch::channel(0).0) }
+    }
+
+    /// Sends the commit on to subscribers; call it from
+    /// `Builder::on_commit`
+    pub fn notify(&self, receipt: &CommitReceipt) {
+        self.commits.send_replace(receipt.generation);
+    }
+
+    /// A service streaming the value of `blob`, which should be
+    /// opened with a `Builder::on_commit` that calls `notify`
+    pub fn server<T>(&self, blob: AtomBlob<T>) -> FeedServer<T>
+        where for <'de> T: Serialize + Deserialize<'de> + Default,
+    {
+        FeedServer {
+            inner: Arc::new(Inner { blob: Mutex::new(blob), commits: self.commits.subscribe() }),
+        }
+    }
+}
+
+impl Default for Feed {
+    fn default() -> Feed {
+        Feed::new()
+    }
+}
+
+/// Serves the feed of a blob, for tonic's `Server::add_service`
+pub struct FeedServer<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    inner: Arc<Inner<T>>,
+}
+
+struct Inner<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    // Cloned for each subscriber
+    blob: Mutex<AtomBlob<T>>,
+    commits: watch::Receiver<u64>,
+}
+
+impl<T> Clone for FeedServer<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    fn clone(&self) -> FeedServer<T> {
+        FeedServer { inner: self.inner.clone() }
+    }
+}
+
+impl<T> NamedService for FeedServer<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    const NAME: &'static str = "atomic_blobject.Feed";
+}
+
+impl<T, B> Service<http::Request<B>> for FeedServer<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+          B: Body + Send + 'static,
+          B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<http::Response<BoxBody>, Infallible>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<StdResult<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        if req.uri().path() != SUBSCRIBE {
+            return Box::pin(async {
+                Ok(http::Response::builder()
+                   .header("grpc-status", (Code::Unimplemented as i32).to_string())
+                   .header(http::header::CONTENT_TYPE, "application/grpc")
+                   .body(empty_body())
+                   .unwrap())
+            });
+        }
+        let subscribe = Subscribe(self.inner.clone());
+        Box::pin(async move {
+            Ok(Grpc::new(ProstCodec::default()).server_streaming(subscribe, req).await)
+        })
+    }
+}
+
+struct Subscribe<T>(Arc<Inner<T>>)
+    where for <'de> T: Serialize + Deserialize<'de> + Default;
+
+impl<T> ServerStreamingService<SubscribeRequest> for Subscribe<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    type Response = Generation;
+    type ResponseStream = ReceiverStream<StdResult<Generation, Status>>;
+    type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, _: Request<SubscribeRequest>) -> Self::Future {
+        let blob = lock(&self.0.blob).clone();
+        let mut commits = self.0.commits.clone();
+        // Room for one value: a slow subscriber holds up the task,
+        // which then reads the newest value, not each in between
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut blob = blob;
+            let mut sent = None;
+            loop {
+                commits.mark_unchanged();
+                let read = tokio::task::spawn_blocking(move || {
+                    let r = read(&mut blob);
+                    (blob, r)
+                }).await;
+                let g = match read {
+                    Ok((b, Ok(g))) => {
+                        blob = b;
+                        g
+                    }
+                    Ok((_, Err(e))) => {
+                        let _ = tx.send(Err(Status::internal(e.to_string()))).await;
+                        return;
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(Status::internal(e.to_string()))).await;
+                        return;
+                    }
+                };
+                if sent != Some(g.generation) {
+                    sent = Some(g.generation);
+                    if tx.send(Ok(g)).await.is_err() {
+                        return;
+                    }
+                }
+                tokio::select! {
+                    r = commits.changed() => if r.is_err() { return },
+                    _ = tx.closed() => return,
+                }
+            }
+        });
+        Box::pin(async { Ok(Response::new(ReceiverStream::new(rx))) })
+    }
+}
+
+fn read<T>(blob: &mut AtomBlob<T>) -> Result<Generation>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    let v = blob.get()?;
+    let value = json_to_vec(&*v).chain_err(|| "serializing blobject")?;
+    Ok(Generation { generation: v.generation(), value })
+}
+
+/// Connects to a `FeedServer`
+pub struct FeedClient<T> {
+    grpc: tonic::client::Grpc<Channel>,
+    _t: PhantomData<fn() -> T>,
+}
+
+impl<T> FeedClient<T>
+    where T: DeserializeOwned,
+{
+    /// Connects to the server at `dst`, like `http://[::1]:50051`
+    pub async fn connect<D>(dst: D) -> Result<FeedClient<T>>
+        where D: TryInto<Endpoint>, D::Error: Into<StdError>,
+    {
+        let channel = Endpoint::new(dst).chain_err(|| "bad feed address")?
+            .connect().await.chain_err(|| "connecting to feed")?;
+        Ok(FeedClient::new(channel))
+    }
+
+    /// A client on a channel of one's own, e.g. for TLS
+    pub fn new(channel: Channel) -> FeedClient<T> {
+        FeedClient { grpc: tonic::client::Grpc::new(channel), _t: PhantomData }
+    }
+
+    /// Subscribes to the blob, whose value is the first received
+    pub async fn subscribe(&mut self) -> Result<Subscription<T>> {
+        self.grpc.ready().await.chain_err(|| "connecting to feed")?;
+        let stream = self.grpc
+            .server_streaming(Request::new(SubscribeRequest {}),
+                              http::uri::PathAndQuery::from_static(SUBSCRIBE),
+                              ProstCodec::default()).await
+            .map_err(status)?
+            .into_inner();
+        Ok(Subscription { stream, _t: PhantomData })
+    }
+}
+
+/// The values of a blob as it's committed
+pub struct Subscription<T> {
+    stream: Streaming<Generation>,
+    _t: PhantomData<fn() -> T>,
+}
+
+impl<T> Subscription<T>
+    where T: DeserializeOwned,
+{
+    /// Waits for the next value and its generation, or `None` once
+    /// the server has ended the feed
+    pub async fn next(&mut self) -> Result<Option<(u64, T)>> {
+        match self.stream.message().await.map_err(status)? {
+            Some(g) => {
+                let v = serde_json::from_slice(&g.value).chain_err(|| "deserializing blobject")?;
+                Ok(Some((g.generation, v)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn status(s: Status) -> super::Error {
+    format!("feed failed: {}", s).into()
+}
+
+/// Asks for a blob's values
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SubscribeRequest {}
+
+impl Message for SubscribeRequest {
+    fn encode_raw(&self, _: &mut impl BufMut) {}
+
+    fn merge_field(&mut self, tag: u32, wire_type: WireType, buf: &mut impl Buf,
+                   ctx: DecodeContext) -> StdResult<(), DecodeError> {
+        encoding::skip_field(wire_type, tag, buf, ctx)
+    }
+
+    fn encoded_len(&self) -> usize {
+        0
+    }
+
+    fn clear(&mut self) {}
+}
+
+/// A value of the blob, as JSON, and its generation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Generation {
+    pub generation: u64,
+    pub value: Vec<u8>,
+}
+
+impl Message for Generation {
+    fn encode_raw(&self, buf: &mut impl BufMut) {
+        if self.generation != 0 {
+            encoding::uint64::encode(1, &self.generation, buf);
+        }
+        if !self.value.is_empty() {
+            encoding::bytes::encode(2, &self.value, buf);
+        }
+    }
+
+    fn merge_field(&mut self, tag: u32, wire_type: WireType, buf: &mut impl Buf,
+                   ctx: DecodeContext) -> StdResult<(), DecodeError> {
+        match tag {
+            1 => encoding::uint64::merge(wire_type, &mut self.generation, buf, ctx),
+            2 => encoding::bytes::merge(wire_type, &mut self.value, buf, ctx),
+            _ => encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        let mut len = 0;
+        if self.generation != 0 {
+            len += encoding::uint64::encoded_len(1, &self.generation);
+        }
+        if !self.value.is_empty() {
+            len += encoding::bytes::encoded_len(2, &self.value);
+        }
+        len
+    }
+
+    fn clear(&mut self) {
+        *self = Generation::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use std::net::TcpListener as StdListener;
+    use tonic::codegen::tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+    use super::{Feed, FeedClient, Generation};
+    use super::super::Builder;
+    use super::super::vfs::MockFs;
+
+    #[test]
+    fn encodes() {
+        let g = Generation { generation: 300, value: b"[1]".to_vec() };
+        let buf = g.encode_to_vec();
+        assert_eq!(buf.len(), g.encoded_len());
+        assert_eq!(Generation::decode(&buf[..]).unwrap(), g);
+        assert_eq!(Generation::decode(&[][..]).unwrap(), Generation::default());
+    }
+
+    #[test]
+    fn streams_commits() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let feed = Feed::new();
+            let notify = feed.clone();
+            let mut blob = Builder::new("feed.json").fs(MockFs::new())
+                .on_commit(move |r| notify.notify(r))
+                .open::<Vec<u32>>().unwrap();
+            let listener = StdListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            let serving = Server::builder().add_service(feed.server(blob.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener));
+            tokio::spawn(serving);
+
+            let mut client = FeedClient::<Vec<u32>>::connect(format!("http://{}", addr))
+                .await.unwrap();
+            let mut subscription = client.subscribe().await.unwrap();
+            let (_, first) = subscription.next().await.unwrap().unwrap();
+            assert_eq!(first, Vec::<u32>::new());
+
+            let blob = tokio::task::spawn_blocking(move || {
+                blob.get_mut().unwrap().push(1);
+                blob
+            }).await.unwrap();
+            let (g, v) = subscription.next().await.unwrap().unwrap();
+            assert_eq!(v, vec![1]);
+            assert_eq!(g, blob.clone().get().unwrap().generation());
+        });
+    }
+}
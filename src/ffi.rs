@@ -0,0 +1,215 @@
+//! C API over `DynAtomBlob`
+//!
+//! Handles are opaque pointers returned by `atomic_blobject_open`
+//! and released with `atomic_blobject_close`. Values cross the
+//! boundary as NUL-terminated JSON strings. Functions returning a
+//! pointer return null on failure, functions returning `c_int`
+//! return 0 on success and -1 on failure; failures are logged. A
+//! panic fails the call the same way, rather than unwinding into C.
+//!
+//! Build a linkable library with e.g.
+//! `cargo rustc --release --features ffi --crate-type cdylib`.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use super::{DynAtomBlob, Result, ResultExt};
+
+/// Opens the blob at `path`
+///
+/// # Safety
+///
+/// `path` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn atomic_blobject_open(path: *const c_char) -> *mut DynAtomBlob {
+    let r = catch(|| cstr(path).and_then(DynAtomBlob::new));
+    match r {
+        Ok(blob) => Box::into_raw(Box::new(blob)),
+        Err(e) => {
+            error!("ffi open: {}", e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Returns the current value as JSON, to be freed with
+/// `atomic_blobject_free_string`
+///
+/// # Safety
+///
+/// `blob` must be a live handle from `atomic_blobject_open`.
+#[no_mangle]
+pub unsafe extern "C" fn atomic_blobject_get(blob: *mut DynAtomBlob) -> *mut c_char {
+    let r = catch(|| {
+        let blob = &mut *blob;
+        let v = blob.get()?;
+        let s = serde_json::to_string(&*v)
+            .chain_err(|| "serializing blobject for ffi")?;
+        CString::new(s).chain_err(|| "serializing blobject for ffi")
+    });
+    match r {
+        Ok(s) => s.into_raw(),
+        Err(e) => {
+            error!("ffi get: {}", e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Replaces the value with the JSON document `json` and commits it
+///
+/// # Safety
+///
+/// `blob` must be a live handle from `atomic_blobject_open` and
+/// `json` a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn atomic_blobject_set(blob: *mut DynAtomBlob,
+                                             json: *const c_char) -> c_int {
+    let r = catch(|| {
+        let blob = &mut *blob;
+        let newval = serde_json::from_str(cstr(json)?)
+            .chain_err(|| "parsing json for ffi")?;
+        let mut v = blob.get_mut()?;
+        *v = newval;
+        match v.commit() {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                // Not tried again as the guard drops, which panics
+                v.discard();
+                Err(e)
+            }
+        }
+    });
+    match r {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("ffi set: {}", e);
+            -1
+        }
+    }
+}
+
+/// Releases a handle from `atomic_blobject_open`
+///
+/// # Safety
+///
+/// `blob` must be null or a live handle, and is invalid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn atomic_blobject_close(blob: *mut DynAtomBlob) {
+    if !blob.is_null() {
+        let blob = Box::from_raw(blob);
+        if let Err(e) = catch(|| {
+            drop(blob);
+            Ok(())
+        }) {
+            error!("ffi close: {}", e);
+        }
+    }
+}
+
+/// Frees a string returned by `atomic_blobject_get`
+///
+/// # Safety
+///
+/// `s` must be null or a string from `atomic_blobject_get`, and is
+/// invalid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn atomic_blobject_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+// Runs `f`, failing rather than unwinding if it panics
+fn catch<R, F>(f: F) -> Result<R>
+    where F: FnOnce() -> Result<R>
+{
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err("blobject panicked in ffi call".into()))
+}
+
+unsafe fn cstr<'a>(s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        return Err("null string passed over ffi".into());
+    }
+    CStr::from_ptr(s).to_str()
+        .chain_err(|| "non-utf8 string passed over ffi")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::fs;
+    use std::path::Path;
+    use std::ptr;
+    use super::*;
+
+    fn path(name: &str) -> CString {
+        let dir = std::env::temp_dir().join(format!("blobject-ffi-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let p = dir.join(name);
+        let _ = fs::remove_file(&p);
+        CString::new(p.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn set_then_get() {
+        let p = path("set.json");
+        unsafe {
+            let blob = atomic_blobject_open(p.as_ptr());
+            assert!(!blob.is_null());
+            let json = CString::new(r#"{"a":[1,2]}"#).unwrap();
+            assert_eq!(atomic_blobject_set(blob, json.as_ptr()), 0);
+            atomic_blobject_close(blob);
+
+            let blob = atomic_blobject_open(p.as_ptr());
+            let s = atomic_blobject_get(blob);
+            assert_eq!(CStr::from_ptr(s).to_str().unwrap(), r#"{"a":[1,2]}"#);
+            atomic_blobject_free_string(s);
+            atomic_blobject_close(blob);
+        }
+    }
+
+    #[test]
+    fn failures() {
+        let p = path("fail.json");
+        unsafe {
+            assert!(atomic_blobject_open(ptr::null()).is_null());
+            let blob = atomic_blobject_open(p.as_ptr());
+            let bad = CString::new("{").unwrap();
+            assert_eq!(atomic_blobject_set(blob, bad.as_ptr()), -1);
+            assert_eq!(atomic_blobject_set(blob, ptr::null()), -1);
+            // Still the value before the failed sets
+            let s = atomic_blobject_get(blob);
+            assert_eq!(CStr::from_ptr(s).to_str().unwrap(), "null");
+            atomic_blobject_free_string(s);
+            atomic_blobject_close(blob);
+            atomic_blobject_close(ptr::null_mut());
+            atomic_blobject_free_string(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn failed_commits() {
+        let p = path("commit.json");
+        let json = CString::new("[1]").unwrap();
+        unsafe {
+            let blob = atomic_blobject_open(p.as_ptr());
+            assert_eq!(atomic_blobject_set(blob, json.as_ptr()), 0);
+            // Nothing can be renamed over a directory with files in it
+            let dir = Path::new(p.to_str().unwrap());
+            fs::remove_file(dir).unwrap();
+            fs::create_dir(dir).unwrap();
+            fs::write(dir.join("f"), b"").unwrap();
+            let json = CString::new("[2]").unwrap();
+            assert_eq!(atomic_blobject_set(blob, json.as_ptr()), -1);
+            fs::remove_dir_all(dir).unwrap();
+            // The failed value was dropped, not kept to commit later
+            let s = atomic_blobject_get(blob);
+            assert_eq!(CStr::from_ptr(s).to_str().unwrap(), "null");
+            atomic_blobject_free_string(s);
+            atomic_blobject_close(blob);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! One reload at a time among readers
+//!
+//! After another process commits, every shared guard taken on the
+//! handles sharing a value finds it stale. The first to look leads a
+//! reload, and the rest wait for it on a condition variable instead
+//! of queueing on the value's write lock to each check the file
+//! again. All of them hold shared flocks, so the file can't change
+//! while they wait, and what the leader loads is what they'd have
+//! loaded. If the leader fails, the next in line tries itself.
+//! `AtomBlob::reload_in_progress` shows when one is under way.
+
+use serde::{Serialize, Deserialize};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use super::{AtomBlob, lock};
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// Whether a shared guard on a handle sharing this value is
+    /// reloading it, with other shared guards waiting on it
+    pub fn reload_in_progress(&self) -> bool {
+        self.shared.reloads.in_progress()
+    }
+}
+
+pub struct Flights {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    running: bool,
+    // Reloads finished, and whether the last one succeeded
+    finished: u64,
+    ok: bool,
+}
+
+/// The reload this reader leads, finished when dropped
+pub struct Lead<'a> {
+    flights: &'a Flights,
+    ok: bool,
+}
+
+impl Flights {
+    pub fn new() -> Flights {
+        Flights { state: Mutex::new(State::default()), cond: Condvar::new() }
+    }
+
+    /// Leads a reload, or waits for the one running, returning `None`
+    /// if it succeeded
+    pub fn join(&self) -> Option<Lead<'_>> {
+        let mut state = self.lock();
+        loop {
+            if !state.running {
+                state.running = true;
+                return Some(Lead { flights: self, ok: false });
+            }
+            let finished = state.finished;
+            while state.finished == finished {
+                state = self.cond.wait(state).unwrap_or_else(PoisonError::into_inner);
+            }
+            if state.ok {
+                return None;
+            }
+        }
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.lock().running
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+}
+
+impl<'a> Lead<'a> {
+    pub fn succeeded(mut self) {
+        self.ok = true;
+    }
+}
+
+impl<'a> Drop for Lead<'a> {
+    fn drop(&mut self) {
+        let mut state = self.flights.lock();
+        state.running = false;
+        state.finished += 1;
+        state.ok = self.ok;
+        self.flights.cond.notify_all();
+    }
+}
@@ -0,0 +1,189 @@
+//! Scoped guards over the dirty flock
+
+#[cfg(not(target_os = "wasi"))]
+use std::cell::Cell;
+use std::io;
+use std::path::Path;
+#[cfg(not(target_os = "wasi"))]
+use super::protocol;
+use super::Result;
+
+pub use super::local_flock::State;
+
+/// A dirty flock on a lock file, or in memory for filesystems only
+/// this process can see
+pub enum DirtyFlock {
+    // With whether the lock protocol's version was found, or recorded,
+    // the last time it was checked, or `None` if it wasn't, or failed
+    #[cfg(not(target_os = "wasi"))]
+    File(super::lockfile::DirtyFlock, Cell<Option<bool>>),
+    Local(super::local_flock::DirtyFlock),
+}
+
+impl DirtyFlock {
+    pub fn new<P>(p: P, in_process: Option<u64>) -> DirtyFlock
+        where P: AsRef<Path>
+    {
+        match in_process {
+            Some(fs) => DirtyFlock::Local(super::local_flock::DirtyFlock::new(fs, p)),
+            #[cfg(not(target_os = "wasi"))]
+            None => DirtyFlock::File(super::lockfile::DirtyFlock::new(p), Cell::new(None)),
+            #[cfg(target_os = "wasi")]
+            None => unreachable!("WASI filesystems are in-process"),
+        }
+    }
+
+    pub fn lock_shared(&self) -> io::Result<State> {
+        match *self {
+            #[cfg(not(target_os = "wasi"))]
+            DirtyFlock::File(ref f, _) => f.lock_shared().map(from_file_state),
+            DirtyFlock::Local(ref f) => f.lock_shared(),
+        }
+    }
+
+    pub fn lock_exclusive(&self) -> io::Result<State> {
+        match *self {
+            #[cfg(not(target_os = "wasi"))]
+            DirtyFlock::File(ref f, _) => f.lock_exclusive().map(from_file_state),
+            DirtyFlock::Local(ref f) => f.lock_exclusive(),
+        }
+    }
+
+    /// Like `lock_shared`, but `None` instead of waiting
+    pub fn try_lock_shared(&self) -> io::Result<Option<State>> {
+        match *self {
+            #[cfg(not(target_os = "wasi"))]
+            DirtyFlock::File(ref f, _) => uncontended(f.try_lock_shared().map(from_file_state)),
+            DirtyFlock::Local(ref f) => f.try_lock_shared(),
+        }
+    }
+
+    /// Like `lock_exclusive`, but `None` instead of waiting
+    pub fn try_lock_exclusive(&self) -> io::Result<Option<State>> {
+        match *self {
+            #[cfg(not(target_os = "wasi"))]
+            DirtyFlock::File(ref f, _) => uncontended(f.try_lock_exclusive().map(from_file_state)),
+            DirtyFlock::Local(ref f) => f.try_lock_exclusive(),
+        }
+    }
+
+    pub fn unlock(&self) -> io::Result<()> {
+        match *self {
+            #[cfg(not(target_os = "wasi"))]
+            DirtyFlock::File(ref f, _) => f.unlock(),
+            DirtyFlock::Local(ref f) => f.unlock(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match *self {
+            #[cfg(not(target_os = "wasi"))]
+            DirtyFlock::File(ref f, _) => f.path(),
+            DirtyFlock::Local(ref f) => f.path(),
+        }
+    }
+
+    /// Fails unless the lock file, just locked in `state`, speaks this
+    /// version of the lock protocol; see `protocol`
+    ///
+    /// The file is only written under the exclusive lock, which moves
+    /// its epoch on, so a clean lock finds what the last check did,
+    /// and only the first lock and dirty ones have to read it again.
+    pub fn check(&self, exclusive: bool, state: State) -> Result<()> {
+        #[cfg(not(target_os = "wasi"))]
+        if let DirtyFlock::File(ref f, ref recorded) = *self {
+            match (state, recorded.take()) {
+                (State::Clean, Some(true)) => recorded.set(Some(true)),
+                (State::Clean, Some(false)) if !exclusive => recorded.set(Some(false)),
+                _ => recorded.set(Some(check_file(f, exclusive)?)),
+            }
+        }
+        #[cfg(target_os = "wasi")]
+        let _ = (exclusive, state);
+        Ok(())
+    }
+}
+
+#[cfg(all(not(feature = "fancy_flocks"), not(target_os = "wasi")))]
+fn check_file(f: &super::lockfile::DirtyFlock, exclusive: bool) -> Result<bool> {
+    f.with_file(|file| protocol::check(file, f.path(), exclusive))
+}
+
+// `fancy_flocks` doesn't lend out the file it locked, so it's opened
+// again
+#[cfg(all(feature = "fancy_flocks", not(target_os = "wasi")))]
+fn check_file(f: &super::lockfile::DirtyFlock, exclusive: bool) -> Result<bool> {
+    use super::ResultExt;
+    let file = std::fs::OpenOptions::new().read(true).write(exclusive).open(f.path())
+        .chain_err(|| "opening blobject lock file")?;
+    protocol::check(&file, f.path(), exclusive)
+}
+
+/// Whether a failed non-blocking lock failed because it's held
+#[cfg(not(target_os = "wasi"))]
+pub fn is_contended(e: &std::io::Error) -> bool {
+    // ERROR_LOCK_VIOLATION
+    e.kind() == std::io::ErrorKind::WouldBlock || (cfg!(windows) && e.raw_os_error() == Some(33))
+}
+
+#[cfg(not(target_os = "wasi"))]
+fn uncontended<T>(r: io::Result<T>) -> io::Result<Option<T>> {
+    match r {
+        Ok(v) => Ok(Some(v)),
+        Err(ref e) if is_contended(e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+fn from_file_state(s: super::lockfile::State) -> State {
+    match s {
+        super::lockfile::State::Dirty => State::Dirty,
+        super::lockfile::State::Clean => State::Clean,
+    }
+}
+
+pub struct FlockGuard<'a> {
+    flock: &'a DirtyFlock,
+    state: State,
+}
+
+impl<'a> FlockGuard<'a> {
+    pub fn shared(flock: &'a DirtyFlock) -> Result<FlockGuard<'a>> {
+        let state = flock.lock_shared()?;
+        FlockGuard { flock, state }.checked(false)
+    }
+
+    pub fn exclusive(flock: &'a DirtyFlock) -> Result<FlockGuard<'a>> {
+        let state = flock.lock_exclusive()?;
+        FlockGuard { flock, state }.checked(true)
+    }
+
+    pub fn try_shared(flock: &'a DirtyFlock) -> Result<Option<FlockGuard<'a>>> {
+        flock.try_lock_shared()?.map(|state| FlockGuard { flock, state }.checked(false))
+            .transpose()
+    }
+
+    pub fn try_exclusive(flock: &'a DirtyFlock) -> Result<Option<FlockGuard<'a>>> {
+        flock.try_lock_exclusive()?.map(|state| FlockGuard { flock, state }.checked(true))
+            .transpose()
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    // Unlocked again on failure, as the guard is dropped
+    fn checked(self, exclusive: bool) -> Result<FlockGuard<'a>> {
+        self.flock.check(exclusive, self.state)?;
+        Ok(self)
+    }
+}
+
+impl<'a> Drop for FlockGuard<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flock.unlock() {
+            error!("dropping scoped flock: {}", e);
+        }
+    }
+}
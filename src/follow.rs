@@ -0,0 +1,312 @@
+//! Read-only handles that mirror a blob written elsewhere
+//!
+//! A `Follower` keeps the latest committed value of a blob that some
+//! other handle, usually in another process, writes. It never writes,
+//! and never locks: blob files are only ever replaced by rename, so a
+//! file opened by path is always a whole commit. That also lets it
+//! follow a blob on a read-only mount. A thread checks the file's id,
+//! and parses it only when it's been replaced, so `Follower::get`
+//! never touches the disk.
+//!
+//! With `Track::Watch` the thread waits on inotify for changes to the
+//! blob's directory. Elsewhere than Linux, or if the directory can't
+//! be watched, it polls like `Track::Poll`.
+
+use serde::{Serialize, Deserialize};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, RwLockReadGuard, Weak};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use super::{AtomBlob, Builder, Result, ResultExt, Shared, Strategy, TypedBuilder, envelope,
+            lock, poisoned, validate};
+use super::vfs::{FileId, Fs};
+
+/// How a `Follower` notices new commits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Track {
+    /// Checks the file every interval
+    Poll(Duration),
+    /// Waits to be told the blob's directory changed, and also checks
+    /// every interval, in case a change isn't reported
+    Watch(Duration),
+}
+
+pub struct Follower<T> {
+    inner: Arc<Inner<T>>,
+}
+
+struct Inner<T> {
+    shared: Shared<T>,
+    fs: Arc<dyn Fs>,
+    path: PathBuf,
+    // One refresh at a time
+    refreshing: Mutex<()>,
+    // The last file that failed to load, not retried until replaced
+    failed: Mutex<Option<FileId>>,
+}
+
+pub struct FollowRef<'a, T: 'a> {
+    v: RwLockReadGuard<'a, T>,
+    shared: &'a Shared<T>,
+}
+
+impl Builder {
+    /// Opens a read-only handle that keeps up with commits made by
+    /// other handles, as `track` says
+    ///
+    /// Only `Strategy::Rename` blobs can be followed, since reading
+    /// the slots of a double-buffered blob needs its lock.
+    pub fn follow<T>(self, track: Track) -> Result<Follower<T>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+    {
+        self.typed().follow(track)
+    }
+}
+
+impl<T> TypedBuilder<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    /// Like `Builder::follow`, checking each value read with the
+    /// validator
+    pub fn follow(mut self, track: Track) -> Result<Follower<T>> {
+        if self.builder.strategy != Strategy::Rename {
+            return Err("following a blobject needs the rename strategy".into());
+        }
+        self.builder.lazy = false;
+        let this = self.builder.pin()?;
+        let inner = Arc::new(Inner {
+            shared: this.load(self.validate)?,
+            fs: this.fs.clone(),
+            path: this.path.clone(),
+            refreshing: Mutex::new(()),
+            failed: Mutex::new(None),
+        });
+
+        let weak = Arc::downgrade(&inner);
+        thread::Builder::new()
+            .name("blobject-follow".to_string())
+            .spawn(move || run(weak, track))
+            .chain_err(|| "starting blobject follow thread")?;
+
+        Ok(Follower { inner })
+    }
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// Like `new`, but a read-only handle that follows commits made
+    /// elsewhere; see `Builder::follow`
+    pub fn follow<P>(p: P, track: Track) -> Result<Follower<T>>
+        where P: AsRef<Path>, T: Send + Sync + 'static,
+    {
+        Builder::new(p).follow(track)
+    }
+}
+
+impl<T> Follower<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// The latest value seen, without touching the disk
+    pub fn get(&self) -> FollowRef<'_, T> {
+        let shared = &self.inner.shared;
+        FollowRef {
+            v: shared.v.read().unwrap_or_else(PoisonError::into_inner),
+            shared,
+        }
+    }
+
+    /// Checks for a new commit now, instead of waiting for the thread
+    pub fn refresh(&self) -> Result<()> {
+        self.inner.refresh()
+    }
+}
+
+impl<T> Inner<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    fn refresh(&self) -> Result<()> {
+        let _refreshing = lock(&self.refreshing);
+        let shared = &self.shared;
+        let id = self.fs.id(&self.path).chain_err(|| "checking followed blobject")?;
+        let known = lock(&shared.loaded).clone();
+        if id == known || (id.is_some() && id == *lock(&self.failed)) {
+            return Ok(());
+        }
+
+        // Parsed without blocking readers
+        let (v, id) = match envelope::reload::<T>(&*self.fs, Strategy::Rename, &self.path,
+                                                  known.as_ref(), shared.parse()) {
+            Some(Ok(Some((v, id)))) => {
+                if let Err(e) = validate(shared.validate.as_deref(), &v.value) {
+                    *lock(&self.failed) = Some(id);
+                    return Err(e);
+                }
+                (v, Some(id))
+            }
+            Some(Ok(None)) => return Ok(()),
+            Some(Err(e)) => {
+                *lock(&self.failed) = id;
+                return Err(e);
+            }
+            None => (envelope::Envelope::bare(T::default()), None),
+        };
+
+        let mut val = shared.v.write().map_err(poisoned)?;
+        *lock(&shared.committed_at) = v.committed_at();
+        *val = v.value;
+        *lock(&shared.loaded) = id;
+        shared.loaded_now(true);
+        shared.unloaded.store(false, Ordering::SeqCst);
+        debug!("followed blobject changed");
+        Ok(())
+    }
+}
+
+fn run<T>(inner: Weak<Inner<T>>, track: Track)
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    let (interval, watch) = match track {
+        Track::Poll(interval) => (interval, None),
+        Track::Watch(interval) => {
+            let watch = inner.upgrade().and_then(|i| {
+                let dir = i.fs.canonicalize(&i.path).ok()?.parent()?.to_owned();
+                match watch::Watch::new(&dir) {
+                    Ok(w) => Some(w),
+                    Err(e) => {
+                        debug!("polling followed blobject, can't watch {}: {}", dir.display(), e);
+                        None
+                    }
+                }
+            });
+            (interval, watch)
+        }
+    };
+
+    loop {
+        match watch {
+            Some(ref w) => {
+                if let Err(e) = w.wait(interval) {
+                    warn!("watching followed blobject: {}", e);
+                    thread::sleep(interval);
+                }
+            }
+            None => thread::sleep(interval),
+        }
+        let inner = match inner.upgrade() {
+            Some(i) => i,
+            None => return,
+        };
+        if let Err(e) = inner.refresh() {
+            warn!("reloading followed blobject: {}", e);
+        }
+    }
+}
+
+impl<'a, T: 'a> FollowRef<'a, T> {
+    /// Whether there's a blob file, as opposed to the value being
+    /// `T::default()` for lack of one
+    pub fn exists(&self) -> bool {
+        lock(&self.shared.loaded).is_some()
+    }
+
+    /// When the value was committed, if the blob is written with
+    /// `Builder::commit_times`
+    pub fn committed_at(&self) -> Option<SystemTime> {
+        *lock(&self.shared.committed_at)
+    }
+
+    /// When the value was read from the file
+    pub fn loaded_at(&self) -> SystemTime {
+        *lock(&self.shared.loaded_at)
+    }
+
+    /// How many times the value has changed since the follower was
+    /// opened; see `BlobRef::generation`
+    pub fn generation(&self) -> u64 {
+        self.shared.generation.load(Ordering::SeqCst)
+    }
+}
+
+impl<'a, T: 'a> Deref for FollowRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.v
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod watch {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// An inotify watch on a directory
+    pub struct Watch(File);
+
+    impl Watch {
+        pub fn new(dir: &Path) -> io::Result<Watch> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let f = unsafe { File::from_raw_fd(fd) };
+            let dir = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let mask = libc::IN_MOVED_TO | libc::IN_CLOSE_WRITE | libc::IN_DELETE;
+            if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Watch(f))
+        }
+
+        /// Waits until the directory changes, or `timeout` passes
+        pub fn wait(&self, timeout: Duration) -> io::Result<()> {
+            let mut pfd = libc::pollfd { fd: self.0.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            let ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            if unsafe { libc::poll(&mut pfd, 1, ms) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            // Which files changed doesn't matter, since a check is
+            // cheap
+            let mut buf = [0u8; 4096];
+            loop {
+                match (&self.0).read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod watch {
+    use std::io;
+    use std::path::Path;
+    use std::time::Duration;
+
+    pub struct Watch;
+
+    impl Watch {
+        pub fn new(_dir: &Path) -> io::Result<Watch> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "no inotify"))
+        }
+
+        pub fn wait(&self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Earlier generations of a blob
+//!
+//! With `Builder::keep_generations`, each commit first keeps the file
+//! it replaces as `<blob>.<generation>.gen`, numbering generations
+//! upward from 1, and deletes all but the newest few, or with
+//! `Builder::keep_generations_for` those current too long ago. Kept
+//! files are hard links where the filesystem has them, so keeping one
+//! costs no copy, and otherwise reflinks or copies, which keep the
+//! file's modification time. They're kept next to the blob, or in
+//! `Builder::generations_dir`. They are never modified, and are read
+//! without locking.
+//!
+//! `AtomBlob::open_at` reads one version, by generation or by the
+//! time it was current.
+
+use serde::{Serialize, Deserialize};
+use std::io;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use super::{AtomBlob, Builder, OnLoadError, Parse, Result, ResultExt, Strategy,
+            envelope};
+use super::vfs::Fs;
+
+/// One kept generation of a blob, loaded on demand
+pub struct HistoricalBlob<T> {
+    generation: u64,
+    path: PathBuf,
+    fs: Arc<dyn Fs>,
+    lenient: bool,
+    enveloped: bool,
+    on_load_error: Option<Arc<OnLoadError>>,
+    ph: PhantomData<fn() -> T>,
+}
+
+impl<T> HistoricalBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// When this generation was committed, if the filesystem knows
+    pub fn committed_at(&self) -> Option<SystemTime> {
+        self.fs.id(&self.path).ok().flatten().and_then(|id| id.modified())
+    }
+
+    /// Reads and parses this generation
+    ///
+    /// Fails if it has been deleted since it was listed.
+    pub fn load(&self) -> Result<T> {
+        let parse = Parse {
+            lenient: self.lenient,
+            on_error: self.on_load_error.as_deref(),
+            enveloped: self.enveloped,
+            buffer_size: None,
+            max_schema: None,
+            mirror: None,
+            deny_duplicates: false,
+            deny_unknown: false,
+            hash: None,
+        };
+        match envelope::reload(&*self.fs, Strategy::Rename, &self.path, None, parse) {
+            Some(r) => Ok(r?.expect("reloading unknown file").0.value),
+            None => Err(io::Error::from(io::ErrorKind::NotFound))
+                .chain_err(|| "loading blobject generation"),
+        }
+    }
+
+    fn version(&self, generation: Option<u64>) -> Result<Version<T>> {
+        Ok(Version {
+            generation,
+            committed_at: self.committed_at(),
+            v: self.load()?,
+        })
+    }
+}
+
+/// Which version `AtomBlob::open_at` opens
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum At {
+    /// A kept generation
+    Generation(u64),
+    /// Whichever version was current at this time, kept or current
+    Time(SystemTime),
+}
+
+impl From<SystemTime> for At {
+    fn from(t: SystemTime) -> At {
+        At::Time(t)
+    }
+}
+
+/// A read-only copy of one version of a blob
+pub struct Version<T> {
+    generation: Option<u64>,
+    committed_at: Option<SystemTime>,
+    v: T,
+}
+
+impl<T> Version<T> {
+    /// The generation, or `None` for the current value
+    pub fn generation(&self) -> Option<u64> {
+        self.generation
+    }
+
+    pub fn committed_at(&self) -> Option<SystemTime> {
+        self.committed_at
+    }
+
+    pub fn into_inner(self) -> T {
+        self.v
+    }
+}
+
+impl<T> Deref for Version<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.v
+    }
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// The kept generations, oldest first, not including the current
+    /// value
+    pub fn history(&self) -> Result<impl Iterator<Item = HistoricalBlob<T>>> {
+        let generations = list(&*self.fs, &self.path, self.shared.generations_dir.as_deref())
+            .chain_err(|| "listing blobject generations")?;
+        let fs = self.fs.clone();
+        let path = self.path.clone();
+        let dir = self.shared.generations_dir.clone();
+        let lenient = self.shared.lenient;
+        let enveloped = self.shared.enveloped();
+        let on_load_error = self.shared.on_load_error.clone();
+        Ok(generations.into_iter().map(move |generation| HistoricalBlob {
+            generation,
+            path: path_of(&path, dir.as_deref(), generation),
+            fs: fs.clone(),
+            lenient,
+            enveloped,
+            on_load_error: on_load_error.clone(),
+            ph: PhantomData,
+        }))
+    }
+
+    /// Reads one version of the blob at `p`, without opening it
+    pub fn open_at<P, A>(p: P, at: A) -> Result<Version<T>>
+        where P: AsRef<Path>, A: Into<At>,
+    {
+        Builder::new(p).open_at(at)
+    }
+}
+
+impl Builder {
+    /// Reads one version of the blob, without opening it
+    pub fn open_at<T, A>(self, at: A) -> Result<Version<T>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default, A: Into<At>,
+    {
+        let this = self.pin()?;
+        if this.strategy != Strategy::Rename {
+            return Err("blobject generations need the rename strategy".into());
+        }
+
+        let kept = |generation, path| HistoricalBlob::<T> {
+            generation,
+            path,
+            fs: this.fs.clone(),
+            lenient: this.lenient,
+            enveloped: this.enveloped(),
+            on_load_error: this.on_load_error.clone(),
+            ph: PhantomData,
+        };
+        let dir = this.generations_dir.as_deref();
+        let t = match at.into() {
+            At::Generation(g) => return kept(g, path_of(&this.path, dir, g)).version(Some(g)),
+            At::Time(t) => t,
+        };
+
+        // The newest version committed by `t`, starting from the
+        // current one
+        let generations = list(&*this.fs, &this.path, dir)
+            .chain_err(|| "listing blobject generations")?;
+        let mut candidates = Vec::new();
+        if this.fs.id(&this.path).chain_err(|| "opening blobject")?.is_some() {
+            candidates.push((None, kept(0, this.path.clone())));
+        }
+        for &g in generations.iter().rev() {
+            candidates.push((Some(g), kept(g, path_of(&this.path, dir, g))));
+        }
+        for (generation, h) in candidates {
+            match h.committed_at() {
+                Some(c) if c <= t => return h.version(generation),
+                Some(_) => (),
+                None => return Err("blobject filesystem doesn't record commit times".into()),
+            }
+        }
+
+        Err("no blobject version that old".into())
+    }
+}
+
+/// Which generations are kept, and where
+#[derive(Clone, Copy)]
+pub struct Retain<'a> {
+    /// The newest this many, or any number if 0
+    pub keep: usize,
+    /// Those current within this long of `now`
+    pub max_age: Option<Duration>,
+    pub now: SystemTime,
+    pub dir: Option<&'a Path>,
+}
+
+/// Keeps the file at `p` as the next generation, then deletes the
+/// oldest beyond what `retain` keeps
+pub fn retain(fs: &dyn Fs, p: &Path, retain: Retain<'_>) -> io::Result<()> {
+    if fs.id(p)?.is_none() {
+        return Ok(());
+    }
+
+    let dir = retain.dir;
+    let mut generations = list(fs, p, dir)?;
+    let next = generations.last().map_or(1, |g| g + 1);
+    fs.link(p, &path_of(p, dir, next))?;
+    generations.push(next);
+
+    let mut excess = match retain.keep {
+        0 => 0,
+        keep => generations.len().saturating_sub(keep),
+    };
+    if let Some(cutoff) = retain.max_age.and_then(|age| retain.now.checked_sub(age)) {
+        // Each generation was current until the next was committed,
+        // and the newest until now
+        let ends = generations[1..].iter()
+            .map(|&g| fs.id(&path_of(p, dir, g)).ok().flatten().and_then(|id| id.modified()));
+        let expired = ends.take_while(|end| end.is_some_and(|end| end < cutoff)).count();
+        excess = excess.max(expired);
+    }
+    for &generation in &generations[..excess] {
+        if let Err(e) = fs.remove(&path_of(p, dir, generation)) {
+            warn!("deleting blobject generation {}: {}", generation, e);
+        }
+    }
+
+    debug!("kept blobject generation {}", next);
+
+    Ok(())
+}
+
+/// Moves the generations kept for `from` in `dir` to `to`
+pub(crate) fn rename(fs: &dyn Fs, from: &Path, to: &Path, dir: Option<&Path>)
+                     -> io::Result<()> {
+    for generation in list(fs, from, dir)? {
+        fs.rename(&path_of(from, dir, generation), &path_of(to, dir, generation))?;
+    }
+    Ok(())
+}
+
+fn path_of(p: &Path, dir: Option<&Path>, generation: u64) -> PathBuf {
+    let kept = p.with_extension(format!("{}.gen", generation));
+    match (dir, kept.file_name()) {
+        (Some(dir), Some(name)) => dir.join(name),
+        _ => kept,
+    }
+}
+
+// The generations kept for `p` in `dir`, or next to it, in order
+fn list(fs: &dyn Fs, p: &Path, dir: Option<&Path>) -> io::Result<Vec<u64>> {
+    let dir = dir.or(p.parent()).unwrap_or(Path::new(""));
+    let stem = match p.file_stem().and_then(|s| s.to_str()) {
+        Some(stem) => format!("{}.", stem),
+        None => return Ok(Vec::new()),
+    };
+
+    let mut generations: Vec<u64> = fs.read_dir(dir)?.iter()
+        .filter_map(|f| f.file_name().and_then(|n| n.to_str()))
+        .filter_map(|n| n.strip_prefix(&*stem)?.strip_suffix(".gen")?.parse().ok())
+        .collect();
+    generations.sort_unstable();
+    Ok(generations)
+}
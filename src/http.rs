@@ -0,0 +1,638 @@
+//! Blobs served over HTTP
+//!
+//! A `Server` serves one blob to many machines: `GET` answers with
+//! the value as JSON and an `ETag`, and `PUT` with an `If-Match` of
+//! that ETag commits a new value, but only if nothing else has been
+//! committed since. A `RemoteAtomBlob` is the other end, with the
+//! same guards as an `AtomBlob`: `get` reads the latest value,
+//! fetching it only if its ETag has changed, and `get_mut` gives a
+//! guard whose commit, if another writer got in first, is settled by
+//! `RemoteAtomBlob::policy`, by default failing with
+//! `ErrorKind::Conflict`.
+//!
+//! ETags are hashes of the JSON, so they survive the server
+//! restarting. The HTTP is the least that works, one request per
+//! connection, and there's no TLS, so put a proxy in front of it for
+//! anything but a trusted network. Still, a client can only hold up
+//! the server so far: requests have to arrive within
+//! `Server::timeout`, lines and bodies are capped in size, and
+//! connections past `Server::max_connections` wait to be accepted.
+
+use serde::{Serialize, Deserialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{AtomBlob, ErrorKind, Policy, Result, ResultExt, json_to_vec, lock, sha256};
+
+// Headers past this many are refused
+const MAX_HEADERS: usize = 64;
+
+// Request and header lines longer than this are refused
+const MAX_LINE: u64 = 8 * 1024;
+
+/// Serves a blob, one thread per connection
+pub struct Server {
+    listener: TcpListener,
+    timeout: Duration,
+    max_body: u64,
+    max_connections: usize,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Server> {
+        let listener = TcpListener::bind(addr).chain_err(|| "binding blobject server")?;
+        Ok(Server {
+            listener,
+            timeout: Duration::from_secs(30),
+            max_body: 16 * 1024 * 1024,
+            max_connections: 64,
+        })
+    }
+
+    /// How long a client has to send its request, and each write of
+    /// the response may wait; 30 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> Server {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The largest `PUT` taken, answering bigger ones with 413; 16
+    /// MiB by default
+    pub fn max_body(mut self, bytes: u64) -> Server {
+        self.max_body = bytes;
+        self
+    }
+
+    /// How many connections are served at once, 64 by default
+    ///
+    /// Further connections wait in the listen backlog.
+    pub fn max_connections(mut self, n: usize) -> Server {
+        self.max_connections = n.max(1);
+        self
+    }
+
+    /// The address bound, for finding the port when binding port 0
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves `blob` at every path, until accepting a connection fails
+    ///
+    /// Each connection gets its own clone of the blob, so the server
+    /// is just another handle to it, and other handles, local or in
+    /// other processes, may keep committing.
+    pub fn serve<T>(self, blob: &AtomBlob<T>) -> Result<()>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+    {
+        let workers = Arc::new(Workers {
+            active: Mutex::new(0),
+            cond: Condvar::new(),
+            max: self.max_connections,
+        });
+        let (timeout, max_body) = (self.timeout, self.max_body);
+        loop {
+            let worker = Workers::start(&workers);
+            let (stream, peer) = self.listener.accept()
+                .chain_err(|| "accepting blobject connection")?;
+            stream.set_write_timeout(Some(timeout))
+                .chain_err(|| "setting blobject connection timeout")?;
+            let mut blob = blob.clone();
+            thread::Builder::new()
+                .name("blobject-http".to_string())
+                .spawn(move || {
+                    let _worker = worker;
+                    if let Err(e) = handle(&mut blob, stream, timeout, max_body) {
+                        debug!("serving blobject to {}: {}", peer, e);
+                    }
+                })
+                .chain_err(|| "starting blobject connection thread")?;
+        }
+    }
+}
+
+// Counts the connections being served
+struct Workers {
+    active: Mutex<usize>,
+    cond: Condvar,
+    max: usize,
+}
+
+// A connection's place among them, given up on drop
+struct Worker(Arc<Workers>);
+
+impl Workers {
+    // Waits for a place
+    fn start(workers: &Arc<Workers>) -> Worker {
+        let mut active = lock(&workers.active);
+        while *active >= workers.max {
+            active = workers.cond.wait(active).unwrap_or_else(PoisonError::into_inner);
+        }
+        *active += 1;
+        Worker(workers.clone())
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        *lock(&self.0.active) -= 1;
+        self.0.cond.notify_one();
+    }
+}
+
+// Reads from a connection until a deadline, however slowly the bytes
+// arrive
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    at: Instant,
+}
+
+impl<'a> Read for Deadline<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.at.saturating_duration_since(Instant::now());
+        if left == Duration::from_secs(0) {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+struct Message {
+    // The request or status line
+    start: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Message {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+struct Response {
+    status: &'static str,
+    etag: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn status(status: &'static str) -> Response {
+        Response { status, etag: None, body: Vec::new() }
+    }
+}
+
+fn handle<T>(blob: &mut AtomBlob<T>, stream: TcpStream, timeout: Duration, max_body: u64)
+             -> io::Result<()>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    let deadline = Deadline { stream: &stream, at: Instant::now() + timeout };
+    let req = match read_message(&mut BufReader::new(deadline), max_body) {
+        Ok(req) => req,
+        // Answered, rather than leaving the client to guess
+        Err(e) => {
+            let status = match e.kind() {
+                io::ErrorKind::InvalidData => "400 Bad Request",
+                io::ErrorKind::FileTooLarge => "413 Content Too Large",
+                _ => return Err(e),
+            };
+            respond(&stream, Response::status(status))?;
+            return Err(e);
+        }
+    };
+    let method = req.start.split(' ').next().unwrap_or("");
+    let resp = match method {
+        "GET" => get(blob, &req),
+        "PUT" => put(blob, &req),
+        _ => Ok(Response::status("405 Method Not Allowed")),
+    };
+    let resp = resp.unwrap_or_else(|e| {
+        warn!("serving blobject: {}", e);
+        Response::status("500 Internal Server Error")
+    });
+    respond(&stream, resp)
+}
+
+fn respond(stream: &TcpStream, resp: Response) -> io::Result<()> {
+    let mut w = io::BufWriter::new(stream);
+    write!(w, "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+           resp.status, resp.body.len())?;
+    if let Some(etag) = resp.etag {
+        write!(w, "ETag: {}\r\n", etag)?;
+    }
+    if !resp.body.is_empty() {
+        write!(w, "Content-Type: application/json\r\n")?;
+    }
+    write!(w, "\r\n")?;
+    w.write_all(&resp.body)?;
+    w.flush()
+}
+
+fn get<T>(blob: &mut AtomBlob<T>, req: &Message) -> Result<Response>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    let body = json_to_vec(&*blob.get()?).chain_err(|| "serializing blobject")?;
+    let etag = etag(&body);
+    if req.header("If-None-Match") == Some(&etag) {
+        return Ok(Response { etag: Some(etag), ..Response::status("304 Not Modified") });
+    }
+    Ok(Response { status: "200 OK", etag: Some(etag), body })
+}
+
+fn put<T>(blob: &mut AtomBlob<T>, req: &Message) -> Result<Response>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    let expected = match req.header("If-Match") {
+        Some(e) => e,
+        None => return Ok(Response::status("428 Precondition Required")),
+    };
+    let v: T = match serde_json::from_slice(&req.body) {
+        Ok(v) => v,
+        Err(_) => return Ok(Response::status("400 Bad Request")),
+    };
+    let matches = |v: &T| -> Result<bool> {
+        let body = json_to_vec(v).chain_err(|| "serializing blobject")?;
+        Ok(expected == "*" || expected == etag(&body))
+    };
+
+    // Checked under a shared guard first, so a stale PUT doesn't
+    // wait for the exclusive lock
+    let current = blob.get()?;
+    if !matches(&current)? {
+        return Ok(Response::status("412 Precondition Failed"));
+    }
+    let mut current = current.upgrade()?;
+    // Another writer may have got in during the upgrade
+    if !matches(&current)? {
+        current.discard();
+        return Ok(Response::status("412 Precondition Failed"));
+    }
+    *current = v;
+    if let Err(e) = current.commit() {
+        // Not tried again as the guard drops, which panics
+        current.discard();
+        return Err(e);
+    }
+    let body = json_to_vec(&*current).chain_err(|| "serializing blobject")?;
+    Ok(Response { etag: Some(etag(&body)), ..Response::status("200 OK") })
+}
+
+fn etag(body: &[u8]) -> String {
+    format!("\"{}\"", sha256::hex(body))
+}
+
+// Reads a message with a body of up to `max_body` bytes
+fn read_message<R: BufRead>(r: &mut R, max_body: u64) -> io::Result<Message> {
+    let start = read_line(r)?;
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(r)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(invalid("too many headers"));
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("bad header"))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+    let mut msg = Message { start, headers, body: Vec::new() };
+    match msg.header("Content-Length") {
+        Some(len) => {
+            let len = len.parse::<u64>().map_err(|_| invalid("bad content length"))?;
+            if len > max_body {
+                return Err(io::Error::new(io::ErrorKind::FileTooLarge, "body too large"));
+            }
+            r.by_ref().take(len).read_to_end(&mut msg.body)?;
+            if msg.body.len() as u64 != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        // Requests without a length have no body, and responses end
+        // with the connection
+        None if msg.start.starts_with("HTTP/") => {
+            r.by_ref().take(max_body.saturating_add(1)).read_to_end(&mut msg.body)?;
+            if msg.body.len() as u64 > max_body {
+                return Err(io::Error::new(io::ErrorKind::FileTooLarge, "body too large"));
+            }
+        }
+        None => (),
+    }
+    Ok(msg)
+}
+
+fn read_line<R: BufRead>(r: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if r.by_ref().take(MAX_LINE).read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if !line.ends_with('\n') {
+        return Err(invalid("line too long"));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// A blob served by a `Server` on another machine
+pub struct RemoteAtomBlob<T> {
+    host: String,
+    path: String,
+    value: T,
+    // The ETag `value` was read or committed as, and its JSON
+    etag: Option<String>,
+    fetched: Vec<u8>,
+    policy: Policy,
+}
+
+pub struct RemoteRef<'a, T: 'a> {
+    blob: &'a RemoteAtomBlob<T>,
+}
+
+pub struct RemoteMutRef<'a, T: 'a>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    blob: &'a mut RemoteAtomBlob<T>,
+    committed: bool,
+}
+
+impl<T> RemoteAtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// Connects to the blob at `url`, like `http://host:8080/config`,
+    /// reading it first
+    pub fn connect(url: &str) -> Result<RemoteAtomBlob<T>> {
+        let rest = match url.strip_prefix("http://") {
+            Some(r) => r,
+            None => return Err(format!("blobject url isn't http: {}", url).into()),
+        };
+        let (host, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let mut blob = RemoteAtomBlob {
+            host: host.to_owned(),
+            path: path.to_owned(),
+            value: T::default(),
+            etag: None,
+            fetched: Vec::new(),
+            policy: Policy::FailOnConflict,
+        };
+        blob.reload()?;
+        Ok(blob)
+    }
+
+    /// Settles commits that lose a race to another writer by `policy`
+    pub fn policy(mut self, policy: Policy) -> RemoteAtomBlob<T> {
+        self.policy = policy;
+        self
+    }
+
+    /// Reads the latest value, fetching it only if it's changed
+    pub fn get(&mut self) -> Result<RemoteRef<'_, T>> {
+        self.reload()?;
+        Ok(RemoteRef { blob: self })
+    }
+
+    /// Reads the latest value for changing and committing
+    ///
+    /// Nothing is locked: if another writer commits meanwhile, the
+    /// commit is settled by `RemoteAtomBlob::policy`.
+    pub fn get_mut(&mut self) -> Result<RemoteMutRef<'_, T>> {
+        self.reload()?;
+        Ok(RemoteMutRef { blob: self, committed: false })
+    }
+
+    /// Commits `v` in place of whatever the current value is
+    pub fn replace(&mut self, v: T) -> Result<()> {
+        self.value = v;
+        match self.put("*")? {
+            true => Ok(()),
+            false => Err(ErrorKind::Conflict.into()),
+        }
+    }
+
+    /// The ETag of the value last read or committed
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        let mut headers = Vec::new();
+        if let Some(ref etag) = self.etag {
+            headers.push(("If-None-Match", etag.as_str()));
+        }
+        let resp = self.request("GET", &headers, &[])
+            .chain_err(|| "reading remote blobject")?;
+        match status(&resp) {
+            304 => Ok(()),
+            200 => {
+                self.value = serde_json::from_slice(&resp.body)
+                    .chain_err(|| "loading remote blobject")?;
+                self.etag = resp.header("ETag").map(str::to_owned);
+                self.fetched = resp.body;
+                Ok(())
+            }
+            s => Err(format!("blobject server answered {}", s).into()),
+        }
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let mut expected = self.etag.clone().unwrap_or_else(|| "*".to_owned());
+        while !self.put(&expected)? {
+            debug!("remote blobject committed over, settling by {:?}", self.policy);
+            let base: T = serde_json::from_slice(&self.fetched)
+                .chain_err(|| "loading remote blobject")?;
+            let mine = mem::take(&mut self.value);
+            self.etag = None;
+            self.reload()?;
+            self.value = self.policy.resolve(&base, &self.value, mine)?;
+            expected = self.etag.clone().unwrap_or_else(|| "*".to_owned());
+        }
+        Ok(())
+    }
+
+    // Whether the server took it, or another writer got in first
+    fn put(&mut self, expected: &str) -> Result<bool> {
+        let body = json_to_vec(&self.value).chain_err(|| "serializing blobject")?;
+        let resp = self.request("PUT", &[("If-Match", expected)], &body)
+            .chain_err(|| "committing remote blobject")?;
+        match status(&resp) {
+            200 => {
+                self.etag = resp.header("ETag").map(str::to_owned);
+                self.fetched = body;
+                debug!("remote blobject committed");
+                Ok(true)
+            }
+            412 => Ok(false),
+            s => {
+                // What's held may no longer be what's on the server
+                self.etag = None;
+                Err(format!("blobject server answered {}", s).into())
+            }
+        }
+    }
+
+    fn request(&self, method: &str, headers: &[(&str, &str)], body: &[u8])
+               -> io::Result<Message> {
+        let stream = TcpStream::connect(&*self.host)?;
+        let mut w = io::BufWriter::new(&stream);
+        write!(w, "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+               method, self.path, self.host, body.len())?;
+        for &(name, value) in headers {
+            write!(w, "{}: {}\r\n", name, value)?;
+        }
+        write!(w, "\r\n")?;
+        w.write_all(body)?;
+        w.flush()?;
+        drop(w);
+        // The server is trusted with as much as it sends
+        read_message(&mut BufReader::new(&stream), u64::MAX)
+    }
+}
+
+fn status(resp: &Message) -> u16 {
+    resp.start.split(' ').nth(1).and_then(|s| s.parse().ok()).unwrap_or(0)
+}
+
+impl<'a, T: 'a> Deref for RemoteRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.blob.value
+    }
+}
+
+impl<'a, T: 'a> Deref for RemoteMutRef<'a, T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.blob.value
+    }
+}
+
+impl<'a, T: 'a> DerefMut for RemoteMutRef<'a, T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.blob.value
+    }
+}
+
+impl<'a, T: 'a> RemoteMutRef<'a, T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// Commits the value, settling it by the blob's policy if another
+    /// writer has committed since it was read
+    pub fn commit(&mut self) -> Result<()> {
+        self.committed = true;
+        self.blob.commit()
+    }
+}
+
+impl<'a, T: 'a> Drop for RemoteMutRef<'a, T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    // Losing a race is to be expected of a remote commit, so unlike
+    // `BlobMutRef` this logs, and doesn't panic
+    fn drop(&mut self) {
+        if !self.committed {
+            if let Err(e) = self.commit() {
+                error!("remote blobject failed to commit on drop: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::*;
+    use super::super::Builder;
+    use super::super::vfs::MockFs;
+
+    // Serves a blob of its own for as long as the tests run
+    fn serve(server: Server) -> SocketAddr {
+        let blob: AtomBlob<Vec<u32>> = Builder::new("served.json").fs(MockFs::new())
+            .open().unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve(&blob));
+        addr
+    }
+
+    // The status line answering `req`
+    fn send(addr: SocketAddr, req: &[u8]) -> String {
+        let mut s = TcpStream::connect(addr).unwrap();
+        s.write_all(req).unwrap();
+        let mut resp = String::new();
+        s.read_to_string(&mut resp).unwrap();
+        resp.lines().next().unwrap_or("").to_owned()
+    }
+
+    #[test]
+    fn limits_messages() {
+        let e = read_message(&mut &[b'a'; 9000][..], 10).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let req = b"PUT / HTTP/1.1\r\nContent-Length: 11\r\n\r\n[1,2,3,4,5]";
+        let e = read_message(&mut &req[..], 10).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::FileTooLarge);
+        assert_eq!(read_message(&mut &req[..], 11).unwrap().body, b"[1,2,3,4,5]");
+        let resp = b"HTTP/1.1 200 OK\r\n\r\n[1,2,3,4,5]";
+        let e = read_message(&mut &resp[..], 10).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::FileTooLarge);
+        let mut headers = b"GET / HTTP/1.1\r\n".to_vec();
+        for _ in 0..=MAX_HEADERS {
+            headers.extend_from_slice(b"X: y\r\n");
+        }
+        headers.extend_from_slice(b"\r\n");
+        let e = read_message(&mut &headers[..], 10).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn serves_within_limits() {
+        let addr = serve(Server::bind("127.0.0.1:0").unwrap().max_body(100));
+        let mut remote = RemoteAtomBlob::<Vec<u32>>::connect(&format!("http://{}/", addr))
+            .unwrap();
+        remote.get_mut().unwrap().push(1);
+        assert_eq!(*remote.get().unwrap(), vec![1]);
+        let big = b"PUT / HTTP/1.1\r\nIf-Match: *\r\nContent-Length: 1000000000000\r\n\r\n";
+        assert_eq!(send(addr, big), "HTTP/1.1 413 Content Too Large");
+        assert_eq!(send(addr, b"GET / HTTP/1.1\r\nbad\r\n\r\n"), "HTTP/1.1 400 Bad Request");
+        assert_eq!(*remote.get().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn idle_clients_time_out() {
+        let server = Server::bind("127.0.0.1:0").unwrap()
+            .timeout(Duration::from_millis(200)).max_connections(1);
+        let addr = serve(server);
+        let start = Instant::now();
+        // Holds the only connection, trickling a byte at a time
+        let mut idle = TcpStream::connect(addr).unwrap();
+        let trickle = thread::spawn(move || {
+            for _ in 0..10 {
+                if idle.write_all(b"G").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(send(addr, b"GET / HTTP/1.1\r\n\r\n"), "HTTP/1.1 200 OK");
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        trickle.join().unwrap();
+    }
+}
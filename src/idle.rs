@@ -0,0 +1,74 @@
+//! Dropping in-memory values that haven't been accessed for a while
+//!
+//! Values opened with an idle timeout are watched by one sweeper
+//! thread per process, which swaps an idle value for `T::default()`
+//! and marks it evicted so the next guard reloads it from disk.
+//! Values with a live guard are never evicted.
+
+use std::sync::{Condvar, Mutex, OnceLock, PoisonError, Weak};
+use std::thread;
+use std::time::Duration;
+use super::{Result, lock};
+
+pub trait Evict: Send + Sync {
+    /// Evicts the value if it hasn't been accessed within `timeout`
+    fn evict_if_idle(&self, timeout: Duration);
+}
+
+struct Sweeper {
+    watched: Mutex<Vec<(Weak<dyn Evict>, Duration)>>,
+    cond: Condvar,
+}
+
+fn sweeper() -> Result<&'static Sweeper> {
+    static SWEEPER: OnceLock<Sweeper> = OnceLock::new();
+    static STARTED: OnceLock<std::result::Result<(), String>> = OnceLock::new();
+
+    let sweeper = SWEEPER.get_or_init(|| Sweeper {
+        watched: Mutex::new(Vec::new()),
+        cond: Condvar::new(),
+    });
+    let started = STARTED.get_or_init(|| {
+        thread::Builder::new()
+            .name("blobject-idle".to_string())
+            .spawn(move || sweep(sweeper))
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    });
+    match *started {
+        Ok(()) => Ok(sweeper),
+        Err(ref e) => Err(format!("starting idle sweeper: {}", e).into()),
+    }
+}
+
+/// Starts evicting `v` once it has been idle for `timeout`
+pub fn watch(v: Weak<dyn Evict>, timeout: Duration) -> Result<()> {
+    let sweeper = sweeper()?;
+    lock(&sweeper.watched).push((v, timeout));
+    sweeper.cond.notify_one();
+    Ok(())
+}
+
+fn sweep(sweeper: &Sweeper) {
+    let mut watched = lock(&sweeper.watched);
+    loop {
+        watched.retain(|(v, _)| v.strong_count() > 0);
+        for &(ref v, timeout) in watched.iter() {
+            if let Some(v) = v.upgrade() {
+                v.evict_if_idle(timeout);
+            }
+        }
+
+        // Checking at half the shortest timeout keeps a value from
+        // outliving its timeout by more than half again
+        let tick = watched.iter().map(|&(_, t)| t / 2).min();
+        watched = match tick {
+            Some(tick) => {
+                let tick = tick.max(Duration::from_millis(10));
+                sweeper.cond.wait_timeout(watched, tick)
+                    .unwrap_or_else(PoisonError::into_inner).0
+            }
+            None => sweeper.cond.wait(watched).unwrap_or_else(PoisonError::into_inner),
+        };
+    }
+}
@@ -0,0 +1,70 @@
+//! Writer-priority intent lock
+//!
+//! A writer takes the intent lock exclusively before queueing for the
+//! exclusive flock, and readers pass through it shared before taking
+//! their shared flock. A waiting writer therefore stops new readers
+//! from arriving, and only has to wait for the ones already in.
+//! Every process sharing a blob must agree on using it.
+//!
+//! On filesystems only this process can see it's a no-op.
+
+use std::io::Result;
+use std::path::Path;
+
+#[cfg(not(target_os = "wasi"))]
+use super::lockfile::SdFlock;
+
+pub struct IntentLock {
+    #[cfg(not(target_os = "wasi"))]
+    flock: Option<SdFlock>,
+}
+
+pub struct IntentGuard<'a>(
+    #[cfg_attr(target_os = "wasi", allow(dead_code))]
+    &'a IntentLock,
+);
+
+impl IntentLock {
+    pub fn new<P>(p: P, in_process: Option<u64>) -> IntentLock
+        where P: AsRef<Path>
+    {
+        #[cfg(target_os = "wasi")]
+        let _ = (p, in_process);
+        IntentLock {
+            #[cfg(not(target_os = "wasi"))]
+            flock: match in_process {
+                Some(_) => None,
+                None => Some(SdFlock::new(p)),
+            },
+        }
+    }
+
+    pub fn lock_shared(&self) -> Result<IntentGuard<'_>> {
+        #[cfg(not(target_os = "wasi"))]
+        if let Some(ref f) = self.flock {
+            f.lock_shared()?;
+        }
+        Ok(IntentGuard(self))
+    }
+
+    pub fn lock_exclusive(&self) -> Result<IntentGuard<'_>> {
+        #[cfg(not(target_os = "wasi"))]
+        if let Some(ref f) = self.flock {
+            f.lock_exclusive()?;
+        }
+        Ok(IntentGuard(self))
+    }
+}
+
+impl<'a> Drop for IntentGuard<'a> {
+    fn drop(&mut self) {
+        #[cfg(not(target_os = "wasi"))]
+        {
+            if let Some(ref f) = (self.0).flock {
+                if let Err(e) = f.unlock() {
+                    error!("dropping intent lock: {}", e);
+                }
+            }
+        }
+    }
+}
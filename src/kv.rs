@@ -0,0 +1,123 @@
+//! A blob holding a map of string keys to values that can expire
+//!
+//! Expiration times are wall-clock milliseconds since the Unix epoch,
+//! so every process sharing the blob agrees on them. Expired entries
+//! are never returned. They're deleted when a lookup finds one, on
+//! any write, and by `KvBlob::purge_expired`.
+
+use serde::{Serialize, Deserialize};
+use serde_derive::{Serialize as SerializeDerive, Deserialize as DeserializeDerive};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use super::{AtomBlob, Result};
+use super::memory::HeapSize;
+
+/// The value of a `KvBlob`'s underlying blob
+pub type Entries<V> = BTreeMap<String, Entry<V>>;
+
+#[derive(Clone, Debug, SerializeDerive, DeserializeDerive)]
+pub struct Entry<V> {
+    value: V,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+}
+
+impl<V: HeapSize> HeapSize for Entry<V> {
+    fn heap_size(&self) -> usize {
+        self.value.heap_size()
+    }
+}
+
+impl<V> Entry<V> {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|e| e <= now)
+    }
+}
+
+pub struct KvBlob<V>
+    where for <'de> V: Serialize + Deserialize<'de>,
+{
+    blob: AtomBlob<Entries<V>>,
+}
+
+impl<V> KvBlob<V>
+    where for <'de> V: Serialize + Deserialize<'de> + Send + Sync + 'static,
+{
+    pub fn new<P>(p: P) -> Result<KvBlob<V>>
+        where P: AsRef<Path>,
+    {
+        Ok(KvBlob::from_blob(AtomBlob::new(p)?))
+    }
+
+    /// Wraps a blob opened with other options
+    pub fn from_blob(blob: AtomBlob<Entries<V>>) -> KvBlob<V> {
+        KvBlob { blob }
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<V>>
+        where V: Clone
+    {
+        let now = now_millis();
+        let entries = self.blob.get()?;
+        match entries.get(key) {
+            Some(e) if e.is_expired(now) => (),
+            Some(e) => return Ok(Some(e.value.clone())),
+            None => return Ok(None),
+        }
+
+        let mut entries = entries.upgrade()?;
+        purge(&mut entries, now);
+        Ok(None)
+    }
+
+    /// Sets `key`, expiring after `ttl` if given
+    pub fn insert(&mut self, key: String, value: V, ttl: Option<Duration>) -> Result<()> {
+        let now = now_millis();
+        let expires = ttl.map(|ttl| now.saturating_add(ttl.as_millis() as u64));
+        let mut entries = self.blob.get_mut()?;
+        purge(&mut entries, now);
+        entries.insert(key, Entry { value, expires });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<Option<V>> {
+        let now = now_millis();
+        let mut entries = self.blob.get_mut()?;
+        purge(&mut entries, now);
+        Ok(entries.remove(key).map(|e| e.value))
+    }
+
+    /// Deletes every expired entry, returning how many there were
+    ///
+    /// Doesn't commit if there were none.
+    pub fn purge_expired(&mut self) -> Result<usize> {
+        let now = now_millis();
+        let entries = self.blob.get()?;
+        if !entries.values().any(|e| e.is_expired(now)) {
+            return Ok(0);
+        }
+        let mut entries = entries.upgrade()?;
+        Ok(purge(&mut entries, now))
+    }
+
+    pub fn blob(&mut self) -> &mut AtomBlob<Entries<V>> {
+        &mut self.blob
+    }
+}
+
+fn purge<V>(entries: &mut Entries<V>, now: u64) -> usize {
+    let before = entries.len();
+    entries.retain(|_, e| !e.is_expired(now));
+    let purged = before - entries.len();
+    if purged > 0 {
+        debug!("purged {} expired blobject entries", purged);
+    }
+    purged
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
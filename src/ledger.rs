@@ -0,0 +1,92 @@
+//! A store's stats blob, kept up to date by its commits
+//!
+//! With `BlobStore::keep_stats`, every commit through the store also
+//! commits a line for its blob to `.stats.json` in the store's
+//! directory: its size, how many times it's been committed, and when
+//! it last was. `BlobStore::stats` reads them back from that one
+//! file, so a dashboard needn't list and stat every blob. The stats
+//! blob is an ordinary blob, so commits from every process using the
+//! store are counted, and a failure to record one is logged rather
+//! than failing the commit it's for. Blobs committed without the
+//! store, or by a store not keeping stats, aren't counted.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use super::{AtomBlob, Builder, Result, lock};
+use super::store::EntryStats;
+
+/// The name of the stats blob's file in the store's directory
+pub const FILE: &str = ".stats.json";
+
+pub struct Ledger {
+    blob: Mutex<AtomBlob<BTreeMap<String, EntryStats>>>,
+}
+
+impl Ledger {
+    /// Opens the stats blob of the store `store` was built for, on its
+    /// filesystem and clock
+    pub fn open(store: &Builder) -> Result<Ledger> {
+        let mut builder = Builder::new(store.path.join(FILE));
+        builder.fs = store.fs.clone();
+        builder.clock = store.clock.clone();
+        // Failed commits are only logged
+        builder.strict_no_panic = true;
+        Ok(Ledger { blob: Mutex::new(builder.open()?) })
+    }
+
+    /// Records a commit of `bytes` to the blob `name` at `at`
+    pub fn record(&self, name: &str, bytes: u64, at: SystemTime) {
+        self.update(|entries| {
+            let entry = entries.entry(name.to_string()).or_insert(EntryStats {
+                bytes,
+                commits: 0,
+                written: at,
+            });
+            entry.bytes = bytes;
+            entry.commits += 1;
+            entry.written = at;
+        })
+    }
+
+    /// Forgets the blob `name`, now deleted
+    pub fn forget(&self, name: &str) {
+        self.update(|entries| {
+            entries.remove(name);
+        })
+    }
+
+    /// Moves the stats of the blob `from` to `to`
+    pub fn rename(&self, from: &str, to: &str) {
+        self.update(|entries| {
+            if let Some(entry) = entries.remove(from) {
+                entries.insert(to.to_string(), entry);
+            }
+        })
+    }
+
+    pub fn entries(&self) -> Result<BTreeMap<String, EntryStats>> {
+        let mut blob = lock(&self.blob);
+        let entries = blob.get()?;
+        Ok(entries.clone())
+    }
+
+    fn update<F>(&self, f: F)
+        where F: FnOnce(&mut BTreeMap<String, EntryStats>)
+    {
+        let mut blob = lock(&self.blob);
+        let r = blob.get_mut().and_then(|mut entries| {
+            f(&mut entries);
+            entries.commit().map(|_| ())
+        });
+        if let Err(e) = r {
+            warn!("recording blobject store stats: {}", e);
+        }
+    }
+}
+
+/// Whether `p` is the stats blob's file in the store at `base`
+pub fn is_ledger(base: &Path, p: &Path) -> bool {
+    p.parent() == Some(base) && p.file_name().is_some_and(|n| n == FILE)
+}
@@ -5,122 +5,2191 @@ extern crate error_chain;
 #[macro_use]
 extern crate log;
 
-use rand::random;
 use serde::{Serialize, Deserialize};
 use serde_json;
+use std::any::Any;
 use std::fs::{self, File};
-use std::io::{self, Write, BufWriter, BufReader};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
 use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::os::unix::io::AsFd;
 use std::path::{Path, PathBuf};
+use std::ptr;
+use std::rc::Rc;
 use std::result::Result as StdResult;
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
-use fancy_flocks::scoped::dirty_flock::{DirtyFlock, DirtyFlockShared,
-                                        DirtyFlockExclusive, State};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, RwLockReadGuard,
+                RwLockWriteGuard, TryLockError, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use clock::{Clock, SystemClock};
+use flock::{DirtyFlock, FlockGuard, State};
+use intent::IntentLock;
+use pipeline::CommitTicket;
+use reentry::Held;
+use vfs::{FileId, Fs, RealFs};
+
+mod ab;
+mod adopt;
+pub mod archive;
+pub mod atomic_io;
+#[cfg(all(feature = "broker", unix))]
+pub mod broker;
+mod chunk;
+mod classify;
+pub mod clock;
+mod counter;
+mod deadline;
+mod dirsync;
+pub mod draft;
+mod dual;
+mod envelope;
+#[cfg(unix)]
+pub mod emergency;
+mod flight;
+mod flock;
+pub mod follow;
+pub mod history;
+mod idle;
+mod intent;
+pub mod kv;
+mod ledger;
+pub mod live;
+mod local_flock;
+#[cfg(all(not(feature = "fancy_flocks"), not(target_os = "wasi")))]
+mod lockfile;
+// The crate `lockfile` reimplements, which shares its lock files
+#[cfg(all(feature = "fancy_flocks", not(target_os = "wasi")))]
+mod lockfile {
+    pub use fancy_flocks::dirty_flock::{DirtyFlock, State};
+    pub use fancy_flocks::sd_flock::SdFlock;
+}
+pub mod memory;
+pub mod merge;
+pub mod migrate;
+mod mirror;
+pub mod owned;
+pub mod pipeline;
+pub mod preserve;
+#[cfg(not(target_os = "wasi"))]
+mod protocol;
+pub mod queue;
+mod quota;
+pub mod raw;
+pub mod reason;
+mod reentry;
+mod registry;
+mod repair;
+pub mod ring;
+pub mod sequence;
+mod sha256;
+#[cfg(not(target_os = "wasi"))]
+pub mod singleton;
+pub mod stats;
+pub mod store;
+mod strict;
+pub mod tolerant;
+#[cfg(not(target_os = "wasi"))]
+pub mod transcode;
+#[cfg(feature = "sidecar")]
+mod sidecar;
+pub mod vfs;
+pub mod wait;
+mod warm;
+pub mod web;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "objects")]
+pub mod object;
+#[cfg(all(feature = "http", not(target_os = "wasi")))]
+pub mod http;
+#[cfg(all(feature = "grpc", not(target_os = "wasi")))]
+pub mod feed;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+pub mod uring;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+#[cfg(all(feature = "async", not(target_os = "wasi")))]
+pub mod async_blob;
+#[cfg(feature = "pyo3")]
+pub mod python;
 
 error_chain! {
     foreign_links {
         Io(::std::io::Error);
     }
+
+    errors {
+        WouldDeadlock {
+            description("blobject already locked by this thread")
+            display("blobject already locked by this thread")
+        }
+        InvalidValue {
+            description("blobject value on disk failed validation")
+            display("blobject value on disk failed validation")
+        }
+        FutureVersion(found: u64, supported: u64) {
+            description("blobject written by a newer schema version")
+            display("blobject written by schema version {}, newer than {}", found, supported)
+        }
+        InsufficientSpace(needed: u64, available: u64) {
+            description("not enough disk space to commit blobject")
+            display("not enough disk space to commit blobject: needed {} bytes, {} available",
+                    needed, available)
+        }
+        Conflict {
+            description("blobject committed by another writer since it was read")
+            display("blobject committed by another writer since it was read")
+        }
+        LockProtocol(found: u32, supported: u32) {
+            description("blobject lock file written by an incompatible version")
+            display("blobject lock file uses lock protocol version {}, incompatible with {}",
+                    found, supported)
+        }
+        Locked {
+            description("blobject locked by another handle")
+            display("blobject locked by another handle")
+        }
+        Poisoned {
+            description("blobject value poisoned by a panic while it was locked")
+            display("blobject value poisoned by a panic while it was locked")
+        }
+    }
+}
+
+/// A blob of untyped JSON
+pub type DynAtomBlob = AtomBlob<serde_json::Value>;
+
+/// What to do with a blob file that can't be parsed, as decided by
+/// `Builder::on_load_error`
+pub enum LoadDecision {
+    /// Parse these bytes instead, e.g. the file with trailing garbage
+    /// stripped
+    Repaired(Vec<u8>),
+    /// Use `T::default()`
+    Default,
+    /// Fail the load with the original error
+    Abort,
+}
+
+/// How commits replace the blob file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Write a temporary file and rename it over the blob
+    #[default]
+    Rename,
+    /// Alternate between two files, `<blob>.a` and `<blob>.b`, and
+    /// point at the current one from `<blob>.ptr`, for filesystems
+    /// where renames aren't atomic
+    ///
+    /// The blob path itself is never written. Every handle on the
+    /// blob, in every process, must use the same strategy.
+    DoubleBuffer,
+    /// Like `Rename`, but blobs larger than `Builder::chunk_size`
+    /// are stored as chunks in `<blob>.chunks/`, and only the chunks
+    /// a commit changes are written
+    ///
+    /// The blob file is then a manifest of the chunks, and each is
+    /// checked as it's read. Every handle on the blob, in every
+    /// process, must use the same strategy.
+    Chunked,
+}
+
+/// What a commit does when the value it was made from has been
+/// committed over since, as set by `Builder::conflict_policy`
+///
+/// Only values edited without holding the blob's locks can race:
+/// `Draft::commit_by_policy`, `RemoteMutRef::commit` and
+/// `ObjectBlob::update`. Guards on an `AtomBlob` hold the locks, so
+/// their commits never conflict.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Policy {
+    /// Commit anyway, discarding the other writer's value
+    LastWriterWins,
+    /// Fail with `ErrorKind::Conflict`, leaving the other writer's
+    /// value
+    #[default]
+    FailOnConflict,
+    /// Commit `merge(base, theirs, mine)`: of the value as it was
+    /// read, the value committed since, and the value being
+    /// committed, all as JSON
+    ///
+    /// `merge::resolve` three-way merges them.
+    Merge(fn(serde_json::Value, serde_json::Value, serde_json::Value) -> serde_json::Value),
+}
+
+impl Policy {
+    // The value to commit instead of `mine`, edited from `base`, now
+    // that `theirs` has been committed
+    fn resolve<T>(self, base: &T, theirs: &T, mine: T) -> Result<T>
+        where for <'de> T: Serialize + Deserialize<'de>
+    {
+        match self {
+            Policy::LastWriterWins => Ok(mine),
+            Policy::FailOnConflict => Err(ErrorKind::Conflict.into()),
+            Policy::Merge(merge) => {
+                let json = |v: &T| serde_json::to_value(v).chain_err(|| "merging blobject");
+                let merged = merge(json(base)?, json(theirs)?, json(&mine)?);
+                serde_json::from_value(merged).chain_err(|| "merging blobject")
+            }
+        }
+    }
+}
+
+impl Strategy {
+    fn open(self, fs: &dyn Fs, p: &Path) -> io::Result<Option<(Box<dyn Read>, FileId)>> {
+        match self {
+            Strategy::Rename => fs.open(p),
+            Strategy::DoubleBuffer => ab::open(fs, p),
+            Strategy::Chunked => chunk::open(fs, p),
+        }
+    }
+
+    fn id(self, fs: &dyn Fs, p: &Path) -> io::Result<Option<FileId>> {
+        match self {
+            Strategy::Rename | Strategy::Chunked => fs.id(p),
+            Strategy::DoubleBuffer => ab::id(fs, p),
+        }
+    }
+
+    fn sync(self, fs: &dyn Fs, p: &Path) -> io::Result<()> {
+        match self {
+            Strategy::Rename => fs.sync(p),
+            Strategy::DoubleBuffer => ab::sync(fs, p),
+            Strategy::Chunked => chunk::sync(fs, p),
+        }
+    }
+}
+
+type OnLoadError = dyn Fn(&Path, &Error, &[u8]) -> LoadDecision + Send + Sync;
+
+type Validate<T> = dyn Fn(&T) -> Result<()> + Send + Sync;
+
+type OnCommit = dyn Fn(&CommitReceipt, Option<&Context>) + Send + Sync;
+
+/// What a guard carries for commit hooks; see
+/// `BlobMutRef::set_context`
+pub type Context = dyn Any + Send + Sync;
+
+fn validate<T>(validate: Option<&Validate<T>>, v: &T) -> Result<()> {
+    match validate {
+        Some(f) => f(v).chain_err(|| ErrorKind::InvalidValue),
+        None => Ok(()),
+    }
+}
+
+// A panic while the value was write-locked may have left it half
+// changed, so it's an error to lock it again
+fn poisoned<G>(_: PoisonError<G>) -> Error {
+    ErrorKind::Poisoned.into()
+}
+
+// Locks bookkeeping, which is whole wherever a panic could interrupt
+// it, so even after one
+fn lock<M>(m: &Mutex<M>) -> MutexGuard<'_, M> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// How blob files are parsed
+#[derive(Clone, Copy)]
+struct Parse<'a> {
+    lenient: bool,
+    on_error: Option<&'a OnLoadError>,
+    // Holding a commit time or schema version as well as the value
+    enveloped: bool,
+    buffer_size: Option<usize>,
+    // The newest schema version to accept
+    max_schema: Option<u64>,
+    // A copy to load instead, if the file fails to load
+    mirror: Option<&'a Path>,
+    deny_duplicates: bool,
+    deny_unknown: bool,
+    // With content hashes, where the digest of the file read goes
+    hash: Option<&'a Mutex<Option<[u8; 32]>>>,
+}
+
+impl<'a> Parse<'a> {
+    // Whether the whole file must be read before parsing
+    fn buffered(&self) -> bool {
+        self.on_error.is_some() || self.deny_duplicates || self.deny_unknown
+    }
+}
+
+// How blob files are written
+#[derive(Clone, Copy, Default)]
+struct Store<'a> {
+    buffer_size: Option<usize>,
+    // Serialized into first, and kept between commits
+    scratch: Option<&'a Mutex<Vec<u8>>>,
+    // With preallocation or space checks, the size of the last file
+    // written
+    last_size: Option<&'a AtomicU64>,
+    preallocate: bool,
+    direct: bool,
+    // With `Strategy::Chunked`
+    chunk_size: Option<u64>,
+    // With content hashes, where the digest of the file written goes
+    hash: Option<&'a Mutex<Option<[u8; 32]>>>,
+    // Free space required beyond the file itself
+    space_margin: Option<u64>,
 }
 
 pub struct AtomBlob<T: Serialize + Deserialize<'static> + Default> {
     flock: DirtyFlock,
-    v: Arc<RwLock<T>>,
+    intent: Option<IntentLock>,
+    shared: Arc<Shared<T>>,
     path: Arc<PathBuf>,
+    fs: Arc<dyn Fs>,
 }
 
-impl<T> AtomBlob<T>
-    where for <'de> T: Serialize + Deserialize<'de> + Default,
+// The in-memory value and its bookkeeping, common to every handle
+// cloned from one open
+struct Shared<T> {
+    v: RwLock<T>,
+    // Not yet loaded, or evicted; the next guard loads it
+    unloaded: AtomicBool,
+    // The file the value was last read from or written to
+    loaded: Mutex<Option<FileId>>,
+    clock: Arc<dyn Clock>,
+    idle_timeout: Option<Duration>,
+    on_load_error: Option<Arc<OnLoadError>>,
+    lenient: bool,
+    strategy: Strategy,
+    keep_generations: usize,
+    generations_for: Option<Duration>,
+    generations_dir: Option<PathBuf>,
+    commit_times: bool,
+    schema_version: Option<u64>,
+    refuse_newer: bool,
+    deny_duplicates: bool,
+    deny_unknown: bool,
+    // As recorded with the value last read or written
+    committed_at: Mutex<Option<SystemTime>>,
+    acquire: wait::Acquire,
+    on_lock_wait: Option<(Duration, Arc<wait::OnLockWait>)>,
+    on_commit: Option<Arc<OnCommit>>,
+    buffer_size: Option<usize>,
+    scratch: Option<Mutex<Vec<u8>>>,
+    // Set when temp files are preallocated or space is checked
+    last_size: Option<AtomicU64>,
+    preallocate: bool,
+    direct_io: bool,
+    chunk_size: u64,
+    space_margin: Option<u64>,
+    // With content hashes, the digest of the file last read or
+    // written, and before it's paired with its id
+    hashed: Option<Mutex<Option<[u8; 32]>>>,
+    content_hash: Mutex<Option<(FileId, [u8; 32])>>,
+    // Set when commits are synced in the background
+    pipeline: OnceLock<pipeline::Pipeline>,
+    mirror: Option<PathBuf>,
+    policy: Policy,
+    // Set when commits are mirrored
+    mirroring: OnceLock<mirror::Mirror>,
+    // Set when commits also write a legacy copy
+    dual: OnceLock<dual::DualWrite<T>>,
+    counters: stats::Counters,
+    validate: Option<Arc<Validate<T>>>,
+    read_repair: bool,
+    // Read by recovering it, and not yet committed again
+    repair_pending: AtomicBool,
+    // Moved out to a guard that can change threads, which holds the
+    // exclusive flock until it puts the value back
+    taken: AtomicBool,
+    no_panic: bool,
+    // Milliseconds on `clock`
+    last_access: AtomicU64,
+    reload_deadline: Option<Duration>,
+    max_staleness: Option<Duration>,
+    // Set when reloads have a deadline
+    reloader: OnceLock<deadline::Reloader<T>>,
+    // A reload that ran past its deadline, and hasn't been taken
+    late: Mutex<Option<deadline::Late<T>>>,
+    // The reload shared guards wait for rather than each reloading
+    reloads: flight::Flights,
+    // When the value was last read or written, and how many times a
+    // different file's value has been
+    loaded_at: Mutex<SystemTime>,
+    generation: AtomicU64,
+    // A snapshot armed for the signal handlers, disarmed by commits
+    #[cfg(unix)]
+    snapshot: std::sync::atomic::AtomicPtr<emergency::Slot>,
+}
+
+impl<T> Shared<T> {
+    fn new(v: T, b: &Builder) -> Shared<T> {
+        let now = b.clock.now().as_millis() as u64;
+        Shared {
+            v: RwLock::new(v),
+            unloaded: AtomicBool::new(false),
+            loaded: Mutex::new(None),
+            clock: b.clock.clone(),
+            idle_timeout: b.idle_timeout,
+            on_load_error: b.on_load_error.clone(),
+            lenient: b.lenient,
+            strategy: b.strategy,
+            keep_generations: b.keep_generations,
+            generations_for: b.generations_for,
+            generations_dir: b.generations_dir.clone(),
+            commit_times: b.commit_times,
+            schema_version: b.schema_version,
+            refuse_newer: b.refuse_newer,
+            deny_duplicates: b.deny_duplicates,
+            deny_unknown: b.deny_unknown,
+            committed_at: Mutex::new(None),
+            acquire: b.acquire,
+            on_lock_wait: b.on_lock_wait.clone(),
+            on_commit: b.on_commit.clone(),
+            buffer_size: b.buffer_size,
+            scratch: if b.reuse_buffer { Some(Mutex::new(Vec::new())) } else { None },
+            last_size: if b.preallocate || b.space_margin.is_some() {
+                Some(AtomicU64::new(0))
+            } else {
+                None
+            },
+            preallocate: b.preallocate,
+            direct_io: b.direct_io,
+            chunk_size: b.chunk_size,
+            space_margin: b.space_margin,
+            hashed: if b.content_hashes { Some(Mutex::new(None)) } else { None },
+            content_hash: Mutex::new(None),
+            pipeline: OnceLock::new(),
+            mirror: b.mirror.clone(),
+            policy: b.policy,
+            mirroring: OnceLock::new(),
+            dual: OnceLock::new(),
+            counters: stats::Counters::new(b.warn_on_reloads),
+            validate: None,
+            read_repair: b.read_repair,
+            no_panic: b.strict_no_panic,
+            repair_pending: AtomicBool::new(false),
+            taken: AtomicBool::new(false),
+            last_access: AtomicU64::new(now),
+            reload_deadline: b.reload_deadline,
+            max_staleness: b.max_staleness,
+            reloader: OnceLock::new(),
+            late: Mutex::new(None),
+            reloads: flight::Flights::new(),
+            loaded_at: Mutex::new(b.clock.system_time()),
+            generation: AtomicU64::new(0),
+            #[cfg(unix)]
+            snapshot: std::sync::atomic::AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn unloaded(b: &Builder) -> Shared<T>
+        where T: Default
+    {
+        let shared = Shared::new(T::default(), b);
+        shared.unloaded.store(true, Ordering::SeqCst);
+        shared
+    }
+
+    fn parse(&self) -> Parse<'_> {
+        Parse {
+            lenient: self.lenient,
+            on_error: self.on_load_error.as_deref(),
+            enveloped: self.enveloped(),
+            buffer_size: self.buffer_size,
+            max_schema: if self.refuse_newer { self.schema_version } else { None },
+            mirror: self.mirror.as_deref(),
+            deny_duplicates: self.deny_duplicates,
+            deny_unknown: self.deny_unknown,
+            hash: self.hashed.as_ref(),
+        }
+    }
+
+    fn enveloped(&self) -> bool {
+        self.commit_times || self.schema_version.is_some()
+    }
+
+    // What to record with a commit made now
+    fn stamp(&self) -> envelope::Stamp {
+        envelope::Stamp {
+            committed_at: if self.commit_times { Some(self.clock.system_time()) } else { None },
+            schema: self.schema_version,
+        }
+    }
+
+    fn store(&self) -> Store<'_> {
+        Store {
+            buffer_size: self.buffer_size,
+            scratch: self.scratch.as_ref(),
+            last_size: self.last_size.as_ref(),
+            preallocate: self.preallocate,
+            direct: self.direct_io,
+            chunk_size: Some(self.chunk_size),
+            space_margin: self.space_margin,
+            hash: self.hashed.as_ref(),
+        }
+    }
+
+    // Pairs the digest of the file just read or written with its id
+    fn hashed(&self, id: Option<&FileId>) {
+        if let Some(hashed) = &self.hashed {
+            let digest = lock(hashed).take();
+            *lock(&self.content_hash) = match (id, digest) {
+                (Some(id), Some(digest)) => Some((id.clone(), digest)),
+                _ => None,
+            };
+        }
+    }
+
+    // Records that the value was read or written, and whether it's
+    // a different file's
+    fn loaded_now(&self, changed: bool) {
+        *lock(&self.loaded_at) = self.clock.system_time();
+        if changed {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Lets a value poisoned while unloaded be locked again, since it's
+    // replaced whole when it's reloaded, as after `BlobMutRef::map`
+    // panics
+    fn unpoison(&self) {
+        if self.unloaded.load(Ordering::SeqCst) {
+            self.v.clear_poison();
+        }
+    }
+
+    // Records an access. A value found already past its timeout is
+    // evicted first, so the clock is honored without waiting for the
+    // sweeper.
+    fn touch(&self)
+        where T: Default
+    {
+        if let Some(timeout) = self.idle_timeout {
+            self.evict_idle(timeout);
+        }
+        let now = self.clock.now().as_millis() as u64;
+        self.last_access.store(now, Ordering::SeqCst);
+    }
+
+    fn evict_idle(&self, timeout: Duration)
+        where T: Default
+    {
+        let idle = (self.clock.now().as_millis() as u64)
+            .saturating_sub(self.last_access.load(Ordering::SeqCst));
+        if idle < timeout.as_millis() as u64 {
+            return;
+        }
+        if let Ok(mut v) = self.v.try_write() {
+            // Out with an owned guard, which is an access
+            if self.taken.load(Ordering::SeqCst) {
+                return;
+            }
+            if !self.unloaded.swap(true, Ordering::SeqCst) {
+                *v = T::default();
+                debug!("evicted idle blobject");
+            }
+        }
+    }
+}
+
+impl<T> idle::Evict for Shared<T>
+    where T: Default + Send + Sync
 {
-    pub fn new<P>(p: P) -> Result<AtomBlob<T>>
+    fn evict_if_idle(&self, timeout: Duration) {
+        self.evict_idle(timeout);
+    }
+}
+
+// The options of `Builder` and `TypedBuilder` that a value opened
+// shared has, which every handle sharing it must agree on. Hooks are
+// told apart by address, and `Policy` by variant and merge function.
+#[derive(PartialEq)]
+struct SharedOptions {
+    idle_timeout: Option<Duration>,
+    revalidate_every: Option<Duration>,
+    reload_deadline: Option<Duration>,
+    max_staleness: Option<Duration>,
+    #[cfg(feature = "sidecar")]
+    sidecar: bool,
+    lenient: bool,
+    strategy: Strategy,
+    keep_generations: usize,
+    generations_for: Option<Duration>,
+    generations_dir: Option<PathBuf>,
+    commit_times: bool,
+    schema_version: Option<u64>,
+    refuse_newer: bool,
+    deny_duplicates: bool,
+    deny_unknown: bool,
+    acquire: wait::Acquire,
+    buffer_size: Option<usize>,
+    reuse_buffer: bool,
+    preallocate: bool,
+    direct_io: bool,
+    chunk_size: u64,
+    content_hashes: bool,
+    space_margin: Option<u64>,
+    background_sync: Option<Duration>,
+    mirror: Option<PathBuf>,
+    policy: (u8, Option<usize>),
+    warn_on_reloads: Option<(u64, Duration)>,
+    read_repair: bool,
+    strict_no_panic: bool,
+    on_load_error: Option<usize>,
+    on_lock_wait: Option<(Duration, Option<usize>)>,
+    on_commit: Option<usize>,
+    validate: Option<usize>,
+    dual_write: Option<(PathBuf, usize)>,
+}
+
+/// Options for opening an `AtomBlob`
+#[derive(Clone)]
+pub struct Builder {
+    path: PathBuf,
+    writer_priority: bool,
+    follow_symlinks: bool,
+    idle_timeout: Option<Duration>,
+    lazy: bool,
+    revalidate_every: Option<Duration>,
+    reload_deadline: Option<Duration>,
+    max_staleness: Option<Duration>,
+    #[cfg(feature = "sidecar")]
+    sidecar: bool,
+    fs: Arc<dyn Fs>,
+    clock: Arc<dyn Clock>,
+    on_load_error: Option<Arc<OnLoadError>>,
+    lenient: bool,
+    strategy: Strategy,
+    keep_generations: usize,
+    generations_for: Option<Duration>,
+    generations_dir: Option<PathBuf>,
+    commit_times: bool,
+    schema_version: Option<u64>,
+    refuse_newer: bool,
+    deny_duplicates: bool,
+    deny_unknown: bool,
+    acquire: wait::Acquire,
+    on_lock_wait: Option<(Duration, Arc<wait::OnLockWait>)>,
+    on_commit: Option<Arc<OnCommit>>,
+    buffer_size: Option<usize>,
+    reuse_buffer: bool,
+    preallocate: bool,
+    direct_io: bool,
+    chunk_size: u64,
+    content_hashes: bool,
+    space_margin: Option<u64>,
+    background_sync: Option<Duration>,
+    mirror: Option<PathBuf>,
+    policy: Policy,
+    warn_on_reloads: Option<(u64, Duration)>,
+    read_repair: bool,
+    strict_no_panic: bool,
+}
+
+impl Builder {
+    pub fn new<P>(p: P) -> Builder
         where P: AsRef<Path>,
     {
-        let p = p.as_ref();
+        Builder {
+            path: p.as_ref().to_owned(),
+            writer_priority: false,
+            follow_symlinks: false,
+            idle_timeout: None,
+            lazy: false,
+            revalidate_every: None,
+            reload_deadline: None,
+            max_staleness: None,
+            #[cfg(feature = "sidecar")]
+            sidecar: false,
+            fs: Arc::new(RealFs),
+            clock: Arc::new(SystemClock),
+            on_load_error: None,
+            lenient: false,
+            strategy: Strategy::Rename,
+            keep_generations: 0,
+            generations_for: None,
+            generations_dir: None,
+            commit_times: false,
+            schema_version: None,
+            refuse_newer: false,
+            deny_duplicates: false,
+            deny_unknown: false,
+            acquire: wait::Acquire::Block,
+            on_lock_wait: None,
+            on_commit: None,
+            buffer_size: None,
+            reuse_buffer: false,
+            preallocate: false,
+            direct_io: false,
+            chunk_size: chunk::DEFAULT_SIZE,
+            content_hashes: false,
+            space_margin: None,
+            background_sync: None,
+            mirror: None,
+            policy: Policy::FailOnConflict,
+            warn_on_reloads: None,
+            read_repair: false,
+            strict_no_panic: false,
+        }
+    }
+
+    /// Keeps `get_mut()` from being starved by a steady stream of
+    /// readers, using an extra `.intent` lock file
+    ///
+    /// Every handle on the path, in every process, must use the same
+    /// setting for it to take effect.
+    pub fn writer_priority(mut self, writer_priority: bool) -> Builder {
+        self.writer_priority = writer_priority;
+        self
+    }
+
+    /// Whether a symlink at the blob path is resolved at open
+    ///
+    /// By default commits rename over the path itself, so the first
+    /// commit replaces a symlink with a regular file. When following,
+    /// the link is resolved once, and the handle, its lock files and
+    /// its commits stay pinned to the target even if the link is
+    /// later swapped.
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Builder {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Drops the in-memory value once no guard has been taken on it
+    /// for `timeout`, reloading it on the next access
+    pub fn idle_timeout(mut self, timeout: Duration) -> Builder {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Defers reading the file until the first `get()` or
+    /// `get_mut()`, so a handle that is never used never touches it
+    pub fn lazy(mut self, lazy: bool) -> Builder {
+        self.lazy = lazy;
+        self
+    }
+
+    /// Checks for changes made by other processes every `interval`
+    /// on a background thread, reloading as needed
+    ///
+    /// A `get()` after another process commits then usually finds the
+    /// new value already parsed.
+    pub fn revalidate_every(mut self, interval: Duration) -> Builder {
+        self.revalidate_every = Some(interval);
+        self
+    }
+
+    /// Lets a `get()` that must reload wait only `deadline` for it,
+    /// then read the value already loaded instead
+    ///
+    /// The late reload carries on in the background, and a later
+    /// guard takes its value. `BlobRef::staleness` says whether a
+    /// guard's value is behind the file. `get_mut()`, and the first
+    /// load of a value, always wait.
+    pub fn reload_deadline(mut self, deadline: Duration) -> Builder {
+        self.reload_deadline = Some(deadline);
+        self
+    }
+
+    /// Lets `get()` read the value without taking the flock for up to
+    /// `ttl` after it was last checked against the file
+    ///
+    /// Within that, a commit from another process goes unseen, and
+    /// the guard doesn't hold it off. Commits through handles in this
+    /// process are seen at once, since they share the value. Once the
+    /// TTL expires the next `get()` takes the flock and reloads if
+    /// the file changed. `get_mut()` always takes the flock.
+    pub fn max_staleness(mut self, ttl: Duration) -> Builder {
+        self.max_staleness = Some(ttl);
+        self
+    }
+
+    /// Keeps a bincode copy of the parsed value in a `.cache` file
+    /// next to the blob, used at open in place of parsing the JSON
+    /// while the JSON hasn't changed
+    ///
+    /// The JSON is still read and hashed to check the cache. Types
+    /// that bincode can't round-trip, like `serde_json::Value`, just
+    /// fall back to parsing.
+    #[cfg(feature = "sidecar")]
+    pub fn sidecar(mut self, sidecar: bool) -> Builder {
+        self.sidecar = sidecar;
+        self
+    }
+
+    /// Stores the blob on `fs` instead of the host filesystem
+    pub fn fs<F>(mut self, fs: F) -> Builder
+        where F: Fs + 'static
+    {
+        self.fs = Arc::new(fs);
+        self
+    }
+
+    /// Measures the idle timeout on `clock` instead of the system
+    /// clock
+    pub fn clock<C>(mut self, clock: C) -> Builder
+        where C: Clock + 'static
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Called with the path, the error and the file's contents when
+    /// a load finds a file that can't be parsed
+    ///
+    /// Without it, or on `LoadDecision::Abort`, the load fails.
+    /// Either way the file is left as it is until the next commit.
+    pub fn on_load_error<F>(mut self, f: F) -> Builder
+        where F: Fn(&Path, &Error, &[u8]) -> LoadDecision + Send + Sync + 'static
+    {
+        self.on_load_error = Some(Arc::new(f));
+        self
+    }
+
+    /// Accepts a file holding a JSON document followed by junk, as a
+    /// torn write on a filesystem without atomic renames can leave
+    /// it, and loads the document
+    ///
+    /// A file cut off partway through the document still fails to
+    /// load, and goes to `on_load_error` if set.
+    pub fn lenient(mut self, lenient: bool) -> Builder {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Fails loads of files that repeat a key in any object, instead
+    /// of keeping the last
+    pub fn deny_duplicate_keys(mut self, deny: bool) -> Builder {
+        self.deny_duplicates = deny;
+        self
+    }
+
+    /// Fails loads of files with fields the value type doesn't keep,
+    /// found by serializing the loaded value and comparing
+    ///
+    /// Fields the type skips when serializing, like `None`s with
+    /// `skip_serializing_if`, count as unknown if the file has them.
+    pub fn deny_unknown_fields(mut self, deny: bool) -> Builder {
+        self.deny_unknown = deny;
+        self
+    }
+
+    /// How commits replace the blob file; `Strategy::Rename` by
+    /// default
+    pub fn strategy(mut self, strategy: Strategy) -> Builder {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Keeps the last `n` replaced versions of the blob, readable
+    /// through `AtomBlob::history`
+    ///
+    /// Only works with `Strategy::Rename`.
+    pub fn keep_generations(mut self, n: usize) -> Builder {
+        self.keep_generations = n;
+        self
+    }
+
+    /// Keeps replaced versions of the blob until they were last
+    /// current longer than `age` ago, on the handle's clock
+    ///
+    /// With `keep_generations` as well, versions are deleted by
+    /// whichever limit is reached first. Only works with
+    /// `Strategy::Rename`.
+    pub fn keep_generations_for(mut self, age: Duration) -> Builder {
+        self.generations_for = Some(age);
+        self
+    }
+
+    /// Keeps replaced versions in `dir`, which must exist, instead of
+    /// next to the blob
+    ///
+    /// Hard links can't cross filesystems, so on another filesystem
+    /// each version is a reflink where the filesystem has them, and
+    /// otherwise a copy.
+    pub fn generations_dir<P>(mut self, dir: P) -> Builder
+        where P: AsRef<Path>
+    {
+        self.generations_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Stores the time of each commit, on the handle's clock, with the
+    /// value, for `BlobRef::committed_at`
+    ///
+    /// This changes the file format, so every handle on the path, in
+    /// every process, must use the same setting.
+    pub fn commit_times(mut self, commit_times: bool) -> Builder {
+        self.commit_times = commit_times;
+        self
+    }
+
+    /// Stores `version`, the schema version of the application's
+    /// value type, with each commit
+    ///
+    /// Like `commit_times`, this changes the file format, so every
+    /// handle on the path, in every process, must set a version.
+    pub fn schema_version(mut self, version: u64) -> Builder {
+        self.schema_version = Some(version);
+        self
+    }
+
+    /// Fails loads of values committed with a newer `schema_version`
+    /// than this handle's with `ErrorKind::FutureVersion`, instead of
+    /// reading them, and losing what the newer schema added on the
+    /// next commit
+    pub fn refuse_newer(mut self, refuse_newer: bool) -> Builder {
+        self.refuse_newer = refuse_newer;
+        self
+    }
+
+    /// How guards take the flock while another handle holds it, by
+    /// default `Acquire::Block`
+    ///
+    /// With `writer_priority`, a reader still waits on the intent
+    /// lock behind a writer queued for the flock.
+    pub fn acquire(mut self, acquire: wait::Acquire) -> Builder {
+        self.acquire = acquire;
+        self
+    }
+
+    /// Calls `f` every `interval` while a guard waits for another
+    /// handle's lock, e.g. to tell the user what they're waiting on
+    ///
+    /// Waiting guards poll the lock instead of queueing for it.
+    pub fn on_lock_wait<F>(mut self, interval: Duration, f: F) -> Builder
+        where F: Fn(&wait::LockWait) + Send + Sync + 'static
+    {
+        self.on_lock_wait = Some((interval, Arc::new(f)));
+        self
+    }
+
+    /// Calls `f` with the receipt of every commit, including those
+    /// made by dropping a guard
+    ///
+    /// `f` runs with the blob locked, so it must not take a guard on
+    /// it.
+    pub fn on_commit<F>(mut self, f: F) -> Builder
+        where F: Fn(&CommitReceipt) + Send + Sync + 'static
+    {
+        self.on_commit = Some(Arc::new(move |r: &CommitReceipt, _: Option<&Context>| f(r)));
+        self
+    }
+
+    /// Like `on_commit`, also passing the context the committing guard
+    /// was given with `BlobMutRef::set_context`, if any
+    ///
+    /// For logging what caused a change, like the id of the request
+    /// that made it. Commits from `AtomBlob` itself rather than a guard
+    /// have no context.
+    pub fn on_commit_with_context<F>(mut self, f: F) -> Builder
+        where F: Fn(&CommitReceipt, Option<&Context>) + Send + Sync + 'static
+    {
+        self.on_commit = Some(Arc::new(f));
+        self
+    }
+
+    /// The size of the buffers the blob file is read and written
+    /// through, instead of the filesystem's default
+    pub fn buffer_size(mut self, bytes: usize) -> Builder {
+        self.buffer_size = Some(bytes);
+        self
+    }
+
+    /// Serializes each commit into a buffer kept for the next one,
+    /// then writes it out at once
+    ///
+    /// This saves reallocating for large blobs that are committed
+    /// often, at the cost of keeping a serialized copy's worth of
+    /// memory for as long as the blob is open.
+    pub fn reuse_buffer(mut self, reuse_buffer: bool) -> Builder {
+        self.reuse_buffer = reuse_buffer;
+        self
+    }
+
+    /// Commits through io_uring, writing, syncing and renaming each
+    /// new blob file in one submission; see `uring`
+    ///
+    /// Stores the blob on `uring::UringFs`, and reuses a buffer as
+    /// with `reuse_buffer`, since the value is written at once.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    pub fn io_uring(mut self) -> Builder {
+        self.fs = Arc::new(uring::UringFs::new());
+        self.reuse_buffer = true;
+        self
+    }
+
+    /// Reserves disk space for each commit's temp file before writing
+    /// it, sized as the serialized value if it's known already, and
+    /// otherwise as the last commit
+    ///
+    /// A full disk then fails the commit before anything is written,
+    /// and the file is less likely to be fragmented. Only Linux can
+    /// reserve space; elsewhere this does nothing.
+    pub fn preallocate(mut self, preallocate: bool) -> Builder {
+        self.preallocate = preallocate;
+        self
+    }
+
+    /// Writes each commit's temp file with `O_DIRECT`, around the page
+    /// cache, so committing a huge blob doesn't evict the rest of the
+    /// program's working set
+    ///
+    /// Writes are aligned and buffered internally. Where the
+    /// filesystem can't write directly, as tmpfs can't, and elsewhere
+    /// than Linux, files are written as usual. Reads still go through
+    /// the cache, and double-buffered and chunked blobs are written as
+    /// usual.
+    pub fn direct_io(mut self, direct_io: bool) -> Builder {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// The size of the chunks `Strategy::Chunked` cuts blobs into,
+    /// and so the size past which a blob is chunked; 4 MiB by
+    /// default
+    ///
+    /// Chunks are cut by their content, so their sizes vary, from a
+    /// quarter of this to four times it. Smaller chunks make smaller
+    /// commits of small edits, with more files.
+    pub fn chunk_size(mut self, size: u64) -> Builder {
+        self.chunk_size = size;
+        self
+    }
+
+    /// Hashes the blob file as it's committed or loaded, for
+    /// `AtomBlob::content_hash`
+    ///
+    /// The bytes are hashed as they're written or read, so the file
+    /// is never read again for it, but SHA-256 costs time in
+    /// proportion to the blob's size.
+    pub fn content_hashes(mut self, content_hashes: bool) -> Builder {
+        self.content_hashes = content_hashes;
+        self
+    }
+
+    /// Fails commits with `ErrorKind::InsufficientSpace` before
+    /// writing anything, unless the disk has room for the new file
+    /// plus `margin` bytes
+    ///
+    /// The new file is sized as the serialized value if it's known
+    /// already, and otherwise as the last commit. Filesystems that
+    /// can't report free space aren't checked.
+    pub fn check_space(mut self, margin: u64) -> Builder {
+        self.space_margin = Some(margin);
+        self
+    }
+
+    /// Syncs each commit to disk from a background thread, in commit
+    /// order, instead of leaving it to the OS
+    ///
+    /// Commits still return once the file is in place, and
+    /// `BlobMutRef::commit_with_ticket` waits for the sync.
+    pub fn background_sync(mut self, background_sync: bool) -> Builder {
+        self.background_sync = if background_sync { Some(Duration::from_secs(0)) } else { None };
+        self
+    }
+
+    /// Also copies each commit to `mirror`, from a background thread,
+    /// and loads that copy if the blob file fails to load
+    ///
+    /// The copy is best-effort: it may lag behind commits, and
+    /// failures to write it are only logged. It's written through the
+    /// same filesystem as the blob, by temp file and rename, and its
+    /// directory must exist. It's only loaded when the blob file fails
+    /// to open or parse, after any `on_load_error` hook, not when the
+    /// blob file is missing. A blob loaded from the mirror counts as
+    /// recovered for `read_repair`.
+    pub fn mirror<P>(mut self, mirror: P) -> Builder
+        where P: AsRef<Path>
+    {
+        self.mirror = Some(mirror.as_ref().to_owned());
+        self
+    }
+
+    /// What a draft committed over by another writer does, with
+    /// `Draft::commit_by_policy`
+    ///
+    /// The default is `Policy::FailOnConflict`.
+    pub fn conflict_policy(mut self, policy: Policy) -> Builder {
+        self.policy = policy;
+        self
+    }
+
+    /// Like `background_sync`, but each sync waits `window` after the
+    /// first commit it covers, so a burst of commits is synced once
+    ///
+    /// Files that are replaced within the window usually never reach
+    /// the disk. Tickets for any commit in the burst wait for that
+    /// one sync.
+    pub fn group_commit(mut self, window: Duration) -> Builder {
+        self.background_sync = Some(window);
+        self
+    }
+
+    /// Logs a warning when the value is reloaded after a change by
+    /// another handle more than `max` times within `per`
+    ///
+    /// See `AtomBlob::stats` for the counts themselves.
+    pub fn warn_on_reloads(mut self, max: u64, per: Duration) -> Builder {
+        self.warn_on_reloads = Some((max, per));
+        self
+    }
+
+    /// Commits a value straight back after it could only be read by
+    /// recovering it, leaving the file in the current format
+    ///
+    /// Values are recovered by `lenient` parsing that ignored trailing
+    /// bytes, by an `on_load_error` repair, and by migration from an
+    /// older `migrate::Versioned` version. A value loaded at open is
+    /// committed before `open` returns, and one reloaded by a shared
+    /// guard is committed by briefly upgrading it.
+    pub fn read_repair(mut self, read_repair: bool) -> Builder {
+        self.read_repair = read_repair;
+        self
+    }
+
+    /// Never panics on the caller's thread, for hosts that can't
+    /// afford to unwind
+    ///
+    /// Locking and committing return errors regardless, including
+    /// `ErrorKind::Poisoned` for a value left locked by a panic. This
+    /// also covers the one place they can't: an exclusive guard
+    /// dropped without committing, whose failed commit is logged
+    /// instead. Call `BlobMutRef::commit` to see the error.
+    pub fn strict_no_panic(mut self, strict_no_panic: bool) -> Builder {
+        self.strict_no_panic = strict_no_panic;
+        self
+    }
+
+    /// The builder for blobs of `T`, for the options that need the
+    /// type: `TypedBuilder::validate` and `TypedBuilder::dual_write`
+    ///
+    /// Set the untyped options first, since the typed builder only
+    /// has its own.
+    pub fn typed<T>(self) -> TypedBuilder<T>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+    {
+        TypedBuilder { builder: self, validate: None, dual_write: None }
+    }
+
+    pub fn open<T>(self) -> Result<AtomBlob<T>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+    {
+        self.typed().open()
+    }
+
+    /// Like `open`, but `None` if there's no blob file
+    pub fn try_open<T>(self) -> Result<Option<AtomBlob<T>>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+    {
+        self.typed().try_open()
+    }
+
+    /// Opens the blob, sharing the in-memory value with any handle
+    /// already opened this way on the same file in this process; see
+    /// `TypedBuilder::open_shared`
+    pub fn open_shared<T>(self) -> Result<AtomBlob<T>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+    {
+        self.typed().open_shared()
+    }
+
+    // The options that shape a value opened shared, to compare with
+    // another opener's
+    fn shared_options(&self, validate: Option<usize>, dual_write: Option<(&PathBuf, usize)>)
+                      -> SharedOptions {
+        fn id<P: ?Sized>(p: Option<&Arc<P>>) -> Option<usize> {
+            p.map(|p| Arc::as_ptr(p) as *const () as usize)
+        }
+        SharedOptions {
+            idle_timeout: self.idle_timeout,
+            revalidate_every: self.revalidate_every,
+            reload_deadline: self.reload_deadline,
+            max_staleness: self.max_staleness,
+            #[cfg(feature = "sidecar")]
+            sidecar: self.sidecar,
+            lenient: self.lenient,
+            strategy: self.strategy,
+            keep_generations: self.keep_generations,
+            generations_for: self.generations_for,
+            generations_dir: self.generations_dir.clone(),
+            commit_times: self.commit_times,
+            schema_version: self.schema_version,
+            refuse_newer: self.refuse_newer,
+            deny_duplicates: self.deny_duplicates,
+            deny_unknown: self.deny_unknown,
+            acquire: self.acquire,
+            buffer_size: self.buffer_size,
+            reuse_buffer: self.reuse_buffer,
+            preallocate: self.preallocate,
+            direct_io: self.direct_io,
+            chunk_size: self.chunk_size,
+            content_hashes: self.content_hashes,
+            space_margin: self.space_margin,
+            background_sync: self.background_sync,
+            mirror: self.mirror.clone(),
+            policy: match self.policy {
+                Policy::LastWriterWins => (0, None),
+                Policy::FailOnConflict => (1, None),
+                Policy::Merge(f) => (2, Some(f as usize)),
+            },
+            warn_on_reloads: self.warn_on_reloads,
+            read_repair: self.read_repair,
+            strict_no_panic: self.strict_no_panic,
+            on_load_error: id(self.on_load_error.as_ref()),
+            on_lock_wait: self.on_lock_wait.as_ref().map(|(after, f)| (*after, id(Some(f)))),
+            on_commit: id(self.on_commit.as_ref()),
+            validate,
+            dual_write: dual_write.map(|(p, c)| (p.clone(), c)),
+        }
+    }
+
+    fn keeps_generations(&self) -> bool {
+        self.keep_generations > 0 || self.generations_for.is_some()
+    }
+
+    fn pin(mut self) -> Result<Builder> {
+        if self.keeps_generations() && self.strategy != Strategy::Rename {
+            return Err("keeping blobject generations needs the rename strategy".into());
+        }
+        if self.follow_symlinks {
+            self.path = resolve_symlinks(&self.path)
+                .chain_err(|| "resolving blobject symlink")?;
+        }
+        Ok(self)
+    }
+
+    fn load<T>(&self, validator: Option<Arc<Validate<T>>>) -> Result<Shared<T>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+    {
+        if self.lazy {
+            let mut shared = Shared::unloaded(self);
+            shared.validate = validator;
+            return Ok(shared);
+        }
+
+        let hashed = if self.content_hashes { Some(Mutex::new(None)) } else { None };
+        let parse = Parse {
+            lenient: self.lenient,
+            on_error: self.on_load_error.as_deref(),
+            enveloped: self.enveloped(),
+            buffer_size: self.buffer_size,
+            max_schema: if self.refuse_newer { self.schema_version } else { None },
+            mirror: self.mirror.as_deref(),
+            deny_duplicates: self.deny_duplicates,
+            deny_unknown: self.deny_unknown,
+            hash: hashed.as_ref(),
+        };
+        repair::take();
+        #[cfg(feature = "sidecar")]
+        let v = match (self.sidecar, self.enveloped()) {
+            (true, true) => sidecar::load(&*self.fs, self.strategy, &self.path, parse)
+                .map(|r| r.and_then(|(v, id): (envelope::Envelope<T>, _)| {
+                    v.check(parse)?;
+                    Ok((v, id))
+                })),
+            (true, false) => sidecar::load(&*self.fs, self.strategy, &self.path, parse)
+                .map(|r| r.map(|(v, id)| (envelope::Envelope::bare(v), id))),
+            (false, _) => envelope::reload(&*self.fs, self.strategy, &self.path, None, parse)
+                .map(|r| r.and_then(|v| v.ok_or_else(|| "reloading unknown blobject file".into()))),
+        };
+        #[cfg(not(feature = "sidecar"))]
+        let v = envelope::reload(&*self.fs, self.strategy, &self.path, None, parse)
+            .map(|r| r.and_then(|v| v.ok_or_else(|| "reloading unknown blobject file".into())));
 
-        if let Some(v) = ser_load(p) {
+        if let Some(v) = v {
             debug!("loaded existing blobject");
-            Ok(AtomBlob {
-                flock: DirtyFlock::new(&p.with_extension("flock")),
-                v: Arc::new(RwLock::new(v?)),
-                path: Arc::new(p.to_owned()),
-            })
+            let (v, id) = v?;
+            validate(validator.as_deref(), &v.value)?;
+            let committed_at = v.committed_at();
+            let mut shared = Shared::new(v.value, self);
+            shared.validate = validator;
+            if repair::take() && self.read_repair {
+                shared.repair_pending.store(true, Ordering::SeqCst);
+            }
+            shared.hashed = hashed;
+            shared.hashed(Some(&id));
+            *lock(&shared.loaded) = Some(id);
+            *lock(&shared.committed_at) = committed_at;
+            Ok(shared)
         } else {
             debug!("created new blobject");
-            Ok(AtomBlob {
-                flock: DirtyFlock::new(&p.with_extension("flock")),
-                v: Arc::new(RwLock::new(T::default())),
-                path: Arc::new(p.to_owned()),
-            })
+            let mut shared = Shared::new(T::default(), self);
+            shared.validate = validator;
+            Ok(shared)
         }
     }
 
+    fn enveloped(&self) -> bool {
+        self.commit_times || self.schema_version.is_some()
+    }
+
+    fn finish<T>(self, shared: Arc<Shared<T>>,
+                 dual_write: Option<&(PathBuf, Arc<dual::Convert<T>>)>) -> Result<AtomBlob<T>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+    {
+        let p = &*self.path;
+        let in_process = self.fs.in_process();
+
+        if let Some(timeout) = self.idle_timeout {
+            let weak = Arc::downgrade(&shared) as Weak<dyn idle::Evict>;
+            idle::watch(weak, timeout)?;
+        }
+
+        if let Some(interval) = self.revalidate_every {
+            warm::revalidate(Arc::downgrade(&shared), p.to_owned(), self.fs.clone(),
+                             self.writer_priority, interval)?;
+        }
+
+        if let (Some(window), None) = (self.background_sync, shared.pipeline.get()) {
+            let pipeline = pipeline::Pipeline::start(self.fs.clone(), shared.strategy,
+                                                     self.path.clone(), window)?;
+            // Another handle opened shared may have started one first
+            let _ = shared.pipeline.set(pipeline);
+        }
+
+        if let (Some(mirror), None) = (&self.mirror, shared.mirroring.get()) {
+            let flock = self.fs.lock_path(&p.with_extension("flock"));
+            let mirroring = mirror::Mirror::start(self.fs.clone(), shared.strategy, p.to_owned(),
+                                                  flock, mirror.clone())?;
+            // Brings a mirror added to an existing blob up to date
+            mirroring.enqueue();
+            let _ = shared.mirroring.set(mirroring);
+        }
+
+        if let (Some((legacy, convert)), None) = (dual_write, shared.dual.get()) {
+            let flock = DirtyFlock::new(self.fs.lock_path(&legacy.with_extension("flock")),
+                                        in_process);
+            let _ = shared.dual.set(dual::DualWrite::new(legacy.clone(), flock, convert));
+        }
+
+        if self.reload_deadline.is_some() {
+            let reloader = deadline::Reloader::new(Arc::downgrade(&shared), self.fs.clone(),
+                                                   self.path.clone());
+            let _ = shared.reloader.set(reloader);
+        }
+
+        let intent = if self.writer_priority {
+            Some(IntentLock::new(self.fs.lock_path(&p.with_extension("intent")), in_process))
+        } else {
+            None
+        };
+
+        let mut blob = AtomBlob {
+            flock: DirtyFlock::new(self.fs.lock_path(&p.with_extension("flock")), in_process),
+            intent,
+            shared,
+            path: Arc::new(self.path),
+            fs: self.fs,
+        };
+        if blob.shared.repair_pending.load(Ordering::SeqCst) {
+            // The shared guard commits it
+            blob.get()?;
+        }
+        Ok(blob)
+    }
+}
+
+/// Options for opening an `AtomBlob<T>` that need its type; see
+/// `Builder::typed`
+pub struct TypedBuilder<T> {
+    builder: Builder,
+    validate: Option<Arc<Validate<T>>>,
+    dual_write: Option<(PathBuf, Arc<dual::Convert<T>>)>,
+}
+
+impl<T> Clone for TypedBuilder<T> {
+    fn clone(&self) -> TypedBuilder<T> {
+        TypedBuilder {
+            builder: self.builder.clone(),
+            validate: self.validate.clone(),
+            dual_write: self.dual_write.clone(),
+        }
+    }
+}
+
+impl<T> TypedBuilder<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    /// Also commits `to(value)` to `legacy` as plain JSON, so binaries
+    /// still reading an older schema there keep working during a
+    /// rollout
+    ///
+    /// The legacy copy is written first, under the flock a handle on
+    /// `legacy` would take, and deleted with the blob. A commit failing
+    /// on it leaves both files as they were; one failing after it, on
+    /// the blob file, leaves the legacy copy ahead. Commits made to
+    /// `legacy` by the old binaries aren't read back, and the next
+    /// commit here replaces them.
+    pub fn dual_write<P, L, F>(mut self, legacy: P, to: F) -> TypedBuilder<T>
+        where P: AsRef<Path>, L: Serialize, F: Fn(&T) -> L + Send + Sync + 'static
+    {
+        let convert = Arc::new(dual::Convert::new(to));
+        self.dual_write = Some((legacy.as_ref().to_owned(), convert));
+        self
+    }
+
+    /// Checks every value read from the file with `f`, failing the
+    /// load or guard with `ErrorKind::InvalidValue` if `f` does
+    ///
+    /// For catching another process that commits values breaking
+    /// the blob's invariants. A value failing on reload isn't used,
+    /// and the guard errors instead. Values committed through this
+    /// handle aren't checked.
+    pub fn validate<F>(mut self, f: F) -> TypedBuilder<T>
+        where F: Fn(&T) -> Result<()> + Send + Sync + 'static
+    {
+        self.validate = Some(Arc::new(f));
+        self
+    }
+
+    pub fn open(self) -> Result<AtomBlob<T>> {
+        let this = self.builder.pin()?;
+        let shared = this.load(self.validate)?;
+        this.finish(Arc::new(shared), self.dual_write.as_ref())
+    }
+
+    /// Like `open`, but `None` if there's no blob file
+    pub fn try_open(self) -> Result<Option<AtomBlob<T>>> {
+        let this = self.builder.pin()?;
+        if this.strategy.id(&*this.fs, &this.path)
+            .chain_err(|| "checking for blobject file")?.is_none()
+        {
+            return Ok(None);
+        }
+        let shared = this.load(self.validate)?;
+        this.finish(Arc::new(shared), self.dual_write.as_ref()).map(Some)
+    }
+
+    /// Opens the blob, sharing the in-memory value with any handle
+    /// already opened this way on the same file in this process
+    ///
+    /// Fails if that handle was opened with other options, but for
+    /// the filesystem and clock, which are the first handle's, and
+    /// `writer_priority`, which is each handle's own. Hooks and
+    /// validators are told apart by identity, so handles sharing a
+    /// value should be opened with clones of one builder.
+    pub fn open_shared(self) -> Result<AtomBlob<T>> {
+        fn id<P: ?Sized>(p: &Arc<P>) -> usize {
+            Arc::as_ptr(p) as *const () as usize
+        }
+        let this = self.builder.pin()?;
+        let options = this.shared_options(self.validate.as_ref().map(id),
+                                          self.dual_write.as_ref().map(|(p, c)| (p, id(c))));
+        let validate = self.validate;
+        let shared = registry::open(&*this.fs, &this.path, options, || this.load(validate))?;
+        this.finish(shared, self.dual_write.as_ref())
+    }
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    pub fn new<P>(p: P) -> Result<AtomBlob<T>>
+        where P: AsRef<Path>, T: Send + Sync + 'static,
+    {
+        Builder::new(p).open()
+    }
+
+    /// Opens the blob at `name` in an already open directory, such as
+    /// a `cap_std::fs::Dir`, reaching its files through `vfs::DirFs`
+    #[cfg(unix)]
+    pub fn new_in<D, P>(dir: &D, name: P) -> Result<AtomBlob<T>>
+        where D: AsFd, P: AsRef<Path>, T: Send + Sync + 'static,
+    {
+        let dir = dir.as_fd().try_clone_to_owned()
+            .and_then(vfs::DirFs::from_fd)
+            .chain_err(|| "opening blobject directory")?;
+        Builder::new(name).fs(dir).open()
+    }
+
+    /// Like `new`, but `None` if there's no blob file
+    pub fn try_open<P>(p: P) -> Result<Option<AtomBlob<T>>>
+        where P: AsRef<Path>, T: Send + Sync + 'static,
+    {
+        Builder::new(p).try_open()
+    }
+
+    /// Like `new`, but shares the in-memory value with other handles
+    /// opened this way on the same file
+    pub fn open_shared<P>(p: P) -> Result<AtomBlob<T>>
+        where P: AsRef<Path>, T: Send + Sync + 'static,
+    {
+        Builder::new(p).open_shared()
+    }
+
+    /// Like `new`, but doesn't read the file until first access
+    pub fn lazy<P>(p: P) -> Result<AtomBlob<T>>
+        where P: AsRef<Path>, T: Send + Sync + 'static,
+    {
+        Builder::new(p).lazy(true).open()
+    }
+
     pub fn get(&mut self) -> Result<BlobRef<T>> {
-        let flock = self.flock.lock_shared()?;
-        if flock.state() == State::Dirty {
-            let mut val = self.v.write().expect("poisoned blobject");
-            if let Some(newval) = ser_load(&*self.path) {
-                *val = newval?;
-            } else {
-                *val = T::default()
+        if let Some(share) = reentry::shared(self.key()) {
+            return Ok(self.parts().read_through(share));
+        }
+        let held = Held::enter_shared(self.key())?;
+        self.parts().lock_shared(held)
+    }
+
+    pub fn get_mut(&mut self) -> Result<BlobMutRef<T>> {
+        let held = Held::enter(self.key())?;
+        self.parts().lock_exclusive(held)
+    }
+
+    /// Reads the value, first committing `init()` if there's no blob
+    /// file
+    ///
+    /// `init` runs under the exclusive flock, so of several handles
+    /// starting at once, in any process, only one runs it, and the
+    /// rest read what it committed.
+    pub fn get_or_init<F>(&mut self, init: F) -> Result<BlobRef<'_, T>>
+        where F: FnOnce() -> T
+    {
+        let guard = self.get()?;
+        if guard.exists() {
+            return Ok(guard);
+        }
+        let mut guard = guard.upgrade()?;
+        if guard.exists() {
+            // Another handle got in first
+            return Ok(guard.downgrade());
+        }
+        *guard = init();
+        guard.commit_and_downgrade()
+    }
+
+    /// The SHA-256 of the blob file as last committed, in lowercase
+    /// hex, if the blob was opened with `Builder::content_hashes`
+    ///
+    /// Takes the shared guard, so the file is only read if another
+    /// handle has committed since. Blobs with equal hashes have equal
+    /// files, including any envelope, and for `Strategy::Chunked` the
+    /// hash is of the value's bytes rather than the manifest. `None`
+    /// if there's no blob file.
+    pub fn content_hash(&mut self) -> Result<Option<String>> {
+        Ok(self.get()?.content_hash())
+    }
+
+    /// Commits `v` in place of the current value, without loading it
+    ///
+    /// `v` is serialized before any lock is taken, so other handles
+    /// are only locked out while the file is written, not while a
+    /// large value is serialized.
+    pub fn replace(&mut self, v: T) -> Result<()> {
+        let shared = &*self.shared;
+        let stamp = shared.stamp();
+        let buf = envelope::to_vec(&v, stamp)?;
+
+        let _held = Held::enter(self.key())?;
+        let parts = self.parts();
+        let _flock = {
+            let _intent = match parts.intent {
+                Some(i) => Some(i.lock_exclusive()?),
+                None => None,
+            };
+            parts.wait_for_flock(true)?
+        };
+        #[cfg(unix)]
+        let _in_flight = emergency::InFlight::new();
+        let mut val = shared.v.write().map_err(poisoned)?;
+        parts.commit_with(stamp.committed_at, None, |fs, strategy, p| {
+            parts.dual_write(Some(&v))?;
+            let bytes = ser_store_bytes(fs, strategy, p, &buf, shared.store())?;
+            Ok((bytes, syncs_as_it_goes(strategy)))
+        })?;
+        *val = v;
+        shared.unloaded.store(false, Ordering::SeqCst);
+
+        debug!("new blobject committed");
+
+        Ok(())
+    }
+
+    fn parts(&self) -> Parts<'_, T> {
+        Parts {
+            flock: &self.flock,
+            intent: self.intent.as_ref(),
+            shared: &self.shared,
+            path: &self.path,
+            fs: &*self.fs,
+        }
+    }
+
+    fn key(&self) -> usize {
+        &*self.shared as *const Shared<T> as usize
+    }
+
+    pub fn clone(&self) -> AtomBlob<T> {
+        let in_process = self.fs.in_process();
+        AtomBlob {
+            flock: DirtyFlock::new(self.flock.path(), in_process),
+            intent: self.intent.as_ref()
+                .map(|_| IntentLock::new(self.fs.lock_path(&self.path.with_extension("intent")),
+                                         in_process)),
+            shared: self.shared.clone(),
+            path: self.path.clone(),
+            fs: self.fs.clone(),
+        }
+    }
+
+    /// Flushes the committed blob to stable storage, then releases
+    /// this handle and its lock file
+    ///
+    /// Waits for the background syncs of commits already made,
+    /// without waiting out a `group_commit` window, and for a mirror
+    /// to copy the last commit. No guard can commit while the blob is
+    /// synced, so it's on disk as of the newest commit when this
+    /// returns.
+    pub fn shutdown(self) -> Result<()> {
+        {
+            let _flock = FlockGuard::exclusive(&self.flock)?;
+            self.shared.strategy.sync(&*self.fs, &self.path)
+                .chain_err(|| "syncing blobject on shutdown")?;
+        }
+        // Tickets already handed out are only answered by the sync
+        // thread, which syncs again, now without a window to wait out
+        if let Some(pipeline) = self.shared.pipeline.get() {
+            pipeline.drain()
+                .chain_err(|| "syncing blobject on shutdown")?;
+        }
+        // Needs the flock shared, so released first
+        if let Some(m) = self.shared.mirroring.get() {
+            m.flush()
+                .chain_err(|| "mirroring blobject on shutdown")?;
+        }
+
+        debug!("blobject shut down");
+
+        Ok(())
+    }
+}
+
+// The pieces of an `AtomBlob` that a guard locks, and may relock
+struct Parts<'a, T: 'a> {
+    flock: &'a DirtyFlock,
+    intent: Option<&'a IntentLock>,
+    shared: &'a Shared<T>,
+    path: &'a Path,
+    fs: &'a dyn Fs,
+}
+
+impl<'a, T: 'a> Clone for Parts<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T: 'a> Copy for Parts<'a, T> {}
+
+impl<'a, T: 'a> Parts<'a, T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    // A guard reading through an owned read guard's locks
+    fn read_through(self, share: Rc<dyn reentry::Share>) -> BlobRef<'a, T> {
+        // Safety: the share is of this value, since the key is the
+        // address of `shared`, and keeps it read-locked for as long as
+        // the guard holds it. `shared` outlives `'a`.
+        let v = unsafe { &*(share.value() as *const T) };
+        BlobRef {
+            v: Reading::Through(v, share),
+            flock: None,
+            held: Held::untracked(),
+            parts: self,
+            ph: PhantomData,
+        }
+    }
+
+    fn lock_shared(self, held: Held) -> Result<BlobRef<'a, T>> {
+        if held.nested() {
+            return self.lock_nested(held);
+        }
+        if let Some(v) = self.cached()? {
+            return Ok(BlobRef {
+                v: Reading::Locked(v),
+                flock: None,
+                held,
+                parts: self,
+                ph: PhantomData,
+            });
+        }
+        let flock = {
+            let _intent = match self.intent {
+                Some(i) => Some(i.lock_shared()?),
+                None => None,
+            };
+            self.wait_for_flock(false)?
+        };
+        self.shared.touch();
+        self.shared.counters.locked(self.now(), flock.state());
+        let v = loop {
+            if self.needs_reload(&flock) {
+                match (self.shared.reload_deadline, self.shared.reloader.get()) {
+                    (Some(d), Some(r)) if !self.is_unloaded() => deadline::reload(self, r, d)?,
+                    _ => {
+                        // Another guard's reload reads the file this one would
+                        if let Some(lead) = self.shared.reloads.join() {
+                            self.shared.unpoison();
+                            let mut val = self.shared.v.write().map_err(poisoned)?;
+                            self.reload(&mut val)?;
+                            drop(val);
+                            lead.succeeded();
+                        }
+                    }
+                }
+            }
+            // Eviction needs the write lock, so once we hold the read
+            // lock on a loaded value it stays loaded
+            let v = self.shared.v.read().map_err(poisoned)?;
+            if !self.is_unloaded() {
+                break v;
+            }
+        };
+        let guard = BlobRef {
+            v: Reading::Locked(v),
+            flock: Some(flock),
+            held,
+            parts: self,
+            ph: PhantomData,
+        };
+        if self.shared.repair_pending.load(Ordering::SeqCst) {
+            debug!("repairing blobject");
+            return guard.upgrade()?.commit_and_downgrade();
+        }
+        Ok(guard)
+    }
+
+    // A shared guard taken while the thread holds another, which needs
+    // the value as it is, since a reload or a repair would wait on the
+    // other's read lock
+    fn lock_nested(self, held: Held) -> Result<BlobRef<'a, T>> {
+        // Held exclusively only while the other guard holds no flock,
+        // and maybe by a writer waiting on its read lock
+        let flock = match FlockGuard::try_shared(self.flock)? {
+            Some(flock) => flock,
+            None => return Err(ErrorKind::WouldDeadlock.into()),
+        };
+        self.shared.touch();
+        self.shared.counters.locked(self.now(), flock.state());
+        if self.needs_reload(&flock) {
+            if self.is_unloaded() || self.is_late() {
+                return Err(ErrorKind::WouldDeadlock.into());
+            }
+            // Dirty only to this handle, if the file is the one loaded
+            let known = lock(&self.shared.loaded).clone();
+            match envelope::reload::<T>(self.fs, self.shared.strategy, self.path, known.as_ref(),
+                                        self.shared.parse()) {
+                Some(Ok(None)) => (),
+                None if known.is_none() => (),
+                Some(Err(e)) => return Err(e),
+                _ => return Err(ErrorKind::WouldDeadlock.into()),
             }
         }
-        let v = self.v.read().expect("poisoned blobject");
+        let v = match self.shared.v.try_read() {
+            Ok(v) => v,
+            Err(TryLockError::Poisoned(e)) => return Err(poisoned(e)),
+            // A writer queued behind the other guard
+            Err(TryLockError::WouldBlock) => return Err(ErrorKind::WouldDeadlock.into()),
+        };
         Ok(BlobRef {
-            flock: flock,
-            v: v,
+            v: Reading::Locked(v),
+            flock: Some(flock),
+            held,
+            parts: self,
+            ph: PhantomData,
+        })
+    }
+
+    fn lock_exclusive(self, held: Held) -> Result<BlobMutRef<'a, T>> {
+        let flock = {
+            let _intent = match self.intent {
+                Some(i) => Some(i.lock_exclusive()?),
+                None => None,
+            };
+            self.wait_for_flock(true)?
+        };
+        self.shared.touch();
+        self.shared.counters.locked(self.now(), flock.state());
+        self.shared.unpoison();
+        let mut v = self.shared.v.write().map_err(poisoned)?;
+        if self.needs_reload(&flock) {
+            self.reload(&mut v)?;
+        }
+        // Guards always commit, so this one repairs it
+        self.shared.repair_pending.store(false, Ordering::SeqCst);
+        Ok(BlobMutRef {
+            v: Value::Locked(v),
+            reason: None,
+            flock,
+            parts: self,
+            context: None,
+            committed: false,
+            delete: false,
+            held,
+            #[cfg(unix)]
+            in_flight: emergency::InFlight::new(),
             ph: PhantomData,
         })
     }
 
-    pub fn get_mut(&mut self) -> Result<BlobMutRef<T>> {
-        let flock = self.flock.lock_exclusive().expect("flock");
-        if flock.state() == State::Dirty {
-            // FIXME try_write
-            let mut val = self.v.write().expect("poisoned blobject");
-            if let Some(newval) = ser_load(&*self.path) {
-                *val = newval?;
-            } else {
-                *val = T::default()
+    // The value, without the flock, if it was checked against the file
+    // within `Builder::max_staleness` and needs nothing since
+    fn cached(&self) -> Result<Option<RwLockReadGuard<'a, T>>> {
+        let ttl = match self.shared.max_staleness {
+            Some(ttl) => ttl,
+            None => return Ok(None),
+        };
+        if !self.shared.counters.checked_within(self.now(), ttl) {
+            return Ok(None);
+        }
+        self.shared.touch();
+        let v = self.shared.v.read().map_err(poisoned)?;
+        if self.is_unloaded() || self.is_late()
+            || self.shared.repair_pending.load(Ordering::SeqCst)
+            || self.shared.taken.load(Ordering::SeqCst)
+        {
+            return Ok(None);
+        }
+        Ok(Some(v))
+    }
+
+    fn wait_for_flock(&self, exclusive: bool) -> Result<FlockGuard<'a>> {
+        wait::lock(self.flock, exclusive, self.fs, self.path, self.shared.acquire,
+                   self.shared.on_lock_wait.as_ref())
+    }
+
+    fn is_unloaded(&self) -> bool {
+        self.shared.unloaded.load(Ordering::SeqCst)
+    }
+
+    // Whether the value may be behind the file
+    fn needs_reload(&self, flock: &FlockGuard<'_>) -> bool {
+        flock.state() == State::Dirty || self.is_unloaded() || self.is_late()
+    }
+
+    // Milliseconds on the blob's clock
+    fn now(&self) -> u64 {
+        self.shared.clock.now().as_millis() as u64
+    }
+
+    // Called with the value write-locked
+    fn reload(&self, val: &mut T) -> Result<()> {
+        // Overtaken, so never taken
+        lock(&self.shared.late).take();
+        let loaded = lock(&self.shared.loaded);
+        let known = if self.is_unloaded() { None } else { loaded.clone() };
+        repair::take();
+        let r = envelope::reload(self.fs, self.shared.strategy, self.path, known.as_ref(),
+                                 self.shared.parse());
+        self.install(val, loaded, r, repair::take())
+    }
+
+    // Takes the value a reload read, if any. Called with the value
+    // write-locked.
+    fn install(&self, val: &mut T, mut loaded: MutexGuard<'_, Option<FileId>>,
+               r: envelope::Reloaded<T>, repaired: bool) -> Result<()> {
+        let was_loaded = !self.is_unloaded();
+        match r {
+            Some(Ok(Some((newval, id)))) => {
+                validate(self.shared.validate.as_deref(), &newval.value)?;
+                if repaired && self.shared.read_repair {
+                    self.shared.repair_pending.store(true, Ordering::SeqCst);
+                }
+                if was_loaded {
+                    self.shared.counters.reloaded(self.now(), self.path);
+                }
+                *lock(&self.shared.committed_at) =
+                    newval.committed_at();
+                *val = newval.value;
+                self.shared.loaded_now(loaded.as_ref() != Some(&id));
+                self.shared.hashed(Some(&id));
+                *loaded = Some(id);
+            }
+            Some(Ok(None)) => {
+                debug!("blobject unchanged on disk");
+            }
+            Some(Err(e)) => return Err(e),
+            None => {
+                *val = T::default();
+                self.shared.loaded_now(loaded.is_some());
+                self.shared.hashed(None);
+                *loaded = None;
+                *lock(&self.shared.committed_at) = None;
+            }
+        }
+        self.shared.unloaded.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl<'a, T: 'a> Parts<'a, T> {
+    fn exists(&self) -> bool {
+        lock(&self.shared.loaded).is_some()
+    }
+
+    fn is_late(&self) -> bool {
+        lock(&self.shared.late).is_some()
+    }
+
+    fn staleness(&self) -> Staleness {
+        match *lock(&self.shared.late) {
+            Some(ref late) => {
+                let now = self.shared.clock.now().as_millis() as u64;
+                Staleness::Stale(Duration::from_millis(now.saturating_sub(late.since)))
             }
+            None => Staleness::Fresh,
         }
-        let v = self.v.write().expect("poisoned blobject");
-        Ok(BlobMutRef {
-            flock: flock,
-            v: v,
-            path: &*self.path,
-            committed: false,
-            ph: PhantomData,
-        })
     }
 
-    pub fn clone(&self) -> AtomBlob<T> {
-        AtomBlob {
-            flock: DirtyFlock::new(self.flock.path()),
-            v: self.v.clone(),
-            path: self.path.clone(),
+    // Stores the legacy copy of `v`, or deletes it, with
+    // `TypedBuilder::dual_write`. Called with the flock held exclusively,
+    // before the blob file is replaced.
+    fn dual_write(&self, v: Option<&T>) -> Result<()> {
+        match self.shared.dual.get() {
+            Some(d) => d.commit(self.fs, v),
+            None => Ok(()),
+        }
+    }
+
+    // Replaces the blob file with `store`, keeping the bookkeeping in
+    // step. Called with the flock held exclusively. Returns a ticket
+    // for the sync if commits are synced in the background.
+    // `store` returns the bytes it wrote, and whether it synced them
+    fn commit_with<F>(&self, committed_at: Option<SystemTime>, context: Option<&Context>,
+                      store: F) -> Result<(Option<CommitTicket>, CommitReceipt)>
+        where F: FnOnce(&dyn Fs, Strategy, &Path) -> Result<(u64, bool)>
+    {
+        let start = self.shared.clock.now();
+        let mut loaded = lock(&self.shared.loaded);
+        // Until we know what's on disk, the next reload must parse
+        *loaded = None;
+        let (fs, strategy) = (self.fs, self.shared.strategy);
+        let shared = self.shared;
+        if shared.keep_generations > 0 || shared.generations_for.is_some() {
+            let retain = history::Retain {
+                keep: shared.keep_generations,
+                max_age: shared.generations_for,
+                now: shared.clock.system_time(),
+                dir: shared.generations_dir.as_deref(),
+            };
+            history::retain(fs, self.path, retain)
+                .chain_err(|| "keeping blobject generation")?;
+        }
+        let (bytes, fsynced) = store(fs, strategy, self.path)?;
+        *loaded = strategy.id(fs, self.path).ok().flatten();
+        self.shared.hashed(loaded.as_ref());
+        *lock(&self.shared.committed_at) = committed_at;
+        self.shared.loaded_now(true);
+        #[cfg(unix)]
+        emergency::committed(&self.shared.snapshot);
+        let receipt = CommitReceipt {
+            generation: self.shared.generation.load(Ordering::SeqCst),
+            bytes,
+            duration: self.shared.clock.now().saturating_sub(start),
+            fsynced,
+        };
+        if let Some(f) = &self.shared.on_commit {
+            f(&receipt, context);
         }
+        if let Some(m) = self.shared.mirroring.get() {
+            m.enqueue();
+        }
+        Ok((self.shared.pipeline.get().map(|p| p.enqueue()), receipt))
     }
 }
 
+/// What a commit did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommitReceipt {
+    /// The value's generation once committed; see
+    /// `BlobRef::generation`
+    pub generation: u64,
+    /// Bytes written to the blob file, or 0 for a delete
+    pub bytes: u64,
+    /// How long the commit took, on the blob's clock
+    pub duration: Duration,
+    /// Whether the file was synced before the commit returned, as
+    /// double-buffered commits and deletes of a file are. Other
+    /// commits are on disk once a `CommitTicket` says so.
+    pub fsynced: bool,
+}
+
+/// How far a guard's value may be behind the blob file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Staleness {
+    /// As of the last commit
+    Fresh,
+    /// Behind since a reload ran past `Builder::reload_deadline`,
+    /// this long ago
+    Stale(Duration),
+}
+
 // NB: Lock drop order
 pub struct BlobRef<'a, T: 'a> {
-    v: RwLockReadGuard<'a, T>,
+    v: Reading<'a, T>,
+    // None while served within `Builder::max_staleness`
+    #[allow(dead_code)] // Using drop side-effect
+    flock: Option<FlockGuard<'a>>,
     #[allow(dead_code)] // Using drop side-effect
-    flock: DirtyFlockShared<'a>,
+    held: Held,
+    parts: Parts<'a, T>,
     ph: PhantomData<&'a mut ()>,
 }
 
+// A `BlobRef`'s value, read-locked by the guard, or by an owned read
+// guard on the same thread that it reads through
+enum Reading<'a, T: 'a> {
+    Locked(RwLockReadGuard<'a, T>),
+    Through(&'a T, #[allow(dead_code)] Rc<dyn reentry::Share>), // Using drop side-effect
+}
+
+impl<'a, T: 'a> Deref for Reading<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Reading::Locked(v) => v,
+            Reading::Through(v, _) => v,
+        }
+    }
+}
+
 // NB: Lock drop order
 pub struct BlobMutRef<'a, T: 'a + Serialize> {
-    v: RwLockWriteGuard<'a, T>,
+    v: Value<'a, T>,
+    #[allow(dead_code)] // Using drop side-effect, before the flock
+    reason: Option<reason::Reason>,
     #[allow(dead_code)] // Using drop side-effect
-    flock: DirtyFlockExclusive<'a>,
-    path: &'a Path,
+    flock: FlockGuard<'a>,
+    parts: Parts<'a, T>,
+    context: Option<Box<Context>>,
     committed: bool,
+    delete: bool,
+    #[allow(dead_code)] // Using drop side-effect
+    held: Held,
+    #[cfg(unix)]
+    #[allow(dead_code)] // Using drop side-effect, after the flock
+    in_flight: emergency::InFlight,
     ph: PhantomData<&'a mut ()>,
 }
 
+// A `BlobMutRef`'s value, locked in place, or moved out for a guard
+// that can change threads, which a `RwLock` guard can't
+enum Value<'a, T: 'a> {
+    Locked(RwLockWriteGuard<'a, T>),
+    Taken(Taken<'a, T>),
+}
+
+// Put back as it drops
+struct Taken<'a, T: 'a> {
+    v: ManuallyDrop<T>,
+    shared: &'a Shared<T>,
+}
+
+impl<'a, T: 'a> Deref for Value<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match *self {
+            Value::Locked(ref v) => v,
+            Value::Taken(ref t) => &t.v,
+        }
+    }
+}
+
+impl<'a, T: 'a> DerefMut for Value<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match *self {
+            Value::Locked(ref mut v) => v,
+            Value::Taken(ref mut t) => &mut t.v,
+        }
+    }
+}
+
+impl<'a, T: 'a> Drop for Taken<'a, T> {
+    fn drop(&mut self) {
+        let mut v = self.shared.v.write().unwrap_or_else(PoisonError::into_inner);
+        // Safety: `self.v` isn't used again
+        *v = unsafe { ManuallyDrop::take(&mut self.v) };
+        self.shared.taken.store(false, Ordering::SeqCst);
+    }
+}
+
+impl<'a, T: 'a> BlobRef<'a, T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// Trades shared access for exclusive access
+    ///
+    /// The shared locks are released before the exclusive ones are
+    /// taken, so another writer may get in between. If one does, the
+    /// value is reloaded before this returns, so the guard never
+    /// exposes anything older than what is on disk.
+    ///
+    /// Fails with `ErrorKind::WouldDeadlock` for a guard reading
+    /// through an owned read guard, whose locks it can't give up, or
+    /// while the thread holds another guard on the value.
+    pub fn upgrade(self) -> Result<BlobMutRef<'a, T>> {
+        let BlobRef { v, flock, mut held, parts, .. } = self;
+        if let Reading::Through(..) = v {
+            return Err(ErrorKind::WouldDeadlock.into());
+        }
+        held.upgrade()?;
+        // NB: Lock drop order
+        drop(v);
+        drop(flock);
+        parts.lock_exclusive(held)
+    }
+
+    /// When the value was committed, if the blob was opened with
+    /// `Builder::commit_times`
+    pub fn committed_at(&self) -> Option<SystemTime> {
+        *lock(&self.parts.shared.committed_at)
+    }
+
+    /// Whether there's a blob file, as opposed to the value being
+    /// `T::default()` for lack of one
+    pub fn exists(&self) -> bool {
+        self.parts.exists()
+    }
+
+    /// The SHA-256 of the file the value is, if the blob was opened
+    /// with `Builder::content_hashes`; see `AtomBlob::content_hash`
+    pub fn content_hash(&self) -> Option<String> {
+        let shared = self.parts.shared;
+        let loaded = lock(&shared.loaded);
+        match (&*lock(&shared.content_hash), &*loaded) {
+            (Some((id, digest)), Some(loaded)) if id == loaded => Some(sha256::encode(digest)),
+            _ => None,
+        }
+    }
+
+    /// Whether the value is behind the file, because a reload ran
+    /// past `Builder::reload_deadline`
+    pub fn staleness(&self) -> Staleness {
+        self.parts.staleness()
+    }
+
+    /// Whether the value is as of the last commit, in any process
+    pub fn is_fresh(&self) -> bool {
+        self.staleness() == Staleness::Fresh
+    }
+
+    /// When the value was last read from the file, or committed, by
+    /// a handle sharing it
+    pub fn loaded_at(&self) -> SystemTime {
+        *lock(&self.parts.shared.loaded_at)
+    }
+
+    /// How many times the value has changed since the blob was
+    /// opened, for telling whether it did between two guards
+    ///
+    /// Goes up with each commit, and each reload that reads a
+    /// different file than the last. It's
+    /// local to handles sharing the value, unlike the generations of
+    /// `Builder::keep_generations`.
+    pub fn generation(&self) -> u64 {
+        self.parts.shared.generation.load(Ordering::SeqCst)
+    }
+}
+
 impl<'a, T: 'a> Deref for BlobRef<'a, T> {
     type Target = T;
 
@@ -152,7 +2221,14 @@ impl<'a, T: 'a> Drop for BlobMutRef<'a, T>
 {
     fn drop(&mut self) {
         if !self.committed {
-            self.commit().expect("blobject failed to commit on drop");
+            if let Err(e) = self.commit() {
+                // A quota is expected to turn commits away
+                if self.parts.shared.no_panic || e.is_over_quota() {
+                    error!("blobject failed to commit on drop: {}", e);
+                } else {
+                    panic!("blobject failed to commit on drop: {:?}", e);
+                }
+            }
         }
     }
 }
@@ -160,63 +2236,669 @@ impl<'a, T: 'a> Drop for BlobMutRef<'a, T>
 impl<'a, T: 'a> BlobMutRef<'a, T>
     where T: Serialize
 {
-    pub fn commit(&mut self) -> Result<()> {
-        ser_store(&self.path, &*self.v)?;
+    pub fn commit(&mut self) -> Result<CommitReceipt> {
+        self.commit_queued().map(|(_, receipt)| receipt)
+    }
+
+    /// Attaches `context` to the guard, for hooks set with
+    /// `Builder::on_commit_with_context` to read when it commits
+    ///
+    /// It replaces any context set before, and is dropped with the
+    /// guard.
+    pub fn set_context<C>(&mut self, context: C)
+        where C: Any + Send + Sync
+    {
+        self.context = Some(Box::new(context));
+    }
+
+    /// The context attached with `set_context`
+    pub fn context(&self) -> Option<&Context> {
+        self.context.as_deref()
+    }
+
+    /// Makes the commit delete the blob file instead of writing the
+    /// value
+    ///
+    /// Afterwards the blob reads as `T::default()`, and `exists` is
+    /// false, until something is committed again. Fails for
+    /// `Strategy::DoubleBuffer`.
+    pub fn delete_on_commit(&mut self) -> Result<()> {
+        if self.parts.shared.strategy != Strategy::Rename {
+            return Err("deleting blobjects needs the rename strategy".into());
+        }
+        self.delete = true;
+        Ok(())
+    }
+
+    /// Whether there's a blob file, as of the last commit or load
+    pub fn exists(&self) -> bool {
+        self.parts.exists()
+    }
+
+    /// Replaces the value with `v`, returning the old value
+    pub fn set(&mut self, v: T) -> T {
+        mem::replace(&mut *self.v, v)
+    }
+
+    /// Drops the guard without committing, leaving the file as it was
+    ///
+    /// Changes made through the guard are thrown away, and the next
+    /// guard rereads the value. After a failed `commit` this drops the
+    /// guard without trying again.
+    pub fn discard(mut self) {
+        self.parts.shared.unloaded.store(true, Ordering::SeqCst);
+        self.committed = true;
+    }
+
+    /// Commits, returning a ticket that waits for the commit to be
+    /// on disk
+    ///
+    /// Without `Builder::background_sync`, the commit is synced
+    /// before this returns.
+    pub fn commit_with_ticket(&mut self) -> Result<CommitTicket> {
+        if let (Some(ticket), _) = self.commit_queued()? {
+            return Ok(ticket);
+        }
+        let parts = &self.parts;
+        parts.shared.strategy.sync(parts.fs, parts.path)
+            .chain_err(|| "syncing blobject commit")?;
+        Ok(CommitTicket::done())
+    }
+
+    fn commit_queued(&mut self) -> Result<(Option<CommitTicket>, CommitReceipt)> {
+        let shared = self.parts.shared;
+        if self.delete {
+            let parts = &self.parts;
+            let context = self.context.as_deref();
+            let ticket = parts.commit_with(None, context, |fs, _, p| {
+                parts.dual_write(None)?;
+                delete(fs, p).map(|synced| (0, synced))
+            })?;
+            // The next guard reads the default
+            shared.unloaded.store(true, Ordering::SeqCst);
+            self.committed = true;
+
+            debug!("blobject deleted");
+
+            return Ok(ticket);
+        }
+        let stamp = shared.stamp();
+        let v = &*self.v;
+        let parts = &self.parts;
+        let context = self.context.as_deref();
+        let r = parts.commit_with(stamp.committed_at, context, |fs, strategy, p| {
+            parts.dual_write(Some(v))?;
+            let bytes = envelope::store(fs, strategy, p, v, stamp, shared.store())?;
+            Ok((bytes, syncs_as_it_goes(strategy)))
+        });
+        let ticket = match r {
+            Err(e) if e.is_over_quota() => {
+                // Not tried again as the guard drops, and the next
+                // guard reads what's on disk
+                shared.unloaded.store(true, Ordering::SeqCst);
+                self.committed = true;
+                return Err(e);
+            }
+            r => r?,
+        };
         self.committed = true;
 
         debug!("new blobject committed");
 
-        Ok(())
+        Ok(ticket)
     }
 }
 
-fn ser_load<P, T>(p: P) -> Option<Result<T>>
-    where P: AsRef<Path>, for <'de> T: Deserialize<'de>
+impl<'a, T: 'a> BlobMutRef<'a, T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
 {
-    let p = p.as_ref();
+    /// Replaces the value with `f(value)`
+    ///
+    /// If `f` panics the value is lost, so the guard doesn't commit,
+    /// the file keeps the old value, and the next guard reads it back.
+    pub fn map<F>(&mut self, f: F)
+        where F: FnOnce(T) -> T
+    {
+        // Unloads the value if `f` unwinds, leaving the default
+        struct Lost<'b>(&'b AtomicBool);
+
+        impl<'b> Drop for Lost<'b> {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let committed = mem::replace(&mut self.committed, true);
+        let lost = Lost(&self.parts.shared.unloaded);
+        let v = mem::take(&mut *self.v);
+        *self.v = f(v);
+        mem::forget(lost);
+        self.committed = committed;
+    }
+
+    /// Commits, then keeps reading the committed value
+    ///
+    /// The exclusive flock is kept, so no other process can change
+    /// the blob until the returned guard is dropped. Within this
+    /// process only the value lock is downgraded.
+    pub fn commit_and_downgrade(mut self) -> Result<BlobRef<'a, T>> {
+        self.commit()?;
+        Ok(self.downgrade())
+    }
+
+    // Keeps reading without committing
+    fn downgrade(self) -> BlobRef<'a, T> {
+        let this = ManuallyDrop::new(self);
+        // Safety: each field is moved out exactly once, and `this`
+        // is never dropped
+        let (v, flock, held, parts) = unsafe {
+            (ptr::read(&this.v), ptr::read(&this.flock),
+             ptr::read(&this.held), this.parts)
+        };
+        drop(unsafe { ptr::read(&this.reason) });
+        drop(unsafe { ptr::read(&this.context) });
+        #[cfg(unix)]
+        drop(unsafe { ptr::read(&this.in_flight) });
+
+        drop(v);
+        let v = parts.shared.v.read().unwrap_or_else(PoisonError::into_inner);
+        BlobRef {
+            v: Reading::Locked(v),
+            flock: Some(flock),
+            held,
+            parts,
+            ph: PhantomData,
+        }
+    }
+}
 
-    let infile = match File::open(p) {
-        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-            return None;
+// Resolves symlinks in the final path component, even if dangling
+fn resolve_symlinks(p: &Path) -> StdResult<PathBuf, io::Error> {
+    let mut p = p.to_owned();
+    for _ in 0..40 {
+        match fs::symlink_metadata(&p) {
+            Ok(ref m) if m.file_type().is_symlink() => {
+                let target = fs::read_link(&p)?;
+                p = match p.parent() {
+                    Some(dir) => dir.join(target),
+                    None => target,
+                };
+            }
+            Ok(_) => return Ok(p),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(p),
+            Err(e) => return Err(e),
         }
+    }
+    Err(io::Error::other("too many levels of symbolic links"))
+}
+
+// Returns `None` if the file doesn't exist, and skips the parse and
+// returns `Ok(None)` if the file is still the one identified by `known`
+fn ser_reload<T>(fs: &dyn Fs, strategy: Strategy, p: &Path, known: Option<&FileId>,
+                 parse: Parse<'_>) -> Option<Result<Option<(T, FileId)>>>
+    where for <'de> T: Serialize + Deserialize<'de> + Default
+{
+    match (ser_reload_file(fs, strategy, p, known, parse), parse.mirror) {
+        (Some(Err(e)), Some(m)) => Some(mirror::fall_back(fs, strategy, p, m, parse, e)),
+        (r, _) => r,
+    }
+}
+
+fn ser_reload_file<T>(fs: &dyn Fs, strategy: Strategy, p: &Path, known: Option<&FileId>,
+                      parse: Parse<'_>) -> Option<Result<Option<(T, FileId)>>>
+    where for <'de> T: Serialize + Deserialize<'de> + Default
+{
+    let (infile, id) = match strategy.open(fs, p) {
+        Ok(None) => return None,
         Err(e) => {
             return Some(Err(e).chain_err(|| "opening blobject"))
         }
-        Ok(f) => f
+        Ok(Some(f)) => f
+    };
+
+    if known == Some(&id) {
+        return Some(Ok(None));
+    }
+    let infile: Box<dyn Read> = match parse.buffer_size {
+        Some(n) => Box::new(BufReader::with_capacity(n, infile)),
+        None => infile,
+    };
+    let mut infile = Hashed { inner: infile, hash: parse.hash.map(|_| sha256::Sha256::new()) };
+
+    let value = match (parse.buffered(), parse.lenient) {
+        (false, false) => serde_json::from_reader(&mut infile)
+            .chain_err(|| "loading blobject"),
+        // Stops reading at the end of the document
+        (false, true) => {
+            let mut docs = serde_json::Deserializer::from_reader(&mut infile).into_iter();
+            let v = docs.next()
+                .unwrap_or_else(|| Err(serde::de::Error::custom("no JSON document")))
+                .chain_err(|| "loading blobject");
+            if v.is_ok() && docs.next().is_some() {
+                warn!("ignoring bytes after blobject");
+                repair::mark();
+            }
+            v
+        }
+        // Recovery and checks need the bytes after the fact
+        (true, _) => {
+            let mut buf = Vec::new();
+            infile.read_to_end(&mut buf)
+                .chain_err(|| "loading blobject")
+                .and_then(|_| ser_parse(p, &buf, parse))
+        }
     };
 
-    let infile = BufReader::new(infile);
-    let value = serde_json::from_reader(infile)
-         .chain_err(|| "loading blobject");
+    if let (Ok(_), Some(hash)) = (&value, parse.hash) {
+        // Including anything after the document
+        let digest = io::copy(&mut infile, &mut io::sink()).ok()
+            .and_then(|_| infile.hash.take())
+            .map(|h| h.finish());
+        *lock(hash) = digest;
+    }
 
-    Some(value)
+    Some(value.map(|v| Some((v, id))))
 }
 
-fn ser_store<P, T>(p: P, t: &T) -> Result<()>
-    where P: AsRef<Path>, T: Serialize
+fn ser_parse<T>(p: &Path, buf: &[u8], parse: Parse<'_>) -> Result<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default
 {
-    let p = p.as_ref();
-    let tmp_ext = format!("{:08x}.tmp", random::<u32>());
-    let tmp_path = p.with_extension(tmp_ext);
+    let e = match ser_parse_checked(buf, parse) {
+        Ok(v) => return Ok(v),
+        Err(e) => e,
+    };
+    let on_error = match parse.on_error {
+        Some(f) => f,
+        None => return Err(e),
+    };
+
+    match on_error(p, &e, buf) {
+        LoadDecision::Repaired(buf) => {
+            warn!("loading repaired blobject after: {}", e);
+            let v = ser_parse_checked(&buf, parse)
+                .chain_err(|| "loading repaired blobject")?;
+            repair::mark();
+            Ok(v)
+        }
+        LoadDecision::Default => {
+            warn!("loading default blobject after: {}", e);
+            Ok(T::default())
+        }
+        LoadDecision::Abort => Err(e),
+    }
+}
+
+fn ser_parse_checked<T>(buf: &[u8], parse: Parse<'_>) -> Result<T>
+    where for <'de> T: Serialize + Deserialize<'de>
+{
+    let v = ser_parse_bytes(buf, parse.lenient)?;
+    strict::check(buf, &v, parse)?;
+    Ok(v)
+}
+
+fn ser_parse_bytes<T>(buf: &[u8], lenient: bool) -> Result<T>
+    where for <'de> T: Deserialize<'de>
+{
+    if !lenient {
+        return serde_json::from_slice(buf)
+            .chain_err(|| "loading blobject");
+    }
+
+    let mut docs = serde_json::Deserializer::from_slice(buf).into_iter();
+    let v = docs.next()
+        .unwrap_or_else(|| Err(serde::de::Error::custom("no JSON document")))
+        .chain_err(|| "loading blobject")?;
+    let rest = &buf[docs.byte_offset()..];
+    if rest.iter().any(|b| !b.is_ascii_whitespace()) {
+        warn!("ignoring {} bytes after blobject", rest.len());
+        repair::mark();
+    }
+    Ok(v)
+}
+
+// Deletes the blob file, durably
+// Whether there was a file, and its removal was synced
+fn delete(fs: &dyn Fs, p: &Path) -> Result<bool> {
+    match fs.remove(p) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        r => r.chain_err(|| "deleting blobject file")?,
+    }
+    let dir = match p.parent() {
+        Some(d) if d != Path::new("") => d,
+        _ => Path::new("."),
+    };
+    fs.sync(dir).chain_err(|| "syncing deleted blobject")?;
+    Ok(true)
+}
+
+// Whether a commit with `strategy` has synced the file once it's
+// stored, as double-buffered commits have
+fn syncs_as_it_goes(strategy: Strategy) -> bool {
+    strategy == Strategy::DoubleBuffer
+}
+
+fn ser_store<T>(fs: &dyn Fs, strategy: Strategy, p: &Path, t: &T) -> Result<()>
+    where T: Serialize
+{
+    ser_store_with(fs, strategy, p, t, Store::default()).map(|_| ())
+}
+
+// Returns the bytes written
+fn ser_store_with<T>(fs: &dyn Fs, strategy: Strategy, p: &Path, t: &T,
+                     store: Store<'_>) -> Result<u64>
+    where T: Serialize
+{
+    if let Some(scratch) = store.scratch {
+        let mut buf = lock(scratch);
+        buf.clear();
+        json_to_writer(&mut *buf, t)
+            .chain_err(|| "serializing blobject to file")?;
+        return ser_store_bytes(fs, strategy, p, &buf, store);
+    }
+
+    // Both need the whole file at once
+    if strategy != Strategy::Rename {
+        let buf = json_to_vec(t)
+            .chain_err(|| "serializing blobject to file")?;
+        return ser_store_bytes(fs, strategy, p, &buf, store);
+    }
+
+    ser_write(fs, p, store, None, |out| {
+        json_to_writer(out, t)
+            .chain_err(|| "serializing blobject to file")
+    })
+}
+
+// Stores an already serialized value
+fn ser_store_bytes(fs: &dyn Fs, strategy: Strategy, p: &Path, buf: &[u8],
+                   store: Store<'_>) -> Result<u64> {
+    // Hashed as it's written, otherwise
+    let record_hash = || {
+        if let Some(hash) = store.hash {
+            *lock(hash) = Some(sha256::digest(buf));
+        }
+    };
+    if strategy == Strategy::DoubleBuffer {
+        check_space(fs, p, store, Some(buf.len() as u64))?;
+        ab::store(fs, p, buf)
+            .chain_err(|| "replacing blobject file")?;
+        record_hash();
+        return Ok(buf.len() as u64);
+    }
+
+    if strategy == Strategy::Chunked {
+        check_space(fs, p, store, Some(buf.len() as u64))?;
+        chunk::store(fs, p, buf, store.chunk_size.unwrap_or(chunk::DEFAULT_SIZE))
+            .chain_err(|| "replacing blobject file")?;
+        record_hash();
+        return Ok(buf.len() as u64);
+    }
+
+    if store.preallocate || store.direct {
+        return ser_write(fs, p, store, Some(buf.len() as u64), |out| {
+            out.write_all(buf)
+                .chain_err(|| "writing blobject file")
+        });
+    }
 
-    let out = File::create(&tmp_path)
+    check_space(fs, p, store, Some(buf.len() as u64))?;
+    // `replace` writes the file this reserves the name of
+    let (tmp_path, _) = tmp_file(fs, p)
         .chain_err(|| "creating tmp file for blobject")?;
-    let mut out = BufWriter::new(out);
+    if let Err(e) = fs.replace(&tmp_path, p, buf) {
+        // Don't leave a partial file behind
+        if let Err(e) = fs.remove(&tmp_path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("deleting blobject tmp file: {}", e);
+            }
+        }
+        return Err(e).chain_err(|| "replacing blobject file");
+    }
+    if let Some(last) = store.last_size {
+        last.store(buf.len() as u64, Ordering::SeqCst);
+    }
+    record_hash();
+
+    Ok(buf.len() as u64)
+}
 
-    serde_json::to_writer_pretty(&mut out, t)
-        .chain_err(|| "serializing blobject to file")?;
+// Writes a tmp file with `write`, then renames it over `p`, returning
+// the bytes written. `size` is what `write` will write, if known.
+fn ser_write<F>(fs: &dyn Fs, p: &Path, store: Store<'_>, size: Option<u64>,
+                write: F) -> Result<u64>
+    where F: FnOnce(&mut dyn Write) -> Result<()>
+{
+    let expected = check_space(fs, p, store, size)?;
+    let reserve = match expected {
+        Some(n) if n > 0 && store.preallocate => Some(n),
+        _ => None,
+    };
+    let (tmp_path, out) = tmp_file(fs, p)
+        .chain_err(|| "creating tmp file for blobject")?;
+    // Writing around the cache or into reserved space takes the file
+    // created again, under the name it now holds
+    let out = match reserve {
+        _ if store.direct => {
+            drop(out);
+            fs.create_direct(&tmp_path, reserve)
+                .chain_err(|| "creating tmp file for blobject")
+        }
+        Some(n) => {
+            drop(out);
+            fs.create_sized(&tmp_path, n)
+                .chain_err(|| "reserving space for blobject file")
+        }
+        None => Ok(out),
+    };
+    let out = match out {
+        Ok(out) => out,
+        Err(e) => {
+            let _ = fs.remove(&tmp_path);
+            return Err(e);
+        }
+    };
+    let mut out = Counted { inner: out, n: 0, hash: store.hash.map(|_| sha256::Sha256::new()) };
+    let r = {
+        let mut buffered;
+        let w: &mut dyn Write = match store.buffer_size {
+            Some(n) => {
+                buffered = BufWriter::with_capacity(n, &mut out);
+                &mut buffered
+            }
+            None => &mut out,
+        };
 
-    out.flush()
-        .chain_err(|| "flushing blobject file")?;
+        write(w).and_then(|()| w.flush().chain_err(|| "flushing blobject file"))
+    };
 
+    let written = out.n;
+    let digest = out.hash.take().map(|h| h.finish());
     drop(out);
+    if let Err(e) = r {
+        // Don't leave a partial file behind
+        if let Err(e) = fs.remove(&tmp_path) {
+            warn!("deleting blobject tmp file: {}", e);
+        }
+        return Err(e);
+    }
+    if let Some(last) = store.last_size {
+        last.store(written, Ordering::SeqCst);
+    }
 
-    atomic_file_rename(&tmp_path, p)
+    fs.rename(&tmp_path, p)
         .chain_err(|| "replacing blobject file")?;
+    if let Some(hash) = store.hash {
+        *lock(hash) = digest;
+    }
+
+    Ok(written)
+}
+
+// Fails if the disk lacks the space `store` requires for a file of
+// `size`, or as big as the last if that's unknown. Returns the
+// expected size.
+fn check_space(fs: &dyn Fs, p: &Path, store: Store<'_>, size: Option<u64>)
+               -> Result<Option<u64>> {
+    let expected = store.last_size.map(|last| {
+        size.unwrap_or_else(|| last.load(Ordering::SeqCst))
+    });
+    if let (Some(margin), Some(expected)) = (store.space_margin, expected) {
+        let available = fs.available(p).chain_err(|| "checking space for blobject")?;
+        let needed = expected.saturating_add(margin);
+        if let Some(available) = available {
+            if available < needed {
+                return Err(ErrorKind::InsufficientSpace(needed, available).into());
+            }
+        }
+    }
+    Ok(expected)
+}
+
+// Counts the bytes written through it
+struct Counted<W> {
+    inner: W,
+    n: u64,
+    hash: Option<sha256::Sha256>,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.n += n as u64;
+        if let Some(h) = &mut self.hash {
+            h.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Hashes what's read through it
+struct Hashed<R> {
+    inner: R,
+    hash: Option<sha256::Sha256>,
+}
+
+impl<R: Read> Read for Hashed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(h) = &mut self.hash {
+            h.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+fn sync_file<P>(p: P) -> StdResult<(), io::Error>
+    where P: AsRef<Path>
+{
+    let p = p.as_ref();
+
+    if sync_contents(p)? {
+        // The rename itself is only durable once the directory is synced
+        sync_dir(p.parent().unwrap_or_else(|| Path::new("")))?;
+    }
 
     Ok(())
 }
 
+// Syncs the file at `p`, returning whether there was one
+fn sync_contents(p: &Path) -> StdResult<bool, io::Error> {
+    match File::open(p) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+        Ok(f) => f.sync_all().map(|_| true),
+    }
+}
+
+fn sync_dir(dir: &Path) -> StdResult<(), io::Error> {
+    #[cfg(unix)]
+    {
+        let dir = if dir == Path::new("") { Path::new(".") } else { dir };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+
+    Ok(())
+}
+
+// Blob files are pretty-printed for people to read, unless built for
+// size
+#[cfg(not(feature = "minimal"))]
+fn json_to_writer<W, T>(w: W, t: &T) -> serde_json::Result<()>
+    where W: Write, T: Serialize + ?Sized
+{
+    serde_json::to_writer_pretty(w, t)
+}
+
+#[cfg(feature = "minimal")]
+fn json_to_writer<W, T>(w: W, t: &T) -> serde_json::Result<()>
+    where W: Write, T: Serialize + ?Sized
+{
+    serde_json::to_writer(w, t)
+}
+
+fn json_to_vec<T>(t: &T) -> serde_json::Result<Vec<u8>>
+    where T: Serialize + ?Sized
+{
+    let mut buf = Vec::with_capacity(128);
+    json_to_writer(&mut buf, t)?;
+    Ok(buf)
+}
+
+// Hashes must agree across processes and builds, which rules out
+// `DefaultHasher`
+fn fnv1a(buf: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &b in buf {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+// Unique among live processes by the pid, and within one by the
+// counter. The time keeps a process that reuses a crashed one's pid
+// from starting on the names it left behind.
+fn tmp_extension() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u32)
+        .unwrap_or(0);
+    format!("{:x}-{:x}-{:08x}.tmp", pid(), n, nanos)
+}
+
+#[cfg(not(target_os = "wasi"))]
+fn pid() -> u32 {
+    std::process::id()
+}
+
+// Every writer is in this process
+#[cfg(target_os = "wasi")]
+fn pid() -> u32 {
+    0
+}
+
+// Creates a tmp file beside `p` under a name no file has, trying
+// again past any left by a crashed process or made by another writer
+fn tmp_file(fs: &dyn Fs, p: &Path) -> io::Result<(PathBuf, Box<dyn Write>)> {
+    for _ in 0..16 {
+        let tmp_path = p.with_extension(tmp_extension());
+        match fs.create_new(&tmp_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                warn!("blobject tmp file {} exists, trying another", tmp_path.display());
+            }
+            r => return Ok((tmp_path, r?)),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, "no free tmp file name for blobject"))
+}
+
 fn atomic_file_rename<P, Q>(src: P, dst: Q) -> StdResult<(), io::Error>
     where P: AsRef<Path>, Q: AsRef<Path>
 {
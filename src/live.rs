@@ -0,0 +1,95 @@
+//! Hot-reloaded config handles, for frameworks that expect one
+//!
+//! Config libraries and servers commonly take a source they can ask
+//! for the current config, as an `Arc` snapshot that stays whole while
+//! it's in use, and replace as the config changes: what `ArcSwap`
+//! gives them. `ConfigSource` is that interface, and a `Live` handle
+//! implements it over a blob, kept current by a `Follower`. Taking a
+//! snapshot neither locks the blob nor touches the disk, and clones
+//! the value only once per commit.
+//!
+//! ```ignore
+//! let config: Live<Config> = Builder::new(p).live(Track::Watch(interval))?;
+//! server.with_config(config); // takes any ConfigSource
+//! ```
+
+use serde::{Serialize, Deserialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use super::{AtomBlob, Builder, Result, lock};
+use super::follow::{Follower, Track};
+
+/// A value that's replaced as it changes, read as snapshots
+pub trait ConfigSource: Send + Sync {
+    type Config;
+
+    /// The value now, unchanged for as long as it's held
+    fn current(&self) -> Arc<Self::Config>;
+
+    /// How many times the value has changed, to tell whether a
+    /// snapshot is still current
+    fn generation(&self) -> u64;
+}
+
+/// A `ConfigSource` following a blob
+pub struct Live<T> {
+    follower: Follower<T>,
+    // The last snapshot taken, and the generation it's of
+    current: Mutex<(u64, Arc<T>)>,
+}
+
+impl Builder {
+    /// Opens a `Live` handle on the blob, following it as `track`
+    /// says; see `Builder::follow`
+    pub fn live<T>(self, track: Track) -> Result<Live<T>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Clone + Send + Sync + 'static,
+    {
+        let follower: Follower<T> = self.follow(track)?;
+        let current = {
+            let v = follower.get();
+            (v.generation(), Arc::new(T::clone(&v)))
+        };
+        Ok(Live { follower, current: Mutex::new(current) })
+    }
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// Like `follow`, but a `ConfigSource`; see `Builder::live`
+    pub fn live<P>(p: P, track: Track) -> Result<Live<T>>
+        where P: AsRef<Path>, T: Clone + Send + Sync + 'static,
+    {
+        Builder::new(p).live(track)
+    }
+}
+
+impl<T> Live<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Clone,
+{
+    /// The follower keeping the value current
+    pub fn follower(&self) -> &Follower<T> {
+        &self.follower
+    }
+}
+
+impl<T> ConfigSource for Live<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Clone + Send + Sync,
+{
+    type Config = T;
+
+    fn current(&self) -> Arc<T> {
+        // The generation changes with the value, under its lock
+        let v = self.follower.get();
+        let generation = v.generation();
+        let mut current = lock(&self.current);
+        if current.0 != generation {
+            *current = (generation, Arc::new(T::clone(&v)));
+        }
+        current.1.clone()
+    }
+
+    fn generation(&self) -> u64 {
+        self.follower.get().generation()
+    }
+}
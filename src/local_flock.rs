@@ -0,0 +1,226 @@
+//! In-process stand-in for `fancy_flocks`
+//!
+//! Used for filesystems only this process can see: in-memory ones
+//! like `vfs::MockFs`, and the host filesystem on WASI, which has no
+//! advisory file locks, and where a sandboxed module is the only
+//! process with access to its preopened directories. The lock and
+//! dirty-tracking protocol is kept in a process-wide table keyed by
+//! filesystem and lock path instead of in a sidecar file.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock, PoisonError};
+use super::lock;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum State {
+    Dirty,
+    Clean,
+}
+
+#[derive(Default)]
+struct Entry {
+    readers: usize,
+    writer: bool,
+    epoch: u64,
+}
+
+type Key = (u64, PathBuf);
+
+struct Table {
+    entries: Mutex<HashMap<Key, Entry>>,
+    cond: Condvar,
+}
+
+fn table() -> &'static Table {
+    static TABLE: OnceLock<Table> = OnceLock::new();
+    TABLE.get_or_init(|| Table {
+        entries: Mutex::new(HashMap::new()),
+        cond: Condvar::new(),
+    })
+}
+
+fn entries() -> MutexGuard<'static, HashMap<Key, Entry>> {
+    lock(&table().entries)
+}
+
+pub struct DirtyFlock {
+    key: Key,
+    known_epoch: Cell<Option<u64>>,
+    exclusive: Cell<bool>,
+}
+
+impl DirtyFlock {
+    pub fn new<P>(fs: u64, p: P) -> DirtyFlock
+        where P: AsRef<Path>
+    {
+        DirtyFlock {
+            key: (fs, p.as_ref().to_owned()),
+            known_epoch: Cell::new(None),
+            exclusive: Cell::new(false),
+        }
+    }
+
+    pub fn lock_shared(&self) -> Result<State> {
+        Ok(self.take(false))
+    }
+
+    pub fn lock_exclusive(&self) -> Result<State> {
+        Ok(self.take(true))
+    }
+
+    pub fn try_lock_shared(&self) -> Result<Option<State>> {
+        Ok(self.try_take(&mut entries(), false))
+    }
+
+    pub fn try_lock_exclusive(&self) -> Result<Option<State>> {
+        Ok(self.try_take(&mut entries(), true))
+    }
+
+    pub fn unlock(&self) -> Result<()> {
+        let mut entries = entries();
+        let entry = entries.get_mut(&self.key).expect("unlocking unlocked flock");
+        if self.exclusive.get() {
+            // Our own writes don't make our view dirty
+            entry.epoch += 1;
+            entry.writer = false;
+            self.known_epoch.set(Some(entry.epoch));
+            self.exclusive.set(false);
+        } else {
+            entry.readers -= 1;
+        }
+        table().cond.notify_all();
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.key.1
+    }
+
+    fn take(&self, exclusive: bool) -> State {
+        let mut entries = entries();
+        loop {
+            if let Some(state) = self.try_take(&mut entries, exclusive) {
+                return state;
+            }
+            entries = table().cond.wait(entries).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn try_take(&self, entries: &mut HashMap<Key, Entry>, exclusive: bool) -> Option<State> {
+        let entry = entries.entry(self.key.clone()).or_default();
+        if entry.writer || (exclusive && entry.readers > 0) {
+            return None;
+        }
+        if exclusive {
+            entry.writer = true;
+        } else {
+            entry.readers += 1;
+        }
+        self.exclusive.set(exclusive);
+        let epoch = entry.epoch;
+        let state = if self.known_epoch.get() == Some(epoch) {
+            State::Clean
+        } else {
+            State::Dirty
+        };
+        self.known_epoch.set(Some(epoch));
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+    use super::super::{AtomBlob, Builder};
+    use super::super::clock::MockClock;
+    use super::super::vfs::MockFs;
+
+    // The table is process-wide, so each test has its own filesystem id
+    fn pair(fs: u64) -> (DirtyFlock, DirtyFlock) {
+        (DirtyFlock::new(fs, "blob.flock"), DirtyFlock::new(fs, "blob.flock"))
+    }
+
+    #[test]
+    fn dirty_after_other_writers() {
+        let (a, b) = pair(u64::MAX);
+        assert_eq!(a.lock_shared().unwrap(), State::Dirty);
+        a.unlock().unwrap();
+        assert_eq!(a.lock_shared().unwrap(), State::Clean);
+        a.unlock().unwrap();
+        // Our own writes leave us clean
+        assert_eq!(a.lock_exclusive().unwrap(), State::Clean);
+        a.unlock().unwrap();
+        assert_eq!(a.lock_shared().unwrap(), State::Clean);
+        a.unlock().unwrap();
+        assert_eq!(b.lock_exclusive().unwrap(), State::Dirty);
+        b.unlock().unwrap();
+        assert_eq!(a.lock_shared().unwrap(), State::Dirty);
+        assert_eq!(b.lock_shared().unwrap(), State::Clean);
+        a.unlock().unwrap();
+        b.unlock().unwrap();
+    }
+
+    #[test]
+    fn exclusive_excludes() {
+        let (a, b) = pair(u64::MAX - 1);
+        a.lock_shared().unwrap();
+        assert!(b.try_lock_shared().unwrap().is_some());
+        b.unlock().unwrap();
+        assert_eq!(b.try_lock_exclusive().unwrap(), None);
+        a.unlock().unwrap();
+        assert!(b.try_lock_exclusive().unwrap().is_some());
+        assert_eq!(a.try_lock_shared().unwrap(), None);
+        assert_eq!(a.try_lock_exclusive().unwrap(), None);
+        b.unlock().unwrap();
+        // Other paths and filesystems are separate
+        let c = DirtyFlock::new(u64::MAX - 1, "other.flock");
+        let d = DirtyFlock::new(u64::MAX - 2, "blob.flock");
+        a.lock_exclusive().unwrap();
+        assert!(c.try_lock_exclusive().unwrap().is_some());
+        assert!(d.try_lock_exclusive().unwrap().is_some());
+        a.unlock().unwrap();
+        c.unlock().unwrap();
+        d.unlock().unwrap();
+    }
+
+    #[test]
+    fn waits_for_the_writer() {
+        let (a, b) = pair(u64::MAX - 3);
+        a.lock_exclusive().unwrap();
+        let waiter = std::thread::spawn(move || {
+            let state = b.lock_shared().unwrap();
+            b.unlock().unwrap();
+            state
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        a.unlock().unwrap();
+        assert_eq!(waiter.join().unwrap(), State::Dirty);
+    }
+
+    #[test]
+    fn handles_reload_on_mock_fs() {
+        let fs = MockFs::new();
+        let clock = MockClock::new();
+        let mut a: AtomBlob<Vec<u32>> = Builder::new("blob.json").fs(fs.clone())
+            .clock(clock.clone()).idle_timeout(Duration::from_secs(60)).open().unwrap();
+        let mut b: AtomBlob<Vec<u32>> = Builder::new("blob.json").fs(fs.clone()).open().unwrap();
+        a.get_mut().unwrap().push(1);
+        assert_eq!(*b.get().unwrap(), vec![1]);
+        b.get_mut().unwrap().push(2);
+        assert_eq!(*a.get().unwrap(), vec![1, 2]);
+        // No lock file on the host
+        assert!(!std::path::Path::new("blob.flock").exists());
+        // Written behind the lock's back, seen only once idle
+        fs.write("blob.json", b"[9]".to_vec());
+        assert_eq!(*a.get().unwrap(), vec![1, 2]);
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(*a.get().unwrap(), vec![9]);
+        let mut c: AtomBlob<Vec<u32>> = Builder::new("blob.json").fs(MockFs::new()).open().unwrap();
+        assert!(c.get().unwrap().is_empty());
+    }
+}
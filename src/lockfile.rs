@@ -0,0 +1,409 @@
+//! Lock files that delete themselves, and know when they're dirty
+//!
+//! This is the protocol of the `fancy_flocks` crate, which the crate
+//! used before, so handles using either share lock files. Building
+//! with the `fancy_flocks` feature uses that crate again.
+//!
+//! A lock file starts with an epoch of 16 bytes: a random era, then a
+//! revision, both little-endian `u64`s, and `protocol` describes what
+//! follows. The first handle to find the
+//! file missing or empty creates it with a random epoch, under the
+//! exclusive lock. Each holder of the exclusive lock increments the
+//! revision as it unlocks, and a handle remembers the epoch it last
+//! saw, so a lock taken after another handle's exclusive lock is
+//! `Dirty`, and one taken after only shared locks is `Clean`. A
+//! recreated file has a new era, so everyone finds it dirty.
+//!
+//! A handle dropped while no other handle holds the lock takes it
+//! exclusively and deletes the file. Another may have opened the file
+//! before it was deleted and be waiting on it, so once it's locked a
+//! handle checks that the file is still the one at the path, and if
+//! not, starts over with the new one. Files are only deleted on Unix,
+//! where that check is by inode.
+
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::result::Result as StdResult;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum State {
+    Dirty,
+    Clean,
+}
+
+/// A lock file, deleted by the last handle dropped
+pub struct SdFlock {
+    path: PathBuf,
+    // While locked
+    file: RefCell<Option<File>>,
+}
+
+type Lock = fn(&File) -> Result<()>;
+
+impl SdFlock {
+    pub fn new<P>(p: P) -> SdFlock
+        where P: AsRef<Path>
+    {
+        SdFlock { path: p.as_ref().to_owned(), file: RefCell::new(None) }
+    }
+
+    pub fn lock_shared(&self) -> Result<()> {
+        self.take(File::lock_shared, false)
+    }
+
+    pub fn lock_exclusive(&self) -> Result<()> {
+        self.take(File::lock, true)
+    }
+
+    /// Like `lock_shared`, failing with `ErrorKind::WouldBlock` instead
+    /// of waiting
+    pub fn try_lock_shared(&self) -> Result<()> {
+        self.take(|f| f.try_lock_shared().map_err(contended), false)
+    }
+
+    /// Like `lock_exclusive`, failing with `ErrorKind::WouldBlock`
+    /// instead of waiting
+    pub fn try_lock_exclusive(&self) -> Result<()> {
+        self.take(|f| f.try_lock().map_err(contended), true)
+    }
+
+    pub fn unlock(&self) -> Result<()> {
+        match self.file.borrow_mut().take() {
+            Some(f) => f.unlock(),
+            None => Err(io::Error::other("unlocking unlocked lock file")),
+        }
+    }
+
+    /// The locked file
+    pub fn file(&mut self) -> &mut File {
+        self.file.get_mut().as_mut().expect("borrowing unlocked lock file")
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn take(&self, lock: Lock, write: bool) -> Result<()> {
+        if self.file.borrow().is_some() {
+            return Err(io::Error::other("locking locked lock file"));
+        }
+        let mut f = open(&self.path, write)?;
+        loop {
+            lock(&f)?;
+            // Deletes happen under the exclusive lock, so if the file
+            // at the path is the one locked, it stays there
+            match fs::metadata(&self.path) {
+                Ok(ref m) if same_file(&f, m)? => break,
+                Ok(_) => (),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+            debug!("lock file deleted while waiting for it");
+            // Closing the old file releases its lock
+            f = open(&self.path, write)?;
+        }
+        *self.file.borrow_mut() = Some(f);
+        Ok(())
+    }
+
+    // Reads or writes the locked file
+    fn with_file<R, E, F>(&self, f: F) -> StdResult<R, E>
+        where F: FnOnce(&File) -> StdResult<R, E>, E: From<io::Error>
+    {
+        match *self.file.borrow() {
+            Some(ref file) => f(file),
+            None => Err(io::Error::other("using unlocked lock file").into()),
+        }
+    }
+}
+
+impl Drop for SdFlock {
+    fn drop(&mut self) {
+        // Closing the file releases a lock still held
+        if self.file.get_mut().take().is_some() {
+            return;
+        }
+        // Otherwise another handle holds it, and will delete it
+        if !cfg!(unix) || self.try_lock_exclusive().is_err() {
+            return;
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!("unable to remove lock file during drop: {}", e);
+        }
+    }
+}
+
+/// A lock file telling whether the value it protects may have
+/// changed since this handle last locked it
+pub struct DirtyFlock {
+    flock: SdFlock,
+    // As last read
+    epoch: Cell<Epoch>,
+    exclusive: Cell<bool>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Epoch {
+    era: u64,
+    rev: u64,
+}
+
+impl DirtyFlock {
+    pub fn new<P>(p: P) -> DirtyFlock
+        where P: AsRef<Path>
+    {
+        DirtyFlock {
+            flock: SdFlock::new(p),
+            epoch: Cell::new(Epoch { era: 0, rev: 0 }),
+            exclusive: Cell::new(false),
+        }
+    }
+
+    pub fn lock_shared(&self) -> Result<State> {
+        self.take(SdFlock::lock_shared, false)
+    }
+
+    pub fn lock_exclusive(&self) -> Result<State> {
+        self.take(SdFlock::lock_exclusive, true)
+    }
+
+    pub fn try_lock_shared(&self) -> Result<State> {
+        self.take(SdFlock::try_lock_shared, false)
+    }
+
+    pub fn try_lock_exclusive(&self) -> Result<State> {
+        self.take(SdFlock::try_lock_exclusive, true)
+    }
+
+    pub fn unlock(&self) -> Result<()> {
+        let bumped = if self.exclusive.replace(false) {
+            let epoch = self.epoch.get();
+            let epoch = Epoch { era: epoch.era, rev: epoch.rev.wrapping_add(1) };
+            self.epoch.set(epoch);
+            self.write_epoch(epoch)
+        } else {
+            Ok(())
+        };
+        // Unlocked even if the epoch wasn't written, leaving the
+        // next handle to find the value clean; there's no telling it
+        // otherwise
+        let unlocked = self.flock.unlock();
+        bumped.and(unlocked)
+    }
+
+    pub fn path(&self) -> &Path {
+        self.flock.path()
+    }
+
+    /// Reads or writes the file while it's locked
+    pub fn with_file<R, E, F>(&self, f: F) -> StdResult<R, E>
+        where F: FnOnce(&File) -> StdResult<R, E>, E: From<io::Error>
+    {
+        self.flock.with_file(f)
+    }
+
+    fn take(&self, lock: fn(&SdFlock) -> Result<()>, exclusive: bool) -> Result<State> {
+        let known = self.epoch.get();
+        loop {
+            if len(fs::metadata(self.path()))? == 0 && self.flock.try_lock_exclusive().is_ok() {
+                // Unless another handle got there first
+                let created = match self.locked_len() {
+                    Ok(0) => self.write_epoch(Epoch { era: random(), rev: random() }),
+                    r => r.map(|_| ()),
+                };
+                self.flock.unlock()?;
+                created?;
+            }
+
+            lock(&self.flock)?;
+            let epoch = match self.locked_len() {
+                // Removed and recreated, not yet with an epoch
+                Ok(0) => {
+                    self.flock.unlock()?;
+                    continue;
+                }
+                Ok(_) => self.read_epoch(),
+                Err(e) => Err(e),
+            };
+            return match epoch {
+                Ok(epoch) => {
+                    self.epoch.set(epoch);
+                    self.exclusive.set(exclusive);
+                    Ok(if epoch == known { State::Clean } else { State::Dirty })
+                }
+                Err(e) => {
+                    let _ = self.flock.unlock();
+                    Err(e)
+                }
+            };
+        }
+    }
+
+    fn locked_len(&self) -> Result<u64> {
+        self.flock.with_file(|f| Ok(f.metadata()?.len()))
+    }
+
+    fn read_epoch(&self) -> Result<Epoch> {
+        let mut buf = [0; 16];
+        self.flock.with_file(|mut f| {
+            f.seek(SeekFrom::Start(0))?;
+            f.read_exact(&mut buf)
+        })?;
+        let (era, rev) = buf.split_at(8);
+        Ok(Epoch { era: u64_le(era), rev: u64_le(rev) })
+    }
+
+    fn write_epoch(&self, epoch: Epoch) -> Result<()> {
+        let mut buf = [0; 16];
+        buf[..8].copy_from_slice(&epoch.era.to_le_bytes());
+        buf[8..].copy_from_slice(&epoch.rev.to_le_bytes());
+        self.flock.with_file(|mut f| {
+            f.seek(SeekFrom::Start(0))?;
+            f.write_all(&buf)
+        })
+    }
+}
+
+fn open(p: &Path, write: bool) -> Result<File> {
+    // Creating needs write or append access
+    OpenOptions::new().read(true).write(write).append(!write).create(true).open(p)
+}
+
+fn contended(e: TryLockError) -> io::Error {
+    match e {
+        TryLockError::WouldBlock => ErrorKind::WouldBlock.into(),
+        TryLockError::Error(e) => e,
+    }
+}
+
+#[cfg(unix)]
+fn same_file(f: &File, m: &fs::Metadata) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let locked = f.metadata()?;
+    Ok(locked.dev() == m.dev() && locked.ino() == m.ino())
+}
+
+// Lock files aren't deleted here, so the file at the path is always
+// the one opened
+#[cfg(not(unix))]
+fn same_file(_f: &File, _m: &fs::Metadata) -> Result<bool> {
+    Ok(true)
+}
+
+// The length of the file, or 0 if there isn't one
+fn len(m: Result<fs::Metadata>) -> Result<u64> {
+    match m {
+        Ok(m) => Ok(m.len()),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+fn u64_le(b: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(b);
+    u64::from_le_bytes(buf)
+}
+
+// The standard library seeds each `RandomState` from the OS
+fn random() -> u64 {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let mut h = RandomState::new().build_hasher();
+    h.write_u64(COUNT.fetch_add(1, Ordering::Relaxed));
+    h.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flocks are per open file, so two handles in one process exclude
+    // each other as they would across processes
+    fn pair(name: &str) -> (PathBuf, DirtyFlock, DirtyFlock) {
+        let p = std::env::temp_dir()
+            .join(format!("blobject-lockfile-{}-{}.flock", name, std::process::id()));
+        let _ = fs::remove_file(&p);
+        (p.clone(), DirtyFlock::new(&p), DirtyFlock::new(&p))
+    }
+
+    #[test]
+    fn dirty_after_other_writers() {
+        let (p, a, b) = pair("dirty");
+        assert_eq!(a.lock_shared().unwrap(), State::Dirty);
+        a.unlock().unwrap();
+        assert_eq!(fs::metadata(&p).unwrap().len(), 16);
+        assert_eq!(a.lock_shared().unwrap(), State::Clean);
+        a.unlock().unwrap();
+        // Our own writes leave us clean
+        assert_eq!(a.lock_exclusive().unwrap(), State::Clean);
+        a.unlock().unwrap();
+        assert_eq!(a.lock_shared().unwrap(), State::Clean);
+        a.unlock().unwrap();
+        assert_eq!(b.lock_exclusive().unwrap(), State::Dirty);
+        b.unlock().unwrap();
+        assert_eq!(a.lock_shared().unwrap(), State::Dirty);
+        assert_eq!(b.lock_shared().unwrap(), State::Clean);
+        a.unlock().unwrap();
+        b.unlock().unwrap();
+    }
+
+    #[test]
+    fn exclusive_excludes() {
+        let (_p, a, b) = pair("excludes");
+        a.lock_shared().unwrap();
+        b.try_lock_shared().unwrap();
+        b.unlock().unwrap();
+        let e = b.try_lock_exclusive().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+        a.unlock().unwrap();
+        b.try_lock_exclusive().unwrap();
+        assert_eq!(a.try_lock_shared().unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(a.try_lock_exclusive().unwrap_err().kind(), ErrorKind::WouldBlock);
+        b.unlock().unwrap();
+        assert!(a.unlock().is_err());
+    }
+
+    #[test]
+    fn revisions_wrap() {
+        let (p, a, b) = pair("wrap");
+        a.lock_exclusive().unwrap();
+        a.epoch.set(Epoch { era: 1, rev: u64::MAX });
+        a.unlock().unwrap();
+        assert_eq!(fs::read(&p).unwrap(), [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(b.lock_shared().unwrap(), State::Dirty);
+        b.unlock().unwrap();
+    }
+
+    #[test]
+    fn recreated_files_are_dirty() {
+        let (p, a, b) = pair("recreated");
+        assert_eq!(a.lock_shared().unwrap(), State::Dirty);
+        a.unlock().unwrap();
+        fs::remove_file(&p).unwrap();
+        assert_eq!(b.lock_shared().unwrap(), State::Dirty);
+        b.unlock().unwrap();
+        assert_eq!(a.lock_shared().unwrap(), State::Dirty);
+        a.unlock().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn last_drop_deletes() {
+        let (p, a, b) = pair("drop");
+        a.lock_shared().unwrap();
+        b.lock_shared().unwrap();
+        b.unlock().unwrap();
+        // Dropped while another holds it
+        drop(b);
+        assert!(p.exists());
+        a.unlock().unwrap();
+        drop(a);
+        assert!(!p.exists());
+    }
+
+}
@@ -0,0 +1,150 @@
+//! Estimating how much memory an in-memory value takes
+//!
+//! Values report the heap memory they own through `HeapSize`, which
+//! is implemented for the standard library types blobs are usually
+//! built from, and `serde_json::Value`. Estimates count allocated
+//! capacity, not allocator overhead, so they're a lower bound.
+
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::mem;
+use std::sync::PoisonError;
+use std::sync::atomic::Ordering;
+use super::AtomBlob;
+
+/// A value that knows how much heap memory it owns
+pub trait HeapSize {
+    /// Bytes allocated by the value, not counting `size_of::<Self>()`
+    fn heap_size(&self) -> usize;
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + HeapSize,
+{
+    /// Roughly how many bytes the in-memory value takes, or 0 if it
+    /// isn't loaded, or is out with an `OwnedBlobMutRef`
+    ///
+    /// The flock isn't taken, so this is the value as last loaded or
+    /// committed by this process.
+    pub fn approx_memory(&self) -> usize {
+        let shared = &*self.shared;
+        let v = shared.v.read().unwrap_or_else(PoisonError::into_inner);
+        if shared.unloaded.load(Ordering::SeqCst) || shared.taken.load(Ordering::SeqCst) {
+            return 0;
+        }
+        mem::size_of::<T>() + v.heap_size()
+    }
+}
+
+macro_rules! no_heap {
+    ($($t:ty),*) => {
+        $(impl HeapSize for $t {
+            fn heap_size(&self) -> usize {
+                0
+            }
+        })*
+    }
+}
+
+no_heap!(bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize,
+         f32, f64, ());
+
+fn items<'a, T, I>(items: I) -> usize
+    where T: HeapSize + 'a, I: IntoIterator<Item = &'a T>,
+{
+    items.into_iter().map(HeapSize::heap_size).sum()
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>() + items(self)
+    }
+}
+
+impl<T: HeapSize> HeapSize for VecDeque<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>() + items(self)
+    }
+}
+
+impl<T: HeapSize, const N: usize> HeapSize for [T; N] {
+    fn heap_size(&self) -> usize {
+        items(self)
+    }
+}
+
+// B-tree nodes hold up to 11 entries, and are usually two thirds full
+const BTREE_FILL: usize = 2;
+const BTREE_SLACK: usize = 3;
+
+impl<K: HeapSize, V: HeapSize> HeapSize for BTreeMap<K, V> {
+    fn heap_size(&self) -> usize {
+        self.len() * mem::size_of::<(K, V)>() * BTREE_SLACK / BTREE_FILL
+            + self.iter().map(|(k, v)| k.heap_size() + v.heap_size()).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for BTreeSet<T> {
+    fn heap_size(&self) -> usize {
+        self.len() * mem::size_of::<T>() * BTREE_SLACK / BTREE_FILL + items(self)
+    }
+}
+
+// Hash tables have a control byte per bucket
+impl<K: HeapSize, V: HeapSize, S> HeapSize for HashMap<K, V, S> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * (mem::size_of::<(K, V)>() + 1)
+            + self.iter().map(|(k, v)| k.heap_size() + v.heap_size()).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize, S> HeapSize for HashSet<T, S> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * (mem::size_of::<T>() + 1) + items(self)
+    }
+}
+
+impl<A: HeapSize, B: HeapSize> HeapSize for (A, B) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size()
+    }
+}
+
+impl<A: HeapSize, B: HeapSize, C: HeapSize> HeapSize for (A, B, C) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size() + self.2.heap_size()
+    }
+}
+
+impl HeapSize for serde_json::Value {
+    fn heap_size(&self) -> usize {
+        use serde_json::Value;
+        match *self {
+            Value::Null | Value::Bool(_) | Value::Number(_) => 0,
+            Value::String(ref s) => s.heap_size(),
+            Value::Array(ref a) => a.heap_size(),
+            Value::Object(ref o) => {
+                o.len() * mem::size_of::<(String, Value)>() * BTREE_SLACK / BTREE_FILL
+                    + o.iter().map(|(k, v)| k.heap_size() + v.heap_size()).sum::<usize>()
+            }
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Three-way merging of untyped JSON
+//!
+//! Objects merge key by key. Any other value, including an array,
+//! changed differently on both sides is a conflict, settled in favor
+//! of the local side and reported.
+
+use serde_json::{Map, Value};
+use super::Result;
+use super::draft::Draft;
+
+/// A value changed differently on both sides of a merge
+///
+/// `None` means the side deleted the value, or for `base`, that both
+/// sides added it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    /// JSON pointer to the value
+    pub path: String,
+    pub base: Option<Value>,
+    pub mine: Option<Value>,
+    pub theirs: Option<Value>,
+}
+
+/// Merges the changes from `base` to `theirs` into `mine`
+pub fn merge3(base: &Value, mine: &Value, theirs: &Value) -> (Value, Vec<Conflict>) {
+    let mut conflicts = Vec::new();
+    let v = merge(&mut String::new(), Some(base), Some(mine), Some(theirs), &mut conflicts);
+    (v.unwrap_or(Value::Null), conflicts)
+}
+
+/// Like `merge3`, settling conflicts in favor of `mine` without
+/// reporting them, for `Policy::Merge`
+pub fn resolve(base: Value, theirs: Value, mine: Value) -> Value {
+    merge3(&base, &mine, &theirs).0
+}
+
+fn merge(path: &mut String, base: Option<&Value>, mine: Option<&Value>,
+         theirs: Option<&Value>, conflicts: &mut Vec<Conflict>) -> Option<Value> {
+    if mine == theirs || theirs == base {
+        return mine.cloned();
+    }
+    if mine == base {
+        return theirs.cloned();
+    }
+
+    let empty = Map::new();
+    match (base, mine, theirs) {
+        (None, Some(Value::Object(m)), Some(Value::Object(t)))
+            => Some(Value::Object(merge_objects(path, &empty, m, t, conflicts))),
+        (Some(Value::Object(b)), Some(Value::Object(m)), Some(Value::Object(t)))
+            => Some(Value::Object(merge_objects(path, b, m, t, conflicts))),
+        _ => {
+            conflicts.push(Conflict {
+                path: path.clone(),
+                base: base.cloned(),
+                mine: mine.cloned(),
+                theirs: theirs.cloned(),
+            });
+            mine.cloned()
+        }
+    }
+}
+
+fn merge_objects(path: &mut String, base: &Map<String, Value>, mine: &Map<String, Value>,
+                 theirs: &Map<String, Value>, conflicts: &mut Vec<Conflict>)
+                 -> Map<String, Value> {
+    let mut merged = Map::new();
+    let keys = mine.keys().chain(theirs.keys().filter(|k| !mine.contains_key(*k)))
+        .chain(base.keys().filter(|k| !mine.contains_key(*k) && !theirs.contains_key(*k)));
+    for key in keys {
+        let len = path.len();
+        path.push('/');
+        path.push_str(&key.replace('~', "~0").replace('/', "~1"));
+        let v = merge(path, base.get(key), mine.get(key), theirs.get(key), conflicts);
+        path.truncate(len);
+        if let Some(v) = v {
+            merged.insert(key.clone(), v);
+        }
+    }
+    merged
+}
+
+impl Draft<Value> {
+    /// Commits the draft, three-way merging it with anything
+    /// committed since it was taken
+    ///
+    /// Conflicts are settled in favor of the draft and returned.
+    pub fn commit_merged(self) -> Result<Vec<Conflict>> {
+        let mut conflicts = Vec::new();
+        self.commit3(|base, theirs, mine| {
+            let (v, c) = merge3(&base, &mine, &theirs);
+            conflicts = c;
+            v
+        })?;
+        if !conflicts.is_empty() {
+            warn!("merged blobject draft with {} conflicts", conflicts.len());
+        }
+        Ok(conflicts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+    use super::{Conflict, merge3};
+    use super::super::Builder;
+    use super::super::vfs::MockFs;
+
+    fn conflict(path: &str, base: Option<Value>, mine: Option<Value>,
+                theirs: Option<Value>) -> Conflict {
+        Conflict { path: path.to_string(), base, mine, theirs }
+    }
+
+    #[test]
+    fn merges_added_and_deleted_keys() {
+        let base = json!({"a": 1, "b": 2, "c": 3});
+        let mine = json!({"a": 1, "c": 3, "d": 4});
+        let theirs = json!({"a": 1, "b": 2, "e": 5});
+        let (v, conflicts) = merge3(&base, &mine, &theirs);
+        assert_eq!(v, json!({"a": 1, "d": 4, "e": 5}));
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn conflicts_on_arrays() {
+        let base = json!({"xs": [1, 2]});
+        let mine = json!({"xs": [1, 2, 3]});
+        let theirs = json!({"xs": [0, 1, 2]});
+        let (v, conflicts) = merge3(&base, &mine, &theirs);
+        assert_eq!(v, mine);
+        assert_eq!(conflicts, vec![
+            conflict("/xs", Some(json!([1, 2])), Some(json!([1, 2, 3])), Some(json!([0, 1, 2]))),
+        ]);
+    }
+
+    #[test]
+    fn conflicts_on_edits_beside_deletions() {
+        let base = json!({"a": 1, "b/c": 2});
+        let mine = json!({"b/c": 3});
+        let theirs = json!({"a": 4});
+        let (v, conflicts) = merge3(&base, &mine, &theirs);
+        assert_eq!(v, json!({"b/c": 3}));
+        assert_eq!(conflicts, vec![
+            conflict("/b~1c", Some(json!(2)), Some(json!(3)), None),
+            conflict("/a", Some(json!(1)), None, Some(json!(4))),
+        ]);
+    }
+
+    #[test]
+    fn merges_objects_both_sides_added() {
+        let base = json!({});
+        let mine = json!({"o": {"a": 1, "x": true}});
+        let theirs = json!({"o": {"b": 2, "x": false}});
+        let (v, conflicts) = merge3(&base, &mine, &theirs);
+        assert_eq!(v, json!({"o": {"a": 1, "b": 2, "x": true}}));
+        assert_eq!(conflicts, vec![
+            conflict("/o/x", None, Some(json!(true)), Some(json!(false))),
+        ]);
+    }
+
+    #[test]
+    fn merges_drafts_past_other_commits() {
+        let mut blob = Builder::new("merge.json").fs(MockFs::new())
+            .open::<Value>().unwrap();
+        *blob.get_mut().unwrap() = json!({"a": 1, "b": 1});
+
+        let mut draft = blob.draft().unwrap();
+        draft["a"] = json!(2);
+
+        blob.clone().get_mut().unwrap()["b"] = json!(3);
+
+        assert!(draft.commit_merged().unwrap().is_empty());
+        assert_eq!(*blob.get().unwrap(), json!({"a": 2, "b": 3}));
+    }
+}
@@ -0,0 +1,91 @@
+//! Reading blobs written with older versions of the value type
+//!
+//! Each version of a value type names the version it replaced with
+//! `Migratable::Previous`, back to the first, whose `Previous` is
+//! itself, and converts from it with `From`. An
+//! `AtomBlob<Versioned<T>>` parses the file as `T`, and if that
+//! fails, as each older version in turn, converting whichever parses
+//! up to `T`. Commits always write `T`.
+//!
+//! ```ignore
+//! impl Migratable for ConfigV1 { type Previous = ConfigV1; }
+//! impl Migratable for ConfigV2 { type Previous = ConfigV1; }
+//! impl From<ConfigV1> for ConfigV2 { ... }
+//!
+//! let blob: AtomBlob<Versioned<ConfigV2>> = AtomBlob::new(p)?;
+//! ```
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error as DeError;
+use serde_json::Value;
+use std::any::{self, TypeId};
+use std::ops::{Deref, DerefMut};
+use super::repair;
+
+/// One version of a value type, that knows the version it replaced
+pub trait Migratable: for <'de> Deserialize<'de> + 'static {
+    /// The version this one replaced, or `Self` for the first version
+    type Previous: Migratable + Into<Self>;
+}
+
+/// A value read as the newest of its versions that parses
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Versioned<T>(pub T);
+
+impl<T> Versioned<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Versioned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Versioned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Serialize> Serialize for Versioned<T> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(s)
+    }
+}
+
+impl<'de, T: Migratable> Deserialize<'de> for Versioned<T> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Versioned<T>, D::Error> {
+        // Binary formats, like the sidecar, are only ever written by
+        // the current version
+        if !d.is_human_readable() {
+            return T::deserialize(d).map(Versioned);
+        }
+        // Kept so each version can try it
+        let v = Value::deserialize(d)?;
+        migrate(&v).map(Versioned).map_err(D::Error::custom)
+    }
+}
+
+fn migrate<T: Migratable>(v: &Value) -> Result<T, serde_json::Error> {
+    let e = match T::deserialize(v) {
+        Ok(t) => return Ok(t),
+        Err(e) => e,
+    };
+    if TypeId::of::<T::Previous>() == TypeId::of::<T>() {
+        return Err(e);
+    }
+    match migrate::<T::Previous>(v) {
+        Ok(prev) => {
+            debug!("migrating blobject to {}", any::type_name::<T>());
+            repair::mark();
+            Ok(prev.into())
+        }
+        // The newest version's error says most about what's wrong
+        Err(_) => Err(e),
+    }
+}
@@ -0,0 +1,134 @@
+//! Copies of a blob kept on a second path
+//!
+//! With `Builder::mirror`, each commit wakes a thread that copies the
+//! blob file to the mirror path, holding a shared flock of its own
+//! while it reads so it always copies a whole commit. Commits that
+//! queue up while it copies share the next copy, since only the
+//! newest matters. A load that fails on the blob file parses the
+//! mirror instead. `Mirror::flush` waits for a copy, for shutdown.
+
+use serde::{Serialize, Deserialize};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use super::{Error, FileId, Parse, Result, ResultExt, Strategy, repair, ser_reload_file, tmp_file};
+use super::flock::{DirtyFlock, FlockGuard};
+use super::vfs::Fs;
+
+// Each job may wait for its copy, and what became of it
+type Job = Option<Sender<StdResult<(), String>>>;
+
+pub struct Mirror {
+    jobs: Sender<Job>,
+}
+
+impl Mirror {
+    /// Starts the copying thread, which exits once this is dropped
+    pub fn start(fs: Arc<dyn Fs>, strategy: Strategy, path: PathBuf, flock: PathBuf,
+                 mirror: PathBuf) -> Result<Mirror> {
+        let (jobs, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("blobject-mirror".to_string())
+            .spawn(move || run(&*fs, strategy, &path, &flock, &mirror, rx))
+            .chain_err(|| "starting blobject mirror thread")?;
+        Ok(Mirror { jobs })
+    }
+
+    /// Queues a copy of the newest commit
+    pub fn enqueue(&self) {
+        if self.jobs.send(None).is_err() {
+            error!("blobject mirror thread is gone");
+        }
+    }
+
+    /// Copies the newest commit, and waits for the copy
+    ///
+    /// The copy takes a shared flock, so the caller mustn't hold an
+    /// exclusive one.
+    pub fn flush(&self) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        if self.jobs.send(Some(tx)).is_err() {
+            return Err("blobject mirror thread is gone".into());
+        }
+        match rx.recv() {
+            Ok(r) => r.map_err(Error::from),
+            Err(_) => Err("blobject mirror thread exited".into()),
+        }
+    }
+}
+
+fn run(fs: &dyn Fs, strategy: Strategy, path: &Path, flock: &Path, mirror: &Path,
+       rx: Receiver<Job>) {
+    let flock = DirtyFlock::new(flock, fs.in_process());
+    while let Ok(job) = rx.recv() {
+        let waiting: Vec<_> = job.into_iter().chain(rx.try_iter().flatten()).collect();
+        let r = copy(fs, strategy, path, &flock, mirror);
+        if let Err(ref e) = r {
+            warn!("mirroring blobject to {}: {}", mirror.display(), e);
+        }
+        for tx in waiting {
+            // The flusher may have given up
+            let _ = tx.send(r.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+        }
+    }
+}
+
+fn copy(fs: &dyn Fs, strategy: Strategy, path: &Path, flock: &DirtyFlock,
+        mirror: &Path) -> Result<()> {
+    let mut buf = Vec::new();
+    {
+        let _flock = FlockGuard::shared(flock)?;
+        match strategy.open(fs, path)? {
+            Some((mut r, _)) => r.read_to_end(&mut buf)?,
+            // Deleted, so the mirror goes too
+            None => {
+                return match fs.remove(mirror) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    r => Ok(r?),
+                };
+            }
+        };
+    }
+
+    let (tmp, mut w) = tmp_file(fs, mirror)?;
+    let r = w.write_all(&buf).and_then(|()| w.flush());
+    drop(w);
+    let r = r.and_then(|()| fs.sync(&tmp))
+        .and_then(|()| fs.rename(&tmp, mirror));
+    if r.is_err() {
+        let _ = fs.remove(&tmp);
+    }
+    r?;
+    fs.sync(mirror)?;
+    debug!("mirrored blobject to {}", mirror.display());
+    Ok(())
+}
+
+/// Loads the mirror after the blob file at `p` failed to load with
+/// `e`, keeping the blob file's id so it isn't parsed again until
+/// replaced
+pub fn fall_back<T>(fs: &dyn Fs, strategy: Strategy, p: &Path, mirror: &Path,
+                    parse: Parse<'_>, e: Error) -> Result<Option<(T, FileId)>>
+    where for <'de> T: Serialize + Deserialize<'de> + Default
+{
+    let id = match strategy.id(fs, p) {
+        Ok(Some(id)) => id,
+        _ => return Err(e),
+    };
+    let parse = Parse { mirror: None, ..parse };
+    match ser_reload_file::<T>(fs, Strategy::Rename, mirror, None, parse) {
+        Some(Ok(Some((v, _)))) => {
+            warn!("loading blobject mirror {} after: {}", mirror.display(), e);
+            repair::mark();
+            Ok(Some((v, id)))
+        }
+        Some(Err(mirror_e)) => {
+            warn!("loading blobject mirror {}: {}", mirror.display(), mirror_e);
+            Err(e)
+        }
+        _ => Err(e),
+    }
+}
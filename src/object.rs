@@ -0,0 +1,219 @@
+//! Typed blobs kept in object storage
+//!
+//! Object stores like S3 and GCS have no locks and no renames, but
+//! they do have conditional writes: a PUT that only succeeds if the
+//! object is still at a version the writer read, by ETag or
+//! generation. An `ObjectBlob` commits that way, so instead of
+//! excluding other writers it finds out it lost a race, reloads, and
+//! tries again. Every commit is whole, and none is lost.
+//!
+//! Stores plug in through `ObjectStore`, a few blocking calls that map
+//! onto any client: with the `object_store` crate, `get_opts` with
+//! `if_none_match` and `put_opts` with `PutMode::Create` or
+//! `PutMode::Update`. `MemoryObjects` is an in-memory store for
+//! tests.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use super::{Policy, Result, ResultExt, json_to_vec};
+
+/// One version of an object, like an ETag or a GCS generation
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ObjectVersion(pub String);
+
+/// What a conditional read found
+pub enum Fetched {
+    /// There's no object
+    Missing,
+    /// The object is still at the version given
+    Unchanged,
+    Object(Vec<u8>, ObjectVersion),
+}
+
+/// Blocking access to an object store
+pub trait ObjectStore: Send + Sync {
+    /// Reads the object at `key`, unless it's still at `known`
+    fn get(&self, key: &str, known: Option<&ObjectVersion>) -> io::Result<Fetched>;
+
+    /// Writes the object at `key`, only if it's at `expected`, or
+    /// with `expected` of `None` only if there's no object
+    ///
+    /// Returns the new version, or `None` if the object wasn't as
+    /// expected and nothing was written.
+    fn put_if(&self, key: &str, data: &[u8], expected: Option<&ObjectVersion>)
+              -> io::Result<Option<ObjectVersion>>;
+}
+
+/// A typed value stored in one object
+pub struct ObjectBlob<T> {
+    store: Arc<dyn ObjectStore>,
+    key: String,
+    value: T,
+    // The version `value` was read from or written as
+    version: Option<ObjectVersion>,
+    policy: Option<Policy>,
+}
+
+impl<T> ObjectBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Clone,
+{
+    /// Opens the blob at `key`, reading it, or `T::default()` if
+    /// there's no object yet
+    pub fn open<S>(store: S, key: &str) -> Result<ObjectBlob<T>>
+        where S: ObjectStore + 'static,
+    {
+        ObjectBlob::open_in(Arc::new(store), key)
+    }
+
+    /// Like `open`, for a store shared with other blobs
+    pub fn open_in(store: Arc<dyn ObjectStore>, key: &str) -> Result<ObjectBlob<T>> {
+        let mut blob = ObjectBlob {
+            store,
+            key: key.to_owned(),
+            value: T::default(),
+            version: None,
+            policy: None,
+        };
+        blob.reload()?;
+        Ok(blob)
+    }
+
+    /// Settles commits that lose a race to another writer by
+    /// `policy`, instead of running the update again
+    pub fn policy(mut self, policy: Policy) -> ObjectBlob<T> {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// The latest value, read again if the object has changed
+    pub fn get(&mut self) -> Result<&T> {
+        self.reload()?;
+        Ok(&self.value)
+    }
+
+    /// The value as last read or written, without asking the store
+    pub fn cached(&self) -> &T {
+        &self.value
+    }
+
+    /// The version of the object the value is, or `None` if there
+    /// was no object
+    pub fn version(&self) -> Option<&ObjectVersion> {
+        self.version.as_ref()
+    }
+
+    /// Applies `f` to the latest value and commits the result,
+    /// returning what `f` returned
+    ///
+    /// If another writer commits first, the value is read again and
+    /// `f` runs again on that, so it may run more than once, and
+    /// should only change the value. With `ObjectBlob::policy` the
+    /// policy settles it instead.
+    pub fn update<F, R>(&mut self, f: F) -> Result<R>
+        where F: FnMut(&mut T) -> R,
+    {
+        self.update_by(self.policy, f)
+    }
+
+    /// Commits `v`, whatever the latest value is
+    pub fn replace(&mut self, v: T) -> Result<()> {
+        self.update_by(None, |cur| *cur = v.clone())
+    }
+
+    fn update_by<F, R>(&mut self, policy: Option<Policy>, mut f: F) -> Result<R>
+        where F: FnMut(&mut T) -> R,
+    {
+        let mut v = self.value.clone();
+        let mut r = f(&mut v);
+        loop {
+            let buf = json_to_vec(&v).chain_err(|| "serializing blobject")?;
+            let written = self.store.put_if(&self.key, &buf, self.version.as_ref())
+                .chain_err(|| "writing blobject object")?;
+            match written {
+                Some(version) => {
+                    self.value = v;
+                    self.version = Some(version);
+                    debug!("blobject object committed");
+                    return Ok(r);
+                }
+                None => {
+                    debug!("blobject object changed under commit, retrying");
+                    let base = self.value.clone();
+                    self.reload()?;
+                    v = match policy {
+                        Some(policy) => policy.resolve(&base, &self.value, v)?,
+                        None => {
+                            let mut v = self.value.clone();
+                            r = f(&mut v);
+                            v
+                        }
+                    };
+                }
+            }
+        }
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        let fetched = self.store.get(&self.key, self.version.as_ref())
+            .chain_err(|| "reading blobject object")?;
+        match fetched {
+            Fetched::Missing => {
+                self.value = T::default();
+                self.version = None;
+            }
+            Fetched::Unchanged => (),
+            Fetched::Object(buf, version) => {
+                self.value = serde_json::from_slice(&buf)
+                    .chain_err(|| "loading blobject object")?;
+                self.version = Some(version);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An in-memory object store
+///
+/// Clones share the same objects. Versions count up from 1 across
+/// the whole store.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryObjects {
+    inner: Arc<Mutex<MemoryInner>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryInner {
+    objects: HashMap<String, (Vec<u8>, ObjectVersion)>,
+    next: u64,
+}
+
+impl MemoryObjects {
+    pub fn new() -> MemoryObjects {
+        MemoryObjects::default()
+    }
+}
+
+impl ObjectStore for MemoryObjects {
+    fn get(&self, key: &str, known: Option<&ObjectVersion>) -> io::Result<Fetched> {
+        let inner = self.inner.lock().expect("poisoned memory objects");
+        Ok(match inner.objects.get(key) {
+            None => Fetched::Missing,
+            Some((_, version)) if Some(version) == known => Fetched::Unchanged,
+            Some((data, version)) => Fetched::Object(data.clone(), version.clone()),
+        })
+    }
+
+    fn put_if(&self, key: &str, data: &[u8], expected: Option<&ObjectVersion>)
+              -> io::Result<Option<ObjectVersion>> {
+        let mut inner = self.inner.lock().expect("poisoned memory objects");
+        if inner.objects.get(key).map(|(_, v)| v) != expected {
+            return Ok(None);
+        }
+        inner.next += 1;
+        let version = ObjectVersion(inner.next.to_string());
+        inner.objects.insert(key.to_owned(), (data.to_vec(), version.clone()));
+        Ok(Some(version))
+    }
+}
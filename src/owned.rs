@@ -0,0 +1,501 @@
+//! Guards that own their handle
+//!
+//! `AtomBlob::get` and `get_mut` borrow the handle mutably for as long
+//! as the guard lives, so a guard can't be kept in a struct beside
+//! other state, or held while the handle is used for anything else.
+//! `get_owned` and `get_mut_owned` take `&self`, and return guards
+//! that own a clone of the handle instead, living as long as they're
+//! kept. Clones share the loaded value, so this costs no reload.
+//!
+//! Owned guards lock like any other, but while an `OwnedBlobRef` is
+//! held its thread's shared guards on the blob, through any handle,
+//! read through it rather than taking locks of their own, so a read
+//! can be kept while clones are read in the same scope. They keep its
+//! locks until they're dropped too. Exclusive guards on the blob from
+//! the thread still fail with `ErrorKind::WouldDeadlock` meanwhile.
+//!
+//! An `OwnedBlobMutRef` is also `Send`, so a change can be handed to
+//! a worker thread, or held across `.await`s in a task. It moves the
+//! value out of the handles' shared one, keeping only the flock, and
+//! puts it back as it drops; meanwhile the process's other guards on
+//! the blob wait for the flock as they would for another process's.
+//!
+//! Which thread holds it can't be told once it may move, so it isn't
+//! counted as any thread's, and isn't refused with `WouldDeadlock`:
+//! a task may be resumed on a thread that another task then locks the
+//! blob from, which must wait rather than fail. Another guard on the
+//! blob, through any clone of the handle, taken by the thread or task
+//! holding an `OwnedBlobMutRef` would wait forever for it to drop. So
+//! `get_mut_owned` and `OwnedBlobRef::upgrade` fail unless
+//! `Builder::acquire` is set to fail fast or time out, and such a
+//! guard fails with `ErrorKind::Locked` instead.
+
+use serde::{Serialize, Deserialize};
+use std::any::Any;
+use std::mem::{self, ManuallyDrop};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use super::{AtomBlob, BlobMutRef, BlobRef, CommitReceipt, Context, ErrorKind, Held, Reading,
+            Result, Taken, Value};
+use super::pipeline::CommitTicket;
+use super::reentry::{self, Share};
+use super::wait::Acquire;
+
+/// A `BlobRef` owning its handle
+pub struct OwnedBlobRef<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    // Shared with the guards reading through it
+    inner: Rc<Inner<T>>,
+}
+
+struct Inner<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    // Borrows from `blob`, so dropped first
+    guard: ManuallyDrop<BlobRef<'static, T>>,
+    blob: NonNull<AtomBlob<T>>,
+}
+
+/// A `BlobMutRef` owning its handle, committing as it drops like any,
+/// that can move between threads
+pub struct OwnedBlobMutRef<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    // Borrows from `blob`, so dropped first
+    guard: ManuallyDrop<BlobMutRef<'static, T>>,
+    blob: NonNull<AtomBlob<T>>,
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    /// Like `get`, but the guard owns a clone of the handle rather
+    /// than borrowing this one
+    pub fn get_owned(&self) -> Result<OwnedBlobRef<T>> {
+        let blob = leak(self.clone());
+        // Safety: the guard is dropped before the handle it borrows,
+        // which doesn't move until then
+        match unsafe { (*blob.as_ptr()).get() } {
+            Ok(guard) => Ok(OwnedBlobRef::new(guard, blob)),
+            Err(e) => {
+                free(blob);
+                Err(e)
+            }
+        }
+    }
+
+    /// Like `get_mut`, but the guard owns a clone of the handle rather
+    /// than borrowing this one
+    ///
+    /// Fails unless `Builder::acquire` fails fast or times out; see
+    /// the module docs.
+    pub fn get_mut_owned(&self) -> Result<OwnedBlobMutRef<T>> {
+        bounded(self)?;
+        let blob = leak(self.clone());
+        // Safety: as in `get_owned`
+        match unsafe { (*blob.as_ptr()).get_mut() } {
+            Ok(mut guard) => {
+                guard.take();
+                Ok(OwnedBlobMutRef { guard: ManuallyDrop::new(guard), blob })
+            }
+            Err(e) => {
+                free(blob);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T> OwnedBlobRef<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    fn new(guard: BlobRef<'static, T>, blob: NonNull<AtomBlob<T>>) -> OwnedBlobRef<T> {
+        let locked = matches!(guard.v, Reading::Locked(_));
+        let inner = Rc::new(Inner { guard: ManuallyDrop::new(guard), blob });
+        // One reading through another's read needn't share it again
+        if locked {
+            // Safety: the handle outlives the guard
+            let key = unsafe { blob.as_ref() }.key();
+            let share: Rc<dyn Share> = inner.clone();
+            reentry::share(key, &share);
+        }
+        OwnedBlobRef { inner }
+    }
+
+    /// The guard, for its other methods
+    pub fn guard(&self) -> &BlobRef<'_, T> {
+        let guard: *const BlobRef<'static, T> = &*self.inner.guard;
+        // Safety: only shortens the lifetime, which the guard can't
+        // be moved out under
+        unsafe { &*guard.cast::<BlobRef<'_, T>>() }
+    }
+
+    /// Trades shared access for exclusive access, as
+    /// `BlobRef::upgrade`
+    ///
+    /// Fails with `ErrorKind::WouldDeadlock` while other guards read
+    /// through this one, and as `AtomBlob::get_mut_owned` does.
+    pub fn upgrade(self) -> Result<OwnedBlobMutRef<T>> {
+        // Safety: the handle outlives `self`
+        bounded(unsafe { self.inner.blob.as_ref() })?;
+        let inner = match Rc::try_unwrap(self.inner) {
+            Ok(inner) => inner,
+            Err(_) => return Err(ErrorKind::WouldDeadlock.into()),
+        };
+        let mut inner = ManuallyDrop::new(inner);
+        let blob = inner.blob;
+        // Safety: `inner` is never dropped, so the guard is taken once
+        let guard = unsafe { ManuallyDrop::take(&mut inner.guard) };
+        match guard.upgrade() {
+            Ok(mut guard) => {
+                guard.take();
+                Ok(OwnedBlobMutRef { guard: ManuallyDrop::new(guard), blob })
+            }
+            Err(e) => {
+                free(blob);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T> Share for Inner<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    fn value(&self) -> *const () {
+        &**self.guard as *const T as *const ()
+    }
+}
+
+impl<T> OwnedBlobMutRef<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    /// The guard, for its other methods
+    ///
+    /// Only shared, since guards swapped between owners would outlive
+    /// their handles.
+    pub fn guard(&self) -> &BlobMutRef<'_, T> {
+        let guard: *const BlobMutRef<'static, T> = &*self.guard;
+        // Safety: as in `OwnedBlobRef::guard`
+        unsafe { &*guard.cast::<BlobMutRef<'_, T>>() }
+    }
+
+    /// See `BlobMutRef::commit`
+    pub fn commit(&mut self) -> Result<CommitReceipt> {
+        self.guard.commit()
+    }
+
+    /// See `BlobMutRef::commit_with_ticket`
+    pub fn commit_with_ticket(&mut self) -> Result<CommitTicket> {
+        self.guard.commit_with_ticket()
+    }
+
+    /// See `BlobMutRef::set_context`
+    pub fn set_context<C>(&mut self, context: C)
+        where C: Any + Send + Sync
+    {
+        self.guard.set_context(context)
+    }
+
+    /// See `BlobMutRef::context`
+    pub fn context(&self) -> Option<&Context> {
+        self.guard.context()
+    }
+
+    /// See `BlobMutRef::set`
+    pub fn set(&mut self, v: T) -> T {
+        self.guard.set(v)
+    }
+
+    /// See `BlobMutRef::delete_on_commit`
+    pub fn delete_on_commit(&mut self) -> Result<()> {
+        self.guard.delete_on_commit()
+    }
+
+    /// Commits, then keeps reading the committed value, as
+    /// `BlobMutRef::commit_and_downgrade`
+    ///
+    /// The shared guard stays on this thread, so this fails with
+    /// `ErrorKind::WouldDeadlock` if the thread holds another guard on
+    /// the blob. Once it's returned, the thread's shared guards on the
+    /// blob read through it, as through any `OwnedBlobRef`.
+    pub fn commit_and_downgrade(self) -> Result<OwnedBlobRef<T>> {
+        // Safety: the handle outlives `self`
+        let held = Held::enter(unsafe { self.blob.as_ref() }.key())?;
+        let mut this = ManuallyDrop::new(self);
+        let blob = this.blob;
+        // Safety: as in `OwnedBlobRef::upgrade`
+        let mut guard = unsafe { ManuallyDrop::take(&mut this.guard) };
+        guard.held = held;
+        match guard.commit_and_downgrade() {
+            Ok(guard) => Ok(OwnedBlobRef::new(guard, blob)),
+            Err(e) => {
+                free(blob);
+                Err(e)
+            }
+        }
+    }
+}
+
+// Safety: an owned guard's value is always `Value::Taken`, since
+// `take` runs before the guard is returned, and nothing puts it back
+// until the guard drops. So it holds no `RwLock` guard, which must be
+// released by the thread that took it; a `Taken` holds the value,
+// which is `Send`, and a reference to `Shared`, which is `Sync` when
+// `T` is. Flocks are held by open file, not thread, so the flock can
+// be released from any. Every other field is `Send`, except the
+// references into `blob`, whose flock and intent lock aren't `Sync`.
+// But `blob` is only reachable through this guard, so those are
+// only ever used by the thread that has it, as if the handle itself
+// had been sent.
+unsafe impl<T> Send for OwnedBlobMutRef<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{}
+
+impl<'a, T: 'a> BlobMutRef<'a, T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    // Moves the value out of the shared one, leaving the guard holding
+    // only the flock
+    fn take(&mut self) {
+        let shared = self.parts.shared;
+        let v = mem::take(&mut *self.v);
+        // Cached reads wait for the flock until it's put back
+        shared.taken.store(true, Ordering::SeqCst);
+        self.v = Value::Taken(Taken { v: ManuallyDrop::new(v), shared });
+        self.held.detach();
+    }
+}
+
+impl<T> Deref for OwnedBlobRef<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner.guard
+    }
+}
+
+impl<T> Deref for OwnedBlobMutRef<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for OwnedBlobMutRef<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for Inner<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    fn drop(&mut self) {
+        // Safety: the guard goes first, and neither is used again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        free(self.blob);
+    }
+}
+
+impl<T> Drop for OwnedBlobMutRef<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + 'static,
+{
+    fn drop(&mut self) {
+        // Safety: as for `Inner`
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        free(self.blob);
+    }
+}
+
+// Refuses owned write guards on handles whose guards would wait for
+// them forever
+fn bounded<T>(blob: &AtomBlob<T>) -> Result<()>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    match blob.shared.acquire {
+        Acquire::FailFast | Acquire::Backoff { timeout: Some(_), .. } => Ok(()),
+        _ => Err("owned write guards need Builder::acquire to fail fast or time out".into()),
+    }
+}
+
+// A handle at a fixed address, until freed
+fn leak<T>(blob: AtomBlob<T>) -> NonNull<AtomBlob<T>>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    NonNull::from(Box::leak(Box::new(blob)))
+}
+
+fn free<T>(blob: NonNull<AtomBlob<T>>)
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    // Safety: only called once per `leak`, after the guard is gone
+    drop(unsafe { Box::from_raw(blob.as_ptr()) });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use super::super::clock::MockClock;
+    use super::super::{AtomBlob, Builder, CommitReceipt, Context, ErrorKind, Shared, Taken};
+    use super::super::reason::Reason;
+    use super::super::vfs::MockFs;
+    use super::super::wait::Acquire;
+
+    fn send<S: Send>() {}
+    fn sync<S: Sync>() {}
+
+    #[test]
+    fn parts_are_send() {
+        // What `OwnedBlobMutRef`'s `Send` relies on
+        send::<AtomBlob<Vec<u32>>>();
+        send::<Taken<'static, Vec<u32>>>();
+        sync::<Shared<Vec<u32>>>();
+        send::<Option<Reason>>();
+        send::<Option<Box<Context>>>();
+        send::<CommitReceipt>();
+    }
+
+    fn open(fs: &MockFs) -> AtomBlob<Vec<u32>> {
+        Builder::new("owned.json").fs(fs.clone()).acquire(Acquire::FailFast).open().unwrap()
+    }
+
+    // Pretty-printed or not, by feature
+    fn stored(fs: &MockFs) -> Vec<u32> {
+        serde_json::from_slice(&fs.read("owned.json").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn commits_from_another_thread() {
+        let fs = MockFs::new();
+        let mut blob = open(&fs);
+        let mut guard = blob.get_mut_owned().unwrap();
+        guard.push(1);
+        thread::spawn(move || {
+            guard.push(2);
+            guard.commit().unwrap();
+        }).join().unwrap();
+        assert_eq!(*blob.get().unwrap(), vec![1, 2]);
+        assert_eq!(stored(&fs), vec![1, 2]);
+        // Or as it drops there
+        let mut guard = blob.get_mut_owned().unwrap();
+        thread::spawn(move || guard.push(3)).join().unwrap();
+        assert_eq!(stored(&fs), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn same_thread_waits_for_the_flock() {
+        let fs = MockFs::new();
+        let blob = open(&fs);
+        let mut clone = blob.clone();
+        let guard = blob.get_mut_owned().unwrap();
+        // Not refused as a deadlock: the flock is contended, as
+        // another thread's would be
+        match *clone.get().err().unwrap().kind() {
+            ErrorKind::Locked => (),
+            ref e => panic!("{}", e),
+        }
+        match *blob.get_owned().err().unwrap().kind() {
+            ErrorKind::Locked => (),
+            ref e => panic!("{}", e),
+        }
+        drop(guard);
+        assert!(clone.get().is_ok());
+    }
+
+    #[test]
+    fn other_threads_wait() {
+        let fs = MockFs::new();
+        let blob = open(&fs);
+        let guard = blob.get_mut_owned().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let mut clone: AtomBlob<Vec<u32>> = Builder::new("owned.json").fs(fs.clone()).open().unwrap();
+        let reader = thread::spawn(move || {
+            let v: Vec<u32> = clone.get().unwrap().clone();
+            tx.send(v).unwrap();
+        });
+        let mut guard = guard;
+        guard.push(4);
+        thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+        drop(guard);
+        reader.join().unwrap();
+        assert_eq!(rx.recv().unwrap(), vec![4]);
+    }
+
+    #[test]
+    fn refuses_handles_that_would_wait_forever() {
+        let fs = MockFs::new();
+        let forever = [Acquire::Block,
+                       Acquire::Backoff { max_interval: Duration::from_millis(1), timeout: None }];
+        for &acquire in &forever {
+            let blob: AtomBlob<Vec<u32>> = Builder::new("owned.json").fs(fs.clone())
+                .acquire(acquire).open().unwrap();
+            assert!(blob.get_mut_owned().is_err());
+            assert!(blob.get_owned().unwrap().upgrade().is_err());
+        }
+        let blob: AtomBlob<Vec<u32>> = Builder::new("owned.json").fs(fs.clone())
+            .acquire(Acquire::Backoff { max_interval: Duration::from_millis(1),
+                                        timeout: Some(Duration::from_millis(10)) })
+            .open().unwrap();
+        let guard = blob.get_mut_owned().unwrap();
+        match *blob.clone().get().err().unwrap().kind() {
+            ErrorKind::Locked => (),
+            ref e => panic!("{}", e),
+        }
+        drop(guard);
+        assert!(blob.get_owned().unwrap().upgrade().is_ok());
+    }
+
+    #[test]
+    fn taken_values_are_out_of_reach() {
+        let fs = MockFs::new();
+        let clock = MockClock::new();
+        let blob: AtomBlob<Vec<u32>> = Builder::new("owned.json").fs(fs.clone())
+            .clock(clock.clone()).acquire(Acquire::FailFast).open().unwrap();
+        let mut guard = blob.get_mut_owned().unwrap();
+        guard.extend(0..100);
+        // Not the default left in its place
+        assert_eq!(blob.approx_memory(), 0);
+        clock.advance(Duration::from_secs(60));
+        blob.shared.evict_idle(Duration::from_secs(1));
+        drop(guard);
+        assert!(!blob.shared.unloaded.load(Ordering::SeqCst));
+        assert!(blob.approx_memory() >= 100 * mem::size_of::<u32>());
+    }
+
+    #[test]
+    fn reads_go_through_owned_reads() {
+        let fs = MockFs::new();
+        let blob = open(&fs);
+        blob.get_mut_owned().unwrap().push(5);
+        let (mut clone, mut other) = (blob.clone(), blob.clone());
+        let owned = blob.get_owned().unwrap();
+        let through = clone.get().unwrap();
+        assert_eq!(*through, vec![5]);
+        match *other.get_mut().err().unwrap().kind() {
+            ErrorKind::WouldDeadlock => (),
+            ref e => panic!("{}", e),
+        }
+        // Its locks last as long as the guards reading through it
+        drop(owned);
+        assert_eq!(*through, vec![5]);
+        assert!(other.get_mut().is_err());
+        drop(through);
+        assert!(other.get_mut().is_ok());
+    }
+}
@@ -0,0 +1,126 @@
+//! Syncing commits to disk off the committing thread
+//!
+//! Commits rename the new file into place before returning, but don't
+//! wait for it to reach the disk. With `Builder::background_sync`,
+//! each commit is handed to a thread that syncs the blob, in commit
+//! order, and a `CommitTicket` from `BlobMutRef::commit_with_ticket`
+//! waits for that. Syncing the blob makes the newest commit durable
+//! with everything before it, so commits that queue up while a sync
+//! runs share the next one.
+//!
+//! `Builder::group_commit` goes further, holding each sync back for a
+//! while so a burst of commits shares one. Each commit still writes
+//! and renames its own file, since other handles must see it, but
+//! files replaced before the sync are deleted while still in the page
+//! cache, and usually never written to disk at all. `Pipeline::drain`
+//! cuts the wait short, for shutdown.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{Result, ResultExt, Strategy};
+use super::vfs::Fs;
+
+// Where a sync's result goes, and whether it skips the window
+type Job = (Sender<io::Result<()>>, bool);
+
+pub struct Pipeline {
+    jobs: Sender<Job>,
+}
+
+impl Pipeline {
+    /// Starts the sync thread, which exits once this is dropped
+    ///
+    /// Each sync waits `window` after the first commit it covers for
+    /// more to join it.
+    pub fn start(fs: Arc<dyn Fs>, strategy: Strategy, path: PathBuf,
+                 window: Duration) -> Result<Pipeline> {
+        let (jobs, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("blobject-sync".to_string())
+            .spawn(move || run(&*fs, strategy, &path, window, rx))
+            .chain_err(|| "starting blobject sync thread")?;
+        Ok(Pipeline { jobs })
+    }
+
+    /// Queues a sync of everything committed so far
+    pub fn enqueue(&self) -> CommitTicket {
+        self.queue(false)
+    }
+
+    /// Syncs everything committed so far without waiting for more
+    /// commits to join, and waits for the sync, which answers every
+    /// ticket already handed out
+    pub fn drain(&self) -> Result<()> {
+        self.queue(true).wait()
+    }
+
+    fn queue(&self, now: bool) -> CommitTicket {
+        let (tx, rx) = mpsc::channel();
+        if self.jobs.send((tx, now)).is_err() {
+            error!("blobject sync thread is gone");
+        }
+        CommitTicket(Some(rx))
+    }
+}
+
+fn run(fs: &dyn Fs, strategy: Strategy, path: &Path, window: Duration,
+       rx: Receiver<Job>) {
+    while let Ok((job, mut now)) = rx.recv() {
+        let mut jobs = vec![job];
+        let deadline = Instant::now() + window;
+        while !now {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                break;
+            }
+            match rx.recv_timeout(left) {
+                Ok((job, drain)) => {
+                    jobs.push(job);
+                    now = drain;
+                }
+                // Dropped senders still get their sync
+                Err(_) => break,
+            }
+        }
+        jobs.extend(rx.try_iter().map(|(job, _)| job));
+        let r = strategy.sync(fs, path);
+        if let Err(ref e) = r {
+            error!("syncing blobject: {}", e);
+        }
+        debug!("synced {} blobject commits", jobs.len());
+        for job in jobs {
+            let r = match r {
+                Ok(()) => Ok(()),
+                Err(ref e) => Err(io::Error::new(e.kind(), e.to_string())),
+            };
+            // The ticket may have been dropped
+            let _ = job.send(r);
+        }
+    }
+}
+
+/// Waits for a commit to be durable
+pub struct CommitTicket(Option<Receiver<io::Result<()>>>);
+
+impl CommitTicket {
+    // A commit already durable
+    pub(crate) fn done() -> CommitTicket {
+        CommitTicket(None)
+    }
+
+    /// Blocks until the commit, and every commit before it, is on disk
+    pub fn wait(self) -> Result<()> {
+        let rx = match self.0 {
+            Some(rx) => rx,
+            None => return Ok(()),
+        };
+        match rx.recv() {
+            Ok(r) => r.chain_err(|| "syncing blobject commit"),
+            Err(_) => Err("blobject sync thread exited".into()),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Keeping fields the value type doesn't know
+//!
+//! serde skips fields it doesn't know, so a process built from an
+//! older version of a value type, committing a blob written by a
+//! newer one, strips the fields the newer one added. An
+//! `AtomBlob<Preserved<T>>` keeps them: on load it remembers the
+//! fields that are lost when the parsed value is serialized again,
+//! the same test as `Builder::deny_unknown_fields`, and commits write
+//! them back beside the value's own.
+//!
+//! ```ignore
+//! let mut blob: AtomBlob<Preserved<Config>> = AtomBlob::new(p)?;
+//! blob.get_mut()?.retries += 1; // keeps the fields Config lacks
+//! ```
+//!
+//! Fields are kept in objects nested in objects, but not in arrays,
+//! whose elements may have moved by the time the value is committed.
+//! A field the value serializes again is its own, even if it skipped
+//! the field when loading. Binary formats, like the sidecar, are only
+//! ever written by the current version, so a value keeping fields
+//! isn't written to them.
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error as DeError;
+use serde::ser::Error as SerError;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+
+/// A value, and the fields it didn't know when loaded
+#[derive(Clone, Debug, Default)]
+pub struct Preserved<T> {
+    value: T,
+    unknown: Unknown,
+}
+
+// The fields of an object the value didn't know, and of the objects
+// in it that it did
+#[derive(Clone, Debug, Default)]
+struct Unknown {
+    fields: Map<String, Value>,
+    nested: BTreeMap<String, Unknown>,
+}
+
+impl<T> Preserved<T> {
+    /// A value keeping no fields
+    pub fn new(value: T) -> Preserved<T> {
+        Preserved { value, unknown: Unknown::default() }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    /// Whether the value was loaded with fields it didn't know
+    pub fn has_unknown(&self) -> bool {
+        !self.unknown.is_empty()
+    }
+}
+
+impl<T> Deref for Preserved<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Preserved<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T: Serialize> Serialize for Preserved<T> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if self.unknown.is_empty() {
+            return self.value.serialize(s);
+        }
+        if !s.is_human_readable() {
+            return Err(S::Error::custom("unknown fields in binary format"));
+        }
+        let mut v = serde_json::to_value(&self.value).map_err(S::Error::custom)?;
+        self.unknown.restore(&mut v);
+        v.serialize(s)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Preserved<T>
+    where T: Serialize + for <'a> Deserialize<'a>
+{
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Preserved<T>, D::Error> {
+        if !d.is_human_readable() {
+            return T::deserialize(d).map(Preserved::new);
+        }
+        let file = Value::deserialize(d)?;
+        let value = T::deserialize(&file).map_err(D::Error::custom)?;
+        let ours = serde_json::to_value(&value).map_err(D::Error::custom)?;
+        let unknown = Unknown::find(file, &ours);
+        if !unknown.is_empty() {
+            debug!("keeping unknown blobject fields");
+        }
+        Ok(Preserved { value, unknown })
+    }
+}
+
+impl Unknown {
+    fn find(file: Value, ours: &Value) -> Unknown {
+        let mut unknown = Unknown::default();
+        if let (Value::Object(file), Value::Object(ours)) = (file, ours) {
+            for (k, v) in file {
+                match ours.get(&k) {
+                    Some(ours) => {
+                        let nested = Unknown::find(v, ours);
+                        if !nested.is_empty() {
+                            unknown.nested.insert(k, nested);
+                        }
+                    }
+                    None => {
+                        unknown.fields.insert(k, v);
+                    }
+                }
+            }
+        }
+        unknown
+    }
+
+    fn restore(&self, ours: &mut Value) {
+        let ours = match *ours {
+            Value::Object(ref mut ours) => ours,
+            // No longer an object, so there's nowhere to put them
+            _ => return,
+        };
+        for (k, v) in &self.fields {
+            if !ours.contains_key(k) {
+                ours.insert(k.clone(), v.clone());
+            }
+        }
+        for (k, nested) in &self.nested {
+            if let Some(v) = ours.get_mut(k) {
+                nested.restore(v);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.nested.is_empty()
+    }
+}
@@ -0,0 +1,154 @@
+//! The lock file protocol, and its version
+//!
+//! Handles coordinate through a lock file beside the blob, named for
+//! it with the extension `flock`, which every process sharing the blob
+//! must read and write the same way. The file holds:
+//!
+//! - bytes 0 to 16, the epoch: a random era and a revision, both
+//!   little-endian `u64`s, as described in the `lockfile` module. A
+//!   handle that finds a different epoch than it last saw reloads.
+//! - bytes 16 to 24, the protocol: the magic `ablk` and the version,
+//!   a little-endian `u32`, currently 1.
+//!
+//! Handles are flocked on the file, shared by guards from `get` and
+//! exclusive by guards from `get_mut`, and the file is deleted by the
+//! last handle to let go of it.
+//!
+//! A file without the protocol bytes, as written by versions of this
+//! crate before they were added, or by `fancy_flocks`, is version 1.
+//! The first exclusive guard adds them. A handle reads them through
+//! the file it locked, the first time it locks it and whenever it
+//! finds it dirty, since they're only written under the exclusive
+//! lock. A handle finding another version fails with `ErrorKind::LockProtocol` rather than misread
+//! the dirty state, so a blob shared with an incompatible version of
+//! this crate is an error and not a silently missed commit. The
+//! version changes only when the protocol does, incompatibly.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use super::{ErrorKind, Result, ResultExt};
+
+pub const VERSION: u32 = 1;
+
+const MAGIC: &[u8; 4] = b"ablk";
+
+// After the epoch
+const OFFSET: u64 = 16;
+
+/// Fails unless the lock file `f` at `p`, already locked, speaks
+/// this version, recording it if the lock is exclusive and the file
+/// has no version yet, and returning whether the file now has it
+pub fn check(mut f: &File, p: &Path, exclusive: bool) -> Result<bool> {
+    let mut buf = [0; 8];
+    f.seek(SeekFrom::Start(OFFSET))?;
+    let mut n = 0;
+    while n < buf.len() {
+        match f.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    match n {
+        0 if exclusive => {
+            let mut protocol = [0; 8];
+            protocol[..4].copy_from_slice(MAGIC);
+            protocol[4..].copy_from_slice(&VERSION.to_le_bytes());
+            f.seek(SeekFrom::Start(OFFSET))?;
+            f.write_all(&protocol).chain_err(|| "recording blobject lock protocol")?;
+            Ok(true)
+        }
+        0 => Ok(false),
+        8 if &buf[..4] == MAGIC => {
+            let mut version = [0; 4];
+            version.copy_from_slice(&buf[4..]);
+            match u32::from_le_bytes(version) {
+                VERSION => Ok(true),
+                found => Err(ErrorKind::LockProtocol(found, VERSION).into()),
+            }
+        }
+        _ => Err(format!("unrecognized blobject lock file at {}", p.display()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::path::PathBuf;
+    use super::*;
+    use super::super::flock::{DirtyFlock, FlockGuard};
+
+    fn check_at(p: &Path, exclusive: bool) -> Result<bool> {
+        let f = OpenOptions::new().read(true).write(exclusive).open(p)?;
+        check(&f, p, exclusive)
+    }
+
+    fn tempfile(name: &str, contents: &[u8]) -> PathBuf {
+        let p = std::env::temp_dir()
+            .join(format!("blobject-protocol-{}-{}", name, std::process::id()));
+        fs::write(&p, contents).unwrap();
+        p
+    }
+
+    #[test]
+    fn exclusive_records_the_version() {
+        let p = tempfile("record", &[7; 16]);
+        assert!(!check_at(&p, false).unwrap());
+        assert_eq!(fs::read(&p).unwrap().len(), 16);
+        assert!(check_at(&p, true).unwrap());
+        let b = fs::read(&p).unwrap();
+        assert_eq!(&b[..16], &[7; 16]);
+        assert_eq!(&b[16..20], MAGIC);
+        assert_eq!(&b[20..], &VERSION.to_le_bytes());
+        assert!(check_at(&p, false).unwrap());
+        assert!(check_at(&p, true).unwrap());
+        assert_eq!(fs::read(&p).unwrap(), b);
+        fs::remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut b = vec![0; 16];
+        b.extend_from_slice(MAGIC);
+        b.extend_from_slice(&2u32.to_le_bytes());
+        let p = tempfile("version", &b);
+        for &exclusive in &[false, true] {
+            match check_at(&p, exclusive).unwrap_err().kind() {
+                ErrorKind::LockProtocol(2, VERSION) => (),
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+        assert_eq!(fs::read(&p).unwrap(), b);
+        fs::remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_versions_once_clean() {
+        let mut b = vec![1; 16];
+        b.extend_from_slice(MAGIC);
+        b.extend_from_slice(&2u32.to_le_bytes());
+        let p = tempfile("clean", &b);
+        let flock = DirtyFlock::new(&p, None);
+        // Locked again the second time, clean, without reading it
+        for _ in 0..2 {
+            match FlockGuard::shared(&flock) {
+                Err(e) => assert!(matches!(e.kind(), ErrorKind::LockProtocol(2, VERSION))),
+                Ok(_) => panic!("locked a file of another version"),
+            }
+        }
+        drop(flock);
+        let _ = fs::remove_file(&p);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let p = tempfile("garbage", b"0123456789abcdefxyz");
+        assert!(check_at(&p, false).is_err());
+        assert!(check_at(&p, true).is_err());
+        fs::write(&p, b"0123456789abcdefxyzwxyzw").unwrap();
+        assert!(check_at(&p, true).is_err());
+        fs::remove_file(&p).unwrap();
+    }
+}
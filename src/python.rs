@@ -0,0 +1,222 @@
+//! Python bindings over `DynAtomBlob`
+//!
+//! ```python
+//! import atomic_blobject
+//!
+//! blob = atomic_blobject.AtomBlob("state.json")
+//! print(blob.get())
+//!
+//! with blob.edit() as edit:
+//!     edit.value["runs"] = edit.value.get("runs", 0) + 1
+//! ```
+//!
+//! `edit()` holds the exclusive lock from `__enter__` to `__exit__`
+//! and commits `edit.value` on a clean exit. If the block raises,
+//! nothing is written. Meanwhile the blob's other methods raise
+//! rather than wait for the lock the edit holds.
+//!
+//! Build the extension with e.g.
+//! `cargo rustc --release --features pyo3,pyo3/extension-module --crate-type cdylib`.
+
+use pyo3::exceptions::{PyIOError, PyRuntimeError};
+use pyo3::prelude::*;
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use super::{BlobMutRef, DynAtomBlob, Error, ErrorKind, Result, ResultExt};
+
+#[pyclass(name = "AtomBlob", unsendable)]
+pub struct PyAtomBlob {
+    blob: DynAtomBlob,
+    // Whether an edit is entered, shared with the edits
+    editing: Rc<Cell<bool>>,
+}
+
+#[pymethods]
+impl PyAtomBlob {
+    #[new]
+    fn new(path: PathBuf) -> PyResult<PyAtomBlob> {
+        let blob = DynAtomBlob::new(path).map_err(to_py_err)?;
+        Ok(PyAtomBlob { blob, editing: Rc::new(Cell::new(false)) })
+    }
+
+    /// Returns a copy of the current value
+    fn get(&mut self, py: Python<'_>) -> PyResult<Py<PyAny>> {
+        not_editing(&self.editing)?;
+        let blob = &mut self.blob;
+        let json = py.detach(|| -> Result<String> {
+            let v = blob.get()?;
+            serde_json::to_string(&*v)
+                .chain_err(|| "serializing blobject for python")
+        }).map_err(to_py_err)?;
+        from_json(py, &json)
+    }
+
+    /// Replaces the value and commits it
+    fn set(&mut self, py: Python<'_>, value: Bound<'_, PyAny>) -> PyResult<()> {
+        not_editing(&self.editing)?;
+        let newval = to_value(py, &value)?;
+        let blob = &mut self.blob;
+        py.detach(|| -> Result<()> {
+            let mut v = blob.get_mut()?;
+            *v = newval;
+            commit(v)
+        }).map_err(to_py_err)
+    }
+
+    /// Returns a context manager holding exclusive access
+    fn edit(&self) -> Edit {
+        Edit {
+            blob: Some(self.blob.clone()),
+            editing: self.editing.clone(),
+            session: None,
+            value: None,
+        }
+    }
+}
+
+/// Exclusive access to a blob, held between `__enter__` and `__exit__`
+#[pyclass(unsendable)]
+pub struct Edit {
+    blob: Option<DynAtomBlob>,
+    editing: Rc<Cell<bool>>,
+    session: Option<Session>,
+    #[pyo3(get, set)]
+    value: Option<Py<PyAny>>,
+}
+
+// The guard borrows its handle, so it lives on a thread of its own
+// for the duration of the `with` block. That thread's guard can't be
+// told apart from another thread's, so the blob's methods check
+// `editing` instead of failing with `ErrorKind::WouldDeadlock`.
+struct Session {
+    commit_tx: Sender<Option<serde_json::Value>>,
+    // The commit's result, once the value has loaded
+    exit_rx: Receiver<Result<()>>,
+}
+
+#[pymethods]
+impl Edit {
+    fn __enter__(mut slf: PyRefMut<'_, Self>, py: Python<'_>) -> PyResult<Py<Edit>> {
+        not_editing(&slf.editing)?;
+        let mut blob = slf.blob.take()
+            .ok_or_else(|| PyRuntimeError::new_err("edit already entered"))?;
+        let (loaded_tx, loaded_rx) = channel();
+        let (commit_tx, commit_rx) = channel();
+        let (exit_tx, exit_rx) = channel();
+
+        thread::spawn(move || {
+            let mut v = match load(&mut blob) {
+                Ok((v, json)) => {
+                    let _ = loaded_tx.send(Ok(json));
+                    v
+                }
+                Err(e) => {
+                    let _ = loaded_tx.send(Err(e));
+                    return;
+                }
+            };
+            let r = match commit_rx.recv() {
+                Ok(Some(newval)) => {
+                    *v = newval;
+                    commit(v)
+                }
+                // Leave the file untouched on an abandoned edit
+                _ => {
+                    v.discard();
+                    Ok(())
+                }
+            };
+            let _ = exit_tx.send(r);
+        });
+
+        let json = py.detach(move || loaded_rx.recv())
+            .unwrap_or_else(|_| Err("python edit thread died".into()))
+            .map_err(to_py_err)?;
+        slf.value = Some(from_json(py, &json)?);
+        slf.session = Some(Session { commit_tx, exit_rx });
+        slf.editing.set(true);
+        Ok(slf.into())
+    }
+
+    fn __exit__(&mut self, py: Python<'_>,
+                exc_type: Option<Bound<'_, PyAny>>,
+                _exc_value: Option<Bound<'_, PyAny>>,
+                _traceback: Option<Bound<'_, PyAny>>) -> PyResult<bool> {
+        let session = self.session.take()
+            .ok_or_else(|| PyRuntimeError::new_err("edit not entered"))?;
+        self.editing.set(false);
+        let newval = match (&exc_type, &self.value) {
+            (None, Some(value)) => Some(to_value(py, value.bind(py))?),
+            _ => None,
+        };
+        let _ = session.commit_tx.send(newval);
+        let exit_rx = session.exit_rx;
+        py.detach(move || exit_rx.recv())
+            .unwrap_or_else(|_| Err("python edit thread died".into()))
+            .map_err(to_py_err)?;
+        Ok(false)
+    }
+}
+
+impl Drop for Edit {
+    // Left without `__exit__`, the edit thread abandons the edit as
+    // the session drops
+    fn drop(&mut self) {
+        if self.session.is_some() {
+            self.editing.set(false);
+        }
+    }
+}
+
+#[pymodule]
+fn atomic_blobject(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyAtomBlob>()?;
+    m.add_class::<Edit>()?;
+    Ok(())
+}
+
+// The exclusive guard, and the value as JSON
+fn load(blob: &mut DynAtomBlob) -> Result<(BlobMutRef<'_, serde_json::Value>, String)> {
+    let v = blob.get_mut()?;
+    let json = serde_json::to_string(&*v)
+        .chain_err(|| "serializing blobject for python")?;
+    Ok((v, json))
+}
+
+// Commits, dropping the guard without trying again if that fails,
+// since dropping it would panic
+fn commit(mut v: BlobMutRef<'_, serde_json::Value>) -> Result<()> {
+    match v.commit() {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            v.discard();
+            Err(e)
+        }
+    }
+}
+
+// Fails rather than wait for the lock an entered edit holds
+fn not_editing(editing: &Cell<bool>) -> PyResult<()> {
+    if editing.get() {
+        return Err(to_py_err(ErrorKind::WouldDeadlock.into()));
+    }
+    Ok(())
+}
+
+fn to_py_err(e: Error) -> PyErr {
+    PyIOError::new_err(e.to_string())
+}
+
+fn from_json(py: Python<'_>, json: &str) -> PyResult<Py<PyAny>> {
+    let v = py.import("json")?.call_method1("loads", (json,))?;
+    Ok(v.unbind())
+}
+
+fn to_value(py: Python<'_>, value: &Bound<'_, PyAny>) -> PyResult<serde_json::Value> {
+    let json: String = py.import("json")?.call_method1("dumps", (value,))?.extract()?;
+    serde_json::from_str(&json)
+        .map_err(|e| PyRuntimeError::new_err(e.to_string()))
+}
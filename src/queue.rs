@@ -0,0 +1,252 @@
+//! A durable queue that appends instead of rewriting
+//!
+//! A `QueueBlob` keeps its items in numbered segment files,
+//! `<blob>.<n>.seg`, and a small blob at its own path recording where
+//! to push and pop. A push appends one record to the newest segment
+//! and syncs it, then commits the small blob, so its cost doesn't
+//! grow with the queue. A pop reads the oldest segment, and deletes
+//! it once every item in it has been popped.
+//!
+//! Records are lines with a checksum, each starting with the newline
+//! that ends the one before, so a push torn by a crash leaves a bad
+//! line that's skipped, and doesn't spoil the next push. A crash
+//! after a record is synced but before the push commits keeps the
+//! record, so an item is popped at least once, not exactly once.
+//!
+//! Every push and pop holds the small blob's exclusive flock, so any
+//! number of processes can share a queue.
+
+use serde::{Serialize, Deserialize};
+use serde_derive::{Serialize as SerializeDerive, Deserialize as DeserializeDerive};
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use super::{AtomBlob, Builder, Result, ResultExt, fnv1a};
+use super::vfs::Fs;
+
+const SEGMENT_LEN: u64 = 1024;
+
+// Where to push and pop
+#[derive(Clone, Debug, Default, SerializeDerive, DeserializeDerive)]
+struct Head {
+    // The oldest segment, and how many records have been popped from
+    // it
+    first: u64,
+    read: u64,
+    // The segment pushes append to, and how many records they have
+    last: u64,
+    written: u64,
+}
+
+pub struct QueueBlob<T> {
+    head: AtomBlob<Head>,
+    path: PathBuf,
+    fs: Arc<dyn Fs>,
+    segment_len: u64,
+    ph: PhantomData<fn(T) -> T>,
+}
+
+impl<T> QueueBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de>,
+{
+    pub fn new<P>(p: P) -> Result<QueueBlob<T>>
+        where P: AsRef<Path>,
+    {
+        QueueBlob::from_builder(Builder::new(p))
+    }
+
+    /// Opens the queue at the builder's path, with its options for
+    /// the small blob
+    pub fn from_builder(builder: Builder) -> Result<QueueBlob<T>> {
+        let (path, fs) = (builder.path.clone(), builder.fs.clone());
+        Ok(QueueBlob {
+            head: builder.open()?,
+            path,
+            fs,
+            segment_len: SEGMENT_LEN,
+            ph: PhantomData,
+        })
+    }
+
+    /// Starts a new segment after this many pushes, 1024 by default
+    ///
+    /// A pop reads the whole oldest segment, so shorter segments
+    /// make pops cheaper and leave more files.
+    pub fn segment_len(mut self, n: u64) -> QueueBlob<T> {
+        self.segment_len = n.max(1);
+        self
+    }
+
+    /// Appends `v`, returning once it's on disk
+    pub fn push(&mut self, v: &T) -> Result<()> {
+        let line = serde_json::to_string(v)
+            .chain_err(|| "serializing blobject queue item")?;
+        let mut head = self.head.get_mut()?;
+        let (last, written) = if head.written >= self.segment_len {
+            (head.last + 1, 0)
+        } else {
+            (head.last, head.written)
+        };
+        // A failed append leaves the head as it was, without
+        // committing it again
+        if let Err(e) = append(&*self.fs, &segment_path(&self.path, last), &line) {
+            head.discard();
+            return Err(e);
+        }
+        head.last = last;
+        head.written = written + 1;
+        Ok(())
+    }
+
+    /// Removes and returns the oldest item
+    pub fn pop(&mut self) -> Result<Option<T>> {
+        Ok(self.drain(1)?.pop())
+    }
+
+    /// Removes and returns up to `n` of the oldest items, oldest first
+    ///
+    /// Doesn't commit if the queue is empty.
+    pub fn drain(&mut self, n: usize) -> Result<Vec<T>> {
+        let (fs, path) = (&*self.fs, &*self.path);
+        let head = self.head.get()?;
+        let first = records(fs, &segment_path(path, head.first))?;
+        if first.len() as u64 <= head.read && head.first == head.last {
+            return Ok(Vec::new());
+        }
+
+        let mut head = head.upgrade()?;
+        let mut items = Vec::new();
+        let mut done = Vec::new();
+        loop {
+            let segment = records(fs, &segment_path(path, head.first))?;
+            for r in segment.iter().skip(head.read as usize).take(n - items.len()) {
+                items.push(serde_json::from_str(r)
+                           .chain_err(|| "parsing blobject queue item")?);
+                head.read += 1;
+            }
+            if (segment.len() as u64) > head.read || head.first == head.last {
+                break;
+            }
+            done.push(head.first);
+            head.first += 1;
+            head.read = 0;
+            if items.len() == n {
+                break;
+            }
+        }
+        head.commit()?;
+        drop(head);
+
+        // Nothing refers to them once the pop is committed
+        for segment in done {
+            if let Err(e) = fs.remove(&segment_path(path, segment)) {
+                warn!("deleting blobject queue segment {}: {}", segment, e);
+            }
+        }
+
+        Ok(items)
+    }
+}
+
+pub(crate) fn segment_path(p: &Path, segment: u64) -> PathBuf {
+    p.with_extension(format!("{}.seg", segment))
+}
+
+/// Appends a record to a segment, durably
+pub(crate) fn append(fs: &dyn Fs, segment: &Path, record: &str) -> Result<()> {
+    let mut out = fs.append(segment)
+        .chain_err(|| "opening blobject segment")?;
+    write!(out, "\n{:016x} {}", fnv1a(record.as_bytes()), record)
+        .and_then(|()| out.flush())
+        .chain_err(|| "appending to blobject segment")?;
+    drop(out);
+    fs.sync(segment)
+        .chain_err(|| "syncing blobject segment")
+}
+
+/// The intact records in a segment, still serialized
+pub(crate) fn records(fs: &dyn Fs, p: &Path) -> Result<Vec<String>> {
+    let mut buf = Vec::new();
+    match fs.open(p).chain_err(|| "opening blobject segment")? {
+        Some((mut r, _)) => r.read_to_end(&mut buf)
+            .chain_err(|| "reading blobject segment")?,
+        None => return Ok(Vec::new()),
+    };
+    // A torn character only spoils its own record
+    let buf = String::from_utf8_lossy(&buf);
+    let records = buf.split('\n')
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            let (check, record) = line.split_once(' ')?;
+            if u64::from_str_radix(check, 16).ok() != Some(fnv1a(record.as_bytes())) {
+                warn!("skipping torn blobject segment record");
+                return None;
+            }
+            Some(record.to_owned())
+        })
+        .collect();
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueueBlob, segment_path};
+    use super::super::Builder;
+    use super::super::vfs::MockFs;
+
+    fn queue(fs: &MockFs) -> QueueBlob<u32> {
+        QueueBlob::from_builder(Builder::new("queue.json").fs(fs.clone())).unwrap()
+    }
+
+    #[test]
+    fn skips_torn_records() {
+        let fs = MockFs::new();
+        let mut q = queue(&fs);
+        q.push(&1).unwrap();
+        q.push(&2).unwrap();
+        let seg = segment_path("queue.json".as_ref(), 0);
+        let mut data = fs.read(&seg).unwrap();
+        data.truncate(data.len() - 1);
+        fs.write(&seg, data);
+        q.push(&3).unwrap();
+        assert_eq!(q.drain(10).unwrap(), vec![1, 3]);
+        assert_eq!(q.pop().unwrap(), None);
+    }
+
+    #[test]
+    fn drains_across_segments() {
+        let fs = MockFs::new();
+        let mut q = queue(&fs).segment_len(2);
+        for i in 0..5 {
+            q.push(&i).unwrap();
+        }
+        assert_eq!(q.drain(3).unwrap(), vec![0, 1, 2]);
+        assert_eq!(fs.read(segment_path("queue.json".as_ref(), 0)), None);
+        assert_eq!(q.drain(10).unwrap(), vec![3, 4]);
+        assert_eq!(fs.read(segment_path("queue.json".as_ref(), 1)), None);
+        assert_eq!(q.drain(10).unwrap(), Vec::<u32>::new());
+        q.push(&5).unwrap();
+        assert_eq!(q.pop().unwrap(), Some(5));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn rotates_only_after_appending() {
+        use std::io;
+        use super::super::fault::{FaultyFs, Op};
+
+        let fs = FaultyFs::new(MockFs::new(), |op, _, p: &std::path::Path| {
+            let fail = op == Op::Write && p == segment_path("queue.json".as_ref(), 1);
+            if fail { Some(io::Error::other("injected")) } else { None }
+        });
+        let mut q: QueueBlob<u32> = QueueBlob::from_builder(Builder::new("queue.json").fs(fs))
+            .unwrap().segment_len(1);
+        q.push(&1).unwrap();
+        assert!(q.push(&2).is_err());
+        let head = q.head.get().unwrap();
+        assert_eq!((head.last, head.written), (0, 1));
+        drop(head);
+        assert_eq!(q.drain(10).unwrap(), vec![1]);
+    }
+}
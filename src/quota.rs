@@ -0,0 +1,275 @@
+//! Keeping a store's files within a size
+//!
+//! With `BlobStore::quota`, the blobs a store opens write through a
+//! `QuotaFs`, which, before a commit's file is renamed into place,
+//! adds up the store's files as they would be after it. A commit that
+//! would take them over the quota is failed, or makes room by
+//! deleting the blobs written least recently, or is left to a
+//! callback, as the store's `OverQuota` says. Commits through one
+//! store are checked one at a time, so they can't together overshoot
+//! the quota; commits from other processes are counted once they're
+//! on disk.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use super::{archive, ledger, lock};
+use super::flock::{DirtyFlock, FlockGuard};
+use super::store::OverQuota;
+use super::vfs::{FileId, Fs};
+
+pub type Evicted = dyn Fn(&Path) + Send + Sync;
+
+// What a commit over the quota fails with, inside an I/O error of
+// `io::ErrorKind::StorageFull`
+#[derive(Debug)]
+pub struct Rejected {
+    after: u64,
+    bytes: u64,
+}
+
+// Shared by the blobs of a store
+pub struct Quota {
+    base: PathBuf,
+    bytes: u64,
+    over: OverQuota,
+    // Called with each blob file evicted
+    evicted: Option<Box<Evicted>>,
+    // Held while a commit is checked and renamed into place
+    checking: Mutex<()>,
+}
+
+// Passes everything to `inner`, checking commits into the store first
+pub struct QuotaFs {
+    inner: Arc<dyn Fs>,
+    quota: Arc<Quota>,
+}
+
+impl Quota {
+    pub fn new(base: PathBuf, bytes: u64, over: OverQuota, evicted: Option<Box<Evicted>>)
+               -> Quota {
+        Quota { base, bytes, over, evicted, checking: Mutex::new(()) }
+    }
+
+    // Fails unless `size` bytes can replace the file at `p`, making
+    // room first if the policy says to
+    fn admit(&self, fs: &dyn Fs, p: &Path, size: u64) -> io::Result<()> {
+        let old = self::size(fs, p)?.unwrap_or(0);
+        let after = usage(fs, &self.base)?.saturating_sub(old).saturating_add(size);
+        if after <= self.bytes {
+            return Ok(());
+        }
+        let admitted = match self.over {
+            OverQuota::Reject => false,
+            OverQuota::Evict => self.evict(fs, p, after)? <= self.bytes,
+            OverQuota::Call(ref f) => f(p, after, self.bytes),
+        };
+        if !admitted {
+            let rejected = Rejected { after, bytes: self.bytes };
+            return Err(io::Error::new(io::ErrorKind::StorageFull, rejected));
+        }
+        Ok(())
+    }
+
+    // Deletes the blobs written least recently, but `p`'s, until the
+    // store would use no more than its quota, returning what it would
+    fn evict(&self, fs: &dyn Fs, p: &Path, mut after: u64) -> io::Result<u64> {
+        let mut blobs: Vec<(Option<SystemTime>, PathBuf)> = Vec::new();
+        for f in walk(fs, &self.base)? {
+            let is_blob = f.extension().is_some_and(|e| e == "json")
+                && !archive::is_archive(&f) && !ledger::is_ledger(&self.base, &f);
+            if is_blob && f != p {
+                let written = fs.id(&f)?.and_then(|id| id.modified());
+                blobs.push((written, f));
+            }
+        }
+        blobs.sort();
+
+        for (_, blob) in blobs {
+            if after <= self.bytes {
+                break;
+            }
+            let flock = DirtyFlock::new(fs.lock_path(&blob.with_extension("flock")), fs.in_process());
+            // Blobs in use aren't the least recently used
+            let _flock = match FlockGuard::try_exclusive(&flock) {
+                Ok(Some(flock)) => flock,
+                Ok(None) | Err(_) => continue,
+            };
+            for f in &[blob.with_extension("cache"), blob.clone()] {
+                let freed = size(fs, f)?.unwrap_or(0);
+                // Made durable by the next sync of the directory
+                match fs.remove(f) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    r => r?,
+                }
+                after = after.saturating_sub(freed);
+            }
+            if let Some(ref evicted) = self.evicted {
+                evicted(&blob);
+            }
+            debug!("evicted blobject {} for the store's quota", blob.display());
+        }
+        Ok(after)
+    }
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "blobject store over its quota: {} of {} bytes", self.after, self.bytes)
+    }
+}
+
+impl StdError for Rejected {}
+
+impl QuotaFs {
+    pub fn new(inner: Arc<dyn Fs>, quota: Arc<Quota>) -> QuotaFs {
+        QuotaFs { inner, quota }
+    }
+
+    fn in_store(&self, p: &Path) -> bool {
+        p.starts_with(&self.quota.base)
+    }
+}
+
+impl Fs for QuotaFs {
+    fn open(&self, p: &Path) -> io::Result<Option<(Box<dyn Read>, FileId)>> {
+        self.inner.open(p)
+    }
+
+    fn id(&self, p: &Path) -> io::Result<Option<FileId>> {
+        self.inner.id(p)
+    }
+
+    fn create(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        self.inner.create(p)
+    }
+
+    fn create_new(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        self.inner.create_new(p)
+    }
+
+    fn create_sized(&self, p: &Path, size: u64) -> io::Result<Box<dyn Write>> {
+        self.inner.create_sized(p, size)
+    }
+
+    fn create_direct(&self, p: &Path, size: Option<u64>) -> io::Result<Box<dyn Write>> {
+        self.inner.create_direct(p, size)
+    }
+
+    fn append(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        self.inner.append(p)
+    }
+
+    fn rename(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        // Only commits bring new bytes into the store
+        if !is_tmp(src) || !self.in_store(dst) {
+            return self.inner.rename(src, dst);
+        }
+        let _checking = lock(&self.quota.checking);
+        let size = size(&*self.inner, src)?.unwrap_or(0);
+        self.quota.admit(&*self.inner, dst, size)?;
+        self.inner.rename(src, dst)
+    }
+
+    fn replace(&self, tmp: &Path, p: &Path, buf: &[u8]) -> io::Result<()> {
+        if !self.in_store(p) {
+            return self.inner.replace(tmp, p, buf);
+        }
+        let _checking = lock(&self.quota.checking);
+        self.quota.admit(&*self.inner, p, buf.len() as u64)?;
+        self.inner.replace(tmp, p, buf)
+    }
+
+    fn remove(&self, p: &Path) -> io::Result<()> {
+        self.inner.remove(p)
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.inner.link(src, dst)
+    }
+
+    fn copy(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.inner.copy(src, dst)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(dir)
+    }
+
+    fn walk(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.walk(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(dir)
+    }
+
+    fn sync(&self, p: &Path) -> io::Result<()> {
+        self.inner.sync(p)
+    }
+
+    fn sync_contents(&self, p: &Path) -> io::Result<()> {
+        self.inner.sync_contents(p)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.inner.sync_dir(dir)
+    }
+
+    fn available(&self, p: &Path) -> io::Result<Option<u64>> {
+        self.inner.available(p)
+    }
+
+    fn canonicalize(&self, p: &Path) -> io::Result<PathBuf> {
+        self.inner.canonicalize(p)
+    }
+
+    fn lock_path(&self, p: &Path) -> PathBuf {
+        self.inner.lock_path(p)
+    }
+
+    fn in_process(&self) -> Option<u64> {
+        self.inner.in_process()
+    }
+}
+
+/// The bytes in the files under `base`, not counting the temp files
+/// of commits in progress
+pub fn usage(fs: &dyn Fs, base: &Path) -> io::Result<u64> {
+    let mut used = 0;
+    for f in walk(fs, base)? {
+        if !is_tmp(&f) {
+            used += size(fs, &f)?.unwrap_or(0);
+        }
+    }
+    Ok(used)
+}
+
+fn walk(fs: &dyn Fs, base: &Path) -> io::Result<Vec<PathBuf>> {
+    match fs.walk(base) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        r => r,
+    }
+}
+
+// The size of the file at `p`, read through if the filesystem doesn't
+// know it
+fn size(fs: &dyn Fs, p: &Path) -> io::Result<Option<u64>> {
+    match fs.id(p)? {
+        None => Ok(None),
+        Some(id) => match id.size() {
+            Some(n) => Ok(Some(n)),
+            None => match fs.open(p)? {
+                Some((mut r, _)) => io::copy(&mut r, &mut io::sink()).map(Some),
+                None => Ok(None),
+            },
+        },
+    }
+}
+
+fn is_tmp(p: &Path) -> bool {
+    p.extension().is_some_and(|e| e == "tmp")
+}
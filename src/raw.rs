@@ -0,0 +1,210 @@
+//! Holding a blob's locks without its value, and the lock protocol
+//! for programs that don't use this crate
+//!
+//! `AtomBlob::lock_shared_raw` and `lock_exclusive_raw` take the same
+//! locks as `get` and `get_mut` without loading anything, so the blob
+//! file can be handed to another tool, like a backup or a `jq`
+//! pipeline, while they're held. `AtomBlob::freeze` also syncs the
+//! blob first, for backups and filesystem snapshots, and
+//! `AtomBlob::snapshot_to` copies the committed file, as a reflink
+//! where the filesystem has them, so even a huge blob copies at once.
+//!
+//! A program that doesn't link this crate can take part too, by
+//! following the protocol below. `<blob>` is the blob path with its
+//! extension replaced, as in `Path::with_extension`.
+//!
+//! - Locks are `flock(2)` locks on `<blob>.flock`, created if
+//!   missing. The lock file is deleted when no handle needs it, so
+//!   after locking, check that the path still names the file that was
+//!   locked, by comparing inodes. If it doesn't, close it and start
+//!   over.
+//! - To read, hold `LOCK_SH` while reading the blob file.
+//! - To write, hold `LOCK_EX`. Write the new value to a temporary file
+//!   in the same directory and rename it over the blob file. Then,
+//!   before unlocking, overwrite the first 16 bytes of the lock file
+//!   with any 16 bytes not there before, e.g. from `/dev/urandom`,
+//!   which tells handles that their copy is stale.
+//! - If any handle uses `Builder::writer_priority`, also hold
+//!   `<blob>.intent`, shared to read and exclusive to write, while
+//!   taking the lock above. It may be released once that lock is held.
+//!
+//! For example, from a shell, ignoring the inode check:
+//!
+//! ```sh
+//! flock -x blob.flock sh -c '
+//!     jq ".n += 1" blob.json > blob.json.tmp &&
+//!     mv blob.json.tmp blob.json &&
+//!     head -c 16 /dev/urandom | dd of=blob.flock conv=notrunc status=none'
+//! ```
+//!
+//! Blobs using `Strategy::DoubleBuffer` never store the value in the
+//! blob file, and those using `Strategy::Chunked` may not, so external
+//! programs shouldn't write them. Writes they
+//! make to a blob with `keep_generations` don't keep a generation.
+
+use serde::{Serialize, Deserialize};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::{AtomBlob, FlockGuard, Held, Result, ResultExt, Strategy, tmp_file};
+
+/// The locks on a blob, held until dropped
+// NB: Lock drop order
+pub struct RawLock<'a> {
+    #[allow(dead_code)] // Using drop side-effect
+    flock: FlockGuard<'a>,
+    #[allow(dead_code)] // Using drop side-effect
+    held: Held,
+    path: &'a Path,
+    // Set for exclusive locks, since anything may have been written
+    unloaded: Option<&'a AtomicBool>,
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// Takes the locks `get` would, without loading the value
+    ///
+    /// No handle can commit until the lock is dropped.
+    pub fn lock_shared_raw(&mut self) -> Result<RawLock<'_>> {
+        let held = Held::enter_shared(self.key())?;
+        let flock = {
+            let _intent = match self.intent {
+                Some(ref i) if !held.nested() => Some(i.lock_shared()?),
+                _ => None,
+            };
+            FlockGuard::shared(&self.flock)?
+        };
+        Ok(RawLock {
+            flock,
+            held,
+            path: &self.path,
+            unloaded: None,
+        })
+    }
+
+    /// Takes the locks `get_mut` would, without loading the value
+    ///
+    /// The blob file may be replaced while the lock is held. Every
+    /// handle reloads it afterwards.
+    pub fn lock_exclusive_raw(&mut self) -> Result<RawLock<'_>> {
+        let held = Held::enter(self.key())?;
+        let flock = {
+            let _intent = match self.intent {
+                Some(ref i) => Some(i.lock_exclusive()?),
+                None => None,
+            };
+            FlockGuard::exclusive(&self.flock)?
+        };
+        Ok(RawLock {
+            flock,
+            held,
+            path: &self.path,
+            unloaded: Some(&self.shared.unloaded),
+        })
+    }
+
+    /// Copies the committed blob file to `dst`, on the blob's
+    /// filesystem, as it was when this was called
+    ///
+    /// The copy is a reflink where the filesystem has them, sharing
+    /// the file's blocks until either is changed, and otherwise made
+    /// in the kernel where it can be. It's written to a temporary
+    /// file, synced, then renamed to `dst`, so `dst` is always whole.
+    /// Commits wait while it's made, but reads go ahead.
+    pub fn snapshot_to<P: AsRef<Path>>(&mut self, dst: P) -> Result<()> {
+        let dst = dst.as_ref();
+        let held = Held::enter_shared(self.key())?;
+        let _flock = {
+            let _intent = match self.intent {
+                Some(ref i) if !held.nested() => Some(i.lock_shared()?),
+                _ => None,
+            };
+            FlockGuard::shared(&self.flock)?
+        };
+        let (fs, path) = (&*self.fs, &*self.path);
+        if self.shared.strategy.id(fs, path).chain_err(|| "snapshotting blobject")?.is_none() {
+            return Err("no committed blobject to snapshot".into());
+        }
+
+        let (tmp, mut w) = tmp_file(fs, dst).chain_err(|| "creating tmp file for blobject snapshot")?;
+        let r = match self.shared.strategy {
+            Strategy::Rename => {
+                drop(w);
+                fs.copy(path, &tmp)
+            }
+            // The value is in whichever slot is current, or in chunks
+            Strategy::DoubleBuffer | Strategy::Chunked => self.shared.strategy.open(fs, path).and_then(|f| {
+                let (mut r, _) = f.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                io::copy(&mut r, &mut w)?;
+                w.flush()
+            }),
+        };
+        let r = r.and_then(|()| fs.sync(&tmp))
+            .and_then(|()| fs.rename(&tmp, dst));
+        if r.is_err() {
+            let _ = fs.remove(&tmp);
+        }
+        r.chain_err(|| "snapshotting blobject")?;
+        fs.sync(dst).chain_err(|| "syncing blobject snapshot")?;
+
+        debug!("snapshotted blobject to {}", dst.display());
+
+        Ok(())
+    }
+
+    /// Stops every handle from committing, and makes the blob durable,
+    /// for taking a consistent backup or snapshot
+    ///
+    /// When this returns no commit is half done, and none starts until
+    /// the guard is dropped. Readers wait too, since the exclusive
+    /// lock is held.
+    pub fn freeze(&mut self) -> Result<FreezeGuard<'_>> {
+        let held = Held::enter(self.key())?;
+        let flock = {
+            let _intent = match self.intent {
+                Some(ref i) => Some(i.lock_exclusive()?),
+                None => None,
+            };
+            FlockGuard::exclusive(&self.flock)?
+        };
+        self.shared.strategy.sync(&*self.fs, &self.path)
+            .chain_err(|| "syncing frozen blobject")?;
+
+        debug!("froze blobject");
+
+        Ok(FreezeGuard(RawLock {
+            flock,
+            held,
+            path: &self.path,
+            unloaded: None,
+        }))
+    }
+}
+
+/// A frozen blob, thawed when dropped
+pub struct FreezeGuard<'a>(RawLock<'a>);
+
+impl<'a> FreezeGuard<'a> {
+    /// The blob file
+    pub fn path(&self) -> &Path {
+        self.0.path()
+    }
+}
+
+impl<'a> RawLock<'a> {
+    /// The blob file
+    pub fn path(&self) -> &Path {
+        self.path
+    }
+}
+
+impl<'a> Drop for RawLock<'a> {
+    fn drop(&mut self) {
+        // Unlocking tells every other handle to reload, but this
+        // handle's flock will take the value it has as current
+        if let Some(unloaded) = self.unloaded {
+            unloaded.store(true, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Saying why a blob is locked
+//!
+//! While a guard from `AtomBlob::get_mut_with_reason` is held, the
+//! reason, the holder's pid and the time the lock was taken are kept
+//! in `<blob>.reason`, so any process, including one waiting for the
+//! lock, can find out what it's waiting on with `lock_info`.
+
+use serde::{Serialize, Deserialize};
+use serde_derive::{Serialize as SerializeDerive, Deserialize as DeserializeDerive};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use super::{AtomBlob, BlobMutRef, Held, Result, ResultExt, Strategy, pid, ser_store};
+use super::vfs::Fs;
+
+/// Who holds a blob's exclusive lock, and why
+#[derive(Clone, Debug, PartialEq, Eq, SerializeDerive, DeserializeDerive)]
+pub struct LockInfo {
+    pub reason: String,
+    pub pid: u32,
+    /// When the lock was taken, on the holder's clock
+    pub since: SystemTime,
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    /// Like `get_mut`, recording `reason` for `lock_info` until the
+    /// guard is dropped
+    ///
+    /// Failing to record the reason is logged, and doesn't fail the
+    /// lock.
+    pub fn get_mut_with_reason(&mut self, reason: &str) -> Result<BlobMutRef<'_, T>> {
+        let held = Held::enter(self.key())?;
+        let mut guard = self.parts().lock_exclusive(held)?;
+        let info = LockInfo {
+            reason: reason.to_owned(),
+            pid: pid(),
+            since: self.shared.clock.system_time(),
+        };
+        guard.reason = Reason::record(self.fs.clone(), &self.path, &info);
+        Ok(guard)
+    }
+
+    /// Why the blob is locked exclusively, if it was locked with a
+    /// reason
+    ///
+    /// The lock isn't taken, so this answers even while the lock is
+    /// held.
+    pub fn lock_info(&self) -> Result<Option<LockInfo>> {
+        read(&*self.fs, &self.path)
+    }
+}
+
+/// The reason recorded for the blob at `p`
+pub fn read(fs: &dyn Fs, p: &Path) -> Result<Option<LockInfo>> {
+    let f = match fs.open(&path_of(p)).chain_err(|| "opening blobject lock reason")? {
+        Some((f, _)) => f,
+        None => return Ok(None),
+    };
+    let info: LockInfo = serde_json::from_reader(BufReader::new(f))
+        .chain_err(|| "parsing blobject lock reason")?;
+
+    // A holder that died left its record behind
+    if fs.in_process().is_none() && !is_alive(info.pid) {
+        return Ok(None);
+    }
+    Ok(Some(info))
+}
+
+/// A recorded reason, deleted when dropped
+pub struct Reason {
+    fs: Arc<dyn Fs>,
+    path: PathBuf,
+}
+
+impl Reason {
+    fn record(fs: Arc<dyn Fs>, p: &Path, info: &LockInfo) -> Option<Reason> {
+        let path = path_of(p);
+        match ser_store(&*fs, Strategy::Rename, &path, info) {
+            Ok(()) => Some(Reason { fs, path }),
+            Err(e) => {
+                warn!("recording blobject lock reason: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Reason {
+    fn drop(&mut self) {
+        if let Err(e) = self.fs.remove(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("deleting blobject lock reason: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    let r = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    r == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    true
+}
+
+fn path_of(p: &Path) -> PathBuf {
+    p.with_extension("reason")
+}
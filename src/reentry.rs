@@ -0,0 +1,186 @@
+//! Per-thread tracking of held guards
+//!
+//! Taking a guard on a value this thread already holds through
+//! another handle can block on our own flock or `RwLock`, so it is
+//! refused with `ErrorKind::WouldDeadlock` instead: an exclusive guard
+//! while the thread holds any other, and any guard while it holds an
+//! exclusive one. Shared guards can be nested, through clones of the
+//! handle, as long as they need no lock another thread could be
+//! queued for behind the first; see `Held::nested`.
+//!
+//! An owned read guard also shares its read with the thread: a shared
+//! guard taken on the same value while it's held reads through it,
+//! taking no locks of its own.
+
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use super::{ErrorKind, Result};
+
+thread_local! {
+    // Keys, and whether they're held exclusively
+    static HELD: RefCell<Vec<(usize, bool)>> = const { RefCell::new(Vec::new()) };
+    static SHARES: RefCell<Vec<(usize, Weak<dyn Share>)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A read lock on a value, kept for as long as any guard reads
+/// through it
+pub trait Share {
+    /// The value, whose type only the value's key tells
+    fn value(&self) -> *const ();
+}
+
+/// Shares the read of the value at `key` with the thread's later
+/// shared guards on it
+pub fn share(key: usize, share: &Rc<dyn Share>) {
+    SHARES.with(|shares| {
+        let mut shares = shares.borrow_mut();
+        shares.retain(|(_, s)| s.strong_count() > 0);
+        shares.push((key, Rc::downgrade(share)));
+    })
+}
+
+/// The read this thread shares of the value at `key`, if any
+pub fn shared(key: usize) -> Option<Rc<dyn Share>> {
+    SHARES.with(|shares| {
+        shares.borrow().iter()
+            .filter(|(k, _)| *k == key)
+            .find_map(|(_, s)| s.upgrade())
+    })
+}
+
+pub struct Held {
+    // None once detached from the thread
+    entry: Option<(usize, bool)>,
+    nested: bool,
+}
+
+impl Held {
+    /// Records that this thread is about to lock the value at `key`
+    /// exclusively
+    pub fn enter(key: usize) -> Result<Held> {
+        Held::push(key, true)
+    }
+
+    /// Records that this thread is about to lock the value at `key`
+    /// shared
+    pub fn enter_shared(key: usize) -> Result<Held> {
+        Held::push(key, false)
+    }
+
+    /// For a guard covered by another's entry
+    pub fn untracked() -> Held {
+        Held { entry: None, nested: false }
+    }
+
+    /// Whether the thread held another shared guard on the value as
+    /// this one was entered
+    ///
+    /// The other guard holds the value's read lock, so this one can't
+    /// wait for it behind a queued writer, or take the write lock to
+    /// reload the value, and it can't wait for the intent lock a
+    /// writer holds while it waits on the other's flock.
+    pub fn nested(&self) -> bool {
+        self.nested
+    }
+
+    /// Trades a shared entry for an exclusive one, failing if the
+    /// thread holds another guard on the value
+    pub fn upgrade(&mut self) -> Result<()> {
+        if let Some((key, false)) = self.entry {
+            HELD.with(|held| -> Result<()> {
+                let mut held = held.borrow_mut();
+                if held.iter().filter(|&&(k, _)| k == key).count() > 1 {
+                    return Err(ErrorKind::WouldDeadlock.into());
+                }
+                if let Some(entry) = held.iter_mut().find(|e| e.0 == key) {
+                    entry.1 = true;
+                }
+                Ok(())
+            })?;
+            self.entry = Some((key, true));
+            self.nested = false;
+        }
+        Ok(())
+    }
+
+    /// Stops tracking the guard as this thread's, for guards that can
+    /// move to another
+    pub fn detach(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            let _ = HELD.try_with(|held| {
+                let mut held = held.borrow_mut();
+                if let Some(i) = held.iter().position(|&e| e == entry) {
+                    held.swap_remove(i);
+                }
+            });
+        }
+    }
+
+    fn push(key: usize, exclusive: bool) -> Result<Held> {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            let mut nested = false;
+            for &(_, x) in held.iter().filter(|e| e.0 == key) {
+                if exclusive || x {
+                    return Err(ErrorKind::WouldDeadlock.into());
+                }
+                nested = true;
+            }
+            held.push((key, exclusive));
+            Ok(Held { entry: Some((key, exclusive)), nested })
+        })
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        self.detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{AtomBlob, Builder, ErrorKind};
+    use super::super::vfs::MockFs;
+
+    fn pair() -> (AtomBlob<Vec<u32>>, AtomBlob<Vec<u32>>) {
+        let mut a: AtomBlob<Vec<u32>> = Builder::new("held.json").fs(MockFs::new()).open().unwrap();
+        a.get_mut().unwrap().push(1);
+        let b = a.clone();
+        (a, b)
+    }
+
+    fn deadlocks<T>(r: super::Result<T>) -> bool {
+        matches!(r.map(|_| ()).unwrap_err().kind(), ErrorKind::WouldDeadlock)
+    }
+
+    #[test]
+    fn nests_shared_guards() {
+        let (mut a, mut b) = pair();
+        let mut c = b.clone();
+        let outer = a.get().unwrap();
+        {
+            let inner = b.get().unwrap();
+            assert_eq!(*inner, vec![1]);
+            assert_eq!(*c.get().unwrap(), vec![1]);
+            assert!(deadlocks(inner.upgrade()));
+        }
+        assert!(deadlocks(b.get_mut()));
+        drop(outer);
+        b.get().unwrap().upgrade().unwrap().push(2);
+        assert_eq!(*a.get().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn refuses_guards_beside_exclusive_ones() {
+        let (mut a, mut b) = pair();
+        let guard = a.get_mut().unwrap();
+        assert!(deadlocks(b.get()));
+        assert!(deadlocks(b.get_mut()));
+        drop(guard);
+        let guard = a.get().unwrap().upgrade().unwrap();
+        assert!(deadlocks(b.get()));
+        drop(guard);
+        b.get().unwrap();
+    }
+}
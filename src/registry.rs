@@ -0,0 +1,67 @@
+//! Process-wide registry of in-memory values, keyed by canonical path
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use super::{Result, ResultExt, SharedOptions, lock};
+use super::vfs::Fs;
+
+// In-process filesystems each have their own paths
+type Key = (Option<u64>, PathBuf);
+
+// Each value with the options it was opened with
+type Entries = HashMap<Key, (Weak<dyn Any + Send + Sync>, SharedOptions)>;
+
+fn entries() -> &'static Mutex<Entries> {
+    static ENTRIES: OnceLock<Mutex<Entries>> = OnceLock::new();
+    ENTRIES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Returns the value already open for `p`, or registers a new one
+/// from `load`, failing if it's open with other `options`
+///
+/// `load` runs without the registry locked, so opening one path
+/// doesn't wait for another to load. Of two callers loading the same
+/// path at once, the first to register wins, and the other gets its
+/// value.
+pub fn open<T, F>(fs: &dyn Fs, p: &Path, options: SharedOptions, load: F) -> Result<Arc<T>>
+    where T: Send + Sync + 'static, F: FnOnce() -> Result<T>
+{
+    let path = fs.canonicalize(p)
+        .chain_err(|| "canonicalizing blobject path")?;
+    let key = (fs.in_process(), path);
+
+    if let Some(v) = find(&lock(entries()), &key, &options)? {
+        return Ok(v);
+    }
+
+    let v = Arc::new(load()?);
+    let mut entries = lock(entries());
+    if let Some(v) = find(&entries, &key, &options)? {
+        return Ok(v);
+    }
+    let weak: Weak<dyn Any + Send + Sync> = Arc::downgrade(&v) as _;
+    entries.retain(|_, (v, _)| v.strong_count() > 0);
+    entries.insert(key, (weak, options));
+
+    Ok(v)
+}
+
+fn find<T>(entries: &Entries, key: &Key, options: &SharedOptions) -> Result<Option<Arc<T>>>
+    where T: Send + Sync + 'static,
+{
+    let (v, opened_with) = match entries.get(key) {
+        Some((v, opened_with)) => match v.upgrade() {
+            Some(v) => (v, opened_with),
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+    if opened_with != options {
+        return Err("blobject already open shared with different options".into());
+    }
+    v.downcast::<T>()
+        .map(Some)
+        .map_err(|_| "blobject already open with a different type".into())
+}
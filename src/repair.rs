@@ -0,0 +1,24 @@
+//! Noticing values that were only read by working around their file
+//!
+//! Parsing marks the thread when it had to recover a value: by
+//! ignoring trailing bytes, through an `on_load_error` repair, or by
+//! migrating from an older version. With `Builder::read_repair`, the
+//! value is then committed back in the current format, so later loads
+//! needn't recover it again.
+
+use std::cell::Cell;
+
+thread_local! {
+    static RECOVERED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the value being parsed on this thread as recovered
+pub fn mark() {
+    RECOVERED.with(|r| r.set(true));
+}
+
+/// Whether a value parsed on this thread since the last call was
+/// recovered
+pub fn take() -> bool {
+    RECOVERED.with(|r| r.replace(false))
+}
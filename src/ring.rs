@@ -0,0 +1,160 @@
+//! A durable log of recent items that forgets old ones
+//!
+//! A `RingBlob` keeps the last `capacity` items pushed, and with
+//! `RingBlob::max_age` only those pushed within it. Items are
+//! appended to segment files the way `QueueBlob` stores them, each
+//! stamped with the wall-clock time, and whole segments are deleted
+//! once every item in them is beyond the limits, so a push costs the
+//! same however long the ring has been running. Up to a quarter of
+//! the capacity again is kept on disk, and skipped by reads.
+
+use serde::{Serialize, Deserialize};
+use serde_derive::{Serialize as SerializeDerive, Deserialize as DeserializeDerive};
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use super::{AtomBlob, Builder, Result, ResultExt};
+use super::clock::Clock;
+use super::queue::{append, records, segment_path};
+use super::vfs::Fs;
+
+// Which segments are kept
+#[derive(Clone, Debug, Default, SerializeDerive, DeserializeDerive)]
+struct Head {
+    first: u64,
+    // The segment pushes append to, and how many items it has
+    last: u64,
+    written: u64,
+    // When the newest item in each segment was pushed, oldest segment
+    // first, in milliseconds since the Unix epoch
+    ends: VecDeque<u64>,
+}
+
+pub struct RingBlob<T> {
+    head: AtomBlob<Head>,
+    path: PathBuf,
+    fs: Arc<dyn Fs>,
+    clock: Arc<dyn Clock>,
+    capacity: u64,
+    segment_len: u64,
+    max_age: Option<Duration>,
+    ph: PhantomData<fn(T) -> T>,
+}
+
+impl<T> RingBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de>,
+{
+    /// Opens the ring at `p`, keeping the last `capacity` items
+    pub fn new<P>(p: P, capacity: u64) -> Result<RingBlob<T>>
+        where P: AsRef<Path>,
+    {
+        RingBlob::from_builder(Builder::new(p), capacity)
+    }
+
+    /// Opens the ring at the builder's path, with its options for the
+    /// small blob recording which segments are kept
+    pub fn from_builder(builder: Builder, capacity: u64) -> Result<RingBlob<T>> {
+        let capacity = capacity.max(1);
+        let (path, fs, clock) = (builder.path.clone(), builder.fs.clone(),
+                                 builder.clock.clone());
+        Ok(RingBlob {
+            head: builder.open()?,
+            path,
+            fs,
+            clock,
+            capacity,
+            segment_len: capacity.div_ceil(4),
+            max_age: None,
+            ph: PhantomData,
+        })
+    }
+
+    /// Also forgets items pushed longer ago than `age`
+    pub fn max_age(mut self, age: Duration) -> RingBlob<T> {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// Appends `v`, returning once it's on disk
+    pub fn push(&mut self, v: &T) -> Result<()> {
+        let at = self.now();
+        let cutoff = self.cutoff(at);
+        let record = serde_json::to_string(&(at, v))
+            .chain_err(|| "serializing blobject ring item")?;
+        let (fs, path) = (&*self.fs, &*self.path);
+        let mut head = self.head.get_mut()?;
+        if head.written >= self.segment_len {
+            head.last += 1;
+            head.written = 0;
+        }
+        append(fs, &segment_path(path, head.last), &record)?;
+        head.written += 1;
+        let segments = (head.last - head.first + 1) as usize;
+        if head.ends.len() == segments {
+            head.ends.pop_back();
+        }
+        head.ends.push_back(at);
+
+        let mut done = Vec::new();
+        while head.first < head.last {
+            let kept = (head.last - head.first) * self.segment_len + head.written;
+            let too_many = kept - self.segment_len >= self.capacity;
+            let too_old = head.ends.front().is_some_and(|&end| end < cutoff);
+            if !too_many && !too_old {
+                break;
+            }
+            done.push(head.first);
+            head.first += 1;
+            head.ends.pop_front();
+        }
+        head.commit()?;
+        drop(head);
+
+        for segment in done {
+            if let Err(e) = fs.remove(&segment_path(path, segment)) {
+                warn!("deleting blobject ring segment {}: {}", segment, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The items kept, oldest first
+    pub fn items(&mut self) -> Result<Vec<T>> {
+        let cutoff = self.cutoff(self.now());
+        let (fs, path) = (&*self.fs, &*self.path);
+        let head = self.head.get()?;
+        let mut items = VecDeque::new();
+        for segment in head.first..=head.last {
+            for r in records(fs, &segment_path(path, segment))? {
+                let (at, v): (u64, T) = serde_json::from_str(&r)
+                    .chain_err(|| "parsing blobject ring item")?;
+                if at < cutoff {
+                    continue;
+                }
+                items.push_back(v);
+                if items.len() as u64 > self.capacity {
+                    items.pop_front();
+                }
+            }
+        }
+        Ok(items.into())
+    }
+
+    // Milliseconds since the Unix epoch
+    fn now(&self) -> u64 {
+        self.clock.system_time().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+
+    // Items pushed before this are forgotten
+    fn cutoff(&self, now: u64) -> u64 {
+        match self.max_age {
+            Some(age) => now.saturating_sub(age.as_millis() as u64),
+            None => 0,
+        }
+    }
+}
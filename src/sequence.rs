@@ -0,0 +1,87 @@
+//! Unique, increasing ids shared between processes
+//!
+//! The blob holds only a high-water mark: every id below it may have
+//! been handed out. A `SequenceBlob` reserves a block of ids at a time
+//! by raising the mark and syncing it to disk, then hands them out
+//! from memory without touching the file. Ids are never reused, even
+//! after a crash, but a crash or a dropped handle skips whatever was
+//! left of its block, and ids from different handles interleave.
+
+use std::ops::Range;
+use std::path::Path;
+use super::{AtomBlob, Result, ResultExt};
+
+/// How many ids a `SequenceBlob` reserves at a time by default
+pub const DEFAULT_BLOCK: u64 = 1000;
+
+pub struct SequenceBlob {
+    blob: AtomBlob<u64>,
+    block: u64,
+    reserved: Range<u64>,
+}
+
+impl SequenceBlob {
+    pub fn new<P>(p: P) -> Result<SequenceBlob>
+        where P: AsRef<Path>,
+    {
+        Ok(SequenceBlob::from_blob(AtomBlob::new(p)?))
+    }
+
+    /// Wraps a blob opened with other options
+    pub fn from_blob(blob: AtomBlob<u64>) -> SequenceBlob {
+        SequenceBlob {
+            blob,
+            block: DEFAULT_BLOCK,
+            reserved: 0..0,
+        }
+    }
+
+    /// Sets how many ids to reserve at a time
+    ///
+    /// Larger blocks touch the file less often, and skip more ids
+    /// when a handle goes away.
+    pub fn block(mut self, block: u64) -> SequenceBlob {
+        self.block = block.max(1);
+        self
+    }
+
+    /// The next id
+    pub fn next(&mut self) -> Result<u64> {
+        Ok(self.allocate(1)?.start)
+    }
+
+    /// Allocates `n` consecutive ids
+    pub fn allocate(&mut self, n: u64) -> Result<Range<u64>> {
+        if self.reserved.end - self.reserved.start < n {
+            self.reserve(n.max(self.block))?;
+        }
+        let start = self.reserved.start;
+        self.reserved.start += n;
+        Ok(start..self.reserved.start)
+    }
+
+    // Replaces the reserved ids with `n` new ones
+    fn reserve(&mut self, n: u64) -> Result<()> {
+        let shared = self.blob.shared.clone();
+        let (fs, path) = (self.blob.fs.clone(), self.blob.path.clone());
+        let mut mark = self.blob.get_mut()?;
+        let start = *mark;
+        let end = start.checked_add(n).ok_or("blobject sequence exhausted")?;
+        *mark = end;
+        mark.commit()?;
+        // Nothing from the block may be handed out until no crash can
+        // lose the new mark
+        shared.strategy.sync(&*fs, &path)
+            .chain_err(|| "syncing blobject sequence")?;
+        drop(mark);
+
+        debug!("reserved blobject ids {}..{}", start, end);
+
+        self.reserved = start..end;
+        Ok(())
+    }
+
+    pub fn blob(&mut self) -> &mut AtomBlob<u64> {
+        &mut self.blob
+    }
+}
@@ -0,0 +1,92 @@
+//! Binary cache of the last parsed value
+//!
+//! The `.cache` file holds the hash of the JSON it was built from,
+//! then the value in bincode. It's only a shortcut: any mismatch or
+//! decode error means parsing the JSON as usual and rewriting it.
+
+use serde::{Serialize, Deserialize};
+use std::io::{Read, Write};
+use std::path::Path;
+use super::{Parse, Result, ResultExt, Strategy, fnv1a, ser_parse, tmp_file};
+use super::vfs::{FileId, Fs};
+
+pub fn load<T>(fs: &dyn Fs, strategy: Strategy, p: &Path, parse: Parse<'_>)
+               -> Option<Result<(T, FileId)>>
+    where for <'de> T: Serialize + Deserialize<'de> + Default
+{
+    match strategy.open(fs, p) {
+        Ok(None) => None,
+        Err(e) => Some(Err(e).chain_err(|| "opening blobject")),
+        Ok(Some((f, id))) => Some(load_from(fs, p, f, parse).map(|v| (v, id))),
+    }
+}
+
+fn load_from<T>(fs: &dyn Fs, p: &Path, mut f: Box<dyn Read>,
+                parse: Parse<'_>) -> Result<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default
+{
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf).chain_err(|| "loading blobject")?;
+    let hash = fnv1a(&buf);
+
+    let cache_path = p.with_extension("cache");
+    match read_cache(fs, &cache_path, hash) {
+        Ok(Some(v)) => {
+            debug!("loaded blobject from cache");
+            return Ok(v);
+        }
+        Ok(None) => (),
+        Err(e) => debug!("ignoring blobject cache: {}", e),
+    }
+
+    let v = ser_parse(p, &buf, parse)?;
+    drop(buf);
+
+    if let Err(e) = write_cache(fs, &cache_path, hash, &v) {
+        warn!("writing blobject cache: {}", e);
+    }
+
+    Ok(v)
+}
+
+fn read_cache<T>(fs: &dyn Fs, p: &Path, hash: u64) -> Result<Option<T>>
+    where for <'de> T: Deserialize<'de>
+{
+    let mut f = match fs.open(p).chain_err(|| "opening blobject cache")? {
+        Some((f, _)) => f,
+        None => return Ok(None),
+    };
+
+    let cached: u64 = bincode::deserialize_from(&mut f)
+        .chain_err(|| "reading blobject cache")?;
+    if cached != hash {
+        return Ok(None);
+    }
+    let v = bincode::deserialize_from(&mut f)
+        .chain_err(|| "reading blobject cache")?;
+    Ok(Some(v))
+}
+
+fn write_cache<T>(fs: &dyn Fs, p: &Path, hash: u64, v: &T) -> Result<()>
+    where T: Serialize
+{
+    let (tmp_path, mut out) = tmp_file(fs, p)
+        .chain_err(|| "creating tmp file for blobject cache")?;
+
+    let r = bincode::serialize_into(&mut out, &hash)
+        .and_then(|_| bincode::serialize_into(&mut out, v))
+        .chain_err(|| "serializing blobject cache")
+        .and_then(|_| out.flush().chain_err(|| "flushing blobject cache"));
+    drop(out);
+    if let Err(e) = r {
+        let _ = fs.remove(&tmp_path);
+        return Err(e);
+    }
+
+    fs.rename(&tmp_path, p)
+        .chain_err(|| "replacing blobject cache")?;
+
+    debug!("wrote blobject cache");
+
+    Ok(())
+}
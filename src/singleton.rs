@@ -0,0 +1,178 @@
+//! Making sure only one process does something
+//!
+//! A `Singleton` holds an exclusive lock on a lock file for as long
+//! as it lives, so of the processes trying to acquire it, only one is
+//! leader at a time. The lock is released when the leader exits, even
+//! if it crashes, letting another process take over.
+//!
+//! Locks are only lost if the lock file is deleted or replaced, by
+//! hand or by a cleanup job, after which a second process can become
+//! leader. `is_leader` and `on_lost` notice that.
+
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+use super::{Result, ResultExt, Strategy, ser_store};
+use super::flock::is_contended;
+use super::lockfile::SdFlock;
+use super::vfs::{FileId, RealFs};
+
+pub struct Singleton {
+    flock: SdFlock,
+    id: FileId,
+    // Threads watching for a lost lock stop once this is gone
+    alive: Arc<()>,
+}
+
+impl Singleton {
+    /// Becomes leader, waiting for any other leader to exit
+    pub fn acquire<P>(p: P) -> Result<Singleton>
+        where P: AsRef<Path>,
+    {
+        let flock = SdFlock::new(p);
+        flock.lock_exclusive().chain_err(|| "locking blobject singleton")?;
+        Singleton::new(flock)
+    }
+
+    /// Becomes leader, or returns `None` if another process is
+    pub fn try_acquire<P>(p: P) -> Result<Option<Singleton>>
+        where P: AsRef<Path>,
+    {
+        let flock = SdFlock::new(p);
+        match flock.try_lock_exclusive() {
+            Ok(()) => Singleton::new(flock).map(Some),
+            Err(ref e) if is_contended(e) => Ok(None),
+            Err(e) => Err(e).chain_err(|| "locking blobject singleton"),
+        }
+    }
+
+    fn new(mut flock: SdFlock) -> Result<Singleton> {
+        let id = match flock.file().metadata() {
+            Ok(m) => FileId::of(&m),
+            Err(e) => {
+                unlock(&flock);
+                return Err(e).chain_err(|| "locking blobject singleton");
+            }
+        };
+
+        debug!("became blobject singleton leader");
+
+        Ok(Singleton {
+            flock,
+            id,
+            alive: Arc::new(()),
+        })
+    }
+
+    /// Whether this process still holds the lock
+    pub fn is_leader(&self) -> bool {
+        holds(self.flock.path(), &self.id)
+    }
+
+    /// Records who the leader is, for `owner` to report
+    ///
+    /// The record is deleted when this is dropped.
+    pub fn set_owner<T>(&self, owner: &T) -> Result<()>
+        where T: Serialize,
+    {
+        ser_store(&RealFs, Strategy::Rename, &owner_path(self.flock.path()), owner)
+            .chain_err(|| "recording blobject singleton owner")
+    }
+
+    /// What the current leader of the lock at `p` recorded with
+    /// `set_owner`, or `None` if there's no leader or it recorded
+    /// nothing
+    pub fn owner<P, T>(p: P) -> Result<Option<T>>
+        where P: AsRef<Path>, for <'de> T: Deserialize<'de>,
+    {
+        let p = p.as_ref();
+        // Taking the lock, however briefly, means there's no leader
+        let flock = SdFlock::new(p);
+        match flock.try_lock_shared() {
+            Ok(()) => {
+                unlock(&flock);
+                return Ok(None);
+            }
+            Err(ref e) if is_contended(e) => (),
+            Err(e) => return Err(e).chain_err(|| "checking blobject singleton"),
+        }
+
+        let f = match File::open(owner_path(p)) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).chain_err(|| "reading blobject singleton owner"),
+        };
+        let owner = serde_json::from_reader(BufReader::new(f))
+            .chain_err(|| "parsing blobject singleton owner")?;
+        Ok(Some(owner))
+    }
+
+    /// Calls `f` from a background thread if the lock is lost, checking
+    /// every `interval`
+    ///
+    /// The thread stops when this is dropped, without calling `f`.
+    pub fn on_lost<F>(&self, interval: Duration, f: F) -> Result<()>
+        where F: FnOnce() + Send + 'static,
+    {
+        let path = self.flock.path().to_owned();
+        let id = self.id.clone();
+        let alive = Arc::downgrade(&self.alive);
+        thread::Builder::new()
+            .name("blobject-singleton".to_string())
+            .spawn(move || watch(path, id, alive, interval, f))
+            .chain_err(|| "starting blobject singleton watch")?;
+        Ok(())
+    }
+}
+
+impl Drop for Singleton {
+    fn drop(&mut self) {
+        // The next leader may already be recording itself once the
+        // lock is released
+        if let Err(e) = fs::remove_file(owner_path(self.flock.path())) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("deleting blobject singleton owner: {}", e);
+            }
+        }
+        unlock(&self.flock);
+        debug!("released blobject singleton");
+    }
+}
+
+fn watch<F>(path: PathBuf, id: FileId, alive: Weak<()>, interval: Duration, f: F)
+    where F: FnOnce(),
+{
+    loop {
+        thread::sleep(interval);
+        if alive.upgrade().is_none() {
+            return;
+        }
+        if !holds(&path, &id) {
+            warn!("lost blobject singleton lock {}", path.display());
+            f();
+            return;
+        }
+    }
+}
+
+// Whether the lock file at `p` is still the one locked
+fn holds(p: &Path, id: &FileId) -> bool {
+    match fs::metadata(p) {
+        Ok(m) => FileId::of(&m) == *id,
+        Err(_) => false,
+    }
+}
+
+fn unlock(flock: &SdFlock) {
+    if let Err(e) = flock.unlock() {
+        error!("unlocking blobject singleton: {}", e);
+    }
+}
+
+fn owner_path(p: &Path) -> PathBuf {
+    p.with_extension("owner")
+}
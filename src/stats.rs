@@ -0,0 +1,141 @@
+//! Counting how often the value is found changed on disk
+//!
+//! Every guard checks whether another handle has committed since this
+//! one last looked, and if so reparses the blob file. Two processes
+//! that take turns writing make each other reparse on nearly every
+//! access; `AtomBlob::stats` shows how often that happens, and
+//! `Builder::warn_on_reloads` logs when it happens too often.
+
+use serde::{Serialize, Deserialize};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use super::{AtomBlob, Shared, State, lock};
+
+/// Counts since the blob was opened, shared by every handle cloned
+/// from one open
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Guards taken
+    pub locks: u64,
+    /// Guards that found another handle had taken the exclusive lock
+    /// since this one last looked
+    pub dirty: u64,
+    /// Times the blob file was parsed again after being changed by
+    /// another handle
+    pub reloads: u64,
+    /// Time since the value was last checked against the file, on
+    /// the handle's clock, or `None` if it never has been
+    ///
+    /// The in-memory value is at most this out of date.
+    pub since_checked: Option<Duration>,
+}
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    pub fn stats(&self) -> Stats {
+        let shared = &*self.shared;
+        shared.counters.stats(shared.clock.now().as_millis() as u64)
+    }
+}
+
+/// A blob's counts, for collecting them from blobs of any type
+pub(crate) trait Source: Send + Sync {
+    fn stats(&self) -> Stats;
+}
+
+impl<T> Source for Shared<T>
+    where T: Send + Sync
+{
+    fn stats(&self) -> Stats {
+        self.counters.stats(self.clock.now().as_millis() as u64)
+    }
+}
+
+const NEVER: u64 = u64::MAX;
+
+pub struct Counters {
+    locks: AtomicU64,
+    dirty: AtomicU64,
+    reloads: AtomicU64,
+    // Milliseconds on the blob's clock
+    checked_at: AtomicU64,
+    // The most reloads allowed per period before warning
+    warn: Option<(u64, Duration)>,
+    // The start of the current period, and reloads in it
+    period: Mutex<(u64, u64)>,
+}
+
+impl Counters {
+    pub fn new(warn: Option<(u64, Duration)>) -> Counters {
+        Counters {
+            locks: AtomicU64::new(0),
+            dirty: AtomicU64::new(0),
+            reloads: AtomicU64::new(0),
+            checked_at: AtomicU64::new(NEVER),
+            warn,
+            period: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn stats(&self, now: u64) -> Stats {
+        let checked_at = self.checked_at.load(Ordering::SeqCst);
+        Stats {
+            locks: self.locks.load(Ordering::SeqCst),
+            dirty: self.dirty.load(Ordering::SeqCst),
+            reloads: self.reloads.load(Ordering::SeqCst),
+            since_checked: if checked_at == NEVER {
+                None
+            } else {
+                Some(Duration::from_millis(now.saturating_sub(checked_at)))
+            },
+        }
+    }
+
+    /// A guard took the flock at `now`
+    pub fn locked(&self, now: u64, state: State) {
+        self.locks.fetch_add(1, Ordering::SeqCst);
+        if state == State::Dirty {
+            self.dirty.fetch_add(1, Ordering::SeqCst);
+        }
+        self.checked(now);
+    }
+
+    /// The value was checked against the file at `now`
+    pub fn checked(&self, now: u64) {
+        self.checked_at.store(now, Ordering::SeqCst);
+    }
+
+    /// Whether the value was checked against the file no more than
+    /// `ttl` before `now`
+    pub fn checked_within(&self, now: u64, ttl: Duration) -> bool {
+        match self.checked_at.load(Ordering::SeqCst) {
+            NEVER => false,
+            at => now.saturating_sub(at) < ttl.as_millis() as u64,
+        }
+    }
+
+    /// The file was parsed again at `now`, after another handle
+    /// changed it
+    pub fn reloaded(&self, now: u64, p: &Path) {
+        self.reloads.fetch_add(1, Ordering::SeqCst);
+
+        let (max, per) = match self.warn {
+            Some(w) => w,
+            None => return,
+        };
+        let mut period = lock(&self.period);
+        if now.saturating_sub(period.0) >= per.as_millis() as u64 {
+            *period = (now, 0);
+        }
+        period.1 += 1;
+        // Once per period
+        if period.1 == max + 1 {
+            warn!("blobject {} reloaded more than {} times in {:?}; \
+                   are processes taking turns writing it?",
+                  p.display(), max, per);
+        }
+    }
+}
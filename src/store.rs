@@ -0,0 +1,510 @@
+//! A directory of blobs opened with the same options
+//!
+//! A `BlobStore` names blobs by paths relative to its base directory,
+//! like `users/alice`, kept in `users/alice.json`, and opens each
+//! with the options of the builder it was made from, so a service
+//! configures durability and parsing once for every blob it keeps.
+//! `BlobStore::list` finds the blobs stored, `BlobStore::for_each`
+//! reads each in turn, `BlobStore::gc` deletes
+//! the temp files left by interrupted commits, and `BlobStore::stats`
+//! adds up the counts of the blobs opened through the store.
+//!
+//! Stores with very many blobs can spread them over subdirectories
+//! named by a hash of the name with `BlobStore::fan_out`, so that
+//! `users/alice` is kept in, say, `3f/users/alice.json`.
+//!
+//! Stores committing many blobs at once can share the directory syncs
+//! that make the commits durable with `BlobStore::batch_syncs`, and
+//! stores for caches can be held to a size with `BlobStore::quota`.
+//! With `BlobStore::keep_stats`, a store records the size and commits
+//! of each blob in a stats blob of its own, for dashboards to read
+//! without visiting every blob.
+
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use serde_derive::{Serialize as SerializeDerive, Deserialize as DeserializeDerive};
+use super::{AtomBlob, BlobRef, Builder, Result, ResultExt, Strategy, archive, history,
+            lock, sha256};
+use super::dirsync::DirSyncs;
+use super::flock::{DirtyFlock, FlockGuard};
+use super::ledger::{self, Ledger};
+use super::quota::{self, Quota, QuotaFs};
+use super::stats::{Source, Stats};
+
+const EXTENSION: &str = "json";
+
+pub struct BlobStore {
+    builder: Builder,
+    fan_out: usize,
+    quota: Option<(u64, OverQuota)>,
+    // Made as the first blob is opened, once the store's settings are
+    // known
+    quota_state: OnceLock<Arc<Quota>>,
+    ledger: Option<Arc<Ledger>>,
+    opened: Mutex<Vec<Weak<dyn Source>>>,
+}
+
+/// Decides whether a blob file may take its store over its quota,
+/// given the bytes the store would use and the quota
+pub type OverQuotaFn = dyn Fn(&Path, u64, u64) -> bool + Send + Sync;
+
+/// What a commit that would take a store over its quota does, as
+/// decided by `BlobStore::quota`
+#[derive(Clone)]
+pub enum OverQuota {
+    /// Fail with an `io::Error` of kind `StorageFull`
+    Reject,
+    /// Delete the blobs written least recently, skipping those locked
+    /// by a handle, until the commit fits, or fail as `Reject` if it
+    /// still doesn't
+    Evict,
+    /// Commit anyway if `f(p, used, quota)` returns true, for the blob
+    /// file `p` and the bytes the store would use with it, or fail as
+    /// `Reject`
+    ///
+    /// `f` is called while the store checks commits, so it mustn't
+    /// commit to the store itself.
+    Call(Arc<OverQuotaFn>),
+}
+
+/// Counts for the blobs opened through a store
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Blobs with a handle open
+    pub open: usize,
+    /// The open blobs' counts added up, with the longest time since
+    /// any was checked
+    pub blobs: Stats,
+    /// With `BlobStore::keep_stats`, each blob committed through the
+    /// store by name
+    pub entries: BTreeMap<String, EntryStats>,
+}
+
+/// What a store's stats blob records of one of its blobs
+#[derive(Clone, Debug, PartialEq, Eq, SerializeDerive, DeserializeDerive)]
+pub struct EntryStats {
+    /// Bytes in the blob file as last committed
+    pub bytes: u64,
+    /// Commits since the store began keeping stats
+    pub commits: u64,
+    /// When it was last committed, on the committing handle's clock
+    pub written: SystemTime,
+}
+
+impl BlobStore {
+    /// A store in the directory `base`, opening blobs with the default
+    /// options
+    pub fn new<P>(base: P) -> BlobStore
+        where P: AsRef<Path>,
+    {
+        BlobStore::from_builder(Builder::new(base))
+    }
+
+    /// A store in the builder's path, opening blobs with its options
+    pub fn from_builder(builder: Builder) -> BlobStore {
+        BlobStore {
+            builder,
+            fan_out: 0,
+            quota: None,
+            quota_state: OnceLock::new(),
+            ledger: None,
+            opened: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Spreads blobs over `levels` of subdirectories, each level
+    /// dividing them 256 ways, up to 8 levels
+    ///
+    /// Every user of the store must use the same setting, or they'll
+    /// look for blobs in different places.
+    pub fn fan_out(mut self, levels: usize) -> BlobStore {
+        self.fan_out = levels.min(8);
+        self
+    }
+
+    /// Syncs the directory a synced commit renamed its file into once
+    /// for every sync into it within `window`, instead of once each
+    ///
+    /// Commits are synced by `BlobMutRef::commit_with_ticket`, the
+    /// thread of `Builder::background_sync`, and the like. Each sync
+    /// still syncs its own file, and returns once its directory is
+    /// synced, so commits are as durable as they were, but each waits
+    /// up to `window` longer. Syncs that ask while another runs share
+    /// the next, so even a `window` of zero saves directory syncs when
+    /// commits come faster than the disk takes them. Only blobs opened
+    /// after this share syncs.
+    pub fn batch_syncs(mut self, window: Duration) -> Result<BlobStore> {
+        let fs = DirSyncs::start(self.builder.fs.clone(), window)?;
+        self.builder.fs = Arc::new(fs);
+        Ok(self)
+    }
+
+    /// Holds the store's files to `bytes` in all, doing as `over`
+    /// says with commits that would take them over
+    ///
+    /// Files are added up as each commit is renamed into place, so
+    /// each commit's file may go over while it's being written, and
+    /// stores of many thousands of blobs spend a while on each commit
+    /// adding them up. Kept generations and archives are counted, but
+    /// not evicted. Only blobs opened after this are held to the
+    /// quota, and only with `Strategy::Rename`. A commit that's turned
+    /// away fails with an error for which `Error::is_over_quota` is
+    /// true, and isn't tried again as its guard drops; the blob's next
+    /// guard reads what's on disk, without the change.
+    pub fn quota(mut self, bytes: u64, over: OverQuota) -> BlobStore {
+        self.quota = Some((bytes, over));
+        self
+    }
+
+    /// Records the size, commit count and last commit time of each
+    /// blob committed through the store, for `BlobStore::stats`
+    ///
+    /// The stats are kept in the store's directory, in `.stats.json`,
+    /// and each commit also commits them. Every user of the store
+    /// should use the same setting, or the stats will miss their
+    /// commits.
+    pub fn keep_stats(mut self) -> Result<BlobStore> {
+        self.builder.fs.create_dir_all(&self.builder.path)
+            .chain_err(|| "creating blobject store directory")?;
+        self.ledger = Some(Arc::new(Ledger::open(&self.builder)?));
+        Ok(self)
+    }
+
+    /// The bytes in the store's files, not counting commits in
+    /// progress
+    pub fn usage(&self) -> Result<u64> {
+        quota::usage(&*self.builder.fs, &self.builder.path)
+            .chain_err(|| "adding up blobject store")
+    }
+
+    pub fn base(&self) -> &Path {
+        &self.builder.path
+    }
+
+    /// Opens the blob `name`, creating its directory if needed
+    ///
+    /// Names are relative paths, with `/` between directories, and
+    /// fail to open if they're empty or step outside the store.
+    pub fn open<T>(&self, name: &str) -> Result<AtomBlob<T>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+    {
+        self.open_with(name, |b| b)
+    }
+
+    /// Like `open`, with the store's options changed by `f`
+    pub fn open_with<T, F>(&self, name: &str, f: F) -> Result<AtomBlob<T>>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+              F: FnOnce(Builder) -> Builder,
+    {
+        let path = self.path_of(name)?;
+        if let Some(dir) = path.parent() {
+            self.builder.fs.create_dir_all(dir)
+                .chain_err(|| "creating blobject store directory")?;
+        }
+        let mut builder = self.builder.clone();
+        builder.path = path.clone();
+        if let Some(quota) = self.quota_state() {
+            builder.fs = Arc::new(QuotaFs::new(builder.fs, quota));
+        }
+        let mut builder = f(builder);
+        if self.quota.is_some() && builder.strategy != Strategy::Rename {
+            return Err("blobject store quotas need the rename strategy".into());
+        }
+        if let Some(ref ledger) = self.ledger {
+            let (ledger, name) = (ledger.clone(), name.to_string());
+            let (fs, clock, strategy) = (builder.fs.clone(), builder.clock.clone(), builder.strategy);
+            let hook = builder.on_commit.take();
+            builder.on_commit = Some(Arc::new(move |r, context| {
+                if let Some(ref hook) = hook {
+                    hook(r, context);
+                }
+                match strategy.id(&*fs, &path) {
+                    Ok(Some(_)) => ledger.record(&name, r.bytes, clock.system_time()),
+                    _ => ledger.forget(&name),
+                }
+            }));
+        }
+        let blob = builder.open::<T>()?;
+
+        let shared = Arc::downgrade(&blob.shared) as Weak<dyn Source>;
+        let mut opened = lock(&self.opened);
+        opened.retain(|s| s.strong_count() > 0);
+        opened.push(shared);
+        Ok(blob)
+    }
+
+    /// The names of the blobs stored, in order
+    ///
+    /// Blobs are found by the files the store's strategy writes, and
+    /// archives are skipped.
+    pub fn list(&self) -> Result<Vec<String>> {
+        let exts: &[&str] = match self.builder.strategy {
+            Strategy::Rename | Strategy::Chunked => &[EXTENSION],
+            Strategy::DoubleBuffer => &["a", "b"],
+        };
+        let names: BTreeSet<String> = self.walk()?.iter()
+            .filter(|f| f.extension().and_then(|e| e.to_str()).is_some_and(|e| exts.contains(&e)))
+            .filter(|f| !archive::is_archive(f) && !ledger::is_ledger(&self.builder.path, f))
+            .filter_map(|f| self.name_of(f))
+            .collect();
+        Ok(names.into_iter().collect())
+    }
+
+    /// Calls `f` with each blob stored, in order, stopping at the
+    /// first error
+    ///
+    /// Each blob's shared lock is held only while `f` reads it, so a
+    /// long scan keeps writers waiting for one call at most.
+    pub fn for_each<T, F>(&self, mut f: F) -> Result<()>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+              F: FnMut(&str, BlobRef<'_, T>) -> Result<()>,
+    {
+        for name in self.list()? {
+            let mut blob = self.open_with::<T, _>(&name, |b| b.lazy(true))?;
+            f(&name, blob.get()?)?;
+        }
+        Ok(())
+    }
+
+    /// Like `for_each`, calling `f` from `threads` threads at once, in
+    /// no particular order
+    pub fn par_for_each<T, F>(&self, threads: usize, f: F) -> Result<()>
+        where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+              F: Fn(&str, BlobRef<'_, T>) -> Result<()> + Sync,
+    {
+        let names = self.list()?;
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let scan = || -> Result<()> {
+            while !failed.load(Ordering::SeqCst) {
+                let name = match names.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    Some(name) => name,
+                    None => break,
+                };
+                let r = self.open_with::<T, _>(name, |b| b.lazy(true))
+                    .and_then(|mut blob| f(name, blob.get()?));
+                if r.is_err() {
+                    failed.store(true, Ordering::SeqCst);
+                    return r;
+                }
+            }
+            Ok(())
+        };
+        thread::scope(|s| {
+            let mut workers = Vec::new();
+            for _ in 0..threads.max(1) {
+                let worker = thread::Builder::new()
+                    .name("blobject-scan".to_string())
+                    .spawn_scoped(s, scan);
+                match worker {
+                    Ok(w) => workers.push(w),
+                    Err(e) => {
+                        failed.store(true, Ordering::SeqCst);
+                        return Err(e).chain_err(|| "starting blobject scan thread");
+                    }
+                }
+            }
+            workers.into_iter()
+                .map(|w| w.join().unwrap_or_else(|_| Err("blobject scan panicked".into())))
+                .fold(Ok(()), Result::and)
+        })
+    }
+
+    /// Moves the blob `from` to `to`, with its kept generations and
+    /// archives, failing if `to` exists
+    ///
+    /// Both blobs' exclusive flocks are held, and the blob file moves
+    /// in one rename, so a crash leaves the value under exactly one of
+    /// the names. Handles still open on `from` find it gone. Fails for
+    /// `Strategy::DoubleBuffer`, whose slots can't move at once.
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        if self.builder.strategy != Strategy::Rename {
+            return Err("renaming blobjects needs the rename strategy".into());
+        }
+        let (src, dst) = (self.path_of(from)?, self.path_of(to)?);
+        if src == dst {
+            return Ok(());
+        }
+        let fs = &*self.builder.fs;
+        if let Some(dir) = dst.parent() {
+            fs.create_dir_all(dir)
+                .chain_err(|| "creating blobject store directory")?;
+        }
+
+        // In a fixed order, so concurrent renames can't deadlock
+        let (first, second) = if src < dst { (&src, &dst) } else { (&dst, &src) };
+        let first = DirtyFlock::new(fs.lock_path(&first.with_extension("flock")), fs.in_process());
+        let second = DirtyFlock::new(fs.lock_path(&second.with_extension("flock")), fs.in_process());
+        let _first = FlockGuard::exclusive(&first)?;
+        let _second = FlockGuard::exclusive(&second)?;
+
+        if fs.id(&dst).chain_err(|| "renaming blobject")?.is_some() {
+            return Err(format!("blobject {} already exists", to).into());
+        }
+        if fs.id(&src).chain_err(|| "renaming blobject")?.is_none() {
+            return Err(format!("no blobject {} to rename", from).into());
+        }
+        fs.rename(&src, &dst).chain_err(|| "renaming blobject")?;
+
+        // Left behind by a crash here, these only cost space
+        history::rename(fs, &src, &dst, self.builder.generations_dir.as_deref())
+            .chain_err(|| "moving blobject generations")?;
+        archive::rename(fs, &src, &dst)?;
+        match fs.rename(&src.with_extension("cache"), &dst.with_extension("cache")) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            r => r.chain_err(|| "moving blobject cache")?,
+        }
+
+        fs.sync(&dst).chain_err(|| "syncing renamed blobject")?;
+        if let Some(ref ledger) = self.ledger {
+            ledger.rename(from, to);
+        }
+
+        debug!("renamed blobject {} to {}", from, to);
+
+        Ok(())
+    }
+
+    /// Deletes the temp files left by interrupted commits, returning
+    /// how many
+    ///
+    /// Each blob's exclusive flock is held while its temp files are
+    /// deleted, so commits in progress aren't disturbed.
+    pub fn gc(&self) -> Result<usize> {
+        let fs = &*self.builder.fs;
+        let mut tmps: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        for f in self.walk()? {
+            if let Some(flock) = tmp_flock(&f) {
+                tmps.entry(flock).or_default().push(f);
+            }
+        }
+
+        let mut removed = 0;
+        for (flock, tmps) in tmps {
+            let flock = DirtyFlock::new(fs.lock_path(&flock), fs.in_process());
+            let _flock = FlockGuard::exclusive(&flock)?;
+            for tmp in tmps {
+                match fs.remove(&tmp) {
+                    Ok(()) => removed += 1,
+                    // Renamed into place since
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(e).chain_err(|| "deleting blobject tmp file"),
+                }
+            }
+        }
+
+        debug!("deleted {} blobject tmp files", removed);
+
+        Ok(removed)
+    }
+
+    /// Counts for the blobs open now, and with `BlobStore::keep_stats`,
+    /// the stats of every blob committed through the store
+    ///
+    /// Failing to read the stats blob is logged, leaving no entries.
+    pub fn stats(&self) -> StoreStats {
+        let mut opened = lock(&self.opened);
+        opened.retain(|s| s.strong_count() > 0);
+        let mut stats = StoreStats::default();
+        for blob in opened.iter().filter_map(Weak::upgrade) {
+            let blob = blob.stats();
+            stats.open += 1;
+            stats.blobs.locks += blob.locks;
+            stats.blobs.dirty += blob.dirty;
+            stats.blobs.reloads += blob.reloads;
+            stats.blobs.since_checked = stats.blobs.since_checked.max(blob.since_checked);
+        }
+        if let Some(ref ledger) = self.ledger {
+            match ledger.entries() {
+                Ok(entries) => stats.entries = entries,
+                Err(e) => warn!("reading blobject store stats: {}", e),
+            }
+        }
+        stats
+    }
+
+    fn path_of(&self, name: &str) -> Result<PathBuf> {
+        let rel = Path::new(name);
+        let valid = !name.is_empty()
+            && rel.components().all(|c| matches!(c, Component::Normal(_)));
+        if !valid {
+            return Err(format!("bad blobject name {:?}", name).into());
+        }
+        let file = format!("{}.{}", name, EXTENSION);
+        let path = self.builder.path.join(fan_out_dir(self.fan_out, name)).join(file);
+        if ledger::is_ledger(&self.builder.path, &path) {
+            return Err(format!("blobject name {:?} is reserved", name).into());
+        }
+        Ok(path)
+    }
+
+    fn name_of(&self, f: &Path) -> Option<String> {
+        name_of(&self.builder.path, self.fan_out, f)
+    }
+
+    // The quota's state, shared by every blob opened
+    fn quota_state(&self) -> Option<Arc<Quota>> {
+        let (bytes, ref over) = *self.quota.as_ref()?;
+        let quota = self.quota_state.get_or_init(|| {
+            // Evicted blobs leave the stats
+            let evicted = self.ledger.clone().map(|ledger| {
+                let (base, fan_out) = (self.builder.path.clone(), self.fan_out);
+                Box::new(move |p: &Path| {
+                    if let Some(name) = name_of(&base, fan_out, p) {
+                        ledger.forget(&name);
+                    }
+                }) as Box<quota::Evicted>
+            });
+            Arc::new(Quota::new(self.builder.path.clone(), bytes, over.clone(), evicted))
+        });
+        Some(quota.clone())
+    }
+
+    fn walk(&self) -> Result<Vec<PathBuf>> {
+        match self.builder.fs.walk(&self.builder.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            r => r.chain_err(|| "listing blobject store"),
+        }
+    }
+}
+
+// The subdirectories of the base the blob `name` is kept in
+fn fan_out_dir(fan_out: usize, name: &str) -> PathBuf {
+    let hash = sha256::hex(name.as_bytes());
+    (0..fan_out).map(|i| &hash[i * 2..i * 2 + 2]).collect()
+}
+
+// The name of the blob kept in `f`, in a store at `base`
+fn name_of(base: &Path, fan_out: usize, f: &Path) -> Option<String> {
+    let rel = f.strip_prefix(base).ok()?.with_extension("");
+    let parts: Option<Vec<&str>> = rel.components()
+        .map(|c| c.as_os_str().to_str())
+        .collect();
+    let parts = parts?;
+    if parts.len() <= fan_out {
+        return None;
+    }
+    let name = parts[fan_out..].join("/");
+    // Skips files that aren't where the store would put them
+    if fan_out_dir(fan_out, &name) != parts[..fan_out].iter().collect::<PathBuf>() {
+        return None;
+    }
+    Some(name)
+}
+
+// The flock of the blob that a temp file named like
+// `<stem>.<pid>-<n>-<time>.tmp` was written for
+fn tmp_flock(f: &Path) -> Option<PathBuf> {
+    let name = f.file_name()?.to_str()?.strip_suffix(".tmp")?;
+    let (stem, tag) = name.rsplit_once('.')?;
+    if tag.is_empty() || !tag.bytes().all(|b| b.is_ascii_hexdigit() || b == b'-') {
+        return None;
+    }
+    Some(f.with_file_name(format!("{}.flock", stem)))
+}
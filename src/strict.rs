@@ -0,0 +1,122 @@
+//! Rejecting blob files that parse, but not the way they read
+//!
+//! Maps and untyped values keep the last of duplicated keys, and
+//! serde skips fields it doesn't know, so a hand edit that repeats or
+//! misspells a key loads without complaint. `Builder::deny_duplicate_keys` fails
+//! loads of files with a key repeated in any object.
+//! `Builder::deny_unknown_fields` fails loads of files with a field
+//! that's lost when the parsed value is serialized again, like
+//! `#[serde(deny_unknown_fields)]` but decided at runtime, and
+//! reaching into types that didn't opt in.
+
+use serde::Serialize;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fmt;
+use super::{Parse, Result, ResultExt};
+
+/// Checks the first document in `buf`, which `v` was parsed from
+pub fn check<T>(buf: &[u8], v: &T, parse: Parse<'_>) -> Result<()>
+    where T: Serialize
+{
+    if parse.deny_duplicates {
+        first::<NoDuplicates>(buf).chain_err(|| "checking blobject for duplicate keys")?;
+    }
+    if parse.deny_unknown {
+        let file: Value = first(buf).chain_err(|| "checking blobject for unknown fields")?;
+        let ours = serde_json::to_value(v).chain_err(|| "checking blobject for unknown fields")?;
+        let mut path = String::new();
+        unknown(&file, &ours, &mut path)?;
+    }
+    Ok(())
+}
+
+fn first<T>(buf: &[u8]) -> serde_json::Result<T>
+    where for <'de> T: Deserialize<'de>
+{
+    serde_json::Deserializer::from_slice(buf).into_iter().next()
+        .unwrap_or_else(|| Err(de::Error::custom("no JSON document")))
+}
+
+fn unknown(file: &Value, ours: &Value, path: &mut String) -> Result<()> {
+    match (file, ours) {
+        (Value::Object(file), Value::Object(ours)) => {
+            for (k, v) in file {
+                let len = path.len();
+                path.push('.');
+                path.push_str(k);
+                match ours.get(k) {
+                    Some(ours) => unknown(v, ours, path)?,
+                    None => return Err(format!("unknown field {} in blobject", path).into()),
+                }
+                path.truncate(len);
+            }
+        }
+        (Value::Array(file), Value::Array(ours)) => {
+            for (i, (v, ours)) in file.iter().zip(ours).enumerate() {
+                let len = path.len();
+                path.push_str(&format!("[{}]", i));
+                unknown(v, ours, path)?;
+                path.truncate(len);
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+macro_rules! scalars {
+    ($($f:ident($t:ty),)*) => {
+        $(fn $f<E>(self, _: $t) -> std::result::Result<NoDuplicates, E> {
+            Ok(NoDuplicates)
+        })*
+    }
+}
+
+// Any JSON value whose objects have no repeated keys
+struct NoDuplicates;
+
+impl<'de> Deserialize<'de> for NoDuplicates {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<NoDuplicates, D::Error> {
+        d.deserialize_any(NoDuplicatesVisitor)
+    }
+}
+
+struct NoDuplicatesVisitor;
+
+impl<'de> Visitor<'de> for NoDuplicatesVisitor {
+    type Value = NoDuplicates;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<NoDuplicates, A::Error> {
+        let mut keys = HashSet::new();
+        while let Some(k) = map.next_key::<String>()? {
+            if !keys.insert(k.clone()) {
+                return Err(de::Error::custom(format!("duplicate key `{}`", k)));
+            }
+            map.next_value::<NoDuplicates>()?;
+        }
+        Ok(NoDuplicates)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<NoDuplicates, A::Error> {
+        while seq.next_element::<NoDuplicates>()?.is_some() {}
+        Ok(NoDuplicates)
+    }
+
+    scalars! {
+        visit_bool(bool),
+        visit_i64(i64),
+        visit_u64(u64),
+        visit_f64(f64),
+        visit_str(&str),
+    }
+
+    fn visit_unit<E>(self) -> std::result::Result<NoDuplicates, E> {
+        Ok(NoDuplicates)
+    }
+}
@@ -0,0 +1,168 @@
+//! Reading enums with variants added by newer versions
+//!
+//! When processes built from different versions share a blob, a newer
+//! one may commit an enum variant an older one doesn't know, and the
+//! older one would then fail to load the blob at all. An enum that
+//! implements `Evolving` designates a variant to stand in for the ones
+//! it doesn't know, holding the value as it was read, and a
+//! `Tolerant` enum, or a field with
+//! `#[serde(with = "atomic_blobject::tolerant")]`, reads an unknown
+//! variant as that stand-in. Serializing the stand-in
+//! writes the value back as it was, so an older process committing
+//! the blob doesn't lose what the newer one wrote.
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! enum Shape {
+//!     Circle(f64),
+//!     Square(f64),
+//!     #[serde(skip)]
+//!     Unknown(Value),
+//! }
+//!
+//! impl Evolving for Shape {
+//!     fn unknown(raw: Value) -> Shape { Shape::Unknown(raw) }
+//!     fn raw(&self) -> Option<&Value> {
+//!         match self { Shape::Unknown(raw) => Some(raw), _ => None }
+//!     }
+//! }
+//!
+//! #[derive(Serialize, Deserialize, Default)]
+//! struct Drawing {
+//!     shapes: Vec<Tolerant<Shape>>,
+//!     #[serde(with = "atomic_blobject::tolerant")]
+//!     background: Shape,
+//! }
+//! ```
+//!
+//! Only externally tagged enums, serde's default, are tolerated, and
+//! only their variants: a known variant whose content doesn't parse
+//! is still an error. Binary formats, like the sidecar, are only ever
+//! written by the current version, and are read as is, so a value
+//! holding a stand-in isn't written to them.
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::ser::Error as SerError;
+use serde::de::{Error as DeError, Visitor};
+use serde::de::value::Error as ProbeError;
+use serde_json::Value;
+use std::cell::Cell;
+use std::ops::{Deref, DerefMut};
+
+/// An enum with a variant standing in for those it doesn't know
+pub trait Evolving: Sized {
+    /// The stand-in for a variant this version doesn't know, holding
+    /// it as read
+    fn unknown(raw: Value) -> Self;
+
+    /// What a stand-in holds, or `None` for a known variant
+    fn raw(&self) -> Option<&Value>;
+}
+
+/// An enum read as its stand-in when the variant is unknown
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tolerant<T>(pub T);
+
+impl<T> Tolerant<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Tolerant<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Tolerant<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Evolving + Serialize> Serialize for Tolerant<T> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        serialize(&self.0, s)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Tolerant<T>
+    where T: Evolving + for <'a> Deserialize<'a>
+{
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Tolerant<T>, D::Error> {
+        deserialize(d).map(Tolerant)
+    }
+}
+
+/// Serializes `v`, or what it holds if it's the stand-in
+pub fn serialize<T, S>(v: &T, s: S) -> Result<S::Ok, S::Error>
+    where T: Evolving + Serialize, S: Serializer
+{
+    match v.raw() {
+        // It wouldn't read back as a `T`, so isn't cached
+        Some(_) if !s.is_human_readable() => {
+            Err(S::Error::custom("unknown variant in binary format"))
+        }
+        Some(raw) => raw.serialize(s),
+        None => v.serialize(s),
+    }
+}
+
+/// Deserializes a `T`, as its stand-in if the variant is unknown
+pub fn deserialize<'de, T, D>(d: D) -> Result<T, D::Error>
+    where T: Evolving + for <'a> Deserialize<'a>, D: Deserializer<'de>
+{
+    if !d.is_human_readable() {
+        return T::deserialize(d);
+    }
+    // Kept for the stand-in
+    let v = Value::deserialize(d)?;
+    let variant = match v {
+        Value::String(ref tag) => Some(tag),
+        Value::Object(ref m) if m.len() == 1 => m.keys().next(),
+        _ => None,
+    };
+    match (variant, variants::<T>()) {
+        (Some(tag), Some(known)) if !known.contains(&tag.as_str()) => {
+            debug!("reading unknown variant {} as a stand-in", tag);
+            Ok(T::unknown(v))
+        }
+        _ => T::deserialize(v).map_err(D::Error::custom),
+    }
+}
+
+// The variants `T` deserializes, if it's an externally tagged enum.
+// Derived impls name them when asking for an enum, so a deserializer
+// that only records them finds out.
+fn variants<T: for <'a> Deserialize<'a>>() -> Option<&'static [&'static str]> {
+    let probe = Probe(Cell::new(None));
+    let _ = T::deserialize(&probe);
+    probe.0.get()
+}
+
+struct Probe(Cell<Option<&'static [&'static str]>>);
+
+impl<'de> Deserializer<'de> for &Probe {
+    type Error = ProbeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeError> {
+        Err(DeError::custom("not an enum"))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str,
+                                         variants: &'static [&'static str], _visitor: V)
+                                         -> Result<V::Value, ProbeError>
+    {
+        self.0.set(Some(variants));
+        Err(DeError::custom("probed enum"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
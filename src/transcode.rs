@@ -0,0 +1,239 @@
+//! Converting blob files between formats as they stream
+//!
+//! `convert` reads a blob file in one format and writes it in another
+//! without building the value: each number, string, sequence and map
+//! is written as soon as it's read, so a blob of any size converts in
+//! little memory, and without the value type. `transcode` does the
+//! same from any self-describing serde deserializer to any
+//! serializer.
+//!
+//! `Json` is built in. Other formats plug in through `Source` and
+//! `Sink`, a few lines over their crates. For CBOR with `ciborium`:
+//!
+//! ```ignore
+//! struct Cbor;
+//!
+//! impl Source for Cbor {
+//!     fn transcode<S: Serializer>(&self, r: &mut dyn BufRead, s: S)
+//!                                 -> StdResult<S::Ok, String> {
+//!         let mut d = ciborium::de::Deserializer::from_reader(r);
+//!         transcode(&mut d, s).map_err(|e| e.to_string())
+//!     }
+//! }
+//! ```
+//!
+//! and a `Sink` hands `src.transcode` its serializer the same way.
+//! Self-describing formats, like CBOR and MessagePack, can be
+//! sources; bincode can only be a sink.
+
+use serde::{Deserializer, Serialize, Serializer};
+use serde::de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, SerializeMap, SerializeSeq};
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::result::Result as StdResult;
+use super::{Error, Result, ResultExt, Store, ser_write};
+use super::flock::{DirtyFlock, FlockGuard};
+use super::vfs::{Fs, RealFs};
+
+// Read and written in pieces this big
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A format values can be read from
+pub trait Source {
+    /// Reads one value from `r`, writing it to `s` as it goes
+    fn transcode<S: Serializer>(&self, r: &mut dyn BufRead, s: S) -> StdResult<S::Ok, String>;
+}
+
+/// A format values can be written in
+pub trait Sink {
+    /// Reads one value from `r` in `src`'s format, writing it to `w`
+    /// as it goes
+    fn convert<Src: Source>(&self, src: &Src, r: &mut dyn BufRead, w: &mut dyn Write)
+                            -> StdResult<(), String>;
+}
+
+/// JSON, as blobs are stored
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl Source for Json {
+    fn transcode<S: Serializer>(&self, r: &mut dyn BufRead, s: S) -> StdResult<S::Ok, String> {
+        let mut d = serde_json::Deserializer::from_reader(r);
+        let ok = transcode(&mut d, s).map_err(|e| e.to_string())?;
+        d.end().map_err(|e| e.to_string())?;
+        Ok(ok)
+    }
+}
+
+impl Sink for Json {
+    fn convert<Src: Source>(&self, src: &Src, r: &mut dyn BufRead, w: &mut dyn Write)
+                            -> StdResult<(), String> {
+        src.transcode(r, &mut serde_json::Serializer::new(w))
+    }
+}
+
+/// Converts the blob file at `src` from `src_format` to `dst_format`,
+/// replacing `dst` with the result
+///
+/// The conversion holds the shared flock on `src` and the exclusive
+/// flock on `dst`, so it reads a whole commit, and handles on `dst`
+/// reload afterwards. `dst` is replaced by rename, and may be `src`
+/// itself. Only `Strategy::Rename` blobs can be converted.
+pub fn convert<S, D>(src: &Path, src_format: S, dst: &Path, dst_format: D) -> Result<()>
+    where S: Source, D: Sink
+{
+    let fs = RealFs;
+    let flock = |p: &Path| DirtyFlock::new(fs.lock_path(&p.with_extension("flock")), None);
+    let (src_flock, dst_flock) = (flock(src), flock(dst));
+    let _dst_flock = FlockGuard::exclusive(&dst_flock)?;
+    // The exclusive flock covers reading too
+    let _src_flock = if src_flock.path() == dst_flock.path() {
+        None
+    } else {
+        Some(FlockGuard::shared(&src_flock)?)
+    };
+
+    let (r, _) = fs.open(src).chain_err(|| "opening blobject to convert")?
+        .ok_or_else(|| format!("no blobject to convert at {}", src.display()))?;
+    let mut r = BufReader::with_capacity(BUFFER_SIZE, r);
+    let store = Store { buffer_size: Some(BUFFER_SIZE), ..Store::default() };
+    ser_write(&fs, dst, store, None, |w| {
+        dst_format.convert(&src_format, &mut r, w)
+            .map_err(|e| Error::from(format!("converting blobject: {}", e)))
+    })?;
+    fs.sync(dst).chain_err(|| "syncing converted blobject")?;
+
+    debug!("converted blobject {} to {}", src.display(), dst.display());
+
+    Ok(())
+}
+
+/// Writes whatever `d` reads to `s`, as it reads it
+pub fn transcode<'de, D, S>(d: D, s: S) -> StdResult<S::Ok, S::Error>
+    where D: Deserializer<'de>, S: Serializer
+{
+    Transcoder::new(d).serialize(s)
+}
+
+// Serializes by deserializing, once
+struct Transcoder<D>(RefCell<Option<D>>);
+
+impl<D> Transcoder<D> {
+    fn new(d: D) -> Transcoder<D> {
+        Transcoder(RefCell::new(Some(d)))
+    }
+}
+
+impl<'de, D: Deserializer<'de>> Serialize for Transcoder<D> {
+    fn serialize<S: Serializer>(&self, s: S) -> StdResult<S::Ok, S::Error> {
+        let d = self.0.borrow_mut().take()
+            .ok_or_else(|| ser::Error::custom("transcoding a value twice"))?;
+        d.deserialize_any(Visit(s)).map_err(ser::Error::custom)
+    }
+}
+
+// Writes each thing visited to the serializer, failing with the
+// deserializer's error type
+struct Visit<S>(S);
+
+fn failed<E: fmt::Display, F: de::Error>(e: E) -> F {
+    F::custom(e)
+}
+
+macro_rules! forward {
+    ($($visit:ident($t:ty) => $ser:ident,)*) => {
+        $(fn $visit<E: de::Error>(self, v: $t) -> StdResult<S::Ok, E> {
+            self.0.$ser(v).map_err(failed)
+        })*
+    }
+}
+
+impl<'de, S: Serializer> Visitor<'de> for Visit<S> {
+    type Value = S::Ok;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("any value")
+    }
+
+    forward! {
+        visit_bool(bool) => serialize_bool,
+        visit_i8(i8) => serialize_i8,
+        visit_i16(i16) => serialize_i16,
+        visit_i32(i32) => serialize_i32,
+        visit_i64(i64) => serialize_i64,
+        visit_i128(i128) => serialize_i128,
+        visit_u8(u8) => serialize_u8,
+        visit_u16(u16) => serialize_u16,
+        visit_u32(u32) => serialize_u32,
+        visit_u64(u64) => serialize_u64,
+        visit_u128(u128) => serialize_u128,
+        visit_f32(f32) => serialize_f32,
+        visit_f64(f64) => serialize_f64,
+        visit_char(char) => serialize_char,
+        visit_str(&str) => serialize_str,
+        visit_bytes(&[u8]) => serialize_bytes,
+    }
+
+    fn visit_none<E: de::Error>(self) -> StdResult<S::Ok, E> {
+        self.0.serialize_none().map_err(failed)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, d: D) -> StdResult<S::Ok, D::Error> {
+        self.0.serialize_some(&Transcoder::new(d)).map_err(failed)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> StdResult<S::Ok, E> {
+        self.0.serialize_unit().map_err(failed)
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(self, d: D) -> StdResult<S::Ok, D::Error> {
+        self.0.serialize_newtype_struct("<transcoded>", &Transcoder::new(d)).map_err(failed)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> StdResult<S::Ok, A::Error> {
+        let mut out = self.0.serialize_seq(seq.size_hint()).map_err(failed)?;
+        while seq.next_element_seed(Element(&mut out))?.is_some() {}
+        out.end().map_err(failed)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> StdResult<S::Ok, A::Error> {
+        let mut out = self.0.serialize_map(map.size_hint()).map_err(failed)?;
+        while map.next_key_seed(Key(&mut out))?.is_some() {
+            map.next_value_seed(Value(&mut out))?;
+        }
+        out.end().map_err(failed)
+    }
+}
+
+struct Element<'a, S: 'a>(&'a mut S);
+
+impl<'de, 'a, S: SerializeSeq> DeserializeSeed<'de> for Element<'a, S> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> StdResult<(), D::Error> {
+        self.0.serialize_element(&Transcoder::new(d)).map_err(failed)
+    }
+}
+
+struct Key<'a, S: 'a>(&'a mut S);
+
+impl<'de, 'a, S: SerializeMap> DeserializeSeed<'de> for Key<'a, S> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> StdResult<(), D::Error> {
+        self.0.serialize_key(&Transcoder::new(d)).map_err(failed)
+    }
+}
+
+struct Value<'a, S: 'a>(&'a mut S);
+
+impl<'de, 'a, S: SerializeMap> DeserializeSeed<'de> for Value<'a, S> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> StdResult<(), D::Error> {
+        self.0.serialize_value(&Transcoder::new(d)).map_err(failed)
+    }
+}
@@ -0,0 +1,523 @@
+//! Committing through io_uring on Linux
+//!
+//! A small blob's commit is mostly syscalls: write the temp file,
+//! sync it, rename it over the blob file, each one a trip into the
+//! kernel. `UringFs` submits the three together, linked so each runs
+//! only once the one before it succeeds, and waits for all of them in
+//! one `io_uring_enter`. Syncing a blob, as `Builder::background_sync`
+//! does, likewise syncs the file and its directory in one submission.
+//! The file is synced before the rename, so after a crash the blob
+//! file is always a whole commit, though until the directory is
+//! synced it may be the one before.
+//!
+//! `Builder::io_uring` opens a blob this way. Where io_uring isn't
+//! there, as on kernels before 5.11 or under seccomp policies that
+//! deny it, or an operation isn't supported, `UringFs` does the same
+//! with ordinary syscalls, like `RealFs`.
+
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicU32, Ordering};
+use super::vfs::{FileId, Fs, RealFs};
+
+/// The host filesystem, committing through io_uring
+#[derive(Default)]
+pub struct UringFs {
+    // Set up on first use, or `None` if io_uring isn't available
+    ring: OnceLock<Option<Mutex<Ring>>>,
+}
+
+impl UringFs {
+    pub fn new() -> UringFs {
+        UringFs::default()
+    }
+
+    fn ring(&self) -> Option<&Mutex<Ring>> {
+        self.ring.get_or_init(|| match Ring::new(ENTRIES) {
+            Ok(r) => Some(Mutex::new(r)),
+            Err(e) => {
+                debug!("committing blobjects without io_uring: {}", e);
+                None
+            }
+        }).as_ref()
+    }
+}
+
+impl Fs for UringFs {
+    fn open(&self, p: &Path) -> io::Result<Option<(Box<dyn Read>, FileId)>> {
+        RealFs.open(p)
+    }
+
+    fn id(&self, p: &Path) -> io::Result<Option<FileId>> {
+        RealFs.id(p)
+    }
+
+    fn create(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        RealFs.create(p)
+    }
+
+    fn create_new(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        RealFs.create_new(p)
+    }
+
+    fn create_sized(&self, p: &Path, size: u64) -> io::Result<Box<dyn Write>> {
+        RealFs.create_sized(p, size)
+    }
+
+    fn append(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        RealFs.append(p)
+    }
+
+    fn rename(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        RealFs.rename(src, dst)
+    }
+
+    fn replace(&self, tmp: &Path, p: &Path, buf: &[u8]) -> io::Result<()> {
+        let f = File::create(tmp)?;
+        let ring = match self.ring() {
+            Some(r) => r,
+            None => {
+                f.write_all_at(buf, 0)?;
+                f.sync_all()?;
+                return RealFs.rename(tmp, p);
+            }
+        };
+        let (from, to) = (c_path(tmp)?, c_path(p)?);
+        let len = u32::try_from(buf.len()).unwrap_or(u32::MAX);
+        let ops = [
+            Op::Write(f.as_raw_fd(), &buf[..len as usize]),
+            Op::Fsync(f.as_raw_fd()),
+            Op::Rename(&from, &to),
+        ];
+        let res = ring.lock().map_err(|_| io::Error::other("poisoned blobject ring"))?.run(&ops, true)?;
+
+        // A short write breaks the chain, so the rest is done here
+        let written = settle(res[0], || Ok(0), |n| n as usize)?;
+        if written < buf.len() {
+            f.write_all_at(&buf[written..], written as u64)?;
+        }
+        settle(res[1], || f.sync_all(), |_| ())?;
+        settle(res[2], || RealFs.rename(tmp, p), |_| ())
+    }
+
+    fn remove(&self, p: &Path) -> io::Result<()> {
+        RealFs.remove(p)
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        RealFs.link(src, dst)
+    }
+
+    fn copy(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        RealFs.copy(src, dst)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        RealFs.read_dir(dir)
+    }
+
+    fn walk(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        RealFs.walk(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        RealFs.create_dir_all(dir)
+    }
+
+    fn sync(&self, p: &Path) -> io::Result<()> {
+        let ring = match self.ring() {
+            Some(r) => r,
+            None => return RealFs.sync(p),
+        };
+        let f = match File::open(p) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            r => r?,
+        };
+        let dir = match p.parent() {
+            Some(d) if d != Path::new("") => d,
+            _ => Path::new("."),
+        };
+        let dir = File::open(dir)?;
+        let ops = [Op::Fsync(f.as_raw_fd()), Op::Fsync(dir.as_raw_fd())];
+        let res = ring.lock().map_err(|_| io::Error::other("poisoned blobject ring"))?.run(&ops, false)?;
+        settle(res[0], || f.sync_all(), |_| ())?;
+        settle(res[1], || dir.sync_all(), |_| ())
+    }
+
+    fn available(&self, p: &Path) -> io::Result<Option<u64>> {
+        RealFs.available(p)
+    }
+
+    fn canonicalize(&self, p: &Path) -> io::Result<PathBuf> {
+        RealFs.canonicalize(p)
+    }
+}
+
+fn c_path(p: &Path) -> io::Result<CString> {
+    CString::new(p.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+// The result of an operation, or if it was cancelled or isn't
+// supported, the result of `redo`
+fn settle<R, F, G>(res: i32, redo: F, ok: G) -> io::Result<R>
+    where F: FnOnce() -> io::Result<R>, G: FnOnce(u32) -> R,
+{
+    if res >= 0 {
+        return Ok(ok(res as u32));
+    }
+    match -res {
+        libc::ECANCELED | libc::EINVAL | libc::EOPNOTSUPP => redo(),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+// Enough for any one submission
+const ENTRIES: u32 = 4;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IOSQE_IO_LINK: u8 = 1 << 2;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_WRITE: u8 = 23;
+const IORING_OP_RENAMEAT: u8 = 35;
+
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)] // Laid out for the kernel
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)] // Laid out for the kernel
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)] // Laid out for the kernel
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)] // Laid out for the kernel
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    file_index: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+#[allow(dead_code)] // Laid out for the kernel
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+enum Op<'a> {
+    Write(RawFd, &'a [u8]),
+    Fsync(RawFd),
+    Rename(&'a CString, &'a CString),
+}
+
+impl<'a> Op<'a> {
+    fn sqe(&self) -> Sqe {
+        match *self {
+            Op::Write(fd, buf) => Sqe {
+                opcode: IORING_OP_WRITE,
+                fd,
+                off: 0,
+                addr: buf.as_ptr() as u64,
+                len: buf.len() as u32,
+                ..Sqe::default()
+            },
+            Op::Fsync(fd) => Sqe { opcode: IORING_OP_FSYNC, fd, ..Sqe::default() },
+            Op::Rename(from, to) => Sqe {
+                opcode: IORING_OP_RENAMEAT,
+                fd: libc::AT_FDCWD,
+                addr: from.as_ptr() as u64,
+                len: libc::AT_FDCWD as u32,
+                off: to.as_ptr() as u64,
+                ..Sqe::default()
+            },
+        }
+    }
+}
+
+// A mapped region of the ring
+struct Map(*mut libc::c_void, usize);
+
+impl Map {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Map> {
+        let p = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+        };
+        if p == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Map(p, len))
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { (self.0 as *mut u8).add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.0, self.1) };
+    }
+}
+
+struct Ring {
+    // NB: Unmapped before the ring is closed
+    sq: Map,
+    cq: Map,
+    sqes: Map,
+    params: Params,
+    fd: OwnedFd,
+}
+
+// The maps are only touched under the `UringFs` mutex
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let raw = fd.as_raw_fd();
+        let (sq_off, cq_off) = (&params.sq_off, &params.cq_off);
+        let sq_len = sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>();
+        let cq_len = cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * mem::size_of::<Sqe>();
+        Ok(Ring {
+            sq: Map::new(raw, sq_len, IORING_OFF_SQ_RING)?,
+            cq: Map::new(raw, cq_len, IORING_OFF_CQ_RING)?,
+            sqes: Map::new(raw, sqes_len, IORING_OFF_SQES)?,
+            params,
+            fd,
+        })
+    }
+
+    // Submits `ops`, each linked to the next if `linked`, and waits
+    // for them all, returning their results in order
+    fn run(&mut self, ops: &[Op<'_>], linked: bool) -> io::Result<Vec<i32>> {
+        let n = ops.len() as u32;
+        assert!(n <= self.params.sq_entries, "too many blobject ring operations");
+        let sq_off = &self.params.sq_off;
+        unsafe {
+            let tail = &*self.sq.at::<AtomicU32>(sq_off.tail);
+            let mask = *self.sq.at::<u32>(sq_off.ring_mask);
+            let array = self.sq.at::<u32>(sq_off.array);
+            let sqes = self.sqes.at::<Sqe>(0);
+            let mut t = tail.load(Ordering::Relaxed);
+            for (i, op) in ops.iter().enumerate() {
+                let mut sqe = op.sqe();
+                sqe.user_data = i as u64;
+                if linked && i + 1 < ops.len() {
+                    sqe.flags |= IOSQE_IO_LINK;
+                }
+                let idx = t & mask;
+                ptr::write(sqes.add(idx as usize), sqe);
+                *array.add(idx as usize) = idx;
+                t = t.wrapping_add(1);
+            }
+            tail.store(t, Ordering::Release);
+        }
+
+        let mut res = vec![0; ops.len()];
+        let (mut submit, mut done) = (n, 0);
+        while done < n {
+            let r = unsafe {
+                libc::syscall(libc::SYS_io_uring_enter, self.fd.as_raw_fd(), submit, n - done,
+                              IORING_ENTER_GETEVENTS, ptr::null::<libc::sigset_t>(), 0)
+            };
+            if r < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    // Nothing was submitted, and what's queued points
+                    // into buffers about to be freed
+                    self.unqueue();
+                    return Err(e);
+                }
+            } else {
+                submit = submit.saturating_sub(r as u32);
+            }
+            done += self.reap(&mut res);
+        }
+        Ok(res)
+    }
+
+    fn unqueue(&mut self) {
+        let sq_off = &self.params.sq_off;
+        unsafe {
+            let head = (*self.sq.at::<AtomicU32>(sq_off.head)).load(Ordering::Acquire);
+            (*self.sq.at::<AtomicU32>(sq_off.tail)).store(head, Ordering::Release);
+        }
+    }
+
+    // Takes every completion there is, returning how many
+    fn reap(&mut self, res: &mut [i32]) -> u32 {
+        let cq_off = &self.params.cq_off;
+        unsafe {
+            let head = &*self.cq.at::<AtomicU32>(cq_off.head);
+            let tail = (*self.cq.at::<AtomicU32>(cq_off.tail)).load(Ordering::Acquire);
+            let mask = *self.cq.at::<u32>(cq_off.ring_mask);
+            let cqes = self.cq.at::<Cqe>(cq_off.cqes);
+            let mut h = head.load(Ordering::Relaxed);
+            let mut n = 0;
+            while h != tail {
+                let cqe = &*cqes.add((h & mask) as usize);
+                if let Some(r) = res.get_mut(cqe.user_data as usize) {
+                    *r = cqe.res;
+                }
+                h = h.wrapping_add(1);
+                n += 1;
+            }
+            head.store(h, Ordering::Release);
+            n
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blobject-uring-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn layouts_match_the_kernel() {
+        assert_eq!(mem::size_of::<Sqe>(), 64);
+        assert_eq!(mem::size_of::<Cqe>(), 16);
+        assert_eq!(mem::size_of::<SqringOffsets>(), 40);
+        assert_eq!(mem::size_of::<CqringOffsets>(), 40);
+        assert_eq!(mem::size_of::<Params>(), 120);
+    }
+
+    #[test]
+    fn settles() {
+        assert_eq!(settle(5, || Ok(0), |n| n).unwrap(), 5);
+        assert_eq!(settle(-libc::ECANCELED, || Ok(7), |n| n).unwrap(), 7);
+        assert_eq!(settle(-libc::EINVAL, || Ok(7), |n| n).unwrap(), 7);
+        let e = settle(-libc::ENOENT, || Ok(7), |n| n).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn runs_linked_ops() {
+        let mut ring = match Ring::new(ENTRIES) {
+            Ok(ring) => ring,
+            // Not allowed here, which `UringFs` falls back from
+            Err(_) => return,
+        };
+        let d = dir("ops");
+        let f = File::create(d.join("f")).unwrap();
+        let (from, to) = (c_path(&d.join("f")).unwrap(), c_path(&d.join("g")).unwrap());
+        let ops = [Op::Write(f.as_raw_fd(), b"hello"), Op::Fsync(f.as_raw_fd()),
+                   Op::Rename(&from, &to)];
+        let res = ring.run(&ops, true).unwrap();
+        assert_eq!(res[0], 5);
+        assert!(res[1] == 0 || res[1] == -libc::EINVAL, "{:?}", res);
+        if res[2] == 0 {
+            assert_eq!(fs::read(d.join("g")).unwrap(), b"hello");
+        }
+        // A failed op cancels those linked after it
+        let ops = [Op::Write(-1, b"x"), Op::Fsync(f.as_raw_fd())];
+        assert_eq!(ring.run(&ops, true).unwrap(), [-libc::EBADF, -libc::ECANCELED]);
+        // Except a rename, which the kernel never fails a link for,
+        // so it can only come last
+        let missing = c_path(&d.join("missing")).unwrap();
+        let ops = [Op::Rename(&missing, &to), Op::Fsync(f.as_raw_fd())];
+        assert_eq!(ring.run(&ops, true).unwrap()[0], -libc::ENOENT);
+        // The ring is reusable after each run
+        for _ in 0..ENTRIES * 2 {
+            assert_eq!(ring.run(&[Op::Fsync(f.as_raw_fd())], false).unwrap().len(), 1);
+        }
+        let _ = fs::remove_dir_all(&d);
+    }
+
+    #[test]
+    fn replaces_and_syncs() {
+        let d = dir("replace");
+        let fs_ = UringFs::new();
+        let (tmp, p) = (d.join("blob.tmp"), d.join("blob.json"));
+        for i in 0..3 {
+            let buf = format!("[{}]", i).repeat(1000);
+            fs_.replace(&tmp, &p, buf.as_bytes()).unwrap();
+            assert_eq!(fs::read_to_string(&p).unwrap(), buf);
+            assert!(!tmp.exists());
+        }
+        fs_.sync(&p).unwrap();
+        // Nothing to sync isn't a failure
+        fs_.sync(&d.join("missing.json")).unwrap();
+        let _ = fs::remove_dir_all(&d);
+    }
+}
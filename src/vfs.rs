@@ -0,0 +1,1214 @@
+//! The filesystem a blob is stored on
+//!
+//! Handles use `RealFs` unless opened with `Builder::fs`. `DirFs`
+//! reaches files relative to a directory opened once, for sandboxed
+//! processes that can't open paths. `MockFs` keeps files in memory,
+//! so code using blobs can be tested without touching the disk.
+//! Handles on an in-process filesystem like `MockFs` lock each other
+//! through an in-memory table instead of lock files, and otherwise
+//! behave like handles in separate processes sharing a directory.
+//! With the `fault-injection` feature, `fault::FaultyFs` fails chosen
+//! calls of another filesystem.
+
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+#[cfg(unix)]
+use std::path::Component;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use super::{atomic_file_rename, sync_contents, sync_dir, sync_file};
+
+pub trait Fs: Send + Sync {
+    /// Opens the file at `p` with its id, or `None` if there isn't one
+    fn open(&self, p: &Path) -> io::Result<Option<(Box<dyn Read>, FileId)>>;
+
+    /// The id of the file at `p`, or `None` if there isn't one
+    fn id(&self, p: &Path) -> io::Result<Option<FileId>>;
+
+    /// Creates or truncates the file at `p`
+    fn create(&self, p: &Path) -> io::Result<Box<dyn Write>>;
+
+    /// Like `create`, failing with `AlreadyExists` if there's a file
+    /// at `p`, as tmp files are made so two writers don't share one
+    ///
+    /// By default `create` if `id` finds no file, which another
+    /// process can race.
+    fn create_new(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        if self.id(p)?.is_some() {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        self.create(p)
+    }
+
+    /// Like `create`, reserving disk space for `size` bytes up front
+    /// if the filesystem can
+    ///
+    /// Fails if the space isn't available. The file is still empty
+    /// until written.
+    fn create_sized(&self, p: &Path, size: u64) -> io::Result<Box<dyn Write>> {
+        let _ = size;
+        self.create(p)
+    }
+
+    /// Like `create`, writing around the page cache where the
+    /// filesystem can, and reserving `size` bytes if given, as
+    /// `create_sized` does
+    ///
+    /// By default `create` or `create_sized`.
+    fn create_direct(&self, p: &Path, size: Option<u64>) -> io::Result<Box<dyn Write>> {
+        match size {
+            Some(n) => self.create_sized(p, n),
+            None => self.create(p),
+        }
+    }
+
+    /// Opens the file at `p` for writing at its end, creating it if
+    /// there isn't one
+    ///
+    /// By default the file is rewritten with its old contents first,
+    /// which is only fit for small files.
+    fn append(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        let mut old = Vec::new();
+        if let Some((mut r, _)) = self.open(p)? {
+            r.read_to_end(&mut old)?;
+        }
+        let mut w = self.create(p)?;
+        w.write_all(&old)?;
+        Ok(w)
+    }
+
+    /// Replaces `dst` with `src`, atomically for readers of `dst`
+    fn rename(&self, src: &Path, dst: &Path) -> io::Result<()>;
+
+    /// Writes `buf` to a new file at `tmp`, then renames it over `p`,
+    /// as commits do when the value is already serialized
+    ///
+    /// By default with `create` and `rename`. A filesystem may also
+    /// sync the file before it's renamed.
+    fn replace(&self, tmp: &Path, p: &Path, buf: &[u8]) -> io::Result<()> {
+        let mut w = self.create(tmp)?;
+        w.write_all(buf)?;
+        w.flush()?;
+        drop(w);
+        self.rename(tmp, p)
+    }
+
+    fn remove(&self, p: &Path) -> io::Result<()>;
+
+    /// Makes `dst` another name for the file at `src`
+    ///
+    /// By default the file is copied. Copies should keep the file's
+    /// modification time, which dates kept generations.
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let (mut r, _) = self.open(src)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let mut w = self.create(dst)?;
+        io::copy(&mut r, &mut w)?;
+        w.flush()
+    }
+
+    /// Copies the file at `src` to `dst`, sharing its storage where
+    /// the filesystem can
+    ///
+    /// By default the file is read and written.
+    fn copy(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let (mut r, _) = self.open(src)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let mut w = self.create(dst)?;
+        io::copy(&mut r, &mut w)?;
+        w.flush()
+    }
+
+    /// The paths of the files in `dir`, where `""` is the current
+    /// directory
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// The paths of the files under `dir`, at any depth
+    ///
+    /// By default only the files directly in `dir`.
+    fn walk(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.read_dir(dir)
+    }
+
+    /// Creates the directory `dir` and any missing parents
+    ///
+    /// By default does nothing, for filesystems without directories.
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let _ = dir;
+        Ok(())
+    }
+
+    /// Makes the file at `p`, and its name, durable
+    fn sync(&self, p: &Path) -> io::Result<()>;
+
+    /// Makes the file at `p` durable, leaving its name to `sync_dir`
+    ///
+    /// By default `sync`, which does both.
+    fn sync_contents(&self, p: &Path) -> io::Result<()> {
+        self.sync(p)
+    }
+
+    /// Makes the names of the files in `dir` durable, where `""` is
+    /// the current directory
+    ///
+    /// By default does nothing, for filesystems whose `sync_contents`
+    /// covers names too.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let _ = dir;
+        Ok(())
+    }
+
+    /// The bytes free for writing new files beside `p`, or `None` if
+    /// the filesystem can't tell
+    fn available(&self, p: &Path) -> io::Result<Option<u64>> {
+        let _ = p;
+        Ok(None)
+    }
+
+    /// The path that every path naming the same file as `p` maps to,
+    /// even if there's no file there yet
+    fn canonicalize(&self, p: &Path) -> io::Result<PathBuf>;
+
+    /// The path the lock file named `p` is opened at
+    ///
+    /// Lock files are always opened by path, by default `p` itself.
+    fn lock_path(&self, p: &Path) -> PathBuf {
+        p.to_owned()
+    }
+
+    /// For a filesystem that only this process can see, a key unique
+    /// to it
+    ///
+    /// Locks on such a filesystem are kept in memory under this key,
+    /// and aren't visible to handles on any other filesystem.
+    fn in_process(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Identifies one version of a file
+///
+/// Every commit renames a fresh file into place, so a matching id
+/// means the file hasn't been replaced since it was last read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileId(Repr);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Repr {
+    Disk {
+        len: u64,
+        modified: Option<SystemTime>,
+        #[cfg(unix)]
+        dev: u64,
+        #[cfg(unix)]
+        ino: u64,
+    },
+    Counter(u64),
+}
+
+impl FileId {
+    pub fn of(m: &fs::Metadata) -> FileId {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        FileId(Repr::Disk {
+            len: m.len(),
+            modified: m.modified().ok(),
+            #[cfg(unix)]
+            dev: m.dev(),
+            #[cfg(unix)]
+            ino: m.ino(),
+        })
+    }
+
+    /// For filesystems that number each file they create
+    pub fn counter(n: u64) -> FileId {
+        FileId(Repr::Counter(n))
+    }
+
+    /// When the file was last written, if known
+    pub fn modified(&self) -> Option<SystemTime> {
+        match self.0 {
+            Repr::Disk { modified, .. } => modified,
+            Repr::Counter(_) => None,
+        }
+    }
+
+    /// The size of the file in bytes, if known
+    pub fn size(&self) -> Option<u64> {
+        match self.0 {
+            Repr::Disk { len, .. } => Some(len),
+            Repr::Counter(_) => None,
+        }
+    }
+}
+
+/// The filesystem of the host, via `std::fs`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFs;
+
+impl Fs for RealFs {
+    fn open(&self, p: &Path) -> io::Result<Option<(Box<dyn Read>, FileId)>> {
+        let f = match File::open(p) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            r => r?,
+        };
+        let id = FileId::of(&f.metadata()?);
+        Ok(Some((Box::new(BufReader::new(f)), id)))
+    }
+
+    fn id(&self, p: &Path) -> io::Result<Option<FileId>> {
+        match fs::metadata(p) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            r => Ok(Some(FileId::of(&r?))),
+        }
+    }
+
+    fn create(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        Ok(Box::new(BufWriter::new(File::create(p)?)))
+    }
+
+    fn create_new(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        let f = fs::OpenOptions::new().write(true).create_new(true).open(p)?;
+        Ok(Box::new(BufWriter::new(f)))
+    }
+
+    fn create_sized(&self, p: &Path, size: u64) -> io::Result<Box<dyn Write>> {
+        let f = File::create(p)?;
+        if let Err(e) = preallocate(&f, size) {
+            drop(f);
+            let _ = fs::remove_file(p);
+            return Err(e);
+        }
+        Ok(Box::new(BufWriter::new(f)))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_direct(&self, p: &Path, size: Option<u64>) -> io::Result<Box<dyn Write>> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let (f, direct) = open_direct(|flags| {
+            fs::OpenOptions::new().write(true).create(true).truncate(true)
+                .custom_flags(flags).open(p)
+        })?;
+        if let Some(size) = size {
+            if let Err(e) = preallocate(&f, size) {
+                drop(f);
+                let _ = fs::remove_file(p);
+                return Err(e);
+            }
+        }
+        Ok(direct_writer(f, direct))
+    }
+
+    fn append(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        let f = fs::OpenOptions::new().append(true).create(true).open(p)?;
+        Ok(Box::new(BufWriter::new(f)))
+    }
+
+    fn rename(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        atomic_file_rename(src, dst)
+    }
+
+    fn remove(&self, p: &Path) -> io::Result<()> {
+        fs::remove_file(p)
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        if fs::hard_link(src, dst).is_ok() {
+            return Ok(());
+        }
+        // Not every filesystem has hard links, and they can't cross
+        // filesystems
+        self.copy(src, dst)
+    }
+
+    fn copy(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        copy_file(&File::open(src)?, &File::create(dst)?)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let entries = if dir == Path::new("") {
+            fs::read_dir(".")?
+        } else {
+            fs::read_dir(dir)?
+        };
+        entries.map(|e| e.map(|e| dir.join(e.file_name()))).collect()
+    }
+
+    fn walk(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        let mut dirs = vec![dir.to_owned()];
+        while let Some(dir) = dirs.pop() {
+            let entries = if dir == Path::new("") {
+                fs::read_dir(".")?
+            } else {
+                fs::read_dir(&dir)?
+            };
+            for e in entries {
+                let e = e?;
+                let path = dir.join(e.file_name());
+                if e.file_type()?.is_dir() {
+                    dirs.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn sync(&self, p: &Path) -> io::Result<()> {
+        sync_file(p)
+    }
+
+    fn sync_contents(&self, p: &Path) -> io::Result<()> {
+        sync_contents(p).map(|_| ())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        sync_dir(dir)
+    }
+
+    fn available(&self, p: &Path) -> io::Result<Option<u64>> {
+        available(p)
+    }
+
+    fn canonicalize(&self, p: &Path) -> io::Result<PathBuf> {
+        match fs::canonicalize(p) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let name = p.file_name()
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+                let dir = match p.parent() {
+                    Some(d) if d != Path::new("") => d,
+                    _ => Path::new("."),
+                };
+                Ok(fs::canonicalize(dir)?.join(name))
+            }
+            r => r,
+        }
+    }
+
+    #[cfg(target_os = "wasi")]
+    fn in_process(&self) -> Option<u64> {
+        // See `local_flock`
+        Some(0)
+    }
+}
+
+/// The files under one directory, reached relative to it with the
+/// `*at` system calls
+///
+/// The directory is opened once, by `DirFs::open`, and blob paths are
+/// taken relative to it: they can't be absolute or contain `..`. The
+/// process can then give up access to paths, with landlock, seccomp
+/// or capsicum, except that lock files are still opened by path, in
+/// the directory as it was named when opened.
+/// `Builder::follow_symlinks` resolves by path too, and mustn't be
+/// used with it.
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct DirFs {
+    dir: Arc<File>,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl DirFs {
+    /// Opens the directory `dir`
+    pub fn open<P>(dir: P) -> io::Result<DirFs>
+        where P: AsRef<Path>
+    {
+        let path = fs::canonicalize(dir)?;
+        let c = c_path(&path)?;
+        let fd = cvt(unsafe {
+            libc::open(c.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC)
+        })?;
+        Ok(DirFs {
+            dir: Arc::new(unsafe { File::from_raw_fd(fd) }),
+            path,
+        })
+    }
+
+    /// Takes over an already open directory, such as one from
+    /// `cap_std::fs::Dir`, without naming it
+    ///
+    /// Lock files are opened by the directory's path, which is looked
+    /// up from the descriptor: through `/proc/self/fd` on Linux and
+    /// `F_GETPATH` on macOS. Elsewhere this fails.
+    pub fn from_fd(dir: OwnedFd) -> io::Result<DirFs> {
+        let dir = File::from(dir);
+        if !dir.metadata()?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "blobject directory fd isn't a directory"));
+        }
+        let path = fd_path(&dir)?;
+        Ok(DirFs {
+            dir: Arc::new(dir),
+            path,
+        })
+    }
+
+    /// The directory, as it was named when opened
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn fd(&self) -> RawFd {
+        self.dir.as_raw_fd()
+    }
+
+    fn open_at(&self, p: &Path, flags: libc::c_int) -> io::Result<File> {
+        let c = relative(p)?;
+        let fd = cvt(unsafe {
+            libc::openat(self.fd(), c.as_ptr(), flags | libc::O_CLOEXEC, 0o666 as libc::c_uint)
+        })?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    fn parent_at(&self, p: &Path) -> io::Result<File> {
+        let dir = p.parent().unwrap_or_else(|| Path::new(""));
+        self.open_at(dir, libc::O_RDONLY | libc::O_DIRECTORY)
+    }
+
+    fn is_dir_at(&self, p: &Path) -> io::Result<bool> {
+        let c = relative(p)?;
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        cvt(unsafe { libc::fstatat(self.fd(), c.as_ptr(), &mut st, libc::AT_SYMLINK_NOFOLLOW) })?;
+        Ok(st.st_mode & libc::S_IFMT == libc::S_IFDIR)
+    }
+
+    // The paths in `dir`, each with whether it's a directory
+    fn entries(&self, dir: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        let fd = self.open_at(dir, libc::O_RDONLY | libc::O_DIRECTORY)?.into_raw_fd();
+        let stream = unsafe { libc::fdopendir(fd) };
+        if stream.is_null() {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        let mut names = Vec::new();
+        loop {
+            // An error reading ends the listing like its end does
+            let e = unsafe { libc::readdir(stream) };
+            if e.is_null() {
+                break;
+            }
+            let (name, kind) = unsafe { (CStr::from_ptr((*e).d_name.as_ptr()), (*e).d_type) };
+            let name = OsStr::from_bytes(name.to_bytes());
+            if name != "." && name != ".." {
+                names.push((dir.join(name), kind));
+            }
+        }
+        unsafe { libc::closedir(stream) };
+
+        names.into_iter()
+            .map(|(p, kind)| {
+                let is_dir = match kind {
+                    libc::DT_DIR => true,
+                    // Not every filesystem says
+                    libc::DT_UNKNOWN => self.is_dir_at(&p)?,
+                    _ => false,
+                };
+                Ok((p, is_dir))
+            })
+            .collect()
+    }
+}
+
+#[cfg(unix)]
+impl Fs for DirFs {
+    fn open(&self, p: &Path) -> io::Result<Option<(Box<dyn Read>, FileId)>> {
+        let f = match self.open_at(p, libc::O_RDONLY) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            r => r?,
+        };
+        let id = FileId::of(&f.metadata()?);
+        Ok(Some((Box::new(BufReader::new(f)), id)))
+    }
+
+    fn id(&self, p: &Path) -> io::Result<Option<FileId>> {
+        match self.open_at(p, libc::O_RDONLY) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            r => Ok(Some(FileId::of(&r?.metadata()?))),
+        }
+    }
+
+    fn create(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        let f = self.open_at(p, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC)?;
+        Ok(Box::new(BufWriter::new(f)))
+    }
+
+    fn create_new(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        let f = self.open_at(p, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL)?;
+        Ok(Box::new(BufWriter::new(f)))
+    }
+
+    fn create_sized(&self, p: &Path, size: u64) -> io::Result<Box<dyn Write>> {
+        let f = self.open_at(p, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC)?;
+        if let Err(e) = preallocate(&f, size) {
+            drop(f);
+            let _ = self.remove(p);
+            return Err(e);
+        }
+        Ok(Box::new(BufWriter::new(f)))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_direct(&self, p: &Path, size: Option<u64>) -> io::Result<Box<dyn Write>> {
+        let (f, direct) = open_direct(|flags| {
+            self.open_at(p, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | flags)
+        })?;
+        if let Some(size) = size {
+            if let Err(e) = preallocate(&f, size) {
+                drop(f);
+                let _ = self.remove(p);
+                return Err(e);
+            }
+        }
+        Ok(direct_writer(f, direct))
+    }
+
+    fn append(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        let f = self.open_at(p, libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND)?;
+        Ok(Box::new(BufWriter::new(f)))
+    }
+
+    fn rename(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let (src, dst) = (relative(src)?, relative(dst)?);
+        cvt(unsafe { libc::renameat(self.fd(), src.as_ptr(), self.fd(), dst.as_ptr()) })
+            .map(|_| ())
+    }
+
+    fn remove(&self, p: &Path) -> io::Result<()> {
+        let c = relative(p)?;
+        cvt(unsafe { libc::unlinkat(self.fd(), c.as_ptr(), 0) }).map(|_| ())
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let (c_src, c_dst) = (relative(src)?, relative(dst)?);
+        if unsafe { libc::linkat(self.fd(), c_src.as_ptr(), self.fd(), c_dst.as_ptr(), 0) } == 0 {
+            return Ok(());
+        }
+        // Not every filesystem has hard links
+        self.copy(src, dst)
+    }
+
+    fn copy(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let r = self.open_at(src, libc::O_RDONLY)?;
+        let w = self.open_at(dst, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC)?;
+        copy_file(&r, &w)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self.entries(dir)?.into_iter().map(|(p, _)| p).collect())
+    }
+
+    fn walk(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        let mut dirs = vec![dir.to_owned()];
+        while let Some(dir) = dirs.pop() {
+            for (p, is_dir) in self.entries(&dir)? {
+                if is_dir {
+                    dirs.push(p);
+                } else {
+                    files.push(p);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut sub = PathBuf::new();
+        for c in dir.components() {
+            sub.push(c);
+            let c = relative(&sub)?;
+            if unsafe { libc::mkdirat(self.fd(), c.as_ptr(), 0o777) } != 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&self, p: &Path) -> io::Result<()> {
+        match self.open_at(p, libc::O_RDONLY) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(());
+            }
+            r => r?.sync_all()?,
+        }
+        // The rename itself is only durable once the directory is synced
+        self.parent_at(p)?.sync_all()
+    }
+
+    fn sync_contents(&self, p: &Path) -> io::Result<()> {
+        match self.open_at(p, libc::O_RDONLY) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            r => r?.sync_all(),
+        }
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.open_at(dir, libc::O_RDONLY | libc::O_DIRECTORY)?.sync_all()
+    }
+
+    fn available(&self, p: &Path) -> io::Result<Option<u64>> {
+        let dir = self.parent_at(p)?;
+        let mut st: libc::statvfs = unsafe { mem::zeroed() };
+        cvt(unsafe { libc::fstatvfs(dir.as_raw_fd(), &mut st) })?;
+        Ok(Some(st.f_bavail as u64 * st.f_frsize as u64))
+    }
+
+    fn canonicalize(&self, p: &Path) -> io::Result<PathBuf> {
+        relative(p)?;
+        // Without `.`, so each file has one key
+        let mut canonical = self.path.clone();
+        canonical.extend(p.components().filter(|c| *c != Component::CurDir));
+        Ok(canonical)
+    }
+
+    fn lock_path(&self, p: &Path) -> PathBuf {
+        self.path.join(p)
+    }
+}
+
+// `p` as a C string, if it stays under the directory it's relative
+// to, with `""` the directory itself
+#[cfg(unix)]
+fn relative(p: &Path) -> io::Result<CString> {
+    if p.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("{} isn't under the blobject directory", p.display())));
+    }
+    if p == Path::new("") {
+        return c_path(Path::new("."));
+    }
+    c_path(p)
+}
+
+#[cfg(unix)]
+fn c_path(p: &Path) -> io::Result<CString> {
+    CString::new(p.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(target_os = "linux")]
+fn fd_path(f: &File) -> io::Result<PathBuf> {
+    fs::read_link(format!("/proc/self/fd/{}", f.as_raw_fd()))
+}
+
+#[cfg(target_os = "macos")]
+fn fd_path(f: &File) -> io::Result<PathBuf> {
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    cvt(unsafe { libc::fcntl(f.as_raw_fd(), libc::F_GETPATH, buf.as_mut_ptr()) })?;
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    buf.truncate(len);
+    Ok(PathBuf::from(OsStr::from_bytes(&buf)))
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+fn fd_path(_f: &File) -> io::Result<PathBuf> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+                       "can't find the path of a blobject directory fd"))
+}
+
+#[cfg(unix)]
+fn cvt(r: libc::c_int) -> io::Result<libc::c_int> {
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(r)
+}
+
+/// An in-memory filesystem
+///
+/// Clones share the same files. Paths are used as given, without
+/// resolving `.`, `..` or symlinks, and no directories are needed.
+#[derive(Clone, Debug)]
+pub struct MockFs {
+    inner: Arc<MockInner>,
+}
+
+#[derive(Debug)]
+struct MockInner {
+    key: u64,
+    files: Mutex<HashMap<PathBuf, MockFile>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Clone, Debug)]
+struct MockFile {
+    data: Arc<Vec<u8>>,
+    id: u64,
+}
+
+impl Default for MockFs {
+    fn default() -> MockFs {
+        MockFs::new()
+    }
+}
+
+impl MockFs {
+    pub fn new() -> MockFs {
+        // Starts above `RealFs`'s key under WASI
+        static NEXT_KEY: AtomicU64 = AtomicU64::new(1);
+        MockFs {
+            inner: Arc::new(MockInner {
+                key: NEXT_KEY.fetch_add(1, Ordering::SeqCst),
+                files: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// The contents of the file at `p`
+    pub fn read<P>(&self, p: P) -> Option<Vec<u8>>
+        where P: AsRef<Path>
+    {
+        self.files().get(p.as_ref()).map(|f| (*f.data).clone())
+    }
+
+    /// Replaces the file at `p`, as a process ignoring the blob's
+    /// locks would
+    pub fn write<P>(&self, p: P, data: Vec<u8>)
+        where P: AsRef<Path>
+    {
+        self.store(p.as_ref().to_owned(), data);
+    }
+
+    fn files(&self) -> ::std::sync::MutexGuard<'_, HashMap<PathBuf, MockFile>> {
+        self.inner.files.lock().expect("poisoned mock fs")
+    }
+
+    fn store(&self, p: PathBuf, data: Vec<u8>) -> u64 {
+        let id = self.inner.next_id.fetch_add(1, Ordering::SeqCst);
+        self.files().insert(p, MockFile { data: Arc::new(data), id });
+        id
+    }
+}
+
+impl Fs for MockFs {
+    fn open(&self, p: &Path) -> io::Result<Option<(Box<dyn Read>, FileId)>> {
+        Ok(self.files().get(p).map(|f| {
+            let r: Box<dyn Read> = Box::new(Cursor::new(SharedBytes(f.data.clone())));
+            (r, FileId::counter(f.id))
+        }))
+    }
+
+    fn id(&self, p: &Path) -> io::Result<Option<FileId>> {
+        Ok(self.files().get(p).map(|f| FileId::counter(f.id)))
+    }
+
+    fn create(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        let id = self.store(p.to_owned(), Vec::new());
+        Ok(Box::new(MockWriter {
+            fs: self.clone(),
+            path: p.to_owned(),
+            id,
+            buf: Vec::new(),
+        }))
+    }
+
+    fn create_new(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        let mut files = self.files();
+        if files.contains_key(p) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let id = self.inner.next_id.fetch_add(1, Ordering::SeqCst);
+        files.insert(p.to_owned(), MockFile { data: Arc::new(Vec::new()), id });
+        Ok(Box::new(MockWriter {
+            fs: self.clone(),
+            path: p.to_owned(),
+            id,
+            buf: Vec::new(),
+        }))
+    }
+
+    fn append(&self, p: &Path) -> io::Result<Box<dyn Write>> {
+        let existing = self.files().get(p).cloned();
+        let (id, buf) = match existing {
+            Some(f) => (f.id, (*f.data).clone()),
+            None => (self.store(p.to_owned(), Vec::new()), Vec::new()),
+        };
+        Ok(Box::new(MockWriter {
+            fs: self.clone(),
+            path: p.to_owned(),
+            id,
+            buf,
+        }))
+    }
+
+    fn rename(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let mut files = self.files();
+        let f = files.remove(src)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        files.insert(dst.to_owned(), f);
+        Ok(())
+    }
+
+    fn remove(&self, p: &Path) -> io::Result<()> {
+        self.files().remove(p)
+            .map(|_| ())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let mut files = self.files();
+        let f = files.get(src).cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        files.insert(dst.to_owned(), f);
+        Ok(())
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self.files().keys()
+           .filter(|p| p.parent() == Some(dir))
+           .cloned()
+           .collect())
+    }
+
+    fn walk(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self.files().keys()
+           .filter(|p| p.starts_with(dir))
+           .cloned()
+           .collect())
+    }
+
+    fn sync(&self, _p: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn canonicalize(&self, p: &Path) -> io::Result<PathBuf> {
+        Ok(p.to_owned())
+    }
+
+    fn in_process(&self) -> Option<u64> {
+        Some(self.inner.key)
+    }
+}
+
+struct SharedBytes(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// Contents become visible on flush, like a buffered file
+struct MockWriter {
+    fs: MockFs,
+    path: PathBuf,
+    id: u64,
+    buf: Vec<u8>,
+}
+
+impl Write for MockWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut files = self.fs.files();
+        // Unless it was renamed or removed in the meantime
+        if let Some(f) = files.get_mut(&self.path) {
+            if f.id == self.id {
+                f.data = Arc::new(self.buf.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MockWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// Copies `src` into `dst`, sharing its blocks where the filesystem
+// can, and keeping its modification time, which is when it was
+// committed
+fn copy_file(src: &File, dst: &File) -> io::Result<()> {
+    if let Err(e) = reflink(src, dst) {
+        debug!("copying blobject file, can't reflink: {}", e);
+        // Between files this is `copy_file_range` on Linux, which
+        // shares blocks on some filesystems without reflinks, and
+        // otherwise at least copies in the kernel
+        io::copy(&mut &*src, &mut &*dst)?;
+    }
+    dst.set_modified(src.metadata()?.modified()?)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &File, dst: &File) -> io::Result<()> {
+    cvt(unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) }).map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &File, _dst: &File) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "no reflinks"))
+}
+
+// Reserves blocks without changing the file's length, so a short
+// write leaves no padding
+#[cfg(target_os = "linux")]
+fn preallocate(f: &File, size: u64) -> io::Result<()> {
+    // A length of 0 is invalid
+    if size == 0 {
+        return Ok(());
+    }
+    let r = unsafe {
+        libc::fallocate(f.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, size as libc::off_t)
+    };
+    if r == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        // Not every filesystem can
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(()),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_f: &File, _size: u64) -> io::Result<()> {
+    Ok(())
+}
+
+// Opens a file with `open`, passing it `O_DIRECT`, or nothing if the
+// filesystem can't, and says which
+#[cfg(target_os = "linux")]
+fn open_direct<F>(open: F) -> io::Result<(File, bool)>
+    where F: Fn(libc::c_int) -> io::Result<File>
+{
+    match open(libc::O_DIRECT) {
+        Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            debug!("writing blobject file through the page cache, no O_DIRECT");
+            Ok((open(0)?, false))
+        }
+        r => Ok((r?, true)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn direct_writer(f: File, direct: bool) -> Box<dyn Write> {
+    if direct {
+        Box::new(DirectWriter::new(f))
+    } else {
+        Box::new(BufWriter::new(f))
+    }
+}
+
+// Direct writes must be aligned in memory, length and file offset,
+// and this is enough for any block size in use
+#[cfg(target_os = "linux")]
+const DIRECT_ALIGN: usize = 4096;
+#[cfg(target_os = "linux")]
+const DIRECT_BUFFER: usize = 1 << 20;
+
+// Writes an `O_DIRECT` file in aligned blocks, and the last partial
+// block, which can't be written directly, through the page cache
+#[cfg(target_os = "linux")]
+struct DirectWriter {
+    f: File,
+    // Aligned from `start`
+    buf: Vec<u8>,
+    start: usize,
+    len: usize,
+    // Where `buf` goes in the file
+    pos: u64,
+    direct: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl DirectWriter {
+    fn new(f: File) -> DirectWriter {
+        let buf = vec![0; DIRECT_BUFFER + DIRECT_ALIGN];
+        let start = buf.as_ptr().align_offset(DIRECT_ALIGN);
+        DirectWriter { f, buf, start, len: 0, pos: 0, direct: true }
+    }
+
+    // Writes out the first `n` bytes of the buffer
+    fn write_out(&mut self, n: usize) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        let mut done = 0;
+        while done < n {
+            let data = &self.buf[self.start + done..self.start + n];
+            match self.f.write_at(data, self.pos) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(w) => {
+                    done += w;
+                    self.pos += w as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                // Not every file on a filesystem with `O_DIRECT` can be
+                // written that way
+                Err(ref e) if self.direct && e.raw_os_error() == Some(libc::EINVAL) => {
+                    debug!("writing blobject file through the page cache: {}", e);
+                    self.undirect()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.buf.copy_within(self.start + n..self.start + self.len, self.start);
+        self.len -= n;
+        Ok(())
+    }
+
+    fn undirect(&mut self) -> io::Result<()> {
+        let fd = self.f.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.direct = false;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Write for DirectWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(DIRECT_BUFFER - self.len);
+        let at = self.start + self.len;
+        self.buf[at..at + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        if self.len == DIRECT_BUFFER {
+            self.write_out(DIRECT_BUFFER)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.direct {
+            self.write_out(self.len / DIRECT_ALIGN * DIRECT_ALIGN)?;
+            if self.len > 0 {
+                // Anything written after is unaligned too
+                self.undirect()?;
+            }
+        }
+        self.write_out(self.len)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for DirectWriter {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            let _ = self.flush();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn available(p: &Path) -> io::Result<Option<u64>> {
+    let dir = match p.parent() {
+        Some(d) if d != Path::new("") => d,
+        _ => Path::new("."),
+    };
+    let dir = c_path(dir)?;
+    let mut st: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(dir.as_ptr(), &mut st) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(st.f_bavail as u64 * st.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn available(_p: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direct_buffer_is_aligned() {
+        let f = tempfile("aligned");
+        let w = DirectWriter::new(File::create(&f).unwrap());
+        assert_eq!((w.buf.as_ptr() as usize + w.start) % DIRECT_ALIGN, 0);
+        assert!(w.start + DIRECT_BUFFER <= w.buf.len());
+        let _ = fs::remove_file(&f);
+    }
+
+    #[test]
+    fn direct_writes_whole_files() {
+        let p = tempfile("direct");
+        let sizes = [0, 1, DIRECT_ALIGN - 1, DIRECT_ALIGN, DIRECT_ALIGN + 1, DIRECT_BUFFER,
+                     DIRECT_BUFFER * 2 + 123];
+        for &size in &sizes {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let mut w = RealFs.create_direct(&p, Some(size as u64)).unwrap();
+            // In uneven pieces, as serializers write
+            for chunk in data.chunks(1000) {
+                w.write_all(chunk).unwrap();
+            }
+            w.flush().unwrap();
+            drop(w);
+            assert_eq!(fs::read(&p).unwrap(), data, "size {}", size);
+        }
+        let _ = fs::remove_file(&p);
+    }
+
+    #[test]
+    fn direct_falls_back() {
+        let p = tempfile("fallback");
+        let (f, direct) = open_direct(|flags| {
+            if flags & libc::O_DIRECT != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            File::create(&p)
+        }).unwrap();
+        assert!(!direct);
+        let mut w = direct_writer(f, direct);
+        w.write_all(b"[1]").unwrap();
+        drop(w);
+        assert_eq!(fs::read(&p).unwrap(), b"[1]");
+        let _ = fs::remove_file(&p);
+    }
+
+    #[test]
+    fn creates_new_files_once() {
+        let p = tempfile("new");
+        let _ = fs::remove_file(&p);
+        let mock = MockFs::new();
+        for fs in [&RealFs as &dyn Fs, &mock] {
+            fs.create_new(&p).unwrap();
+            let e = fs.create_new(&p).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+            fs.remove(&p).unwrap();
+        }
+    }
+
+    fn tempfile(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("blobject-vfs-{}-{}", name, std::process::id()))
+    }
+}
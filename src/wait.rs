@@ -0,0 +1,101 @@
+//! Reporting on guards waiting for the flock
+//!
+//! With `Builder::on_lock_wait`, a guard that finds the flock held
+//! polls for it instead of blocking, calling back every so often with
+//! how long it's waited and, if the holder gave one with
+//! `get_mut_with_reason`, who holds it and why.
+//!
+//! `Builder::acquire` decides whether a guard waits at all: by
+//! default it blocks until the flock is free, but it can poll with
+//! backoff, giving up after a while, or fail at once with
+//! `ErrorKind::Locked`.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{DirtyFlock, ErrorKind, FlockGuard, Fs, Result};
+use super::reason::{self, LockInfo};
+
+/// One report from a guard waiting for the flock
+#[derive(Clone, Debug)]
+pub struct LockWait {
+    /// Time since the guard started waiting
+    pub elapsed: Duration,
+    /// Whether the guard is for `get_mut`
+    pub exclusive: bool,
+    pub holder: Option<LockInfo>,
+}
+
+pub type OnLockWait = dyn Fn(&LockWait) + Send + Sync;
+
+/// How `get` and `get_mut` take the flock when another handle holds
+/// it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Acquire {
+    /// Wait as long as it takes
+    Block,
+    /// Poll, first after a millisecond and then twice as long each
+    /// time up to `max_interval`, failing with `ErrorKind::Locked`
+    /// after `timeout` if there is one
+    Backoff { max_interval: Duration, timeout: Option<Duration> },
+    /// Fail with `ErrorKind::Locked` rather than wait
+    FailFast,
+}
+
+// The longest between polls, so the lock is taken soon after it's
+// released
+const POLL: Duration = Duration::from_millis(10);
+
+// The first backoff
+const BACKOFF: Duration = Duration::from_millis(1);
+
+pub fn lock<'a>(flock: &'a DirtyFlock, exclusive: bool, fs: &dyn Fs, p: &Path, acquire: Acquire,
+                on_wait: Option<&(Duration, Arc<OnLockWait>)>) -> Result<FlockGuard<'a>> {
+    match (acquire, on_wait) {
+        (Acquire::Block, None) if exclusive => return FlockGuard::exclusive(flock),
+        (Acquire::Block, None) => return FlockGuard::shared(flock),
+        _ => (),
+    }
+
+    let start = Instant::now();
+    let mut next = on_wait.map(|&(interval, _)| interval);
+    let mut backoff = BACKOFF;
+    loop {
+        let guard = if exclusive {
+            FlockGuard::try_exclusive(flock)?
+        } else {
+            FlockGuard::try_shared(flock)?
+        };
+        if let Some(guard) = guard {
+            return Ok(guard);
+        }
+
+        let elapsed = start.elapsed();
+        let sleep = match acquire {
+            Acquire::Block => POLL,
+            Acquire::Backoff { timeout: Some(t), .. } if elapsed >= t => {
+                return Err(ErrorKind::Locked.into());
+            }
+            Acquire::Backoff { max_interval, timeout } => {
+                let sleep = backoff.min(max_interval);
+                backoff = backoff.saturating_mul(2);
+                timeout.map_or(sleep, |t| sleep.min(t - elapsed))
+            }
+            Acquire::FailFast => return Err(ErrorKind::Locked.into()),
+        };
+        if let (Some(&(interval, ref f)), Some(n)) = (on_wait, next.as_mut()) {
+            if elapsed >= *n {
+                let holder = reason::read(fs, p).unwrap_or_else(|e| {
+                    debug!("reading blobject lock reason: {}", e);
+                    None
+                });
+                f(&LockWait { elapsed, exclusive, holder });
+                *n += interval;
+            }
+            thread::sleep(sleep.min(interval));
+        } else {
+            thread::sleep(sleep);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Loading and revalidating off the calling thread
+
+use serde::{Serialize, Deserialize};
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::Ordering;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use super::{AtomBlob, DirtyFlock, FlockGuard, Fs, IntentLock, Parts, Result,
+            ResultExt, Shared, State, poisoned};
+
+impl<T> AtomBlob<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    /// Loads the current value on a background thread
+    ///
+    /// A later `get()` that finds the same file on disk doesn't parse
+    /// it again.
+    pub fn warm(&self) -> JoinHandle<Result<()>> {
+        let mut blob = self.clone();
+        thread::spawn(move || {
+            blob.get()?;
+            debug!("warmed blobject");
+            Ok(())
+        })
+    }
+}
+
+pub fn revalidate<T>(shared: Weak<Shared<T>>, path: PathBuf, fs: Arc<dyn Fs>,
+                     writer_priority: bool, interval: Duration) -> Result<()>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Send + Sync + 'static,
+{
+    thread::Builder::new()
+        .name("blobject-revalidate".to_string())
+        .spawn(move || {
+            let in_process = fs.in_process();
+            let flock = DirtyFlock::new(fs.lock_path(&path.with_extension("flock")), in_process);
+            let intent = if writer_priority {
+                Some(IntentLock::new(fs.lock_path(&path.with_extension("intent")), in_process))
+            } else {
+                None
+            };
+
+            loop {
+                thread::sleep(interval);
+                let shared = match shared.upgrade() {
+                    Some(shared) => shared,
+                    None => break,
+                };
+                let parts = Parts {
+                    flock: &flock,
+                    intent: intent.as_ref(),
+                    shared: &shared,
+                    path: &path,
+                    fs: &*fs,
+                };
+                if let Err(e) = revalidate_once(parts) {
+                    warn!("revalidating blobject: {}", e);
+                }
+            }
+        })
+        .chain_err(|| "starting blobject revalidation")?;
+
+    Ok(())
+}
+
+// Unlike a guard this doesn't count as an access, and leaves
+// unloaded values unloaded
+fn revalidate_once<T>(parts: Parts<'_, T>) -> Result<()>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    let _intent = match parts.intent {
+        Some(i) => Some(i.lock_shared()?),
+        None => None,
+    };
+    let flock = FlockGuard::shared(parts.flock)?;
+    parts.shared.counters.checked(parts.now());
+    if flock.state() == State::Dirty
+        && !parts.shared.unloaded.load(Ordering::SeqCst)
+    {
+        let mut val = parts.shared.v.write().map_err(poisoned)?;
+        parts.reload(&mut val)?;
+    }
+    Ok(())
+}
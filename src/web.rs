@@ -0,0 +1,306 @@
+//! Blobs as shared state for web handlers
+//!
+//! Web frameworks hand each request handler a clone of the app's
+//! state, which must be `Clone + Send + Sync`, and `AtomBlob` needs
+//! `&mut` for every guard. A `BlobState` is that state: a cheap clone
+//! of a pool of handles to one blob, from which a handler takes a
+//! `Blob`, a snapshot of the value it can hold as long as it likes,
+//! or a `BlobWriter`, for an update made under the exclusive lock and
+//! committed before it returns. Each call takes a handle of its own
+//! from the pool, so snapshots are taken alongside each other, and
+//! wait on a writer only for the blob's locks. A snapshot is cloned
+//! from the value only once per change, and shared by the handlers
+//! that read it until the next.
+//!
+//! With the `axum` feature, `Blob` and `BlobWriter` are extractors,
+//! from any state a `BlobState` can be had from by `FromRef`:
+//!
+//! ```ignore
+//! #[derive(Clone)]
+//! struct App { config: BlobState<Config> }
+//!
+//! impl FromRef<App> for BlobState<Config> {
+//!     fn from_ref(app: &App) -> Self { app.config.clone() }
+//! }
+//!
+//! async fn show(config: Blob<Config>) -> Json<Config> {
+//!     Json((*config).clone())
+//! }
+//!
+//! let app = Router::new().route("/config", get(show)).with_state(app);
+//! ```
+//!
+//! The `Blob` extractor takes its snapshot on a blocking thread.
+//! Updating may wait on the blob's locks, so from async handlers
+//! `BlobWriter::update` belongs on a blocking thread too, or use
+//! `async_blob` instead.
+
+use serde::{Serialize, Deserialize};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use super::{AtomBlob, Result, lock};
+#[cfg(feature = "axum")]
+use axum::extract::{FromRef, FromRequestParts};
+#[cfg(feature = "axum")]
+use axum::http::StatusCode;
+#[cfg(feature = "axum")]
+use axum::http::request::Parts;
+#[cfg(feature = "axum")]
+use axum::response::{IntoResponse, Response};
+#[cfg(feature = "axum")]
+use std::convert::Infallible;
+#[cfg(feature = "axum")]
+use std::result::Result as StdResult;
+#[cfg(feature = "axum")]
+use super::Error;
+
+/// A blob shared by handlers
+pub struct BlobState<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    inner: Arc<Inner<T>>,
+}
+
+struct Inner<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    // Handles no call is using, and the one others are cloned from
+    // when none are idle
+    idle: Mutex<Vec<AtomBlob<T>>>,
+    blob: Mutex<AtomBlob<T>>,
+    // The last snapshot taken, and the generation it's of
+    snapshot: Mutex<Option<(u64, Arc<T>)>>,
+}
+
+/// The value as of when it was taken
+pub struct Blob<T>(Arc<T>);
+
+/// Makes updates to a `BlobState`'s blob
+pub struct BlobWriter<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    state: BlobState<T>,
+}
+
+impl<T> BlobState<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Clone,
+{
+    pub fn new(blob: AtomBlob<T>) -> BlobState<T> {
+        BlobState {
+            inner: Arc::new(Inner {
+                idle: Mutex::new(Vec::new()),
+                blob: Mutex::new(blob),
+                snapshot: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Opens the blob at `p`, as `AtomBlob::new`
+    pub fn open<P>(p: P) -> Result<BlobState<T>>
+        where P: AsRef<Path>, T: Send + Sync + 'static,
+    {
+        AtomBlob::new(p).map(BlobState::new)
+    }
+
+    /// The value now, under the shared lock only while it's checked
+    pub fn snapshot(&self) -> Result<Blob<T>> {
+        self.with_handle(|blob| {
+            let v = blob.get()?;
+            let generation = v.generation();
+            if let Some((g, ref s)) = *lock(&self.inner.snapshot) {
+                if g == generation {
+                    return Ok(Blob(s.clone()));
+                }
+            }
+            let s = Arc::new(T::clone(&v));
+            *lock(&self.inner.snapshot) = Some((generation, s.clone()));
+            Ok(Blob(s))
+        })
+    }
+
+    // Runs `f` on a handle no other call is using
+    fn with_handle<F, R>(&self, f: F) -> R
+        where F: FnOnce(&mut AtomBlob<T>) -> R
+    {
+        let idle = lock(&self.inner.idle).pop();
+        let mut blob = idle.unwrap_or_else(|| lock(&self.inner.blob).clone());
+        let r = f(&mut blob);
+        lock(&self.inner.idle).push(blob);
+        r
+    }
+
+    /// A writer for the blob
+    pub fn writer(&self) -> BlobWriter<T> {
+        BlobWriter { state: self.clone() }
+    }
+}
+
+impl<T> Clone for BlobState<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    fn clone(&self) -> BlobState<T> {
+        BlobState { inner: self.inner.clone() }
+    }
+}
+
+impl<T> BlobWriter<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default + Clone,
+{
+    /// Changes the value with `f` under the exclusive lock, and
+    /// commits it, returning what `f` did
+    ///
+    /// A failed commit is tried again as the guard drops, like any
+    /// guard's; see `Builder::strict_no_panic`.
+    pub fn update<F, R>(&self, f: F) -> Result<R>
+        where F: FnOnce(&mut T) -> R
+    {
+        self.state.with_handle(|blob| {
+            let mut guard = blob.get_mut()?;
+            let r = f(&mut guard);
+            guard.commit()?;
+            Ok(r)
+        })
+    }
+
+    /// The state it writes to
+    pub fn state(&self) -> &BlobState<T> {
+        &self.state
+    }
+}
+
+impl<T> Clone for BlobWriter<T>
+    where for <'de> T: Serialize + Deserialize<'de> + Default,
+{
+    fn clone(&self) -> BlobWriter<T> {
+        BlobWriter { state: self.state.clone() }
+    }
+}
+
+impl<T> Blob<T> {
+    /// The snapshot, to keep past the `Blob`
+    pub fn into_arc(self) -> Arc<T> {
+        self.0
+    }
+}
+
+impl<T> Clone for Blob<T> {
+    fn clone(&self) -> Blob<T> {
+        Blob(self.0.clone())
+    }
+}
+
+impl<T> Deref for Blob<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// Why a `Blob` couldn't be extracted: answered with a 500
+#[cfg(feature = "axum")]
+#[derive(Debug)]
+pub struct BlobRejection(pub Error);
+
+#[cfg(feature = "axum")]
+#[axum::async_trait]
+impl<S, T> FromRequestParts<S> for Blob<T>
+    where BlobState<T>: FromRef<S>,
+          S: Send + Sync,
+          for <'de> T: Serialize + Deserialize<'de> + Default + Clone + Send + Sync + 'static,
+{
+    type Rejection = BlobRejection;
+
+    async fn from_request_parts(_: &mut Parts, state: &S) -> StdResult<Blob<T>, BlobRejection> {
+        let state = BlobState::<T>::from_ref(state);
+        match tokio::task::spawn_blocking(move || state.snapshot()).await {
+            Ok(r) => r.map_err(BlobRejection),
+            Err(e) => Err(BlobRejection(format!("snapshot failed: {}", e).into())),
+        }
+    }
+}
+
+#[cfg(feature = "axum")]
+#[axum::async_trait]
+impl<S, T> FromRequestParts<S> for BlobWriter<T>
+    where BlobState<T>: FromRef<S>,
+          S: Send + Sync,
+          for <'de> T: Serialize + Deserialize<'de> + Default + Clone,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(_: &mut Parts, state: &S) -> StdResult<BlobWriter<T>, Infallible> {
+        Ok(BlobState::<T>::from_ref(state).writer())
+    }
+}
+
+#[cfg(feature = "axum")]
+impl IntoResponse for BlobRejection {
+    fn into_response(self) -> Response {
+        (StatusCode::INTERNAL_SERVER_ERROR, self.0.to_string()).into_response()
+    }
+}
+
+#[cfg(all(test, feature = "axum"))]
+mod tests {
+    use axum::extract::{FromRef, FromRequestParts};
+    use axum::http::Request;
+    use std::sync::mpsc;
+    use std::thread;
+    use super::{Blob, BlobState, BlobWriter};
+    use super::super::Builder;
+    use super::super::vfs::MockFs;
+    use super::super::wait::Acquire;
+
+    #[derive(Clone)]
+    struct App {
+        counts: BlobState<Vec<u32>>,
+    }
+
+    impl FromRef<App> for BlobState<Vec<u32>> {
+        fn from_ref(app: &App) -> Self {
+            app.counts.clone()
+        }
+    }
+
+    fn app() -> App {
+        let blob = Builder::new("web.json").fs(MockFs::new()).acquire(Acquire::FailFast).open().unwrap();
+        App { counts: BlobState::new(blob) }
+    }
+
+    #[test]
+    fn extracts() {
+        let app = app();
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let mut parts = Request::builder().body(()).unwrap().into_parts().0;
+            let writer = BlobWriter::<Vec<u32>>::from_request_parts(&mut parts, &app).await.unwrap();
+            writer.update(|v| v.push(1)).unwrap();
+            let blob = Blob::<Vec<u32>>::from_request_parts(&mut parts, &app).await.unwrap();
+            assert_eq!(*blob, vec![1]);
+        });
+    }
+
+    #[test]
+    fn snapshots_beside_updates() {
+        let app = app();
+        app.counts.writer().update(|v| v.push(1)).unwrap();
+        let (started, wait) = mpsc::channel();
+        let (finish, finished) = mpsc::channel::<()>();
+        let writer = app.counts.writer();
+        let updating = thread::spawn(move || {
+            writer.update(|v| {
+                started.send(()).unwrap();
+                finished.recv().unwrap();
+                v.push(2);
+            }).unwrap();
+        });
+        wait.recv().unwrap();
+        // Waits on the writer's lock, failing fast, not on its handle
+        assert!(app.counts.snapshot().is_err());
+        finish.send(()).unwrap();
+        updating.join().unwrap();
+        assert_eq!(*app.counts.snapshot().unwrap(), vec![1, 2]);
+    }
+}